@@ -1,6 +1,6 @@
 use crate::utils::ephemeral_distacian_cluster;
 use clap::{Parser, Subcommand, ValueEnum};
-use distacean::{ClusterDistaceanConfig, Distacean, NodeId};
+use distacean::{ClusterDistaceanConfig, Distacean, FIFOMessage, NodeId};
 use tracing_subscriber::EnvFilter;
 mod utils;
 
@@ -62,6 +62,7 @@ async fn main() -> std::io::Result<()> {
                     (2, "127.0.0.1:22002".to_string()),
                     (3, "127.0.0.1:22003".to_string()),
                 ],
+                ..Default::default()
             })
             .await
             .map_err(|e| {
@@ -103,18 +104,26 @@ async fn main() -> std::io::Result<()> {
         tokio::spawn(async move {
             let queues = dist_clone.fifo_queues();
             loop {
-                let items: Vec<String> = queues
-                    .dequeue(queue_name, 2)
+                let messages: Vec<FIFOMessage<String>> = queues
+                    .reserve_wait(
+                        queue_name,
+                        2,
+                        std::time::Duration::from_secs(30),
+                        std::time::Duration::from_secs(5),
+                    )
                     .await
-                    .expect("Failed to dequeue");
-                if items.is_empty() {
-                    println!("Queue is empty, waiting to dequeue");
-                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    .expect("Failed to reserve");
+                if messages.is_empty() {
+                    println!("Queue is still empty, waiting to dequeue");
                     continue;
                 }
-                for item in items {
-                    println!("Dequeued {} from {}", item, queue_name);
+                for message in &messages {
+                    println!("Dequeued {} from {}", message.value, queue_name);
                 }
+                queues
+                    .ack(queue_name, messages.iter().map(|message| message.handle))
+                    .await
+                    .expect("Failed to ack");
             }
         });
     }
@@ -1,13 +1,16 @@
 use actix_web::{App, HttpResponse, HttpServer, Responder, web};
 use clap::{Parser, Subcommand};
-use distacean::{ClusterDistaceanConfig, Distacean, NodeId, ReadConsistency, ReadSource};
+use distacean::{
+    ClusterDistaceanConfig, Distacean, IndexDefinition, MessagePackNamed, NodeId, ReadConsistency,
+    ReadSource,
+};
 use tracing_subscriber::EnvFilter;
 
 use crate::utils::ephemeral_distacian_cluster;
 mod utils;
 
 struct AppState {
-    distkv: distacean::DistKV,
+    distkv: distacean::DistKV<MessagePackNamed>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -50,7 +53,7 @@ async fn redirect(data: web::Data<AppState>, path: web::Path<String>) -> impl Re
     {
         Ok(Some(url)) => url,
         Ok(None) => {
-            return HttpResponse::InternalServerError().body(format!("Url not found in DistKV"));
+            return HttpResponse::NotFound().body("Url not found in DistKV");
         }
         Err(e) => {
             return HttpResponse::InternalServerError()
@@ -63,6 +66,37 @@ async fn redirect(data: web::Data<AppState>, path: web::Path<String>) -> impl Re
         .finish()
 }
 
+#[derive(serde::Deserialize)]
+struct LookupQuery {
+    url: String,
+}
+
+async fn lookup(data: web::Data<AppState>, query: web::Query<LookupQuery>) -> impl Responder {
+    let url = query.into_inner().url;
+    let matches = match data
+        .distkv
+        .query_index("url", url.clone()..=url)
+        .limit(1)
+        .local()
+        .as_is()
+        .execute::<ShortenRequest>()
+        .await
+    {
+        Ok(matches) => matches,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to query the URL index: {}", e));
+        }
+    };
+
+    match matches.into_iter().next() {
+        Some((_, short, _)) => {
+            HttpResponse::Ok().body(format!("http://localhost:8080/l/{}", short.hash))
+        }
+        None => HttpResponse::NotFound().body("Url not found in DistKV"),
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(name = "distacean", subcommand = "Ephemeral")]
 struct Cli {
@@ -117,6 +151,7 @@ async fn main() -> std::io::Result<()> {
                     (2, "127.0.0.1:22002".to_string()),
                     (3, "127.0.0.1:22003".to_string()),
                 ],
+                ..Default::default()
             })
             .await
             .map_err(|e| {
@@ -131,7 +166,12 @@ async fn main() -> std::io::Result<()> {
     // Sleep for a second to warm up
     tokio::time::sleep(std::time::Duration::from_secs(1)).await;
 
-    let kv = distacean.kv_store();
+    // Indexes find fields by name, so values are written as named MessagePack maps
+    let kv = distacean.kv_store().with_codec(MessagePackNamed);
+    // Index stored URLs so that they can be looked up without knowing their hash
+    kv.create_index("url", IndexDefinition::new("", &["url"]))
+        .await
+        .map_err(|e| std::io::Error::other(format!("Failed to create URL index: {}", e)))?;
     let app_state = web::Data::new(AppState { distkv: kv });
 
     println!("Starting URL shortener at http://localhost:{http_port}");
@@ -141,6 +181,7 @@ async fn main() -> std::io::Result<()> {
             .app_data(app_state.clone())
             .route("/shorten", web::post().to(shorten))
             .route("/l/{hash}", web::get().to(redirect))
+            .route("/lookup", web::get().to(lookup))
     })
     .bind(format!("127.0.0.1:{http_port}"))?
     .run()
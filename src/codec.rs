@@ -0,0 +1,156 @@
+use serde::{Serialize, de::DeserializeOwned};
+use thiserror::Error;
+
+/// Error raised while encoding or decoding a value with a [`Codec`].
+#[derive(Error, Debug)]
+#[error("Codec error: {0}")]
+pub struct CodecError(Box<dyn std::error::Error + Send + Sync>);
+
+impl CodecError {
+    pub fn new(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self(error.into())
+    }
+}
+
+/// Converts values of type `T` to and from the bytes stored in the cluster.
+///
+/// A codec is chosen per `DistKV` handle with `DistKV::with_codec`, so handles with different
+/// codecs can share the same store.
+pub trait Codec<T>: Clone + Send + Sync + 'static {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// MessagePack via `rmp_serde`. This is the default codec. Structs are written as arrays, in
+/// field order.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for MessagePack {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        rmp_serde::to_vec(value).map_err(CodecError::new)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        rmp_serde::from_slice(bytes).map_err(CodecError::new)
+    }
+}
+
+/// MessagePack with structs written as maps keyed by field name, so that secondary indexes can
+/// find their fields. Indexed keyspaces require it. Values written by `MessagePack` still
+/// decode.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackNamed;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for MessagePackNamed {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        rmp_serde::to_vec_named(value).map_err(CodecError::new)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        rmp_serde::from_slice(bytes).map_err(CodecError::new)
+    }
+}
+
+/// JSON via `serde_json`.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl<T: Serialize + DeserializeOwned> Codec<T> for Json {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(CodecError::new)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(CodecError::new)
+    }
+}
+
+/// Bincode via `bincode`.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl<T: Serialize + DeserializeOwned> Codec<T> for Bincode {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(value).map_err(CodecError::new)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        bincode::deserialize(bytes).map_err(CodecError::new)
+    }
+}
+
+/// Stores bytes and UTF-8 strings as they are, without any framing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Raw;
+
+impl Codec<Vec<u8>> for Raw {
+    fn encode(&self, value: &Vec<u8>) -> Result<Vec<u8>, CodecError> {
+        Ok(value.clone())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, CodecError> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Codec<String> for Raw {
+    fn encode(&self, value: &String) -> Result<Vec<u8>, CodecError> {
+        Ok(value.as_bytes().to_vec())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<String, CodecError> {
+        String::from_utf8(bytes.to_vec()).map_err(CodecError::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Order {
+        id: u64,
+        status: String,
+    }
+
+    fn order() -> Order {
+        Order {
+            id: 7,
+            status: "paid".into(),
+        }
+    }
+
+    #[test]
+    fn message_pack_codecs_round_trip_and_read_each_other() {
+        let positional = Codec::<Order>::encode(&MessagePack, &order()).unwrap();
+        let named = Codec::<Order>::encode(&MessagePackNamed, &order()).unwrap();
+        assert_ne!(positional, named);
+
+        for bytes in [&positional, &named] {
+            assert_eq!(
+                Codec::<Order>::decode(&MessagePack, bytes).unwrap(),
+                order()
+            );
+            assert_eq!(
+                Codec::<Order>::decode(&MessagePackNamed, bytes).unwrap(),
+                order()
+            );
+        }
+    }
+
+    #[test]
+    fn raw_stores_bytes_unframed_and_rejects_invalid_utf8() {
+        let bytes = vec![0x00, 0xFF, 0x10];
+        assert_eq!(Raw.encode(&bytes).unwrap(), bytes);
+        assert_eq!(Codec::<Vec<u8>>::decode(&Raw, &bytes).unwrap(), bytes);
+
+        assert_eq!(Raw.encode(&"key".to_string()).unwrap(), b"key");
+        assert!(Codec::<String>::decode(&Raw, &bytes).is_err());
+    }
+}
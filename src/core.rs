@@ -10,21 +10,34 @@ use openraft::raft::linearizable_read::Linearizer;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
 
+use crate::codec::MessagePack;
 use crate::distkv::DistKV;
 use crate::distkv::DistKVCore;
+use crate::limits::Limits;
 use crate::network_tcp::RaftPeerManager;
 use crate::network_tcp::TcpStreamStarter;
 use crate::peernet::PeerConnection;
 use crate::peernet::PeerManager;
 use crate::peernet::StartableStream;
+use crate::protocol::ForwardedWriteResult;
 use crate::protocol::LinearizerData;
 use crate::protocol::ReadPolicy;
+use crate::protocol::ReadQuery;
+use crate::protocol::ReadQueryError;
+use crate::protocol::ReadQueryResult;
 use crate::protocol::RequestType;
+use crate::raft::FIFOOperation;
 use crate::raft::RequestOperation;
+use crate::raft::ResponseResult;
+use crate::raft::SessionToken;
 use crate::raft::StateMachineStore;
 use crate::raft::TypeConfig;
+use crate::raft::store::fifo::FIFOResponse;
 use crate::raft::{NodeId, Raft, Request, Response};
+use crate::retention::{Retention, run_compactor};
 use crate::router::route_peer_connection_messages;
+use crate::util::LeaderContact;
+use crate::util::now_ms;
 
 // #[derive(Error, Debug)]
 // pub enum DistaceanSetupError {
@@ -32,14 +45,22 @@ use crate::router::route_peer_connection_messages;
 //     Unknown,
 // }
 
+/// Build with `..Default::default()` to keep the default limits and retention.
+#[derive(Debug, Clone, Default)]
 pub struct SingleNodeDistaceanConfig {
     pub node_id: NodeId,
+    pub limits: Limits,
+    pub retention: Retention,
 }
 
+/// Build with `..Default::default()` to keep the default limits and retention.
+#[derive(Debug, Clone, Default)]
 pub struct ClusterDistaceanConfig {
     pub node_id: NodeId,
     pub tcp_port: u16,
     pub nodes: Vec<(NodeId, String)>,
+    pub limits: Limits,
+    pub retention: Retention,
 }
 
 // pub enum DistaceanSetupConfig {
@@ -59,6 +80,8 @@ pub struct DistaceanCore {
     peer_manager: Arc<PeerManager<TcpStream, TcpStreamStarter>>,
     pub(crate) state_machine_store: StateMachineStore,
     request_seq_id: std::sync::atomic::AtomicU64,
+    leader_contact: LeaderContact,
+    pub(crate) limits: Limits,
 }
 
 #[derive(Clone)]
@@ -123,11 +146,21 @@ impl Distacean {
         .unwrap();
 
         let rclone = raft.clone();
+        let state_machine_store_clone = state_machine_store.clone();
+        let leader_contact = LeaderContact::new();
+        let leader_contact_clone = leader_contact.clone();
+        let limits_clone = opts.limits.clone();
         tokio::spawn(async move {
             loop {
                 match on_new_peer_receiver.recv().await {
                     Ok(msg) => {
-                        route_peer_connection_messages(msg, rclone.clone());
+                        route_peer_connection_messages(
+                            msg,
+                            rclone.clone(),
+                            state_machine_store_clone.clone(),
+                            leader_contact_clone.clone(),
+                            limits_clone.clone(),
+                        );
                     }
                     Err(e) => {
                         eprintln!("[{}] Error receiving message: {}", opts.tcp_port, e);
@@ -148,6 +181,13 @@ impl Distacean {
             tracing::info!("Cluster already initialized, skipping init");
         }
 
+        tokio::spawn(run_compactor(
+            opts.node_id,
+            raft.clone(),
+            state_machine_store.clone(),
+            opts.retention,
+        ));
+
         Ok(Self {
             core: Arc::new(DistaceanCore {
                 node_id: opts.node_id,
@@ -155,6 +195,8 @@ impl Distacean {
                 peer_manager,
                 state_machine_store,
                 request_seq_id: std::sync::atomic::AtomicU64::new(1),
+                leader_contact,
+                limits: opts.limits,
             }),
         })
     }
@@ -210,6 +252,13 @@ impl Distacean {
             tracing::info!("Cluster already initialized, skipping init");
         }
 
+        tokio::spawn(run_compactor(
+            opts.node_id,
+            raft.clone(),
+            state_machine_store.clone(),
+            opts.retention,
+        ));
+
         Ok(Self {
             core: Arc::new(DistaceanCore {
                 node_id: opts.node_id,
@@ -217,6 +266,8 @@ impl Distacean {
                 peer_manager,
                 state_machine_store,
                 request_seq_id: std::sync::atomic::AtomicU64::new(1),
+                leader_contact: LeaderContact::new(),
+                limits: opts.limits,
             }),
         })
     }
@@ -226,6 +277,7 @@ impl Distacean {
             core: Arc::new(DistKVCore {
                 distacean: self.core.clone(),
             }),
+            codec: MessagePack,
         }
     }
 
@@ -235,6 +287,12 @@ impl Distacean {
         }
     }
 
+    pub fn priority_queues(self: &Self) -> crate::fifo::priority::DistPriorityQueue {
+        crate::fifo::priority::DistPriorityQueue {
+            distacean: self.core.clone(),
+        }
+    }
+
     pub async fn wait_until_ready(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         loop {
             if self.core.raft.current_leader().await.is_some() {
@@ -255,6 +313,23 @@ pub enum LeaderResponse {
 pub enum ReadSource {
     Local,
     Leader,
+    /// Read locally when this node has applied every log entry it knows of, otherwise read
+    /// through the leader. Reads with a read policy, i.e. any consistency but `AsIs`, always go
+    /// through the leader with this source; pick `Local` to linearize them on this node instead.
+    Any,
+}
+
+/// Bound on how far behind the leader a local read may be. `Entries` measures lag against the
+/// commit index carried by the last AppendEntries from the leader, so a node cut off from the
+/// leader also needs a `Time` bound to notice it.
+#[derive(Debug, Clone, Copy)]
+pub enum Staleness {
+    /// The local state machine is at most this many entries behind the last commit index
+    /// announced by the leader
+    Entries(u64),
+    /// The leader was heard from at most this long ago. The entries it sent then may still be
+    /// waiting to be applied locally.
+    Time(std::time::Duration),
 }
 
 impl DistaceanCore {
@@ -301,6 +376,36 @@ impl DistaceanCore {
         &self,
         req: RequestOperation,
     ) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
+        let (response, _) = self.write_with_token(req).await?;
+        Ok(response)
+    }
+
+    /// Like `write_or_forward_to_leader`, also returning the causality token of the write.
+    /// Writes forwarded by a follower reach the log in the order they were sent.
+    pub(crate) async fn write_with_token(
+        &self,
+        req: RequestOperation,
+    ) -> Result<(Response, SessionToken), Box<dyn std::error::Error + Send + Sync>> {
+        self.write_entry(req, false).await
+    }
+
+    /// Like `write_with_token`, but a follower lets the leader propose this write concurrently
+    /// with its other pipelined writes, so it may reach the log before writes sent earlier.
+    pub(crate) async fn write_pipelined(
+        &self,
+        req: RequestOperation,
+    ) -> Result<(Response, SessionToken), Box<dyn std::error::Error + Send + Sync>> {
+        self.write_entry(req, true).await
+    }
+
+    async fn write_entry(
+        &self,
+        req: RequestOperation,
+        pipelined: bool,
+    ) -> Result<(Response, SessionToken), Box<dyn std::error::Error + Send + Sync>> {
+        // Reject oversized writes before they reach the raft log
+        self.limits.check(&req)?;
+
         let node_id = self.node_id;
         let seq_id = self
             .request_seq_id
@@ -313,27 +418,80 @@ impl DistaceanCore {
                         client_id: node_id,
                         seq_id: Some(seq_id),
                         op: req,
+                        time_ms: now_ms(),
                     })
                     .await
                     .decompose()
                     .unwrap()?;
-                Ok(res.response().clone())
+                Ok((res.response().clone(), SessionToken::from_write(&res)))
             }
             LeaderResponse::NodeIsFollower(leader_peer) => {
                 // If not leader, forward to leader
-                let req_bytes = rmp_serde::to_vec(&RequestType::AppRequest(Request {
+                // The leader stamps the time when it proposes the entry
+                let request = Request {
                     client_id: node_id,
                     seq_id: Some(seq_id),
                     op: req,
-                }))?;
+                    time_ms: 0,
+                };
+                let req_bytes = rmp_serde::to_vec(&if pipelined {
+                    RequestType::PipelinedAppRequest(request)
+                } else {
+                    RequestType::AppRequest(request)
+                })?;
                 let res_bytes = leader_peer.req_res(req_bytes).await.unwrap();
-                let res: Result<
-                    openraft::raft::ClientWriteResponse<TypeConfig>,
-                    openraft::error::ClientWriteError<TypeConfig>,
-                > = rmp_serde::from_slice(&res_bytes)?;
+                let res: ForwardedWriteResult = rmp_serde::from_slice(&res_bytes)?;
 
                 match res {
-                    Ok(r) => Ok(r.response().clone()),
+                    Ok(Ok(r)) => Ok((r.response().clone(), SessionToken::from_write(&r))),
+                    Ok(Err(e)) => Err(Box::new(e)),
+                    Err(e) => Err(Box::new(e)),
+                }
+            }
+            LeaderResponse::NoLeader => Err("No leader available".into()),
+        }
+    }
+
+    /// Apply a FIFO dequeue, reserve or priority dequeue, waiting up to `timeout` for items if the
+    /// queue is empty, or an enqueue, waiting up to `timeout` for room if the queue is full and
+    /// blocks producers. The wait runs on the leader, so followers forward the whole long poll
+    /// to it.
+    pub(crate) async fn write_wait_on_queue(
+        &self,
+        req: RequestOperation,
+        timeout: std::time::Duration,
+    ) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
+        self.limits.check(&req)?;
+
+        let seq_id = self
+            .request_seq_id
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let request = Request {
+            client_id: self.node_id,
+            seq_id: Some(seq_id),
+            op: req,
+            time_ms: 0,
+        };
+        match self.get_leader_peer().await? {
+            LeaderResponse::NodeIsLeader => {
+                let res =
+                    wait_on_queue(&self.raft, &self.state_machine_store, request, timeout).await?;
+                Ok(res.response().clone())
+            }
+            LeaderResponse::NodeIsFollower(leader_peer) => {
+                let req_bytes = rmp_serde::to_vec(&RequestType::WaitOnQueue {
+                    request,
+                    timeout_ms: timeout.as_millis() as u64,
+                })?;
+                // Leave the leader time to answer after its wait ends
+                let res_bytes = leader_peer
+                    .req_res_with_timeout(req_bytes, timeout + std::time::Duration::from_secs(10))
+                    .await?;
+                let res: ForwardedWriteResult = rmp_serde::from_slice(&res_bytes)?;
+
+                match res {
+                    Ok(Ok(r)) => Ok(r.response().clone()),
+                    Ok(Err(e)) => Err(Box::new(e)),
                     Err(e) => Err(Box::new(e)),
                 }
             }
@@ -356,7 +514,7 @@ impl DistaceanCore {
                     .unwrap()?;
                 linearizer
             }
-            ReadSource::Leader => {
+            ReadSource::Leader | ReadSource::Any => {
                 let leader = self.get_leader_peer().await?;
 
                 match leader {
@@ -393,6 +551,219 @@ impl DistaceanCore {
         let data = linearizer.await_ready(&self.raft).await?;
         Ok(data)
     }
+
+    /// Whether the local state machine is within `max_staleness` of the leader. The leader
+    /// itself is always fresh enough, and a node that knows of no leader, or was never
+    /// contacted by one, never is.
+    pub(crate) fn is_within_staleness(&self, max_staleness: Staleness) -> bool {
+        let metrics = self.raft.metrics().borrow().clone();
+        let Some(leader_id) = metrics.current_leader else {
+            return false;
+        };
+        if leader_id == self.node_id {
+            return true;
+        }
+        let Some(elapsed) = self.leader_contact.elapsed() else {
+            return false;
+        };
+
+        match max_staleness {
+            Staleness::Entries(entries) => {
+                let applied = metrics.last_applied.map_or(0, |log_id| log_id.index());
+                self.leader_contact.leader_commit().saturating_sub(applied) <= entries
+            }
+            Staleness::Time(time) => elapsed <= time,
+        }
+    }
+
+    /// Execute `query` according to `source`, linearizing with `read_policy` first when one is
+    /// given. Without a read policy, leader reads are executed on the leader itself. With
+    /// `after`, the node serving the read first waits until it has applied that write.
+    pub(crate) async fn execute_read(
+        &self,
+        source: ReadSource,
+        read_policy: Option<ReadPolicy>,
+        after: Option<SessionToken>,
+        query: ReadQuery,
+    ) -> Result<ReadQueryResult, ReadQueryError> {
+        let other = |e: Box<dyn std::error::Error + Send + Sync>| {
+            ReadQueryError::Other(format!("Failed to execute read: {}", e))
+        };
+
+        let source = match source {
+            ReadSource::Any
+                if read_policy.is_none() && self.is_within_staleness(Staleness::Entries(0)) =>
+            {
+                ReadSource::Local
+            }
+            ReadSource::Any => ReadSource::Leader,
+            source => source,
+        };
+
+        match (source, read_policy) {
+            (_, Some(read_policy)) => {
+                self.get_linearizer(source, read_policy)
+                    .await
+                    .map_err(other)?;
+            }
+            (ReadSource::Local, None) => {}
+            (_, None) => match self.get_leader_peer().await.map_err(other)? {
+                LeaderResponse::NodeIsLeader => {}
+                LeaderResponse::NodeIsFollower(leader_peer) => {
+                    let data = rmp_serde::to_vec(&RequestType::Read {
+                        query,
+                        read_policy: None,
+                        after,
+                    })
+                    .map_err(|e| other(e.into()))?;
+                    let res = leader_peer.req_res(data).await.map_err(other)?;
+                    return rmp_serde::from_slice(&res).map_err(|e| other(e.into()))?;
+                }
+                LeaderResponse::NoLeader => {
+                    return Err(ReadQueryError::Other("No leader available".to_string()));
+                }
+            },
+        }
+
+        if let Some(token) = after {
+            wait_applied(&self.raft, token).await?;
+        }
+        self.state_machine_store.execute_query(query).await
+    }
+}
+
+/// Wait until `raft` has applied the log entry of `token`.
+pub(crate) async fn wait_applied(raft: &Raft, token: SessionToken) -> Result<(), ReadQueryError> {
+    let mut metrics = raft.metrics();
+    let wait = async {
+        loop {
+            let applied = metrics
+                .borrow()
+                .last_applied
+                .map_or(0, |log_id| log_id.index());
+            if applied >= token.index {
+                return Ok(());
+            }
+            if metrics.changed().await.is_err() {
+                return Err(ReadQueryError::Other("Raft was shut down".to_string()));
+            }
+        }
+    };
+    match tokio::time::timeout(std::time::Duration::from_secs(10), wait).await {
+        Ok(result) => result,
+        Err(_) => Err(ReadQueryError::Other(format!(
+            "Timed out waiting to apply log index {}",
+            token.index
+        ))),
+    }
+}
+
+/// Propose `request`, a FIFO dequeue or reserve, on the leader until it returns items or
+/// `timeout` passes. The state machine wakes the wait whenever items are appended to the queue.
+/// Scheduled items becoming due, and for a reserve expiring reservations, append nothing, so
+/// each wait also ends when the next of them is due. Only the first attempt is proposed
+/// unconditionally; later ones wait until the local state machine has an item to hand out.
+pub(crate) async fn wait_on_queue(
+    raft: &Raft,
+    state_machine_store: &StateMachineStore,
+    mut request: Request,
+    timeout: std::time::Duration,
+) -> Result<
+    openraft::raft::ClientWriteResponse<TypeConfig>,
+    openraft::error::ClientWriteError<TypeConfig>,
+> {
+    let (queue_key, notifier) = match &request.op {
+        RequestOperation::FIFO(FIFOOperation::Dequeue(op)) => {
+            (op.queue_key.clone(), state_machine_store.queue_notifier())
+        }
+        RequestOperation::FIFO(FIFOOperation::Reserve(op)) => {
+            (op.queue_key.clone(), state_machine_store.queue_notifier())
+        }
+        RequestOperation::FIFO(FIFOOperation::PriorityDequeue(op)) => {
+            (op.queue_key.clone(), state_machine_store.queue_notifier())
+        }
+        RequestOperation::FIFO(FIFOOperation::Enqueue(op)) => {
+            (op.queue_key.clone(), state_machine_store.space_notifier())
+        }
+        RequestOperation::FIFO(FIFOOperation::PriorityEnqueue(op)) => {
+            (op.queue_key.clone(), state_machine_store.space_notifier())
+        }
+        _ => (Vec::new(), state_machine_store.queue_notifier()),
+    };
+    // Priority queues have no scheduled items, and producers wait for room, not for items
+    let wakes_when_due = matches!(
+        request.op,
+        RequestOperation::FIFO(FIFOOperation::Dequeue(_) | FIFOOperation::Reserve(_))
+    );
+    let reserve = matches!(
+        request.op,
+        RequestOperation::FIFO(FIFOOperation::Reserve(_))
+    );
+    let notify = notifier.subscribe(&queue_key);
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    let mut last = None;
+    loop {
+        // Register before the attempt so that a change right after it is not missed
+        let notified = notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        // After the first attempt, only go through raft once the local state machine has
+        // something to hand out, so that an empty poll does not append an entry per wakeup
+        let propose = last.is_none()
+            || !wakes_when_due
+            || state_machine_store
+                .has_deliverable(queue_key.clone(), reserve, now_ms())
+                .await
+                .unwrap_or(true);
+        if propose {
+            request.time_ms = now_ms();
+            let res = raft
+                .client_write(request.clone())
+                .await
+                .decompose()
+                .unwrap()?;
+            let retry = match res.response() {
+                Response::Result {
+                    res: ResponseResult::FIFO(FIFOResponse::Dequeue(dequeue)),
+                    ..
+                } => dequeue.items.is_empty(),
+                Response::Result {
+                    res: ResponseResult::FIFO(FIFOResponse::Reserve(reserve)),
+                    ..
+                } => reserve.items.is_empty(),
+                Response::Result {
+                    res: ResponseResult::FIFO(FIFOResponse::PriorityDequeue { items }),
+                    ..
+                } => items.is_empty(),
+                Response::Result {
+                    res: ResponseResult::FIFO(FIFOResponse::QueueFull { block, .. }),
+                    ..
+                } => *block,
+                _ => false,
+            };
+            if !retry {
+                return Ok(res);
+            }
+            last = Some(res);
+        }
+
+        let mut wake_at = deadline;
+        if wakes_when_due {
+            if let Ok(Some(due_ms)) = state_machine_store
+                .next_due_ms(queue_key.clone(), reserve)
+                .await
+            {
+                let until_due = std::time::Duration::from_millis(due_ms.saturating_sub(now_ms()));
+                wake_at = wake_at.min(tokio::time::Instant::now() + until_due);
+            }
+        }
+        if tokio::time::timeout_at(wake_at, notified).await.is_err() && wake_at >= deadline {
+            // The first attempt is always proposed
+            return Ok(last.unwrap());
+        }
+    }
 }
 
 async fn run_listener(
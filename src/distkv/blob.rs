@@ -0,0 +1,58 @@
+use futures::StreamExt;
+
+use crate::core::DistaceanCore;
+use crate::distkv::SetError;
+use crate::raft::KVOperation;
+use crate::raft::KVResponse;
+use crate::raft::RequestOperation;
+use crate::raft::Response;
+use crate::raft::ResponseResult;
+use crate::raft::store::kv::KVPutIfAbsent;
+use crate::raft::store::kv::blob::{BlobManifest, chunk_key};
+
+/// Number of chunk writes in flight at once
+const BLOB_CHUNK_WRITES_IN_FLIGHT: usize = 8;
+
+/// Write `value` as content-addressed chunks of at most `chunk_bytes`, each in its own raft
+/// entry, and return the manifest to store in place of the value with the blob flag set.
+/// Chunks are written with put-if-absent, so identical chunks are stored once and shared
+/// between values. The state machine counts the stored blobs referencing each chunk and
+/// deletes chunks once no blob references them. Reads reassemble blobs in the state machine,
+/// from the same snapshot as the manifest.
+pub(crate) async fn write_blob(
+    distacean: &DistaceanCore,
+    value: &[u8],
+    chunk_bytes: usize,
+) -> Result<Vec<u8>, SetError> {
+    let chunks: Vec<(Vec<u8>, Vec<u8>)> = value
+        .chunks(chunk_bytes)
+        .map(|chunk| (chunk_key(chunk), chunk.to_vec()))
+        .collect();
+    let manifest = BlobManifest {
+        size: value.len() as u64,
+        chunks: chunks.iter().map(|(key, _)| key.clone()).collect(),
+    };
+
+    let results: Vec<Result<(), SetError>> = futures::stream::iter(chunks)
+        .map(|(key, chunk)| async move {
+            let (response, _) = distacean
+                .write_with_token(RequestOperation::KV(KVOperation::PutIfAbsent(
+                    KVPutIfAbsent { key, value: chunk },
+                )))
+                .await
+                .map_err(SetError::from_write)?;
+            match response {
+                Response::Result {
+                    res: ResponseResult::KV(KVResponse::PutIfAbsent(_)),
+                    ..
+                } => Ok(()),
+                _ => Err(SetError::Other("Unexpected response type".into())),
+            }
+        })
+        .buffered(BLOB_CHUNK_WRITES_IN_FLIGHT)
+        .collect()
+        .await;
+    results.into_iter().collect::<Result<(), SetError>>()?;
+
+    rmp_serde::to_vec(&manifest).map_err(|e| SetError::Other(e.into()))
+}
@@ -0,0 +1,446 @@
+use std::marker::PhantomData;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+
+use crate::codec::Codec;
+use crate::codec::CodecError;
+use crate::codec::MessagePack;
+use crate::codec::MessagePackNamed;
+use crate::core::ReadSource;
+use crate::distkv::DistKV;
+use crate::distkv::InitialDeleteBuilder;
+use crate::distkv::InitialSetBuilder;
+use crate::distkv::SetError;
+use crate::distkv::operator_delete::DeleteRequest;
+use crate::distkv::operator_delete_range::DeleteRangeRequest;
+use crate::distkv::operator_read::KVReadError;
+use crate::distkv::operator_read::ReadConsistency;
+use crate::distkv::operator_read::execute_query;
+use crate::distkv::operator_set::SetRequest;
+use crate::protocol::ReadQuery;
+use crate::protocol::ReadQueryResult;
+use crate::raft::SetResponse;
+use crate::raft::store::kv::common::prefix_end;
+use crate::raft::store::kv::index::IndexDefinition;
+
+/// First byte of every keyspace key. Plain keys starting with this byte are reserved.
+pub(crate) const KEYSPACE_PREFIX: u8 = 0xFE;
+
+/// Key encoding that sorts byte-wise in the same order as the values themselves, so that scans
+/// of a keyspace return keys in their natural order. Encodings are self-delimiting, which makes
+/// tuples of ordered keys ordered keys too.
+pub trait OrderedKey: Sized {
+    fn write_ordered(&self, out: &mut Vec<u8>);
+    fn read_ordered(input: &mut &[u8]) -> Result<Self, CodecError>;
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], CodecError> {
+    if input.len() < len {
+        return Err(CodecError::new("Truncated ordered key"));
+    }
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+    Ok(head)
+}
+
+macro_rules! ordered_unsigned {
+    ($($ty:ty),*) => {$(
+        impl OrderedKey for $ty {
+            fn write_ordered(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn read_ordered(input: &mut &[u8]) -> Result<Self, CodecError> {
+                let bytes = take(input, std::mem::size_of::<$ty>())?;
+                Ok(<$ty>::from_be_bytes(bytes.try_into().unwrap()))
+            }
+        }
+    )*};
+}
+
+// Signed integers flip the sign bit so that negative values sort before positive ones
+macro_rules! ordered_signed {
+    ($($ty:ty => $unsigned:ty),*) => {$(
+        impl OrderedKey for $ty {
+            fn write_ordered(&self, out: &mut Vec<u8>) {
+                let flipped = (*self as $unsigned) ^ (1 << (<$unsigned>::BITS - 1));
+                out.extend_from_slice(&flipped.to_be_bytes());
+            }
+
+            fn read_ordered(input: &mut &[u8]) -> Result<Self, CodecError> {
+                let bytes = take(input, std::mem::size_of::<$ty>())?;
+                let flipped = <$unsigned>::from_be_bytes(bytes.try_into().unwrap());
+                Ok((flipped ^ (1 << (<$unsigned>::BITS - 1))) as $ty)
+            }
+        }
+    )*};
+}
+
+ordered_unsigned!(u8, u16, u32, u64, u128);
+ordered_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl OrderedKey for bool {
+    fn write_ordered(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn read_ordered(input: &mut &[u8]) -> Result<Self, CodecError> {
+        match take(input, 1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(CodecError::new("Invalid ordered bool")),
+        }
+    }
+}
+
+// Byte strings escape 0x00 as 0x00 0xFF and end with 0x00 0x01, which keeps them ordered and
+// lets another key component follow them.
+impl OrderedKey for Vec<u8> {
+    fn write_ordered(&self, out: &mut Vec<u8>) {
+        for byte in self {
+            out.push(*byte);
+            if *byte == 0x00 {
+                out.push(0xFF);
+            }
+        }
+        out.extend_from_slice(&[0x00, 0x01]);
+    }
+
+    fn read_ordered(input: &mut &[u8]) -> Result<Self, CodecError> {
+        let mut bytes = Vec::new();
+        loop {
+            match take(input, 1)?[0] {
+                0x00 => match take(input, 1)?[0] {
+                    0xFF => bytes.push(0x00),
+                    0x01 => return Ok(bytes),
+                    _ => return Err(CodecError::new("Invalid ordered byte string")),
+                },
+                byte => bytes.push(byte),
+            }
+        }
+    }
+}
+
+impl OrderedKey for String {
+    fn write_ordered(&self, out: &mut Vec<u8>) {
+        self.as_bytes().to_vec().write_ordered(out);
+    }
+
+    fn read_ordered(input: &mut &[u8]) -> Result<Self, CodecError> {
+        String::from_utf8(Vec::<u8>::read_ordered(input)?).map_err(CodecError::new)
+    }
+}
+
+impl<A: OrderedKey, B: OrderedKey> OrderedKey for (A, B) {
+    fn write_ordered(&self, out: &mut Vec<u8>) {
+        self.0.write_ordered(out);
+        self.1.write_ordered(out);
+    }
+
+    fn read_ordered(input: &mut &[u8]) -> Result<Self, CodecError> {
+        Ok((A::read_ordered(input)?, B::read_ordered(input)?))
+    }
+}
+
+impl<A: OrderedKey, B: OrderedKey, C: OrderedKey> OrderedKey for (A, B, C) {
+    fn write_ordered(&self, out: &mut Vec<u8>) {
+        self.0.write_ordered(out);
+        self.1.write_ordered(out);
+        self.2.write_ordered(out);
+    }
+
+    fn read_ordered(input: &mut &[u8]) -> Result<Self, CodecError> {
+        Ok((
+            A::read_ordered(input)?,
+            B::read_ordered(input)?,
+            C::read_ordered(input)?,
+        ))
+    }
+}
+
+/// Typed handle to a namespace of the store. Keys of type `K` are stored with an
+/// order-preserving encoding under a prefix derived from the keyspace name, and values of type
+/// `V` are encoded with the codec `C`.
+pub struct Keyspace<K, V, C = MessagePack> {
+    kv: DistKV<C>,
+    prefix: Vec<u8>,
+    source: ReadSource,
+    consistency: ReadConsistency,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V, C: Clone> Clone for Keyspace<K, V, C> {
+    fn clone(&self) -> Self {
+        Self {
+            kv: self.kv.clone(),
+            prefix: self.prefix.clone(),
+            source: self.source,
+            consistency: self.consistency,
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<K, V, C> Keyspace<K, V, C>
+where
+    K: OrderedKey,
+    C: Codec<V>,
+{
+    pub(crate) fn new(kv: DistKV<C>, name: &str) -> Self {
+        let mut prefix = vec![KEYSPACE_PREFIX];
+        name.to_string().write_ordered(&mut prefix);
+        Self {
+            kv,
+            prefix,
+            source: ReadSource::Leader,
+            consistency: ReadConsistency::Linearizable,
+            _types: PhantomData,
+        }
+    }
+
+    /// Serve `get` and `scan` with the given source and consistency instead of linearizable
+    /// leader reads.
+    pub fn with_reads(mut self, source: ReadSource, consistency: ReadConsistency) -> Self {
+        self.source = source;
+        self.consistency = consistency;
+        self
+    }
+
+    fn encode_key(&self, key: &K) -> Vec<u8> {
+        let mut bytes = self.prefix.clone();
+        key.write_ordered(&mut bytes);
+        bytes
+    }
+
+    fn decode_key(&self, bytes: &[u8]) -> Result<K, CodecError> {
+        let mut input = bytes
+            .strip_prefix(self.prefix.as_slice())
+            .ok_or_else(|| CodecError::new("Key outside of keyspace"))?;
+        let key = K::read_ordered(&mut input)?;
+        if !input.is_empty() {
+            return Err(CodecError::new("Trailing bytes after ordered key"));
+        }
+        Ok(key)
+    }
+
+    fn set_request(&self, key: &K, value: &V) -> InitialSetBuilder {
+        SetRequest::builder()
+            .distacean(self.kv.core.distacean.clone())
+            .key(Ok(self.encode_key(key)))
+            .value(self.kv.codec.encode(value))
+    }
+
+    /// Value and revision of `key`, or `None` if it does not exist.
+    pub async fn get(&self, key: &K) -> Result<Option<(V, u64)>, KVReadError> {
+        self.kv
+            .read(self.encode_key(key))
+            .source(self.source)
+            .consistency(self.consistency)
+            .execute_with_revision::<V>()
+            .await
+    }
+
+    pub async fn set(&self, key: &K, value: &V) -> Result<SetResponse, SetError> {
+        self.set_request(key, value).execute().await
+    }
+
+    /// Set `key` only if its revision is still `expected_revision`.
+    pub async fn cas(
+        &self,
+        key: &K,
+        value: &V,
+        expected_revision: u64,
+    ) -> Result<SetResponse, SetError> {
+        self.set_request(key, value)
+            .expected_revision(expected_revision)
+            .execute()
+            .await
+    }
+
+    /// Delete `key`. Use `expected_revision` to only delete it if no other writer changed it.
+    pub fn delete(&self, key: &K) -> InitialDeleteBuilder {
+        DeleteRequest::builder()
+            .distacean(self.kv.core.distacean.clone())
+            .key(Ok(self.encode_key(key)))
+    }
+
+    /// Entries whose keys fall in `range`, in key order, with their revisions. At most `limit`
+    /// entries are returned when a limit is given.
+    pub async fn scan(
+        &self,
+        range: impl RangeBounds<K>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(K, V, u64)>, KVReadError> {
+        let encode_bound = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(self.encode_key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.encode_key(key)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let start = encode_bound(range.start_bound());
+        let end = encode_bound(range.end_bound());
+
+        let query = ReadQuery::Scan {
+            prefix: self.prefix.clone(),
+            start,
+            end,
+            limit,
+        };
+        let entries = match execute_query(
+            &self.kv.core.distacean,
+            self.source,
+            self.consistency,
+            None,
+            query,
+        )
+        .await?
+        {
+            ReadQueryResult::Entries(entries) => entries,
+            _ => return Err(KVReadError::Unknown("Unexpected read result".into())),
+        };
+
+        let mut results = Vec::with_capacity(entries.len());
+        for (key, stored) in entries {
+            results.push((
+                self.decode_key(&key).map_err(KVReadError::Decode)?,
+                self.kv
+                    .codec
+                    .decode(&stored.data)
+                    .map_err(KVReadError::Decode)?,
+                stored.revision,
+            ));
+        }
+        Ok(results)
+    }
+
+    /// Delete every key of the keyspace in one raft entry, returning how many were deleted.
+    pub async fn drop_keyspace(&self) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        let deleted = DeleteRangeRequest::builder()
+            .distacean(self.kv.core.distacean.clone())
+            .start(Ok(self.prefix.clone()))
+            .end(prefix_end(&self.prefix))
+            .with_count()
+            .execute()
+            .await?;
+        Ok(deleted.unwrap_or_default())
+    }
+}
+
+/// Indexes find fields by name, so only keyspaces written with `MessagePackNamed` can be
+/// indexed.
+impl<K, V> Keyspace<K, V, MessagePackNamed>
+where
+    K: OrderedKey,
+    MessagePackNamed: Codec<V>,
+{
+    /// Register the secondary index `name` over the field at `field` of this keyspace's values.
+    /// Returns the number of indexed keys.
+    pub async fn create_index(
+        &self,
+        name: &str,
+        field: &[&str],
+    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        self.kv
+            .create_index(name, IndexDefinition::new(&self.prefix, field))
+            .await
+    }
+
+    /// Entries whose value in the index `name` falls in `value_range`, ordered by indexed value
+    /// and then by key, with their revisions. At most `limit` entries are returned when a limit
+    /// is given.
+    pub async fn query_index<I: OrderedKey>(
+        &self,
+        name: &str,
+        value_range: impl RangeBounds<I>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(K, V, u64)>, KVReadError> {
+        self.kv
+            .query_index(name, value_range)
+            .maybe_limit(limit)
+            .source(self.source)
+            .consistency(self.consistency)
+            .execute::<V>()
+            .await?
+            .into_iter()
+            .map(|(key, value, revision)| {
+                Ok((
+                    self.decode_key(&key).map_err(KVReadError::Decode)?,
+                    value,
+                    revision,
+                ))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode<K: OrderedKey>(key: &K) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        key.write_ordered(&mut bytes);
+        bytes
+    }
+
+    fn round_trip<K: OrderedKey + PartialEq + std::fmt::Debug>(key: K) {
+        let bytes = encode(&key);
+        let mut input = bytes.as_slice();
+        assert_eq!(K::read_ordered(&mut input).unwrap(), key);
+        assert!(input.is_empty());
+    }
+
+    /// Encodings of `keys`, which are listed in their natural order, sort the same way
+    fn assert_ordered<K: OrderedKey>(keys: &[K]) {
+        let encoded: Vec<_> = keys.iter().map(encode).collect();
+        assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn integers_sort_in_numeric_order() {
+        assert_ordered(&[0u32, 1, 255, 256, u32::MAX]);
+        assert_ordered(&[i64::MIN, -256, -1, 0, 1, i64::MAX]);
+        for key in [i64::MIN, -1, 0, i64::MAX] {
+            round_trip(key);
+        }
+    }
+
+    #[test]
+    fn byte_strings_sort_like_their_bytes_and_keep_zero_bytes() {
+        assert_ordered(&[
+            Vec::new(),
+            vec![0x00],
+            vec![0x00, 0x00],
+            vec![0x00, 0x01],
+            vec![0x01],
+            vec![0xFF],
+        ]);
+        assert_ordered(&["", "a", "ab", "b"].map(String::from));
+        round_trip(vec![0x00, 0xFF, 0x00, 0x01]);
+        round_trip("keyspace".to_string());
+    }
+
+    #[test]
+    fn tuples_sort_by_their_first_component_first() {
+        assert_ordered(&[
+            ("a".to_string(), 9u32),
+            ("ab".to_string(), 0),
+            ("b".to_string(), 0),
+        ]);
+        assert_ordered(&[(1u8, false, -1i32), (1, true, -5), (2, false, 0)]);
+        round_trip((vec![0x00], "x".to_string(), 3u64));
+    }
+
+    #[test]
+    fn truncated_and_invalid_keys_are_rejected() {
+        let bytes = encode(&"abc".to_string());
+        let mut input = &bytes[..bytes.len() - 1];
+        assert!(String::read_ordered(&mut input).is_err());
+
+        let mut input: &[u8] = &[0x61, 0x00, 0x02];
+        assert!(Vec::<u8>::read_ordered(&mut input).is_err());
+        let mut input: &[u8] = &[2];
+        assert!(bool::read_ordered(&mut input).is_err());
+        let mut input: &[u8] = &[0, 0, 0];
+        assert!(u32::read_ordered(&mut input).is_err());
+    }
+}
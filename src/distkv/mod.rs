@@ -1,61 +1,415 @@
+mod blob;
+pub mod keyspace;
+pub mod operator_batch;
+pub mod operator_delete;
+pub mod operator_delete_range;
+pub mod operator_incr;
+pub mod operator_index;
+pub mod operator_put_if_absent;
 pub mod operator_read;
+pub mod operator_read_many;
 pub mod operator_set;
+pub mod operator_txn;
 
+use std::ops::Bound;
+use std::ops::RangeBounds;
 use std::sync::Arc;
 
+use crate::codec::Codec;
+use crate::codec::MessagePack;
+use crate::codec::MessagePackNamed;
 use crate::core::DistaceanCore;
+use crate::distkv::keyspace::KEYSPACE_PREFIX;
+use crate::distkv::keyspace::Keyspace;
+use crate::distkv::keyspace::OrderedKey;
+use crate::distkv::operator_batch::BatchRequest;
+use crate::distkv::operator_batch::PipelineRequest;
+use crate::distkv::operator_delete::DeleteRequest;
+use crate::distkv::operator_delete::DeleteRequestBuilder;
+use crate::distkv::operator_delete_range::DeleteRangeRequest;
+use crate::distkv::operator_delete_range::DeleteRangeRequestBuilder;
+use crate::distkv::operator_incr::IncrRequest;
+use crate::distkv::operator_incr::IncrRequestBuilder;
+use crate::distkv::operator_index::IndexQueryRequest;
+use crate::distkv::operator_index::IndexQueryRequestBuilder;
+use crate::distkv::operator_put_if_absent::GetOrInsertResponse;
+use crate::distkv::operator_put_if_absent::PutIfAbsentRequest;
+use crate::distkv::operator_put_if_absent::PutIfAbsentRequestBuilder;
+use crate::distkv::operator_read::KVReadError;
 use crate::distkv::operator_read::ReadRequest;
 use crate::distkv::operator_read::ReadRequestBuilder;
+use crate::distkv::operator_read_many::ReadManyRequest;
+use crate::distkv::operator_read_many::ReadManyRequestBuilder;
 use crate::distkv::operator_set::SetRequest;
 use crate::distkv::operator_set::SetRequestBuilder;
+use crate::distkv::operator_txn::TxnRequest;
 use crate::raft::KVOperation;
+use crate::raft::KVResponse;
 use crate::raft::RequestOperation;
-use serde::Serialize;
+use crate::raft::Response;
+use crate::raft::ResponseResult;
+use crate::raft::store::kv::blob::BLOB_CHUNK_PREFIX;
+use crate::raft::store::kv::common::prefix_end;
+use crate::raft::store::kv::index::IndexDefinition;
+use thiserror::Error;
 
+pub use self::operator_delete::DeleteError;
+pub use self::operator_incr::IncrError;
 pub use self::operator_set::SetError;
 
+/// Plain keys are rejected when they start with one of the bytes reserved for keyspaces and
+/// blob chunks, so that they can never overwrite or delete data stored there.
+#[derive(Error, Debug, Clone)]
+pub enum ReservedKeyError {
+    #[error("Key starts with the reserved byte 0x{0:02X}")]
+    Key(u8),
+    #[error("Range covers keys reserved for keyspaces and blob chunks")]
+    Range,
+}
+
+/// `key` as a plain key, or an error if it lies under a reserved prefix.
+pub(crate) fn user_key(key: &[u8]) -> Result<Vec<u8>, ReservedKeyError> {
+    match key.first() {
+        Some(&byte) if byte == BLOB_CHUNK_PREFIX || byte == KEYSPACE_PREFIX => {
+            Err(ReservedKeyError::Key(byte))
+        }
+        _ => Ok(key.to_vec()),
+    }
+}
+
+/// Check that `[start, end)` contains no reserved key. An empty `end` means no upper bound.
+/// Reserved keys are exactly the keys in `[BLOB_CHUNK_PREFIX, KEYSPACE_PREFIX + 1)`.
+pub(crate) fn user_range(start: &[u8], end: &[u8]) -> Result<(), ReservedKeyError> {
+    let reserved_start: &[u8] = &[BLOB_CHUNK_PREFIX];
+    let reserved_end: &[u8] = &[KEYSPACE_PREFIX + 1];
+    if start < reserved_end && (end.is_empty() || end > reserved_start) {
+        return Err(ReservedKeyError::Range);
+    }
+    Ok(())
+}
+
 pub(crate) struct DistKVCore {
     pub(crate) distacean: Arc<DistaceanCore>,
 }
 
+/// Handle to the distributed key value store. Values are encoded with the codec `C`, which
+/// defaults to MessagePack; see `with_codec`.
 #[derive(Clone)]
-pub struct DistKV {
+pub struct DistKV<C = MessagePack> {
     pub(crate) core: Arc<DistKVCore>,
+    pub(crate) codec: C,
 }
 
 pub type InitialSetBuilder =
     SetRequestBuilder<operator_set::SetValue<operator_set::SetKey<operator_set::SetDistacean>>>;
-pub type InitialReadBuilder =
-    ReadRequestBuilder<operator_read::SetKey<operator_read::SetDistacean>>;
+pub type InitialIncrBuilder =
+    IncrRequestBuilder<operator_incr::SetDelta<operator_incr::SetKey<operator_incr::SetDistacean>>>;
+pub type InitialDeleteBuilder =
+    DeleteRequestBuilder<operator_delete::SetKey<operator_delete::SetDistacean>>;
+pub type InitialDeleteRangeBuilder = DeleteRangeRequestBuilder<
+    operator_delete_range::SetEnd<
+        operator_delete_range::SetStart<operator_delete_range::SetDistacean>,
+    >,
+>;
+pub type InitialPutIfAbsentBuilder = PutIfAbsentRequestBuilder<
+    operator_put_if_absent::SetValue<
+        operator_put_if_absent::SetKey<operator_put_if_absent::SetDistacean>,
+    >,
+>;
+pub type InitialReadBuilder<C> = ReadRequestBuilder<
+    C,
+    operator_read::SetKey<operator_read::SetCodec<operator_read::SetDistacean>>,
+>;
+pub type InitialReadManyBuilder<C> = ReadManyRequestBuilder<
+    C,
+    operator_read_many::SetKeys<operator_read_many::SetCodec<operator_read_many::SetDistacean>>,
+>;
+pub type InitialIndexQueryBuilder<C> = IndexQueryRequestBuilder<
+    C,
+    operator_index::SetEnd<
+        operator_index::SetStart<
+            operator_index::SetName<operator_index::SetCodec<operator_index::SetDistacean>>,
+        >,
+    >,
+>;
 
 impl DistKVCore {}
 
-impl DistKV {
-    pub fn set<T: Serialize, V: std::borrow::Borrow<T>>(
-        self: &DistKV,
-        key: impl Into<String>,
+impl<C: Clone> DistKV<C> {
+    /// Returns a handle to the same store that encodes values with `codec`.
+    pub fn with_codec<C2>(self: &Self, codec: C2) -> DistKV<C2> {
+        DistKV {
+            core: self.core.clone(),
+            codec,
+        }
+    }
+
+    /// Set `key` to `value`. Encoding errors are reported by `execute` as `SetError::Codec`, and
+    /// keys under a reserved prefix as `SetError::ReservedKey`.
+    pub fn set<T, V: std::borrow::Borrow<T>>(
+        self: &Self,
+        key: impl AsRef<[u8]>,
         value: V,
-    ) -> InitialSetBuilder {
+    ) -> InitialSetBuilder
+    where
+        C: Codec<T>,
+    {
         SetRequest::builder()
             .distacean(self.core.distacean.clone())
-            .key(key.into())
-            .value(rmp_serde::to_vec(value.borrow()).expect("Failed to serialize value"))
+            .key(user_key(key.as_ref()))
+            .value(self.codec.encode(value.borrow()))
+    }
+
+    /// Store `value` at `key` only if the key does not exist. Unlike a CAS against revision 0,
+    /// this also works for keys that existed and were deleted.
+    pub fn put_if_absent<T, V: std::borrow::Borrow<T>>(
+        self: &Self,
+        key: impl AsRef<[u8]>,
+        value: V,
+    ) -> InitialPutIfAbsentBuilder
+    where
+        C: Codec<T>,
+    {
+        PutIfAbsentRequest::builder()
+            .distacean(self.core.distacean.clone())
+            .key(user_key(key.as_ref()))
+            .value(self.codec.encode(value.borrow()))
+    }
+
+    /// Return the value at `key`, inserting the one produced by `f` if the key does not exist.
+    /// `f` is only called when the key looks absent, and the insert is atomic, so concurrent
+    /// callers all end up with the same value.
+    pub async fn get_or_insert_with<T>(
+        self: &Self,
+        key: impl AsRef<[u8]>,
+        f: impl FnOnce() -> T,
+    ) -> Result<GetOrInsertResponse<T>, SetError>
+    where
+        C: Codec<T>,
+    {
+        let key = key.as_ref();
+        let existing = self
+            .read(key)
+            .execute_with_revision::<T>()
+            .await
+            .map_err(|e| match e {
+                KVReadError::Decode(e) => SetError::Codec(e),
+                e => SetError::Other(Box::new(e)),
+            })?;
+        if let Some((value, revision)) = existing {
+            return Ok(GetOrInsertResponse {
+                value,
+                revision,
+                inserted: false,
+            });
+        }
+
+        let value = f();
+        let response = PutIfAbsentRequest::builder()
+            .distacean(self.core.distacean.clone())
+            .key(user_key(key))
+            .value(self.codec.encode(&value))
+            .execute()
+            .await?;
+        match response.existing {
+            Some(existing) => Ok(GetOrInsertResponse {
+                value: self.codec.decode(&existing).map_err(SetError::Codec)?,
+                revision: response.revision,
+                inserted: false,
+            }),
+            None => Ok(GetOrInsertResponse {
+                value,
+                revision: response.revision,
+                inserted: true,
+            }),
+        }
+    }
+
+    /// Delete `key`. Use `expected_revision` to only delete it if no other writer changed it.
+    pub fn delete(self: &Self, key: impl AsRef<[u8]>) -> InitialDeleteBuilder {
+        DeleteRequest::builder()
+            .distacean(self.core.distacean.clone())
+            .key(user_key(key.as_ref()))
+    }
+
+    /// Delete every key in `[start, end)` in one raft entry. An empty `end` means no upper bound.
+    /// Ranges covering keys reserved for keyspaces and blob chunks are rejected; drop keyspaces
+    /// with `Keyspace::drop_keyspace` instead.
+    pub fn delete_range(
+        self: &Self,
+        start: impl AsRef<[u8]>,
+        end: impl AsRef<[u8]>,
+    ) -> InitialDeleteRangeBuilder {
+        let (start, end) = (start.as_ref(), end.as_ref());
+        DeleteRangeRequest::builder()
+            .distacean(self.core.distacean.clone())
+            .start(user_range(start, end).map(|()| start.to_vec()))
+            .end(end.to_vec())
+    }
+
+    /// Delete every key starting with `prefix` in one raft entry.
+    pub fn delete_prefix(self: &Self, prefix: impl AsRef<[u8]>) -> InitialDeleteRangeBuilder {
+        let prefix = prefix.as_ref();
+        self.delete_range(prefix, prefix_end(prefix))
+    }
+
+    /// Discard key history older than `revision`. Reads at revisions below the returned
+    /// compacted revision fail with `KVReadError::Compacted` afterwards. The history itself is
+    /// dropped in bounded steps that the leader proposes in the background. See `Retention` to
+    /// compact automatically.
+    pub async fn compact(
+        self: &Self,
+        revision: u64,
+    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self
+            .core
+            .distacean
+            .write_or_forward_to_leader(RequestOperation::KV(KVOperation::Compact { revision }))
+            .await?;
+
+        match response {
+            Response::Result {
+                res: ResponseResult::KV(KVResponse::Compact { compacted_revision }),
+                ..
+            } => Ok(compacted_revision),
+            _ => Err("Unexpected response type".into()),
+        }
     }
 
-    pub async fn delete(
-        self: &DistKV,
-        key: impl Into<String>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        self.core
+    /// Remove the secondary index `name`. Returns whether it existed.
+    pub async fn drop_index(
+        self: &Self,
+        name: &str,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self
+            .core
             .distacean
-            .write_or_forward_to_leader(RequestOperation::KV(KVOperation::Del { key: key.into() }))
+            .write_or_forward_to_leader(RequestOperation::KV(KVOperation::DropIndex {
+                name: name.to_string(),
+            }))
             .await?;
-        Ok(())
+
+        match response {
+            Response::Result {
+                res: ResponseResult::KV(KVResponse::DropIndex { existed }),
+                ..
+            } => Ok(existed),
+            _ => Err("Unexpected response type".into()),
+        }
     }
 
-    pub fn read(self: &DistKV, key: impl Into<String>) -> InitialReadBuilder {
+    /// Keys whose value in the index `name` falls in `value_range`. Use the ordered key type
+    /// matching the indexed field: `String`, `Vec<u8>`, `bool` or `i64`.
+    pub fn query_index<I: OrderedKey>(
+        self: &Self,
+        name: &str,
+        value_range: impl RangeBounds<I>,
+    ) -> InitialIndexQueryBuilder<C> {
+        let encode_bound = |bound: Bound<&I>| {
+            bound.map(|value| {
+                let mut encoded = Vec::new();
+                value.write_ordered(&mut encoded);
+                encoded
+            })
+        };
+        IndexQueryRequest::builder()
+            .distacean(self.core.distacean.clone())
+            .codec(self.codec.clone())
+            .name(name.to_string())
+            .start(encode_bound(value_range.start_bound()))
+            .end(encode_bound(value_range.end_bound()))
+    }
+
+    /// Atomically add `delta` to the integer counter at `key`.
+    /// Counters are always MessagePack integers, whatever the handle's codec.
+    pub fn incr(self: &Self, key: impl AsRef<[u8]>, delta: i64) -> InitialIncrBuilder {
+        IncrRequest::builder()
+            .distacean(self.core.distacean.clone())
+            .key(user_key(key.as_ref()))
+            .delta(delta)
+    }
+
+    /// Atomically subtract `delta` from the integer counter at `key`. Fails with
+    /// `IncrError::DeltaOverflow` for `i64::MIN`, which has no positive counterpart.
+    pub fn decr(
+        self: &Self,
+        key: impl AsRef<[u8]>,
+        delta: i64,
+    ) -> Result<InitialIncrBuilder, IncrError> {
+        let delta = delta.checked_neg().ok_or(IncrError::DeltaOverflow)?;
+        Ok(self.incr(key, delta))
+    }
+
+    /// Group set, CAS and delete operations into a single raft entry.
+    pub fn batch(self: &Self) -> BatchRequest<C> {
+        BatchRequest::new(self.core.distacean.clone(), self.codec.clone())
+    }
+
+    /// Group KV writes and FIFO enqueues into a single raft entry, guarded by optional
+    /// conditions on keys.
+    pub fn transaction(self: &Self) -> TxnRequest<C> {
+        TxnRequest::new(self.core.distacean.clone(), self.codec.clone())
+    }
+
+    /// Send many writes with several of them in flight at once, for bulk loads.
+    pub fn pipeline(self: &Self) -> PipelineRequest<C> {
+        PipelineRequest::new(self.core.distacean.clone(), self.codec.clone())
+    }
+
+    /// Typed handle to the keyspace `name`. Keyspaces are isolated from each other and from
+    /// plain keys, and can be dropped as a whole.
+    pub fn keyspace<K, V>(self: &Self, name: &str) -> Keyspace<K, V, C>
+    where
+        K: OrderedKey,
+        C: Codec<V>,
+    {
+        Keyspace::new(self.clone(), name)
+    }
+
+    pub fn read(self: &Self, key: impl AsRef<[u8]>) -> InitialReadBuilder<C> {
         ReadRequest::builder()
             .distacean(self.core.distacean.clone())
-            .key(key.into())
+            .codec(self.codec.clone())
+            .key(key.as_ref().to_vec())
+    }
+
+    /// Read many keys with one linearization point and one snapshot of the state machine.
+    pub fn read_many<K: AsRef<[u8]>>(
+        self: &Self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> InitialReadManyBuilder<C> {
+        ReadManyRequest::builder()
+            .distacean(self.core.distacean.clone())
+            .codec(self.codec.clone())
+            .keys(keys.into_iter().map(|key| key.as_ref().to_vec()).collect())
+    }
+}
+
+impl DistKV<MessagePackNamed> {
+    /// Register the secondary index `name`, replacing any index of that name, and index the
+    /// keys that already exist. Index entries are then kept up to date by every write, in the
+    /// same raft entry. Returns the number of indexed keys. Indexes find fields by name, so
+    /// the handle must write values with `MessagePackNamed`.
+    pub async fn create_index(
+        self: &Self,
+        name: &str,
+        definition: IndexDefinition,
+    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self
+            .core
+            .distacean
+            .write_or_forward_to_leader(RequestOperation::KV(KVOperation::CreateIndex {
+                name: name.to_string(),
+                definition,
+            }))
+            .await?;
+
+        match response {
+            Response::Result {
+                res: ResponseResult::KV(KVResponse::CreateIndex { indexed }),
+                ..
+            } => Ok(indexed),
+            _ => Err("Unexpected response type".into()),
+        }
     }
 }
@@ -0,0 +1,337 @@
+use std::sync::Arc;
+
+use futures::StreamExt;
+
+use crate::codec::Codec;
+use crate::codec::CodecError;
+use crate::core::DistaceanCore;
+use crate::distkv::ReservedKeyError;
+use crate::distkv::SetError;
+use crate::distkv::user_key;
+use crate::raft::KVOperation;
+use crate::raft::KVResponse;
+use crate::raft::RequestOperation;
+use crate::raft::Response;
+use crate::raft::ResponseResult;
+use crate::raft::SessionToken;
+use crate::raft::SetResponse;
+use crate::raft::store::kv::KVCas;
+use crate::raft::store::kv::KVDel;
+use crate::raft::store::kv::KVSet;
+
+/// Result of one operation of a batch or pipeline, in the order the operations were added.
+#[derive(Debug, Clone)]
+pub enum BatchOpResult {
+    Set(SetResponse),
+    Cas(SetResponse),
+    CasMismatch { current_revision: u64 },
+    Delete { existed: bool },
+}
+
+impl BatchOpResult {
+    pub(crate) fn from_response(
+        response: KVResponse,
+        token: SessionToken,
+    ) -> Result<Self, SetError> {
+        match response {
+            KVResponse::Set(set_response) => Ok(BatchOpResult::Set(SetResponse {
+                token,
+                ..set_response
+            })),
+            KVResponse::Cas {
+                success: true,
+                response,
+            } => Ok(BatchOpResult::Cas(SetResponse { token, ..response })),
+            KVResponse::Cas {
+                success: false,
+                response,
+            } => Ok(BatchOpResult::CasMismatch {
+                current_revision: response.revision,
+            }),
+            KVResponse::Del { response, .. } => Ok(BatchOpResult::Delete {
+                existed: response.existed,
+            }),
+            _ => Err(SetError::Other("Unexpected response type".into())),
+        }
+    }
+}
+
+/// Collects set, CAS and delete operations for `BatchRequest` and `PipelineRequest`.
+/// The first value that fails to encode and the first reserved key are kept and reported by
+/// `execute`.
+#[derive(Default)]
+struct KVOps {
+    ops: Vec<KVOperation>,
+    codec_error: Option<CodecError>,
+    key_error: Option<ReservedKeyError>,
+}
+
+impl KVOps {
+    fn key(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        match user_key(key) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                self.key_error.get_or_insert(e);
+                None
+            }
+        }
+    }
+
+    /// The first error recorded while collecting operations.
+    fn error(&mut self) -> Option<SetError> {
+        if let Some(e) = self.key_error.take() {
+            return Some(SetError::ReservedKey(e));
+        }
+        self.codec_error.take().map(SetError::Codec)
+    }
+
+    fn encode<T, C: Codec<T>>(&mut self, codec: &C, value: &T) -> Option<Vec<u8>> {
+        match codec.encode(value) {
+            Ok(bytes) => Some(bytes),
+            Err(e) => {
+                self.codec_error.get_or_insert(e);
+                None
+            }
+        }
+    }
+
+    fn set<T, C: Codec<T>>(&mut self, codec: &C, key: impl AsRef<[u8]>, value: &T) {
+        let (Some(key), Some(value)) = (self.key(key.as_ref()), self.encode(codec, value)) else {
+            return;
+        };
+        self.ops.push(KVOperation::Set(KVSet {
+            key,
+            value,
+            return_previous: false,
+            blob: false,
+        }));
+    }
+
+    fn cas<T, C: Codec<T>>(
+        &mut self,
+        codec: &C,
+        key: impl AsRef<[u8]>,
+        value: &T,
+        expected_revision: u64,
+    ) {
+        let (Some(key), Some(value)) = (self.key(key.as_ref()), self.encode(codec, value)) else {
+            return;
+        };
+        self.ops.push(KVOperation::Cas(KVCas {
+            key,
+            expected_revision,
+            value,
+            return_previous: false,
+            blob: false,
+        }));
+    }
+
+    fn delete(&mut self, key: impl AsRef<[u8]>) {
+        let Some(key) = self.key(key.as_ref()) else {
+            return;
+        };
+        self.ops.push(KVOperation::Del(KVDel {
+            key,
+            expected_revision: None,
+            return_previous: false,
+        }));
+    }
+}
+
+/// Write a group of operations as a single `KVOperation::Batch` raft entry. A `pipelined` entry
+/// is not ordered against the other writes of this node.
+async fn write_batch(
+    distacean: &DistaceanCore,
+    ops: Vec<KVOperation>,
+    pipelined: bool,
+) -> Result<Vec<BatchOpResult>, SetError> {
+    let op = RequestOperation::KV(KVOperation::Batch(ops));
+    let written = if pipelined {
+        distacean.write_pipelined(op).await
+    } else {
+        distacean.write_with_token(op).await
+    };
+    let (response, token) = written.map_err(SetError::from_write)?;
+
+    match response {
+        Response::Result {
+            res: ResponseResult::KV(KVResponse::Batch(responses)),
+            ..
+        } => responses
+            .into_iter()
+            .map(|response| BatchOpResult::from_response(response, token))
+            .collect(),
+        _ => Err(SetError::Other("Unexpected response type".into())),
+    }
+}
+
+/// Applies many operations atomically in one raft entry. Operations run in order and each one
+/// sees the writes of the ones before it. A CAS mismatch is reported in its result and does not
+/// abort the rest of the batch.
+pub struct BatchRequest<C> {
+    distacean: Arc<DistaceanCore>,
+    codec: C,
+    ops: KVOps,
+}
+
+impl<C> BatchRequest<C> {
+    pub(crate) fn new(distacean: Arc<DistaceanCore>, codec: C) -> Self {
+        Self {
+            distacean,
+            codec,
+            ops: KVOps::default(),
+        }
+    }
+
+    pub fn set<T>(mut self, key: impl AsRef<[u8]>, value: &T) -> Self
+    where
+        C: Codec<T>,
+    {
+        self.ops.set(&self.codec, key, value);
+        self
+    }
+
+    pub fn cas<T>(mut self, key: impl AsRef<[u8]>, value: &T, expected_revision: u64) -> Self
+    where
+        C: Codec<T>,
+    {
+        self.ops.cas(&self.codec, key, value, expected_revision);
+        self
+    }
+
+    pub fn delete(mut self, key: impl AsRef<[u8]>) -> Self {
+        self.ops.delete(key);
+        self
+    }
+
+    /// Fails with `SetError::Codec` or `SetError::ReservedKey` without writing anything if any
+    /// value failed to encode or any key is reserved.
+    pub async fn execute(mut self) -> Result<Vec<BatchOpResult>, SetError> {
+        if let Some(e) = self.ops.error() {
+            return Err(e);
+        }
+        if self.ops.ops.is_empty() {
+            return Ok(Vec::new());
+        }
+        write_batch(&self.distacean, self.ops.ops, false).await
+    }
+}
+
+/// Sends operations as independent raft entries while keeping up to `max_in_flight` of them
+/// outstanding, which on a follower means many forwarded writes in flight over the leader
+/// connection. With `ops_per_entry` above 1, consecutive operations are grouped into batches.
+/// Results come back in the order the operations were added, but the order in which entries
+/// are applied is not guaranteed: a later entry may be applied before an earlier one, so
+/// operations that depend on each other belong in the same entry or a `BatchRequest`.
+pub struct PipelineRequest<C> {
+    distacean: Arc<DistaceanCore>,
+    codec: C,
+    ops: KVOps,
+    max_in_flight: usize,
+    ops_per_entry: usize,
+}
+
+impl<C> PipelineRequest<C> {
+    pub(crate) fn new(distacean: Arc<DistaceanCore>, codec: C) -> Self {
+        Self {
+            distacean,
+            codec,
+            ops: KVOps::default(),
+            max_in_flight: 32,
+            ops_per_entry: 1,
+        }
+    }
+
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    pub fn ops_per_entry(mut self, ops_per_entry: usize) -> Self {
+        self.ops_per_entry = ops_per_entry.max(1);
+        self
+    }
+
+    pub fn set<T>(mut self, key: impl AsRef<[u8]>, value: &T) -> Self
+    where
+        C: Codec<T>,
+    {
+        self.ops.set(&self.codec, key, value);
+        self
+    }
+
+    pub fn cas<T>(mut self, key: impl AsRef<[u8]>, value: &T, expected_revision: u64) -> Self
+    where
+        C: Codec<T>,
+    {
+        self.ops.cas(&self.codec, key, value, expected_revision);
+        self
+    }
+
+    pub fn delete(mut self, key: impl AsRef<[u8]>) -> Self {
+        self.ops.delete(key);
+        self
+    }
+
+    /// Returns one result per operation, in order. A failed write fails every operation that
+    /// was grouped into the same entry. If any value failed to encode or any key is reserved,
+    /// nothing is written and the single result is `SetError::Codec` or `SetError::ReservedKey`.
+    pub async fn execute(mut self) -> Vec<Result<BatchOpResult, SetError>> {
+        if let Some(e) = self.ops.error() {
+            return vec![Err(e)];
+        }
+        let distacean = self.distacean;
+        let ops_per_entry = self.ops_per_entry;
+
+        let mut entries = Vec::new();
+        let mut ops = self.ops.ops.into_iter().peekable();
+        while ops.peek().is_some() {
+            entries.push(ops.by_ref().take(ops_per_entry).collect::<Vec<_>>());
+        }
+
+        futures::stream::iter(entries)
+            .map(|entry| {
+                let distacean = distacean.clone();
+                async move {
+                    let len = entry.len();
+                    if len == 1 {
+                        let op = entry.into_iter().next().unwrap();
+                        vec![write_single(&distacean, op).await]
+                    } else {
+                        match write_batch(&distacean, entry, true).await {
+                            Ok(results) => results.into_iter().map(Ok).collect(),
+                            Err(e) => {
+                                let message = e.to_string();
+                                (0..len)
+                                    .map(|_| Err(SetError::Other(message.clone().into())))
+                                    .collect()
+                            }
+                        }
+                    }
+                }
+            })
+            .buffered(self.max_in_flight)
+            .flat_map(futures::stream::iter)
+            .collect()
+            .await
+    }
+}
+
+/// Write one operation as its own pipelined raft entry.
+async fn write_single(
+    distacean: &DistaceanCore,
+    op: KVOperation,
+) -> Result<BatchOpResult, SetError> {
+    let (response, token) = distacean
+        .write_pipelined(RequestOperation::KV(op))
+        .await
+        .map_err(SetError::from_write)?;
+
+    match response {
+        Response::Result {
+            res: ResponseResult::KV(response),
+            ..
+        } => BatchOpResult::from_response(response, token),
+        _ => Err(SetError::Other("Unexpected response type".into())),
+    }
+}
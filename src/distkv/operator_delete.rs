@@ -0,0 +1,109 @@
+use std::sync::Arc;
+
+use self::delete_request_builder::State;
+use crate::core::DistaceanCore;
+use crate::distkv::ReservedKeyError;
+use crate::raft::DeleteResponse;
+use crate::raft::KVOperation;
+use crate::raft::KVResponse;
+use crate::raft::RequestOperation;
+use crate::raft::Response;
+use crate::raft::ResponseResult;
+use crate::raft::store::kv::KVDel;
+use bon::Builder;
+
+pub use self::delete_request_builder::{SetDistacean, SetKey, SetReturnPrevious};
+
+#[derive(Builder)]
+pub struct DeleteRequest {
+    distacean: Arc<DistaceanCore>,
+    /// Key, or the error raised because it lies under a reserved prefix
+    key: Result<Vec<u8>, ReservedKeyError>,
+
+    #[builder(default = false)]
+    pub return_previous: bool,
+
+    /// Only delete if the key is still at this revision (0 for a missing key)
+    pub expected_revision: Option<u64>,
+}
+
+impl DeleteRequest {
+    async fn execute(self) -> Result<DeleteResponse, DeleteError> {
+        let key = self.key.map_err(DeleteError::ReservedKey)?;
+        let (response, token) = self
+            .distacean
+            .write_with_token(RequestOperation::KV(KVOperation::Del(KVDel {
+                key,
+                expected_revision: self.expected_revision,
+                return_previous: self.return_previous,
+            })))
+            .await
+            .map_err(DeleteError::Other)?;
+
+        match response {
+            Response::Result {
+                res:
+                    ResponseResult::KV(KVResponse::Del {
+                        success: true,
+                        response,
+                    }),
+                ..
+            } => Ok(DeleteResponse { token, ..response }),
+            Response::Result {
+                res:
+                    ResponseResult::KV(KVResponse::Del {
+                        success: false,
+                        response,
+                    }),
+                ..
+            } => Err(DeleteError::RevisionMismatch {
+                current_revision: response.prev_revision,
+            }),
+            _ => Err(DeleteError::Other("Unexpected response type".into())),
+        }
+    }
+}
+
+impl<S> DeleteRequestBuilder<S>
+where
+    S: State + delete_request_builder::IsComplete,
+{
+    pub async fn execute(self) -> Result<DeleteResponse, DeleteError> {
+        self.build().execute().await
+    }
+}
+
+impl<S> DeleteRequestBuilder<S>
+where
+    S: State,
+    <S as State>::ReturnPrevious: delete_request_builder::IsUnset,
+{
+    pub fn with_previous(self) -> DeleteRequestBuilder<SetReturnPrevious<S>> {
+        self.return_previous(true)
+    }
+}
+
+#[derive(Debug)]
+pub enum DeleteError {
+    RevisionMismatch { current_revision: u64 },
+    ReservedKey(ReservedKeyError),
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl std::fmt::Display for DeleteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeleteError::RevisionMismatch { current_revision } => {
+                write!(
+                    f,
+                    "Revision mismatch: current revision is {}",
+                    current_revision
+                )
+            }
+            DeleteError::ReservedKey(e) => write!(f, "{}", e),
+            DeleteError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DeleteError {}
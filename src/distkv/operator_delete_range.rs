@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use self::delete_range_request_builder::State;
+use crate::core::DistaceanCore;
+use crate::distkv::ReservedKeyError;
+use crate::raft::KVOperation;
+use crate::raft::KVResponse;
+use crate::raft::RequestOperation;
+use crate::raft::Response;
+use crate::raft::ResponseResult;
+use bon::Builder;
+
+pub use self::delete_range_request_builder::{SetCount, SetDistacean, SetEnd, SetStart};
+
+/// Deletes every key in `[start, end)` in one raft entry. An empty `end` means no upper bound.
+#[derive(Builder)]
+pub struct DeleteRangeRequest {
+    distacean: Arc<DistaceanCore>,
+    /// Start of the range, or the error raised because the range covers reserved keys
+    start: Result<Vec<u8>, ReservedKeyError>,
+    end: Vec<u8>,
+
+    /// Return the number of deleted keys
+    #[builder(default = false)]
+    pub count: bool,
+}
+
+impl DeleteRangeRequest {
+    async fn execute(self) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self
+            .distacean
+            .write_or_forward_to_leader(RequestOperation::KV(KVOperation::DeleteRange {
+                start: self.start?,
+                end: self.end,
+                count: self.count,
+            }))
+            .await?;
+
+        match response {
+            Response::Result {
+                res: ResponseResult::KV(KVResponse::DeleteRange { deleted }),
+                ..
+            } => Ok(deleted),
+            _ => Err("Unexpected response type".into()),
+        }
+    }
+}
+
+impl<S> DeleteRangeRequestBuilder<S>
+where
+    S: State + delete_range_request_builder::IsComplete,
+{
+    /// Returns the number of deleted keys if `with_count` was used.
+    pub async fn execute(self) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+        self.build().execute().await
+    }
+}
+
+impl<S> DeleteRangeRequestBuilder<S>
+where
+    S: State,
+    <S as State>::Count: delete_range_request_builder::IsUnset,
+{
+    pub fn with_count(self) -> DeleteRangeRequestBuilder<SetCount<S>> {
+        self.count(true)
+    }
+}
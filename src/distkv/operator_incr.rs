@@ -0,0 +1,98 @@
+use std::sync::Arc;
+
+use self::incr_request_builder::State;
+use crate::core::DistaceanCore;
+use crate::distkv::ReservedKeyError;
+use crate::raft::IncrResult;
+use crate::raft::KVOperation;
+use crate::raft::KVResponse;
+use crate::raft::RequestOperation;
+use crate::raft::Response;
+use crate::raft::ResponseResult;
+use crate::raft::SessionToken;
+use crate::raft::store::kv::KVIncr;
+use bon::Builder;
+use thiserror::Error;
+
+pub use self::incr_request_builder::{SetDelta, SetDistacean, SetKey};
+
+#[derive(Error, Debug)]
+pub enum IncrError {
+    #[error("Counter would leave its bounds: current value is {value} at revision {revision}")]
+    OutOfRange { value: i64, revision: u64 },
+    #[error("Value at revision {revision} is not an integer")]
+    NotAnInteger { revision: u64 },
+    #[error("Delta cannot be negated without overflowing")]
+    DeltaOverflow,
+    #[error("{0}")]
+    ReservedKey(ReservedKeyError),
+    #[error("{0}")]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IncrResponse {
+    /// Value of the counter after the increment
+    pub value: i64,
+    pub revision: u64,
+    /// Causality token of the write
+    pub token: SessionToken,
+}
+
+/// Atomically adds `delta` to an integer counter on the leader, without a read-modify-write
+/// round trip. A missing key counts as 0.
+#[derive(Builder)]
+pub struct IncrRequest {
+    distacean: Arc<DistaceanCore>,
+    /// Key, or the error raised because it lies under a reserved prefix
+    key: Result<Vec<u8>, ReservedKeyError>,
+    delta: i64,
+
+    /// Reject the increment if the new value would be below this bound
+    pub min: Option<i64>,
+    /// Reject the increment if the new value would be above this bound
+    pub max: Option<i64>,
+}
+
+impl IncrRequest {
+    async fn execute(self) -> Result<IncrResponse, IncrError> {
+        let key = self.key.map_err(IncrError::ReservedKey)?;
+        let (response, token) = self
+            .distacean
+            .write_with_token(RequestOperation::KV(KVOperation::Incr(KVIncr {
+                key,
+                delta: self.delta,
+                min: self.min,
+                max: self.max,
+            })))
+            .await
+            .map_err(IncrError::Other)?;
+
+        match response {
+            Response::Result {
+                res: ResponseResult::KV(KVResponse::Incr(result)),
+                ..
+            } => match result {
+                IncrResult::Applied { value, revision } => Ok(IncrResponse {
+                    value,
+                    revision,
+                    token,
+                }),
+                IncrResult::OutOfRange { value, revision } => {
+                    Err(IncrError::OutOfRange { value, revision })
+                }
+                IncrResult::NotAnInteger { revision } => Err(IncrError::NotAnInteger { revision }),
+            },
+            _ => Err(IncrError::Other("Unexpected response type".into())),
+        }
+    }
+}
+
+impl<S> IncrRequestBuilder<S>
+where
+    S: State + incr_request_builder::IsComplete,
+{
+    pub async fn execute(self) -> Result<IncrResponse, IncrError> {
+        self.build().execute().await
+    }
+}
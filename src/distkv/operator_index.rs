@@ -0,0 +1,134 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use self::index_query_request_builder::State;
+use crate::codec::Codec;
+use crate::core::DistaceanCore;
+use crate::core::ReadSource;
+use crate::core::Staleness;
+use crate::distkv::operator_index::index_query_request_builder::SetConsistency;
+use crate::distkv::operator_index::index_query_request_builder::SetSource;
+use crate::distkv::operator_read::KVReadError;
+use crate::distkv::operator_read::ReadConsistency;
+use crate::distkv::operator_read::execute_query;
+use crate::distkv::operator_read::read_target;
+use crate::protocol::ReadQuery;
+use crate::protocol::ReadQueryResult;
+use crate::raft::SessionToken;
+use bon::Builder;
+
+pub use self::index_query_request_builder::{SetCodec, SetDistacean, SetEnd, SetName, SetStart};
+
+/// Looks keys up by their indexed value. `start` and `end` bound the encoded index value; all
+/// entries come from one consistent snapshot of the serving state machine.
+#[derive(Builder)]
+pub struct IndexQueryRequest<C> {
+    distacean: Arc<DistaceanCore>,
+    codec: C,
+    name: String,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+
+    /// Return at most this many entries
+    limit: Option<usize>,
+
+    #[builder(default = ReadSource::Leader)]
+    source: ReadSource,
+    #[builder(default = ReadConsistency::Linearizable)]
+    consistency: ReadConsistency,
+
+    /// Serve the read locally if this node is within this bound of the leader, otherwise read
+    /// from the leader with the requested consistency
+    max_staleness: Option<Staleness>,
+
+    /// Wait until the serving node has applied the write of this token
+    after: Option<SessionToken>,
+}
+
+impl<C> IndexQueryRequest<C> {
+    async fn execute<T>(self) -> Result<Vec<(Vec<u8>, T, u64)>, KVReadError>
+    where
+        C: Codec<T>,
+    {
+        let query = ReadQuery::Index {
+            name: self.name,
+            start: self.start,
+            end: self.end,
+            limit: self.limit,
+        };
+        let (source, consistency) = read_target(
+            &self.distacean,
+            self.source,
+            self.consistency,
+            self.max_staleness,
+        );
+        let entries =
+            match execute_query(&self.distacean, source, consistency, self.after, query).await? {
+                ReadQueryResult::Entries(entries) => entries,
+                _ => return Err(KVReadError::Unknown("Unexpected read result".into())),
+            };
+
+        let mut results = Vec::with_capacity(entries.len());
+        for (key, stored) in entries {
+            results.push((
+                key,
+                self.codec
+                    .decode(&stored.data)
+                    .map_err(KVReadError::Decode)?,
+                stored.revision,
+            ));
+        }
+        Ok(results)
+    }
+}
+
+impl<C, S> IndexQueryRequestBuilder<C, S>
+where
+    S: State + index_query_request_builder::IsComplete,
+{
+    /// Returns the matching keys with their values and revisions, ordered by indexed value and
+    /// then by key.
+    pub async fn execute<T>(self) -> Result<Vec<(Vec<u8>, T, u64)>, KVReadError>
+    where
+        C: Codec<T>,
+    {
+        self.build().execute().await
+    }
+}
+
+impl<C, S> IndexQueryRequestBuilder<C, S>
+where
+    S: State,
+    <S as State>::Source: index_query_request_builder::IsUnset,
+{
+    pub fn local(self) -> IndexQueryRequestBuilder<C, SetSource<S>> {
+        self.source(ReadSource::Local)
+    }
+
+    pub fn leader(self) -> IndexQueryRequestBuilder<C, SetSource<S>> {
+        self.source(ReadSource::Leader)
+    }
+
+    /// Read locally when this node is caught up, otherwise through the leader.
+    pub fn any(self) -> IndexQueryRequestBuilder<C, SetSource<S>> {
+        self.source(ReadSource::Any)
+    }
+}
+
+impl<C, S> IndexQueryRequestBuilder<C, S>
+where
+    S: State,
+    <S as State>::Consistency: index_query_request_builder::IsUnset,
+{
+    pub fn as_is(self) -> IndexQueryRequestBuilder<C, SetConsistency<S>> {
+        self.consistency(ReadConsistency::AsIs)
+    }
+
+    pub fn leader_lease(self) -> IndexQueryRequestBuilder<C, SetConsistency<S>> {
+        self.consistency(ReadConsistency::LeaseRead)
+    }
+
+    pub fn linearizable(self) -> IndexQueryRequestBuilder<C, SetConsistency<S>> {
+        self.consistency(ReadConsistency::Linearizable)
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use self::put_if_absent_request_builder::State;
+use crate::codec::CodecError;
+use crate::core::DistaceanCore;
+use crate::distkv::ReservedKeyError;
+use crate::distkv::SetError;
+use crate::raft::KVOperation;
+use crate::raft::KVResponse;
+use crate::raft::PutIfAbsentResponse;
+use crate::raft::RequestOperation;
+use crate::raft::Response;
+use crate::raft::ResponseResult;
+use crate::raft::store::kv::KVPutIfAbsent;
+use bon::Builder;
+
+pub use self::put_if_absent_request_builder::{SetDistacean, SetKey, SetValue};
+
+/// Stores a value only if the key does not exist, atomically in the state machine.
+#[derive(Builder)]
+pub struct PutIfAbsentRequest {
+    distacean: Arc<DistaceanCore>,
+    /// Key, or the error raised because it lies under a reserved prefix
+    key: Result<Vec<u8>, ReservedKeyError>,
+    /// Encoded value, or the error the codec raised while encoding it
+    value: Result<Vec<u8>, CodecError>,
+}
+
+impl PutIfAbsentRequest {
+    async fn execute(self) -> Result<PutIfAbsentResponse, SetError> {
+        let key = self.key.map_err(SetError::ReservedKey)?;
+        let value = self.value.map_err(SetError::Codec)?;
+        let (response, token) = self
+            .distacean
+            .write_with_token(RequestOperation::KV(KVOperation::PutIfAbsent(
+                KVPutIfAbsent { key, value },
+            )))
+            .await
+            .map_err(SetError::from_write)?;
+
+        match response {
+            Response::Result {
+                res: ResponseResult::KV(KVResponse::PutIfAbsent(response)),
+                ..
+            } => Ok(PutIfAbsentResponse { token, ..response }),
+            _ => Err(SetError::Other("Unexpected response type".into())),
+        }
+    }
+}
+
+impl<S> PutIfAbsentRequestBuilder<S>
+where
+    S: State + put_if_absent_request_builder::IsComplete,
+{
+    pub async fn execute(self) -> Result<PutIfAbsentResponse, SetError> {
+        self.build().execute().await
+    }
+}
+
+/// Result of `DistKV::get_or_insert_with`.
+#[derive(Debug, Clone)]
+pub struct GetOrInsertResponse<T> {
+    /// The existing value, or the newly stored one
+    pub value: T,
+    pub revision: u64,
+    /// Whether the value was inserted by this call
+    pub inserted: bool,
+}
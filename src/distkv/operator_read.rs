@@ -1,24 +1,46 @@
 use std::sync::Arc;
 
 use self::read_request_builder::State;
+use crate::codec::Codec;
+use crate::codec::CodecError;
 use crate::core::DistaceanCore;
 use crate::core::ReadSource;
+use crate::core::Staleness;
 use crate::distkv::operator_read::read_request_builder::SetConsistency;
 use crate::distkv::operator_read::read_request_builder::SetSource;
 use crate::protocol::ReadPolicy;
+use crate::protocol::ReadQuery;
+use crate::protocol::ReadQueryError;
+use crate::protocol::ReadQueryResult;
+use crate::raft::SessionToken;
+use crate::raft::store::kv::StoredValue;
 use bon::Builder;
-use serde::de::DeserializeOwned;
 
-pub use self::read_request_builder::{SetDistacean, SetKey};
+pub use self::read_request_builder::{SetCodec, SetDistacean, SetKey};
 
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum KVReadError {
+    #[error("Revision has been compacted, oldest readable revision is {compacted_revision}")]
+    Compacted { compacted_revision: u64 },
+    #[error("Failed to decode value: {0}")]
+    Decode(CodecError),
     #[error("Unknown error: {0}")]
     Unknown(Box<dyn std::error::Error + Send + Sync>),
 }
 
+/// Version information of a key, mirroring etcd's key metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyMetadata {
+    /// Cluster-wide revision of the last write to the key
+    pub revision: u64,
+    /// Revision at which the key was created
+    pub create_revision: u64,
+    /// Number of writes to the key since it was created
+    pub version: u64,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ReadConsistency {
     AsIs,
@@ -27,123 +49,195 @@ pub enum ReadConsistency {
 }
 
 #[derive(Builder)]
-pub struct ReadRequest {
+pub struct ReadRequest<C> {
     distacean: Arc<DistaceanCore>,
-    key: String,
+    codec: C,
+    key: Vec<u8>,
 
     #[builder(default = ReadSource::Leader)]
     source: ReadSource,
     #[builder(default = ReadConsistency::Linearizable)]
     consistency: ReadConsistency,
+
+    /// Read the value as of this revision instead of the latest one
+    at_revision: Option<u64>,
+
+    /// Serve the read locally if this node is within this bound of the leader, otherwise read
+    /// from the leader with the requested consistency
+    max_staleness: Option<Staleness>,
+
+    /// Wait until the serving node has applied the write of this token, for read-your-writes
+    /// across nodes
+    after: Option<SessionToken>,
 }
 
-impl ReadRequest {
-    async fn get_linearizer(
-        &self,
-    ) -> Result<
-        Option<openraft::raft::linearizable_read::LinearizeState<crate::raft::TypeConfig>>,
-        KVReadError,
-    > {
-        let linearizer_state: Option<
-            openraft::raft::linearizable_read::LinearizeState<crate::raft::TypeConfig>,
-        > = match self.consistency {
-            ReadConsistency::AsIs => None, // TODO: Implement AsIs for leader reads
-            ReadConsistency::LeaseRead => Some(
-                self.distacean
-                    .get_linearizer(self.source, ReadPolicy::LeaseRead)
-                    .await
-                    .map_err(|e| KVReadError::Unknown(e.into()))?,
-            ),
-            ReadConsistency::Linearizable => Some(
-                self.distacean
-                    .get_linearizer(self.source, ReadPolicy::ReadIndex)
-                    .await
-                    .map_err(|e| KVReadError::Unknown(e.into()))?,
-            ),
-        };
-        Ok(linearizer_state)
+impl ReadConsistency {
+    /// Read policy to linearize with, `None` for reads served as is.
+    pub(crate) fn read_policy(self) -> Option<ReadPolicy> {
+        match self {
+            ReadConsistency::AsIs => None,
+            ReadConsistency::LeaseRead => Some(ReadPolicy::LeaseRead),
+            ReadConsistency::Linearizable => Some(ReadPolicy::ReadIndex),
+        }
     }
+}
+
+/// Execute `query` with the given source and consistency, on this node or on the leader.
+pub(crate) async fn execute_query(
+    distacean: &DistaceanCore,
+    source: ReadSource,
+    consistency: ReadConsistency,
+    after: Option<SessionToken>,
+    query: ReadQuery,
+) -> Result<ReadQueryResult, KVReadError> {
+    distacean
+        .execute_read(source, consistency.read_policy(), after, query)
+        .await
+        .map_err(|e| match e {
+            ReadQueryError::Compacted { compacted_revision } => {
+                KVReadError::Compacted { compacted_revision }
+            }
+            ReadQueryError::Other(message) => KVReadError::Unknown(message.into()),
+        })
+}
 
-    async fn execute<T: DeserializeOwned>(self) -> Result<T, KVReadError> {
-        self.get_linearizer().await?;
+/// Source and consistency to serve a read with. With `max_staleness`, the read is served locally
+/// and as is when this node is within the bound, and from the leader otherwise.
+pub(crate) fn read_target(
+    distacean: &DistaceanCore,
+    source: ReadSource,
+    consistency: ReadConsistency,
+    max_staleness: Option<Staleness>,
+) -> (ReadSource, ReadConsistency) {
+    match max_staleness {
+        Some(max_staleness) if distacean.is_within_staleness(max_staleness) => {
+            (ReadSource::Local, ReadConsistency::AsIs)
+        }
+        Some(_) => (ReadSource::Leader, consistency),
+        None => (source, consistency),
+    }
+}
 
-        // Read from local state machine
-        let value = self
-            .distacean
-            .state_machine_store
-            .get(&self.key)
-            .await
-            .map_err(|e| KVReadError::Unknown(e.into()))?
-            .unwrap_or_default();
+impl<C> ReadRequest<C> {
+    /// Read the stored value, at `at_revision` if one was given.
+    async fn read_stored(&self) -> Result<Option<StoredValue>, KVReadError> {
+        let query = ReadQuery::Get {
+            key: self.key.clone(),
+            at_revision: self.at_revision,
+        };
+        let distacean = &self.distacean;
+        let (source, consistency) =
+            read_target(distacean, self.source, self.consistency, self.max_staleness);
+        match execute_query(distacean, source, consistency, self.after, query).await? {
+            ReadQueryResult::Value(stored) => Ok(stored),
+            _ => Err(KVReadError::Unknown("Unexpected read result".into())),
+        }
+    }
 
-        return Ok(rmp_serde::from_slice(&value).map_err(|e| KVReadError::Unknown(e.into()))?);
+    async fn execute<T>(self) -> Result<Option<T>, KVReadError>
+    where
+        C: Codec<T>,
+    {
+        match self.read_stored().await? {
+            Some(stored) => Ok(Some(
+                self.codec
+                    .decode(&stored.data)
+                    .map_err(KVReadError::Decode)?,
+            )),
+            None => Ok(None),
+        }
     }
 
-    async fn execute_with_revision<T: DeserializeOwned>(
-        self,
-    ) -> Result<Option<(T, u64)>, KVReadError> {
-        let _ = self.get_linearizer().await;
+    async fn execute_with_revision<T>(self) -> Result<Option<(T, u64)>, KVReadError>
+    where
+        C: Codec<T>,
+    {
+        Ok(self
+            .execute_with_metadata::<T>()
+            .await?
+            .map(|(value, metadata)| (value, metadata.revision)))
+    }
 
-        // Read from local state machine
-        let result = self
-            .distacean
-            .state_machine_store
-            .get_with_revision(&self.key)
-            .await
-            .map_err(|e| KVReadError::Unknown(e.into()))?;
+    async fn execute_with_metadata<T>(self) -> Result<Option<(T, KeyMetadata)>, KVReadError>
+    where
+        C: Codec<T>,
+    {
+        let result = self.read_stored().await?;
 
         Ok(match result {
-            Some((value, revision)) => Some((
-                rmp_serde::from_slice(&value).map_err(|e| KVReadError::Unknown(e.into()))?,
-                revision,
+            Some(stored) => Some((
+                self.codec
+                    .decode(&stored.data)
+                    .map_err(KVReadError::Decode)?,
+                KeyMetadata {
+                    revision: stored.revision,
+                    create_revision: stored.create_revision,
+                    version: stored.version,
+                },
             )),
             None => None,
         })
     }
 }
 
-impl<S> ReadRequestBuilder<S>
+impl<C, S> ReadRequestBuilder<C, S>
 where
     S: State + read_request_builder::IsComplete,
 {
-    pub async fn execute<T: DeserializeOwned>(self) -> Result<T, KVReadError> {
+    /// Returns `None` if the key does not exist.
+    pub async fn execute<T>(self) -> Result<Option<T>, KVReadError>
+    where
+        C: Codec<T>,
+    {
         self.build().execute().await
     }
-    pub async fn execute_with_revision<T: DeserializeOwned>(
-        self,
-    ) -> Result<Option<(T, u64)>, KVReadError> {
+    pub async fn execute_with_revision<T>(self) -> Result<Option<(T, u64)>, KVReadError>
+    where
+        C: Codec<T>,
+    {
         self.build().execute_with_revision().await
     }
+    pub async fn execute_with_metadata<T>(self) -> Result<Option<(T, KeyMetadata)>, KVReadError>
+    where
+        C: Codec<T>,
+    {
+        self.build().execute_with_metadata().await
+    }
 }
 
-impl<S> ReadRequestBuilder<S>
+impl<C, S> ReadRequestBuilder<C, S>
 where
     S: State,
     <S as State>::Source: read_request_builder::IsUnset,
 {
-    pub fn local(self) -> ReadRequestBuilder<SetSource<S>> {
+    pub fn local(self) -> ReadRequestBuilder<C, SetSource<S>> {
         self.source(ReadSource::Local)
     }
 
-    pub fn leader(self) -> ReadRequestBuilder<SetSource<S>> {
+    pub fn leader(self) -> ReadRequestBuilder<C, SetSource<S>> {
         self.source(ReadSource::Leader)
     }
+
+    /// Read locally when this node is caught up, otherwise through the leader.
+    pub fn any(self) -> ReadRequestBuilder<C, SetSource<S>> {
+        self.source(ReadSource::Any)
+    }
 }
 
-impl<S> ReadRequestBuilder<S>
+impl<C, S> ReadRequestBuilder<C, S>
 where
     S: State,
     <S as State>::Consistency: read_request_builder::IsUnset,
 {
-    pub fn as_is(self) -> ReadRequestBuilder<SetConsistency<S>> {
+    pub fn as_is(self) -> ReadRequestBuilder<C, SetConsistency<S>> {
         self.consistency(ReadConsistency::AsIs)
     }
 
-    pub fn leader_lease(self) -> ReadRequestBuilder<SetConsistency<S>> {
+    pub fn leader_lease(self) -> ReadRequestBuilder<C, SetConsistency<S>> {
         self.consistency(ReadConsistency::LeaseRead)
     }
 
-    pub fn linearizable(self) -> ReadRequestBuilder<SetConsistency<S>> {
+    pub fn linearizable(self) -> ReadRequestBuilder<C, SetConsistency<S>> {
         self.consistency(ReadConsistency::Linearizable)
     }
 }
@@ -0,0 +1,125 @@
+use std::sync::Arc;
+
+use self::read_many_request_builder::State;
+use crate::codec::Codec;
+use crate::core::DistaceanCore;
+use crate::core::ReadSource;
+use crate::core::Staleness;
+use crate::distkv::operator_read::KVReadError;
+use crate::distkv::operator_read::ReadConsistency;
+use crate::distkv::operator_read::execute_query;
+use crate::distkv::operator_read::read_target;
+use crate::distkv::operator_read_many::read_many_request_builder::SetConsistency;
+use crate::distkv::operator_read_many::read_many_request_builder::SetSource;
+use crate::protocol::ReadQuery;
+use crate::protocol::ReadQueryResult;
+use crate::raft::SessionToken;
+use bon::Builder;
+
+pub use self::read_many_request_builder::{SetCodec, SetDistacean, SetKeys};
+
+/// Reads several keys behind a single linearization point. All values come from one
+/// consistent snapshot of the local state machine.
+#[derive(Builder)]
+pub struct ReadManyRequest<C> {
+    distacean: Arc<DistaceanCore>,
+    codec: C,
+    keys: Vec<Vec<u8>>,
+
+    #[builder(default = ReadSource::Leader)]
+    source: ReadSource,
+    #[builder(default = ReadConsistency::Linearizable)]
+    consistency: ReadConsistency,
+
+    /// Serve the read locally if this node is within this bound of the leader, otherwise read
+    /// from the leader with the requested consistency
+    max_staleness: Option<Staleness>,
+
+    /// Wait until the serving node has applied the write of this token
+    after: Option<SessionToken>,
+}
+
+impl<C> ReadManyRequest<C> {
+    async fn execute<T>(self) -> Result<Vec<Option<(T, u64)>>, KVReadError>
+    where
+        C: Codec<T>,
+    {
+        // All keys are read from one snapshot of the state machine
+        let query = ReadQuery::GetMany { keys: self.keys };
+        let (source, consistency) = read_target(
+            &self.distacean,
+            self.source,
+            self.consistency,
+            self.max_staleness,
+        );
+        let results =
+            match execute_query(&self.distacean, source, consistency, self.after, query).await? {
+                ReadQueryResult::Values(values) => values,
+                _ => return Err(KVReadError::Unknown("Unexpected read result".into())),
+            };
+
+        let mut values = Vec::with_capacity(results.len());
+        for result in results {
+            values.push(match result {
+                Some(stored) => Some((
+                    self.codec
+                        .decode(&stored.data)
+                        .map_err(KVReadError::Decode)?,
+                    stored.revision,
+                )),
+                None => None,
+            });
+        }
+        Ok(values)
+    }
+}
+
+impl<C, S> ReadManyRequestBuilder<C, S>
+where
+    S: State + read_many_request_builder::IsComplete,
+{
+    /// Returns one entry per requested key, in request order. Missing keys are `None`.
+    pub async fn execute<T>(self) -> Result<Vec<Option<(T, u64)>>, KVReadError>
+    where
+        C: Codec<T>,
+    {
+        self.build().execute().await
+    }
+}
+
+impl<C, S> ReadManyRequestBuilder<C, S>
+where
+    S: State,
+    <S as State>::Source: read_many_request_builder::IsUnset,
+{
+    pub fn local(self) -> ReadManyRequestBuilder<C, SetSource<S>> {
+        self.source(ReadSource::Local)
+    }
+
+    pub fn leader(self) -> ReadManyRequestBuilder<C, SetSource<S>> {
+        self.source(ReadSource::Leader)
+    }
+
+    /// Read locally when this node is caught up, otherwise through the leader.
+    pub fn any(self) -> ReadManyRequestBuilder<C, SetSource<S>> {
+        self.source(ReadSource::Any)
+    }
+}
+
+impl<C, S> ReadManyRequestBuilder<C, S>
+where
+    S: State,
+    <S as State>::Consistency: read_many_request_builder::IsUnset,
+{
+    pub fn as_is(self) -> ReadManyRequestBuilder<C, SetConsistency<S>> {
+        self.consistency(ReadConsistency::AsIs)
+    }
+
+    pub fn leader_lease(self) -> ReadManyRequestBuilder<C, SetConsistency<S>> {
+        self.consistency(ReadConsistency::LeaseRead)
+    }
+
+    pub fn linearizable(self) -> ReadManyRequestBuilder<C, SetConsistency<S>> {
+        self.consistency(ReadConsistency::Linearizable)
+    }
+}
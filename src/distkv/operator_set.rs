@@ -1,12 +1,17 @@
 use std::sync::Arc;
 
 use self::set_request_builder::State;
+use crate::codec::CodecError;
 use crate::core::DistaceanCore;
+use crate::distkv::ReservedKeyError;
+use crate::distkv::blob::write_blob;
+use crate::limits::SizeLimitError;
 use crate::raft::KVOperation;
 use crate::raft::KVResponse;
 use crate::raft::RequestOperation;
 use crate::raft::Response;
 use crate::raft::ResponseResult;
+use crate::raft::SessionToken;
 use crate::raft::SetResponse;
 use crate::raft::store::kv::KVCas;
 use crate::raft::store::kv::KVSet;
@@ -17,8 +22,10 @@ pub use self::set_request_builder::{SetDistacean, SetKey, SetReturnPrevious, Set
 #[derive(Builder)]
 pub struct SetRequest {
     distacean: Arc<DistaceanCore>,
-    key: String,
-    value: Vec<u8>,
+    /// Key, or the error raised because it lies under a reserved prefix
+    key: Result<Vec<u8>, ReservedKeyError>,
+    /// Encoded value, or the error the codec raised while encoding it
+    value: Result<Vec<u8>, CodecError>,
 
     #[builder(default = false)]
     pub return_previous: bool,
@@ -26,41 +33,63 @@ pub struct SetRequest {
     pub expected_revision: Option<u64>,
 }
 
+/// Attempts at storing a blob whose chunks were collected while it was being written
+const BLOB_WRITE_ATTEMPTS: usize = 3;
+
 impl SetRequest {
     async fn execute(self) -> Result<SetResponse, SetError> {
         let distacean = self.distacean;
-        let key = self.key;
-        let value = self.value;
+        let key = self.key.map_err(SetError::ReservedKey)?;
+        let value = self.value.map_err(SetError::Codec)?;
         let return_previous = self.return_previous;
         let expected_revision = self.expected_revision;
 
-        let response = if let Some(expected_revision) = expected_revision {
-            // CAS operation
-            distacean
-                .write_or_forward_to_leader(RequestOperation::KV(KVOperation::Cas(KVCas {
-                    key,
-                    expected_revision,
-                    value,
-                    return_previous,
-                })))
-                .await
-                .map_err(|e| SetError::Other(e))?
-        } else {
-            // Set operation
-            distacean
-                .write_or_forward_to_leader(RequestOperation::KV(KVOperation::Set(KVSet {
+        // In blob mode, values above the value limit are stored as chunks plus a manifest
+        let chunk_bytes = distacean
+            .limits
+            .blob_chunk_bytes()
+            .filter(|_| value.len() > distacean.limits.max_value_bytes);
+        let (response, token) = match chunk_bytes {
+            Some(chunk_bytes) => {
+                let mut attempts = 1;
+                loop {
+                    let manifest = write_blob(&distacean, &value, chunk_bytes).await?;
+                    let written = write_value(
+                        &distacean,
+                        key.clone(),
+                        manifest,
+                        true,
+                        return_previous,
+                        expected_revision,
+                    )
+                    .await?;
+                    // A chunk shared with a blob removed in the meantime may have been
+                    // collected before the manifest landed; write the chunks again
+                    match written {
+                        (
+                            Response::Result {
+                                res: ResponseResult::KV(KVResponse::MissingBlobChunks),
+                                ..
+                            },
+                            _,
+                        ) if attempts < BLOB_WRITE_ATTEMPTS => attempts += 1,
+                        written => break written,
+                    }
+                }
+            }
+            None => {
+                write_value(
+                    &distacean,
                     key,
                     value,
+                    false,
                     return_previous,
-                })))
-                .await
-                .map_err(|e| SetError::Other(e))?
+                    expected_revision,
+                )
+                .await?
+            }
         };
 
-        if let Response::Empty = response {
-            return Err(SetError::Other("Unexpected response type".into()));
-        }
-
         let res = match response {
             Response::Empty => {
                 return Err(SetError::Other("Unexpected response type".into()));
@@ -70,22 +99,58 @@ impl SetRequest {
 
         // Extract SetResponse from response
         match res {
-            ResponseResult::KV(KVResponse::Set(set_response)) => Ok(set_response),
+            ResponseResult::KV(KVResponse::Set(set_response)) => Ok(SetResponse {
+                token,
+                ..set_response
+            }),
             ResponseResult::KV(KVResponse::Cas {
                 success: true,
                 response,
-            }) => Ok(response),
+            }) => Ok(SetResponse { token, ..response }),
             ResponseResult::KV(KVResponse::Cas {
                 success: false,
                 response,
             }) => Err(SetError::RevisionMismatch {
                 current_revision: response.revision,
             }),
+            ResponseResult::KV(KVResponse::MissingBlobChunks) => Err(SetError::Other(
+                "Blob chunks kept being removed while the blob was written".into(),
+            )),
             _ => Err(SetError::Other("Unexpected response type".into())),
         }
     }
 }
 
+/// Write `value` with a set, or with a CAS if `expected_revision` is given.
+async fn write_value(
+    distacean: &DistaceanCore,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    blob: bool,
+    return_previous: bool,
+    expected_revision: Option<u64>,
+) -> Result<(Response, SessionToken), SetError> {
+    let op = match expected_revision {
+        Some(expected_revision) => KVOperation::Cas(KVCas {
+            key,
+            expected_revision,
+            value,
+            return_previous,
+            blob,
+        }),
+        None => KVOperation::Set(KVSet {
+            key,
+            value,
+            return_previous,
+            blob,
+        }),
+    };
+    distacean
+        .write_with_token(RequestOperation::KV(op))
+        .await
+        .map_err(SetError::from_write)
+}
+
 impl<S> SetRequestBuilder<S>
 where
     S: State + set_request_builder::IsComplete,
@@ -108,6 +173,9 @@ where
 #[derive(Debug)]
 pub enum SetError {
     RevisionMismatch { current_revision: u64 },
+    Codec(CodecError),
+    TooLarge(SizeLimitError),
+    ReservedKey(ReservedKeyError),
     Other(Box<dyn std::error::Error + Send + Sync>),
 }
 
@@ -121,9 +189,22 @@ impl std::fmt::Display for SetError {
                     current_revision
                 )
             }
+            SetError::Codec(e) => write!(f, "{}", e),
+            SetError::TooLarge(e) => write!(f, "{}", e),
+            SetError::ReservedKey(e) => write!(f, "{}", e),
             SetError::Other(e) => write!(f, "{}", e),
         }
     }
 }
 
 impl std::error::Error for SetError {}
+
+impl SetError {
+    /// Wrap an error from a raft write, keeping size limit violations typed.
+    pub(crate) fn from_write(error: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        match error.downcast::<SizeLimitError>() {
+            Ok(error) => SetError::TooLarge(*error),
+            Err(error) => SetError::Other(error),
+        }
+    }
+}
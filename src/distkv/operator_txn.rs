@@ -0,0 +1,244 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::codec::Codec;
+use crate::codec::CodecError;
+use crate::core::DistaceanCore;
+use crate::distkv::ReservedKeyError;
+use crate::distkv::SetError;
+use crate::distkv::operator_batch::BatchOpResult;
+use crate::distkv::user_key;
+use crate::raft::RequestOperation;
+use crate::raft::Response;
+use crate::raft::ResponseResult;
+use crate::raft::SessionToken;
+use crate::raft::Txn;
+use crate::raft::store::fifo::FIFOEnqueue;
+use crate::raft::store::fifo::FIFOResponse;
+use crate::raft::store::kv::KVCas;
+use crate::raft::store::kv::KVDel;
+use crate::raft::store::kv::KVSet;
+use crate::raft::store::txn::TxnCompare;
+use crate::raft::store::txn::TxnOperation;
+
+/// Result of one operation of a transaction, in the order the operations were added.
+#[derive(Debug, Clone)]
+pub enum TxnOpResult {
+    KV(BatchOpResult),
+    Enqueued,
+    /// The queue's limits refused the items, so nothing of the transaction was applied
+    QueueFull,
+}
+
+#[derive(Debug, Clone)]
+pub struct TxnResult {
+    /// Whether every condition held and every operation was applied. Otherwise nothing was
+    /// written.
+    pub succeeded: bool,
+    /// Results of the operations in order. They stop at the operation that declined, such as a
+    /// CAS mismatch or a full queue, and are empty when a condition failed.
+    pub results: Vec<TxnOpResult>,
+    /// Index of the operation that declined and failed the transaction
+    pub failed_op: Option<usize>,
+    /// Causality token of the transaction's raft entry
+    pub token: SessionToken,
+}
+
+/// Applies KV writes and FIFO enqueues atomically in one raft entry, if every condition holds
+/// and no operation declines. Use it for a transactional outbox: store a change and enqueue the
+/// message announcing it, so that neither can happen without the other. Operations run in order
+/// and each one sees the writes of the ones before it; a CAS mismatch or a full queue fails the
+/// whole transaction.
+pub struct TxnRequest<C> {
+    distacean: Arc<DistaceanCore>,
+    codec: C,
+    compare: Vec<TxnCompare>,
+    ops: Vec<TxnOperation>,
+    codec_error: Option<CodecError>,
+    key_error: Option<ReservedKeyError>,
+}
+
+impl<C> TxnRequest<C> {
+    pub(crate) fn new(distacean: Arc<DistaceanCore>, codec: C) -> Self {
+        Self {
+            distacean,
+            codec,
+            compare: Vec::new(),
+            ops: Vec::new(),
+            codec_error: None,
+            key_error: None,
+        }
+    }
+
+    fn key(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        match user_key(key) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                self.key_error.get_or_insert(e);
+                None
+            }
+        }
+    }
+
+    fn encode<T>(&mut self, value: &T) -> Option<Vec<u8>>
+    where
+        C: Codec<T>,
+    {
+        match self.codec.encode(value) {
+            Ok(bytes) => Some(bytes),
+            Err(e) => {
+                self.codec_error.get_or_insert(e);
+                None
+            }
+        }
+    }
+
+    /// Only apply the transaction if `key` is at `revision`, 0 for a missing key.
+    pub fn if_revision(mut self, key: impl AsRef<[u8]>, revision: u64) -> Self {
+        self.compare.push(TxnCompare::Revision {
+            key: key.as_ref().to_vec(),
+            revision,
+        });
+        self
+    }
+
+    /// Only apply the transaction if `key` holds `value`.
+    pub fn if_value<T>(mut self, key: impl AsRef<[u8]>, value: &T) -> Self
+    where
+        C: Codec<T>,
+    {
+        if let Some(value) = self.encode(value) {
+            self.compare.push(TxnCompare::Value {
+                key: key.as_ref().to_vec(),
+                value: Some(value),
+            });
+        }
+        self
+    }
+
+    /// Only apply the transaction if `key` does not exist.
+    pub fn if_missing(mut self, key: impl AsRef<[u8]>) -> Self {
+        self.compare.push(TxnCompare::Value {
+            key: key.as_ref().to_vec(),
+            value: None,
+        });
+        self
+    }
+
+    pub fn set<T>(mut self, key: impl AsRef<[u8]>, value: &T) -> Self
+    where
+        C: Codec<T>,
+    {
+        if let (Some(key), Some(value)) = (self.key(key.as_ref()), self.encode(value)) {
+            self.ops.push(TxnOperation::Set(KVSet {
+                key,
+                value,
+                return_previous: false,
+                blob: false,
+            }));
+        }
+        self
+    }
+
+    pub fn cas<T>(mut self, key: impl AsRef<[u8]>, value: &T, expected_revision: u64) -> Self
+    where
+        C: Codec<T>,
+    {
+        if let (Some(key), Some(value)) = (self.key(key.as_ref()), self.encode(value)) {
+            self.ops.push(TxnOperation::Cas(KVCas {
+                key,
+                expected_revision,
+                value,
+                return_previous: false,
+                blob: false,
+            }));
+        }
+        self
+    }
+
+    pub fn delete(mut self, key: impl AsRef<[u8]>) -> Self {
+        if let Some(key) = self.key(key.as_ref()) {
+            self.ops.push(TxnOperation::Del(KVDel {
+                key,
+                expected_revision: None,
+                return_previous: false,
+            }));
+        }
+        self
+    }
+
+    /// Append `values` to a FIFO queue, encoded like `DistFIFO::enqueue` does.
+    pub fn enqueue<TKey: Serialize, TVal: Serialize>(
+        mut self,
+        queue_name: TKey,
+        values: Vec<TVal>,
+    ) -> Self {
+        let encoded = rmp_serde::to_vec(&queue_name).and_then(|queue_key| {
+            Ok(FIFOEnqueue {
+                queue_key,
+                values: values
+                    .iter()
+                    .map(rmp_serde::to_vec)
+                    .collect::<Result<_, _>>()?,
+                deliver_at_ms: None,
+                delay_ms: None,
+            })
+        });
+        match encoded {
+            Ok(enqueue) => self.ops.push(TxnOperation::Enqueue(enqueue)),
+            Err(e) => {
+                self.codec_error.get_or_insert(CodecError::new(e));
+            }
+        }
+        self
+    }
+
+    /// Fails with `SetError::Codec` or `SetError::ReservedKey` without writing anything if any
+    /// value failed to encode or any written key is reserved.
+    pub async fn execute(self) -> Result<TxnResult, SetError> {
+        if let Some(e) = self.key_error {
+            return Err(SetError::ReservedKey(e));
+        }
+        if let Some(e) = self.codec_error {
+            return Err(SetError::Codec(e));
+        }
+
+        let (response, token) = self
+            .distacean
+            .write_with_token(RequestOperation::Txn(Txn {
+                compare: self.compare,
+                ops: self.ops,
+            }))
+            .await
+            .map_err(SetError::from_write)?;
+
+        let txn_response = match response {
+            Response::Result {
+                res: ResponseResult::Txn(txn_response),
+                ..
+            } => txn_response,
+            _ => return Err(SetError::Other("Unexpected response type".into())),
+        };
+
+        let results = txn_response
+            .responses
+            .into_iter()
+            .map(|response| match response {
+                ResponseResult::KV(response) => Ok(TxnOpResult::KV(BatchOpResult::from_response(
+                    response, token,
+                )?)),
+                ResponseResult::FIFO(FIFOResponse::Enqueue(_)) => Ok(TxnOpResult::Enqueued),
+                ResponseResult::FIFO(FIFOResponse::QueueFull { .. }) => Ok(TxnOpResult::QueueFull),
+                _ => Err(SetError::Other("Unexpected response type".into())),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(TxnResult {
+            succeeded: txn_response.succeeded,
+            results,
+            failed_op: txn_response.failed_op,
+            token,
+        })
+    }
+}
@@ -1,40 +1,280 @@
-use std::{io, sync::Arc};
+pub mod operator_read;
+pub mod priority;
+
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
 use crate::{
     core::DistaceanCore,
+    protocol::ReadQuery,
     raft::{
         FIFOOperation, RequestOperation, Response, ResponseResult,
-        store::fifo::{FIFODequeue, FIFOEnqueue, FIFOResponse},
+        store::fifo::{
+            FIFOAck, FIFOConfigure, FIFOCreateQueue, FIFODeleteQueue, FIFODequeue, FIFOEnqueue,
+            FIFOMove, FIFOPurge, FIFOReserve, FIFOResponse, FIFOUpdateQueue, ReceiptHandle,
+            common::{DropPolicy, QueueConfig},
+        },
     },
 };
 
+use self::operator_read::{
+    Length, ListQueues, Peek, QueueReadRequest, QueueReadRequestBuilder, Stats,
+};
+
+/// Item reserved from a queue. Pass `handle` to `DistFIFO::ack` once it has been processed.
+#[derive(Debug, Clone)]
+pub struct FIFOMessage<T> {
+    pub value: T,
+    pub handle: ReceiptHandle,
+    /// Number of times the item has been delivered, 1 on the first delivery
+    pub deliveries: u32,
+}
+
+/// Limits of a queue, set with `DistFIFO::create_queue` or `DistFIFO::update_queue`. The
+/// defaults leave the queue unbounded.
+#[derive(Debug, Clone, Default)]
+pub struct QueueOptions {
+    /// Most items the queue holds, counting scheduled ones but not reserved ones
+    pub max_length: Option<u64>,
+    /// Largest total size of the encoded items the queue holds, counting scheduled ones but not
+    /// reserved ones
+    pub max_bytes: Option<u64>,
+    /// Time after which a waiting item expires and is dropped instead of delivered
+    pub message_ttl: Option<Duration>,
+    /// What an enqueue does when the new items do not fit within the limits
+    pub drop_policy: DropPolicy,
+}
+
+/// How long a blocked producer waits on the leader before asking again
+const BLOCKED_ENQUEUE_POLL: Duration = Duration::from_secs(30);
+
+/// Returned by `DistFIFO::enqueue`, boxed, when the new items do not fit within the queue's
+/// limits and the queue rejects them: with the `Reject` drop policy, or with `Block` if they
+/// would not even fit into the empty queue. None of them were enqueued. `DistFIFO::move_items`
+/// returns it when the target queue rejects the items, under either policy.
+#[derive(Debug)]
+pub struct QueueFullError {
+    pub length: u64,
+    pub bytes: u64,
+    pub max_length: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl std::fmt::Display for QueueFullError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Queue is full: {} items, {} bytes (max_length: {:?}, max_bytes: {:?})",
+            self.length, self.bytes, self.max_length, self.max_bytes
+        )
+    }
+}
+
+impl std::error::Error for QueueFullError {}
+
+pub type InitialQueueReadBuilder<Q> =
+    QueueReadRequestBuilder<Q, operator_read::SetQuery<operator_read::SetDistacean>>;
+
 #[derive(Clone)]
 pub struct DistFIFO {
     pub(crate) distacean: Arc<DistaceanCore>,
 }
 
 impl DistFIFO {
-    pub async fn enqueue<TKey: Serialize, TVal: Serialize>(
+    /// Create an empty queue with the given limits. Returns false, leaving the queue unchanged,
+    /// if it already exists; use `update_queue` to change its limits. Queues without options
+    /// also come into existence on first enqueue.
+    pub async fn create_queue<TKey: Serialize>(
         self: &DistFIFO,
         queue_name: TKey,
-        values: Vec<TVal>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        self.distacean
-            .write_or_forward_to_leader(RequestOperation::FIFO(FIFOOperation::Enqueue(
-                FIFOEnqueue {
+        options: QueueOptions,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_or_forward_to_leader(RequestOperation::FIFO(FIFOOperation::CreateQueue(
+                FIFOCreateQueue {
+                    queue_key: rmp_serde::to_vec(&queue_name)?,
+                    config: QueueConfig {
+                        max_length: options.max_length,
+                        max_bytes: options.max_bytes,
+                        message_ttl_ms: options.message_ttl.map(|ttl| ttl.as_millis() as u64),
+                        drop_policy: options.drop_policy,
+                        ..QueueConfig::default()
+                    },
+                },
+            )))
+            .await?;
+
+        match res {
+            Response::Result {
+                res: ResponseResult::FIFO(FIFOResponse::CreateQueue { created }),
+                ..
+            } => Ok(created),
+            _ => Err("Unexpected FIFO response type".into()),
+        }
+    }
+
+    /// Replace the limits, message TTL and drop policy of the queue, keeping its items and its
+    /// dead-letter policy. The queue is created if it does not exist yet; returns whether it
+    /// existed. Items it already holds are kept even if they exceed lowered limits.
+    pub async fn update_queue<TKey: Serialize>(
+        self: &DistFIFO,
+        queue_name: TKey,
+        options: QueueOptions,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_or_forward_to_leader(RequestOperation::FIFO(FIFOOperation::UpdateQueue(
+                FIFOUpdateQueue {
                     queue_key: rmp_serde::to_vec(&queue_name)?,
-                    values: values
-                        .into_iter()
-                        .map(|v| rmp_serde::to_vec(&v).unwrap())
-                        .collect(),
+                    max_length: options.max_length,
+                    max_bytes: options.max_bytes,
+                    message_ttl_ms: options.message_ttl.map(|ttl| ttl.as_millis() as u64),
+                    drop_policy: options.drop_policy,
                 },
             )))
             .await?;
-        Ok(())
+
+        match res {
+            Response::Result {
+                res: ResponseResult::FIFO(FIFOResponse::UpdateQueue { existed }),
+                ..
+            } => Ok(existed),
+            _ => Err("Unexpected FIFO response type".into()),
+        }
+    }
+
+    /// Remove the queue: its items, reserved items, settings and metadata. Returns whether it
+    /// existed.
+    pub async fn delete_queue<TKey: Serialize>(
+        self: &DistFIFO,
+        queue_name: TKey,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_or_forward_to_leader(RequestOperation::FIFO(FIFOOperation::DeleteQueue(
+                FIFODeleteQueue {
+                    queue_key: rmp_serde::to_vec(&queue_name)?,
+                },
+            )))
+            .await?;
+
+        match res {
+            Response::Result {
+                res: ResponseResult::FIFO(FIFOResponse::DeleteQueue { existed }),
+                ..
+            } => Ok(existed),
+            _ => Err("Unexpected FIFO response type".into()),
+        }
+    }
+
+    /// Append `values` to the queue. If they do not fit within the queue's limits, the queue's
+    /// drop policy decides: fail with `QueueFullError`, drop the oldest items, or wait until
+    /// consumers have made room.
+    pub async fn enqueue<TKey: Serialize, TVal: Serialize>(
+        self: &DistFIFO,
+        queue_name: TKey,
+        values: Vec<TVal>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.enqueue_op(FIFOEnqueue {
+            queue_key: rmp_serde::to_vec(&queue_name)?,
+            values: encode_values(values)?,
+            deliver_at_ms: None,
+            delay_ms: None,
+        })
+        .await
+    }
+
+    /// Like `enqueue`, but the items stay invisible to consumers until `deliver_at`. Times in
+    /// the past deliver right away.
+    pub async fn enqueue_at<TKey: Serialize, TVal: Serialize>(
+        self: &DistFIFO,
+        queue_name: TKey,
+        values: Vec<TVal>,
+        deliver_at: SystemTime,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let deliver_at_ms = deliver_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.enqueue_op(FIFOEnqueue {
+            queue_key: rmp_serde::to_vec(&queue_name)?,
+            values: encode_values(values)?,
+            deliver_at_ms: Some(deliver_at_ms),
+            delay_ms: None,
+        })
+        .await
+    }
+
+    /// Like `enqueue`, but the items stay invisible to consumers for `delay`, counted from when
+    /// the leader receives them. Use it to retry after a backoff.
+    pub async fn enqueue_delayed<TKey: Serialize, TVal: Serialize>(
+        self: &DistFIFO,
+        queue_name: TKey,
+        values: Vec<TVal>,
+        delay: Duration,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.enqueue_op(FIFOEnqueue {
+            queue_key: rmp_serde::to_vec(&queue_name)?,
+            values: encode_values(values)?,
+            deliver_at_ms: None,
+            delay_ms: Some(delay.as_millis() as u64),
+        })
+        .await
+    }
+
+    async fn enqueue_op(
+        self: &DistFIFO,
+        enqueue: FIFOEnqueue,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let op = RequestOperation::FIFO(FIFOOperation::Enqueue(enqueue));
+        let mut res = self
+            .distacean
+            .write_or_forward_to_leader(op.clone())
+            .await?;
+
+        loop {
+            match res {
+                Response::Result {
+                    res: ResponseResult::FIFO(FIFOResponse::QueueFull { block: true, .. }),
+                    ..
+                } => {
+                    res = self
+                        .distacean
+                        .write_wait_on_queue(op.clone(), BLOCKED_ENQUEUE_POLL)
+                        .await?;
+                }
+                Response::Result {
+                    res:
+                        ResponseResult::FIFO(FIFOResponse::QueueFull {
+                            length,
+                            bytes,
+                            max_length,
+                            max_bytes,
+                            ..
+                        }),
+                    ..
+                } => {
+                    return Err(Box::new(QueueFullError {
+                        length,
+                        bytes,
+                        max_length,
+                        max_bytes,
+                    }));
+                }
+                _ => return Ok(()),
+            }
+        }
     }
 
+    /// Remove up to `count` items from the queue. Items are gone once this returns, so they are
+    /// lost if the consumer fails before processing them; use `reserve` and `ack` for
+    /// at-least-once delivery.
     pub async fn dequeue<TKey: Serialize, TVal: DeserializeOwned>(
         self: &DistFIFO,
         queue_name: TKey,
@@ -67,4 +307,324 @@ impl DistFIFO {
             Err("Unexpected FIFO response type".into())
         }
     }
+
+    /// Like `dequeue`, but if the queue is empty, wait up to `timeout` for items to arrive
+    /// instead of returning right away. Returns an empty `Vec` if none arrived in time.
+    pub async fn dequeue_wait<TKey: Serialize, TVal: DeserializeOwned>(
+        self: &DistFIFO,
+        queue_name: TKey,
+        count: usize,
+        timeout: Duration,
+    ) -> Result<Vec<TVal>, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_wait_on_queue(
+                RequestOperation::FIFO(FIFOOperation::Dequeue(FIFODequeue {
+                    queue_key: rmp_serde::to_vec(&queue_name)?,
+                    count,
+                })),
+                timeout,
+            )
+            .await?;
+
+        match res {
+            Response::Result {
+                res: ResponseResult::FIFO(FIFOResponse::Dequeue(dequeue_res)),
+                ..
+            } => Ok(dequeue_res
+                .items
+                .into_iter()
+                .map(|item_bytes| rmp_serde::from_slice::<TVal>(&item_bytes))
+                .collect::<Result<Vec<TVal>, _>>()?),
+            _ => Err("Unexpected FIFO response type".into()),
+        }
+    }
+
+    /// Reserve up to `count` items. Reserved items stay in the queue, invisible to other
+    /// consumers, until they are acknowledged with `ack`. Items that are not acknowledged within
+    /// `visibility_timeout` are delivered again, with an increased delivery count.
+    pub async fn reserve<TKey: Serialize, TVal: DeserializeOwned>(
+        self: &DistFIFO,
+        queue_name: TKey,
+        count: usize,
+        visibility_timeout: Duration,
+    ) -> Result<Vec<FIFOMessage<TVal>>, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_or_forward_to_leader(reserve_operation(&queue_name, count, visibility_timeout)?)
+            .await?;
+        reserved_messages(res)
+    }
+
+    /// Like `reserve`, but if no item is available, wait up to `timeout` for items to arrive
+    /// instead of returning right away. Returns an empty `Vec` if none arrived in time.
+    pub async fn reserve_wait<TKey: Serialize, TVal: DeserializeOwned>(
+        self: &DistFIFO,
+        queue_name: TKey,
+        count: usize,
+        visibility_timeout: Duration,
+        timeout: Duration,
+    ) -> Result<Vec<FIFOMessage<TVal>>, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_wait_on_queue(
+                reserve_operation(&queue_name, count, visibility_timeout)?,
+                timeout,
+            )
+            .await?;
+        reserved_messages(res)
+    }
+
+    /// Acknowledge reserved items, removing them from the queue for good. Returns how many
+    /// handles were still valid; a handle is stale once its item was acknowledged or delivered
+    /// again after its visibility timeout.
+    pub async fn ack<TKey: Serialize>(
+        self: &DistFIFO,
+        queue_name: TKey,
+        handles: impl IntoIterator<Item = ReceiptHandle>,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_or_forward_to_leader(RequestOperation::FIFO(FIFOOperation::Ack(FIFOAck {
+                queue_key: rmp_serde::to_vec(&queue_name)?,
+                handles: handles.into_iter().collect(),
+            })))
+            .await?;
+
+        match res {
+            Response::Result {
+                res: ResponseResult::FIFO(FIFOResponse::Ack(ack_res)),
+                ..
+            } => Ok(ack_res.acked),
+            _ => Err("Unexpected FIFO response type".into()),
+        }
+    }
+
+    /// Dead-letter items of the queue that are still unacknowledged after `max_deliveries`
+    /// deliveries, moving them to `dead_letter_queue` instead of delivering them again. Without
+    /// a dead-letter queue they are dropped, and without `max_deliveries` they are redelivered
+    /// forever, so passing `None` for both clears the policy.
+    ///
+    /// Dead-lettering is lazy: an item whose visibility timeout expired stays in flight until
+    /// the next `reserve` on the queue finds it. It then joins the dead-letter queue within that
+    /// queue's limits; with a drop policy other than `DropOldest`, items that do not fit are
+    /// dropped.
+    pub async fn set_dead_letter_policy<TKey: Serialize, TDlq: Serialize>(
+        self: &DistFIFO,
+        queue_name: TKey,
+        max_deliveries: Option<u32>,
+        dead_letter_queue: Option<TDlq>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_or_forward_to_leader(RequestOperation::FIFO(FIFOOperation::Configure(
+                FIFOConfigure {
+                    queue_key: rmp_serde::to_vec(&queue_name)?,
+                    max_deliveries,
+                    dead_letter_queue: dead_letter_queue
+                        .map(|dead_letter_queue| rmp_serde::to_vec(&dead_letter_queue))
+                        .transpose()?,
+                },
+            )))
+            .await?;
+
+        match res {
+            Response::Result {
+                res: ResponseResult::FIFO(FIFOResponse::Configure),
+                ..
+            } => Ok(()),
+            _ => Err("Unexpected FIFO response type".into()),
+        }
+    }
+
+    fn read<Q>(self: &DistFIFO, query: ReadQuery) -> InitialQueueReadBuilder<Q> {
+        QueueReadRequest::<Q>::builder()
+            .distacean(self.distacean.clone())
+            .query(query)
+    }
+
+    /// Number of items waiting in the queue, not counting reserved ones.
+    pub fn length<TKey: Serialize>(
+        self: &DistFIFO,
+        queue_name: TKey,
+    ) -> Result<InitialQueueReadBuilder<Length>, rmp_serde::encode::Error> {
+        Ok(self.read(ReadQuery::QueueStats {
+            queue_key: rmp_serde::to_vec(&queue_name)?,
+        }))
+    }
+
+    /// Up to `limit` items from the head of the queue, without removing them. Use it to inspect
+    /// a dead-letter queue.
+    pub fn peek<TKey: Serialize>(
+        self: &DistFIFO,
+        queue_name: TKey,
+        limit: usize,
+    ) -> Result<InitialQueueReadBuilder<Peek>, rmp_serde::encode::Error> {
+        Ok(self.read(ReadQuery::QueuePeek {
+            queue_key: rmp_serde::to_vec(&queue_name)?,
+            limit,
+        }))
+    }
+
+    /// Names of all queues, from their first write or `create_queue` until `delete_queue`.
+    pub fn list_queues(self: &DistFIFO) -> InitialQueueReadBuilder<ListQueues> {
+        self.read(ReadQuery::ListQueues)
+    }
+
+    /// Length, in-flight count, enqueue and dequeue totals and oldest item age of the queue.
+    pub fn stats<TKey: Serialize>(
+        self: &DistFIFO,
+        queue_name: TKey,
+    ) -> Result<InitialQueueReadBuilder<Stats>, rmp_serde::encode::Error> {
+        Ok(self.read(ReadQuery::QueueStats {
+            queue_key: rmp_serde::to_vec(&queue_name)?,
+        }))
+    }
+
+    /// Atomically move up to `count` items from the head of `source_queue` to the tail of
+    /// `target_queue` and return them, like Redis' `RPOPLPUSH`. Use it to hand work from one
+    /// pipeline stage to the next: a crash can no longer happen between the dequeue and the
+    /// enqueue. The items must fit within the target queue's limits, as for `enqueue`, except
+    /// that a full target with the `Block` policy fails with `QueueFullError` instead of waiting.
+    pub async fn move_items<TSrc: Serialize, TDst: Serialize, TVal: DeserializeOwned>(
+        self: &DistFIFO,
+        source_queue: TSrc,
+        target_queue: TDst,
+        count: usize,
+    ) -> Result<Vec<TVal>, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_or_forward_to_leader(RequestOperation::FIFO(FIFOOperation::Move(FIFOMove {
+                queue_key: rmp_serde::to_vec(&source_queue)?,
+                target_key: rmp_serde::to_vec(&target_queue)?,
+                count: Some(count),
+                return_items: true,
+                ignore_limits: false,
+            })))
+            .await?;
+
+        match res {
+            Response::Result {
+                res: ResponseResult::FIFO(FIFOResponse::Move { items, .. }),
+                ..
+            } => Ok(items
+                .into_iter()
+                .map(|item_bytes| rmp_serde::from_slice::<TVal>(&item_bytes))
+                .collect::<Result<Vec<TVal>, _>>()?),
+            Response::Result {
+                res:
+                    ResponseResult::FIFO(FIFOResponse::QueueFull {
+                        length,
+                        bytes,
+                        max_length,
+                        max_bytes,
+                        ..
+                    }),
+                ..
+            } => Err(Box::new(QueueFullError {
+                length,
+                bytes,
+                max_length,
+                max_bytes,
+            })),
+            _ => Err("Unexpected FIFO response type".into()),
+        }
+    }
+
+    /// Move up to `count` items, or all of them, from the head of `dead_letter_queue` back to
+    /// the tail of `target_queue`. Returns the number of moved items. The target queue's limits
+    /// do not apply, so dead-lettered items can always be redriven.
+    pub async fn redrive<TDlq: Serialize, TKey: Serialize>(
+        self: &DistFIFO,
+        dead_letter_queue: TDlq,
+        target_queue: TKey,
+        count: Option<usize>,
+    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_or_forward_to_leader(RequestOperation::FIFO(FIFOOperation::Move(FIFOMove {
+                queue_key: rmp_serde::to_vec(&dead_letter_queue)?,
+                target_key: rmp_serde::to_vec(&target_queue)?,
+                count,
+                return_items: false,
+                ignore_limits: true,
+            })))
+            .await?;
+
+        match res {
+            Response::Result {
+                res: ResponseResult::FIFO(FIFOResponse::Move { moved, .. }),
+                ..
+            } => Ok(moved),
+            _ => Err("Unexpected FIFO response type".into()),
+        }
+    }
+
+    /// Delete every queued item in one range deletion, e.g. to empty a dead-letter queue. The
+    /// queue and its settings remain, and reserved items that are not acknowledged yet are kept.
+    /// Returns the number of deleted items.
+    pub async fn purge_queue<TKey: Serialize>(
+        self: &DistFIFO,
+        queue_name: TKey,
+    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_or_forward_to_leader(RequestOperation::FIFO(FIFOOperation::Purge(FIFOPurge {
+                queue_key: rmp_serde::to_vec(&queue_name)?,
+            })))
+            .await?;
+
+        match res {
+            Response::Result {
+                res: ResponseResult::FIFO(FIFOResponse::Purge { purged }),
+                ..
+            } => Ok(purged),
+            _ => Err("Unexpected FIFO response type".into()),
+        }
+    }
+}
+
+fn encode_values<TVal: Serialize>(
+    values: Vec<TVal>,
+) -> Result<Vec<Vec<u8>>, rmp_serde::encode::Error> {
+    values.iter().map(rmp_serde::to_vec).collect()
+}
+
+fn reserve_operation<TKey: Serialize>(
+    queue_name: &TKey,
+    count: usize,
+    visibility_timeout: Duration,
+) -> Result<RequestOperation, rmp_serde::encode::Error> {
+    Ok(RequestOperation::FIFO(FIFOOperation::Reserve(
+        FIFOReserve {
+            queue_key: rmp_serde::to_vec(queue_name)?,
+            count,
+            visibility_timeout_ms: visibility_timeout.as_millis() as u64,
+        },
+    )))
+}
+
+fn reserved_messages<TVal: DeserializeOwned>(
+    res: Response,
+) -> Result<Vec<FIFOMessage<TVal>>, Box<dyn std::error::Error + Send + Sync>> {
+    match res {
+        Response::Result {
+            res: ResponseResult::FIFO(FIFOResponse::Reserve(reserve_res)),
+            ..
+        } => {
+            let messages = reserve_res
+                .items
+                .into_iter()
+                .map(|item| {
+                    Ok(FIFOMessage {
+                        value: rmp_serde::from_slice::<TVal>(&item.data)?,
+                        handle: item.handle,
+                        deliveries: item.deliveries,
+                    })
+                })
+                .collect::<Result<Vec<_>, rmp_serde::decode::Error>>()?;
+            Ok(messages)
+        }
+        _ => Err("Unexpected FIFO response type".into()),
+    }
 }
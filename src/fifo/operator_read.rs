@@ -0,0 +1,187 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+
+use self::queue_read_request_builder::State;
+use crate::codec::CodecError;
+use crate::core::DistaceanCore;
+use crate::core::ReadSource;
+use crate::distkv::operator_read::KVReadError;
+use crate::distkv::operator_read::ReadConsistency;
+use crate::distkv::operator_read::execute_query;
+use crate::fifo::operator_read::queue_read_request_builder::SetConsistency;
+use crate::fifo::operator_read::queue_read_request_builder::SetSource;
+use crate::protocol::ReadQuery;
+use crate::protocol::ReadQueryResult;
+use crate::raft::SessionToken;
+use crate::raft::store::fifo::common::QueueStats;
+use bon::Builder;
+
+pub use self::queue_read_request_builder::{SetDistacean, SetQuery};
+
+/// Number of waiting items of a queue
+pub struct Length;
+/// First items of a queue, without removing them
+pub struct Peek;
+/// First items of a priority queue with their priorities, without removing them
+pub struct PriorityPeek;
+/// Names of all queues
+pub struct ListQueues;
+/// Counters and oldest item age of a queue
+pub struct Stats;
+
+/// Read-only query of the FIFO and priority queues. `Q` selects what is read and what `execute` returns.
+/// Reads go through the leader and are linearizable unless configured otherwise, like KV reads.
+#[derive(Builder)]
+pub struct QueueReadRequest<Q> {
+    distacean: Arc<DistaceanCore>,
+    query: ReadQuery,
+
+    #[builder(default = ReadSource::Leader)]
+    source: ReadSource,
+    #[builder(default = ReadConsistency::Linearizable)]
+    consistency: ReadConsistency,
+
+    /// Wait until the serving node has applied the write of this token
+    after: Option<SessionToken>,
+
+    #[builder(skip)]
+    _kind: PhantomData<fn() -> Q>,
+}
+
+impl<Q> QueueReadRequest<Q> {
+    async fn execute_query(self) -> Result<ReadQueryResult, KVReadError> {
+        execute_query(
+            &self.distacean,
+            self.source,
+            self.consistency,
+            self.after,
+            self.query,
+        )
+        .await
+    }
+}
+
+fn unexpected_result() -> KVReadError {
+    KVReadError::Unknown("Unexpected read result".into())
+}
+
+impl<S> QueueReadRequestBuilder<Length, S>
+where
+    S: State + queue_read_request_builder::IsComplete,
+{
+    pub async fn execute(self) -> Result<u64, KVReadError> {
+        match self.build().execute_query().await? {
+            ReadQueryResult::QueueStats(stats) => Ok(stats.length),
+            _ => Err(unexpected_result()),
+        }
+    }
+}
+
+impl<S> QueueReadRequestBuilder<Peek, S>
+where
+    S: State + queue_read_request_builder::IsComplete,
+{
+    /// Returns the items from the head of the queue, oldest first.
+    pub async fn execute<T: DeserializeOwned>(self) -> Result<Vec<T>, KVReadError> {
+        match self.build().execute_query().await? {
+            ReadQueryResult::Items(items) => items
+                .iter()
+                .map(|item| {
+                    rmp_serde::from_slice::<T>(item)
+                        .map_err(|e| KVReadError::Decode(CodecError::new(e)))
+                })
+                .collect(),
+            _ => Err(unexpected_result()),
+        }
+    }
+}
+
+impl<S> QueueReadRequestBuilder<PriorityPeek, S>
+where
+    S: State + queue_read_request_builder::IsComplete,
+{
+    /// Returns the `(priority, item)` pairs in dequeue order, highest priority first.
+    pub async fn execute<T: DeserializeOwned>(self) -> Result<Vec<(u32, T)>, KVReadError> {
+        match self.build().execute_query().await? {
+            ReadQueryResult::PriorityItems(items) => items
+                .iter()
+                .map(|(priority, item)| {
+                    rmp_serde::from_slice::<T>(item)
+                        .map(|value| (*priority, value))
+                        .map_err(|e| KVReadError::Decode(CodecError::new(e)))
+                })
+                .collect(),
+            _ => Err(unexpected_result()),
+        }
+    }
+}
+
+impl<S> QueueReadRequestBuilder<ListQueues, S>
+where
+    S: State + queue_read_request_builder::IsComplete,
+{
+    /// Returns the queue names, decoded as `T`, in the order of their encoded keys.
+    pub async fn execute<T: DeserializeOwned>(self) -> Result<Vec<T>, KVReadError> {
+        match self.build().execute_query().await? {
+            ReadQueryResult::Queues(queues) => queues
+                .iter()
+                .map(|queue_key| {
+                    rmp_serde::from_slice::<T>(queue_key)
+                        .map_err(|e| KVReadError::Decode(CodecError::new(e)))
+                })
+                .collect(),
+            _ => Err(unexpected_result()),
+        }
+    }
+}
+
+impl<S> QueueReadRequestBuilder<Stats, S>
+where
+    S: State + queue_read_request_builder::IsComplete,
+{
+    pub async fn execute(self) -> Result<QueueStats, KVReadError> {
+        match self.build().execute_query().await? {
+            ReadQueryResult::QueueStats(stats) => Ok(stats),
+            _ => Err(unexpected_result()),
+        }
+    }
+}
+
+impl<Q, S> QueueReadRequestBuilder<Q, S>
+where
+    S: State,
+    <S as State>::Source: queue_read_request_builder::IsUnset,
+{
+    pub fn local(self) -> QueueReadRequestBuilder<Q, SetSource<S>> {
+        self.source(ReadSource::Local)
+    }
+
+    pub fn leader(self) -> QueueReadRequestBuilder<Q, SetSource<S>> {
+        self.source(ReadSource::Leader)
+    }
+
+    /// Read locally when this node is caught up, otherwise through the leader.
+    pub fn any(self) -> QueueReadRequestBuilder<Q, SetSource<S>> {
+        self.source(ReadSource::Any)
+    }
+}
+
+impl<Q, S> QueueReadRequestBuilder<Q, S>
+where
+    S: State,
+    <S as State>::Consistency: queue_read_request_builder::IsUnset,
+{
+    pub fn as_is(self) -> QueueReadRequestBuilder<Q, SetConsistency<S>> {
+        self.consistency(ReadConsistency::AsIs)
+    }
+
+    pub fn leader_lease(self) -> QueueReadRequestBuilder<Q, SetConsistency<S>> {
+        self.consistency(ReadConsistency::LeaseRead)
+    }
+
+    pub fn linearizable(self) -> QueueReadRequestBuilder<Q, SetConsistency<S>> {
+        self.consistency(ReadConsistency::Linearizable)
+    }
+}
@@ -0,0 +1,259 @@
+use std::{sync::Arc, time::Duration};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    core::DistaceanCore,
+    fifo::{
+        BLOCKED_ENQUEUE_POLL, InitialQueueReadBuilder, QueueFullError, QueueOptions,
+        operator_read::{Length, PriorityPeek, QueueReadRequest, Stats},
+    },
+    protocol::ReadQuery,
+    raft::{
+        FIFOOperation, RequestOperation, Response, ResponseResult,
+        store::fifo::{
+            FIFODeleteQueue, FIFOPriorityDequeue, FIFOPriorityEnqueue, FIFOPurge, FIFOResponse,
+            FIFOUpdateQueue,
+        },
+    },
+};
+
+/// Queues in which every item carries a priority. Dequeues return the highest priority first,
+/// and items of the same priority in the order they were enqueued. Priority queues take the
+/// same limits, message TTL and drop policy as FIFO queues, set with `update_queue`.
+#[derive(Clone)]
+pub struct DistPriorityQueue {
+    pub(crate) distacean: Arc<DistaceanCore>,
+}
+
+impl DistPriorityQueue {
+    /// Replace the limits, message TTL and drop policy of the queue, keeping its items. The
+    /// queue is created if it does not exist yet; returns whether it existed. Items it already
+    /// holds are kept even if they exceed lowered limits.
+    pub async fn update_queue<TKey: Serialize>(
+        self: &DistPriorityQueue,
+        queue_name: TKey,
+        options: QueueOptions,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_or_forward_to_leader(RequestOperation::FIFO(FIFOOperation::PriorityUpdateQueue(
+                FIFOUpdateQueue {
+                    queue_key: rmp_serde::to_vec(&queue_name)?,
+                    max_length: options.max_length,
+                    max_bytes: options.max_bytes,
+                    message_ttl_ms: options.message_ttl.map(|ttl| ttl.as_millis() as u64),
+                    drop_policy: options.drop_policy,
+                },
+            )))
+            .await?;
+
+        match res {
+            Response::Result {
+                res: ResponseResult::FIFO(FIFOResponse::UpdateQueue { existed }),
+                ..
+            } => Ok(existed),
+            _ => Err("Unexpected FIFO response type".into()),
+        }
+    }
+
+    /// Remove the queue: its items, settings and metadata. Returns whether it existed.
+    pub async fn delete_queue<TKey: Serialize>(
+        self: &DistPriorityQueue,
+        queue_name: TKey,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_or_forward_to_leader(RequestOperation::FIFO(FIFOOperation::PriorityDeleteQueue(
+                FIFODeleteQueue {
+                    queue_key: rmp_serde::to_vec(&queue_name)?,
+                },
+            )))
+            .await?;
+
+        match res {
+            Response::Result {
+                res: ResponseResult::FIFO(FIFOResponse::DeleteQueue { existed }),
+                ..
+            } => Ok(existed),
+            _ => Err("Unexpected FIFO response type".into()),
+        }
+    }
+
+    /// Delete every item of the queue, keeping the queue and its settings. Returns the number
+    /// of deleted items.
+    pub async fn purge_queue<TKey: Serialize>(
+        self: &DistPriorityQueue,
+        queue_name: TKey,
+    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_or_forward_to_leader(RequestOperation::FIFO(FIFOOperation::PriorityPurge(
+                FIFOPurge {
+                    queue_key: rmp_serde::to_vec(&queue_name)?,
+                },
+            )))
+            .await?;
+
+        match res {
+            Response::Result {
+                res: ResponseResult::FIFO(FIFOResponse::Purge { purged }),
+                ..
+            } => Ok(purged),
+            _ => Err("Unexpected FIFO response type".into()),
+        }
+    }
+
+    /// Add `(priority, value)` items to the queue. Returns the number of items in the queue. If
+    /// they do not fit within the queue's limits, the queue's drop policy decides: fail with
+    /// `QueueFullError`, drop the oldest items whatever their priority, or wait until consumers
+    /// have made room.
+    pub async fn enqueue<TKey: Serialize, TVal: Serialize>(
+        self: &DistPriorityQueue,
+        queue_name: TKey,
+        items: Vec<(u32, TVal)>,
+    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        let items = items
+            .iter()
+            .map(|(priority, value)| Ok((*priority, rmp_serde::to_vec(value)?)))
+            .collect::<Result<Vec<_>, rmp_serde::encode::Error>>()?;
+        let op = RequestOperation::FIFO(FIFOOperation::PriorityEnqueue(FIFOPriorityEnqueue {
+            queue_key: rmp_serde::to_vec(&queue_name)?,
+            items,
+        }));
+        let mut res = self
+            .distacean
+            .write_or_forward_to_leader(op.clone())
+            .await?;
+
+        loop {
+            match res {
+                Response::Result {
+                    res: ResponseResult::FIFO(FIFOResponse::PriorityEnqueue { length }),
+                    ..
+                } => return Ok(length),
+                Response::Result {
+                    res: ResponseResult::FIFO(FIFOResponse::QueueFull { block: true, .. }),
+                    ..
+                } => {
+                    res = self
+                        .distacean
+                        .write_wait_on_queue(op.clone(), BLOCKED_ENQUEUE_POLL)
+                        .await?;
+                }
+                Response::Result {
+                    res:
+                        ResponseResult::FIFO(FIFOResponse::QueueFull {
+                            length,
+                            bytes,
+                            max_length,
+                            max_bytes,
+                            ..
+                        }),
+                    ..
+                } => {
+                    return Err(Box::new(QueueFullError {
+                        length,
+                        bytes,
+                        max_length,
+                        max_bytes,
+                    }));
+                }
+                _ => return Err("Unexpected FIFO response type".into()),
+            }
+        }
+    }
+
+    /// Remove up to `count` items, highest priority first, with their priorities.
+    pub async fn dequeue<TKey: Serialize, TVal: DeserializeOwned>(
+        self: &DistPriorityQueue,
+        queue_name: TKey,
+        count: usize,
+    ) -> Result<Vec<(u32, TVal)>, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_or_forward_to_leader(dequeue_operation(&queue_name, count)?)
+            .await?;
+        dequeued_items(res)
+    }
+
+    /// Like `dequeue`, but if the queue is empty, wait up to `timeout` for items to arrive
+    /// instead of returning right away. Returns an empty `Vec` if none arrived in time.
+    pub async fn dequeue_wait<TKey: Serialize, TVal: DeserializeOwned>(
+        self: &DistPriorityQueue,
+        queue_name: TKey,
+        count: usize,
+        timeout: Duration,
+    ) -> Result<Vec<(u32, TVal)>, Box<dyn std::error::Error + Send + Sync>> {
+        let res = self
+            .distacean
+            .write_wait_on_queue(dequeue_operation(&queue_name, count)?, timeout)
+            .await?;
+        dequeued_items(res)
+    }
+
+    fn read<Q>(self: &DistPriorityQueue, query: ReadQuery) -> InitialQueueReadBuilder<Q> {
+        QueueReadRequest::<Q>::builder()
+            .distacean(self.distacean.clone())
+            .query(query)
+    }
+
+    /// Number of items waiting in the queue.
+    pub fn length<TKey: Serialize>(
+        self: &DistPriorityQueue,
+        queue_name: TKey,
+    ) -> Result<InitialQueueReadBuilder<Length>, rmp_serde::encode::Error> {
+        Ok(self.read(ReadQuery::PriorityQueueStats {
+            queue_key: rmp_serde::to_vec(&queue_name)?,
+        }))
+    }
+
+    /// Up to `limit` items in dequeue order, with their priorities, without removing them.
+    pub fn peek<TKey: Serialize>(
+        self: &DistPriorityQueue,
+        queue_name: TKey,
+        limit: usize,
+    ) -> Result<InitialQueueReadBuilder<PriorityPeek>, rmp_serde::encode::Error> {
+        Ok(self.read(ReadQuery::PriorityQueuePeek {
+            queue_key: rmp_serde::to_vec(&queue_name)?,
+            limit,
+        }))
+    }
+
+    /// Length, size, enqueue and dequeue totals and oldest item age of the queue.
+    pub fn stats<TKey: Serialize>(
+        self: &DistPriorityQueue,
+        queue_name: TKey,
+    ) -> Result<InitialQueueReadBuilder<Stats>, rmp_serde::encode::Error> {
+        Ok(self.read(ReadQuery::PriorityQueueStats {
+            queue_key: rmp_serde::to_vec(&queue_name)?,
+        }))
+    }
+}
+
+fn dequeue_operation<TKey: Serialize>(
+    queue_name: &TKey,
+    count: usize,
+) -> Result<RequestOperation, rmp_serde::encode::Error> {
+    Ok(RequestOperation::FIFO(FIFOOperation::PriorityDequeue(
+        FIFOPriorityDequeue {
+            queue_key: rmp_serde::to_vec(queue_name)?,
+            count,
+        },
+    )))
+}
+
+fn dequeued_items<TVal: DeserializeOwned>(
+    res: Response,
+) -> Result<Vec<(u32, TVal)>, Box<dyn std::error::Error + Send + Sync>> {
+    match res {
+        Response::Result {
+            res: ResponseResult::FIFO(FIFOResponse::PriorityDequeue { items }),
+            ..
+        } => Ok(items
+            .into_iter()
+            .map(|(priority, data)| Ok((priority, rmp_serde::from_slice::<TVal>(&data)?)))
+            .collect::<Result<Vec<_>, rmp_serde::decode::Error>>()?),
+        _ => Err("Unexpected FIFO response type".into()),
+    }
+}
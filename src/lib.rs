@@ -1,16 +1,37 @@
+mod codec;
 mod core;
 mod distkv;
 mod fifo;
+mod limits;
 mod network_tcp;
 mod peernet;
 mod protocol;
 mod raft;
+mod retention;
 mod router;
 mod util;
 
-pub use crate::core::{ClusterDistaceanConfig, Distacean, ReadSource, SingleNodeDistaceanConfig};
+#[cfg(feature = "bincode")]
+pub use crate::codec::Bincode;
+#[cfg(feature = "json")]
+pub use crate::codec::Json;
+pub use crate::codec::{Codec, CodecError, MessagePack, MessagePackNamed, Raw};
+pub use crate::core::{
+    ClusterDistaceanConfig, Distacean, ReadSource, SingleNodeDistaceanConfig, Staleness,
+};
 pub use crate::distkv::{
-    DistKV, SetError,
-    operator_read::{KVReadError, ReadConsistency},
+    DeleteError, DistKV, ReservedKeyError, SetError,
+    keyspace::{Keyspace, OrderedKey},
+    operator_batch::BatchOpResult,
+    operator_incr::{IncrError, IncrResponse},
+    operator_put_if_absent::GetOrInsertResponse,
+    operator_read::{KVReadError, KeyMetadata, ReadConsistency},
+    operator_txn::{TxnOpResult, TxnResult},
 };
-pub use crate::raft::{NodeId, SetResponse};
+pub use crate::fifo::{FIFOMessage, QueueFullError, QueueOptions};
+pub use crate::limits::{Limits, SizeLimitError};
+pub use crate::raft::store::fifo::ReceiptHandle;
+pub use crate::raft::store::fifo::common::{DropPolicy, QueueStats};
+pub use crate::raft::store::kv::index::IndexDefinition;
+pub use crate::raft::{DeleteResponse, NodeId, PutIfAbsentResponse, SessionToken, SetResponse};
+pub use crate::retention::Retention;
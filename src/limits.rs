@@ -0,0 +1,339 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::raft::store::txn::{TxnCompare, TxnOperation};
+use crate::raft::{FIFOOperation, KVOperation, RequestOperation};
+
+/// Bound on the MessagePack framing around one key, value or operation of a request
+const FRAMING_BYTES: usize = 16;
+
+/// Size limits applied to writes before they are proposed to raft. Every value must fit in one
+/// raft entry and one peer message frame, so oversized writes are rejected up front instead of
+/// stalling replication.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Largest single value, KV value or FIFO item, in bytes
+    pub max_value_bytes: usize,
+    /// Largest request, i.e. one raft entry, in bytes. The size is estimated from the keys
+    /// and values of the request plus a bound on their framing.
+    pub max_request_bytes: usize,
+    /// When set, `DistKV::set` splits values above `max_value_bytes` into content-addressed
+    /// chunks of at most this many bytes, written as separate entries, instead of rejecting
+    /// them. Reads reassemble such values transparently.
+    pub blob_chunk_bytes: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_value_bytes: 1024 * 1024,
+            max_request_bytes: 4 * 1024 * 1024,
+            blob_chunk_bytes: None,
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, Serialize, Deserialize)]
+pub enum SizeLimitError {
+    #[error("Value of {size} bytes exceeds the limit of {limit} bytes")]
+    ValueTooLarge { size: usize, limit: usize },
+    #[error("Request of {size} bytes exceeds the limit of {limit} bytes")]
+    RequestTooLarge { size: usize, limit: usize },
+}
+
+impl Limits {
+    /// Chunk size for blob mode, never above the value limit.
+    pub(crate) fn blob_chunk_bytes(&self) -> Option<usize> {
+        self.blob_chunk_bytes
+            .map(|chunk_bytes| chunk_bytes.clamp(1, self.max_value_bytes))
+    }
+
+    pub(crate) fn check_value(&self, size: usize) -> Result<(), SizeLimitError> {
+        if size > self.max_value_bytes {
+            return Err(SizeLimitError::ValueTooLarge {
+                size,
+                limit: self.max_value_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check every value of `op` and the estimated size of the whole request.
+    pub(crate) fn check(&self, op: &RequestOperation) -> Result<(), SizeLimitError> {
+        self.check_value(largest_value(op))?;
+        let size = request_size(op);
+        if size > self.max_request_bytes {
+            return Err(SizeLimitError::RequestTooLarge {
+                size,
+                limit: self.max_request_bytes,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn largest_value(op: &RequestOperation) -> usize {
+    match op {
+        RequestOperation::KV(kv_op) => largest_kv_value(kv_op),
+        RequestOperation::FIFO(fifo_op) => largest_fifo_value(fifo_op),
+        RequestOperation::Txn(txn) => txn.ops.iter().map(largest_txn_value).max().unwrap_or(0),
+    }
+}
+
+fn largest_txn_value(op: &TxnOperation) -> usize {
+    match op {
+        TxnOperation::Set(kvset) => kvset.value.len(),
+        TxnOperation::Cas(kvcas) => kvcas.value.len(),
+        TxnOperation::PutIfAbsent(kvput) => kvput.value.len(),
+        TxnOperation::Enqueue(enqueue) => enqueue.values.iter().map(Vec::len).max().unwrap_or(0),
+        TxnOperation::PriorityEnqueue(enqueue) => enqueue
+            .items
+            .iter()
+            .map(|(_, value)| value.len())
+            .max()
+            .unwrap_or(0),
+        TxnOperation::Del(_) | TxnOperation::Incr(_) => 0,
+    }
+}
+
+fn largest_fifo_value(op: &FIFOOperation) -> usize {
+    match op {
+        FIFOOperation::Enqueue(enqueue) => enqueue.values.iter().map(Vec::len).max().unwrap_or(0),
+        FIFOOperation::PriorityEnqueue(enqueue) => enqueue
+            .items
+            .iter()
+            .map(|(_, value)| value.len())
+            .max()
+            .unwrap_or(0),
+        FIFOOperation::Dequeue(_)
+        | FIFOOperation::Reserve(_)
+        | FIFOOperation::Ack(_)
+        | FIFOOperation::Configure(_)
+        | FIFOOperation::Move(_)
+        | FIFOOperation::Purge(_)
+        | FIFOOperation::CreateQueue(_)
+        | FIFOOperation::DeleteQueue(_)
+        | FIFOOperation::PriorityDequeue(_)
+        | FIFOOperation::UpdateQueue(_)
+        | FIFOOperation::PriorityUpdateQueue(_)
+        | FIFOOperation::PriorityPurge(_)
+        | FIFOOperation::PriorityDeleteQueue(_) => 0,
+    }
+}
+
+fn largest_kv_value(op: &KVOperation) -> usize {
+    match op {
+        KVOperation::Set(kvset) => kvset.value.len(),
+        KVOperation::Cas(kvcas) => kvcas.value.len(),
+        KVOperation::PutIfAbsent(kvput) => kvput.value.len(),
+        KVOperation::Batch(ops) => ops.iter().map(largest_kv_value).max().unwrap_or(0),
+        KVOperation::Del(_)
+        | KVOperation::Compact { .. }
+        | KVOperation::Incr(_)
+        | KVOperation::DeleteRange { .. }
+        | KVOperation::CreateIndex { .. }
+        | KVOperation::DropIndex { .. } => 0,
+    }
+}
+
+/// Bound on the encoded size of a byte vector. serde writes `Vec<u8>` as a MessagePack array of
+/// integers, in which every byte from 0x80 up takes two bytes.
+fn encoded_len(bytes: &[u8]) -> usize {
+    2 * bytes.len()
+}
+
+/// Estimated encoded size of `op`, computed from its keys and values without serializing it.
+/// Never below the actual size as long as the framing of each field fits in `FRAMING_BYTES`.
+fn request_size(op: &RequestOperation) -> usize {
+    match op {
+        RequestOperation::KV(kv_op) => kv_size(kv_op),
+        RequestOperation::FIFO(fifo_op) => fifo_size(fifo_op),
+        RequestOperation::Txn(txn) => {
+            let compare: usize = txn
+                .compare
+                .iter()
+                .map(|compare| match compare {
+                    TxnCompare::Revision { key, .. } => encoded_len(key) + FRAMING_BYTES,
+                    TxnCompare::Value { key, value } => {
+                        encoded_len(key)
+                            + value.as_deref().map_or(0, encoded_len)
+                            + 2 * FRAMING_BYTES
+                    }
+                })
+                .sum();
+            let ops: usize = txn.ops.iter().map(txn_op_size).sum();
+            compare + ops + FRAMING_BYTES
+        }
+    }
+}
+
+fn kv_size(op: &KVOperation) -> usize {
+    let payload = match op {
+        KVOperation::Set(kvset) => encoded_len(&kvset.key) + encoded_len(&kvset.value),
+        KVOperation::Cas(kvcas) => encoded_len(&kvcas.key) + encoded_len(&kvcas.value),
+        KVOperation::PutIfAbsent(kvput) => encoded_len(&kvput.key) + encoded_len(&kvput.value),
+        KVOperation::Del(kvdel) => encoded_len(&kvdel.key),
+        KVOperation::Incr(kvincr) => encoded_len(&kvincr.key),
+        KVOperation::DeleteRange { start, end, .. } => encoded_len(start) + encoded_len(end),
+        KVOperation::Batch(ops) => ops.iter().map(kv_size).sum(),
+        KVOperation::CreateIndex { name, definition } => {
+            name.len()
+                + encoded_len(&definition.key_prefix)
+                + definition
+                    .field
+                    .iter()
+                    .map(|name| name.len() + FRAMING_BYTES)
+                    .sum::<usize>()
+        }
+        KVOperation::DropIndex { name } => name.len(),
+        KVOperation::Compact { .. } => 0,
+    };
+    payload + 2 * FRAMING_BYTES
+}
+
+fn fifo_size(op: &FIFOOperation) -> usize {
+    let payload = match op {
+        FIFOOperation::Enqueue(enqueue) => {
+            encoded_len(&enqueue.queue_key)
+                + enqueue
+                    .values
+                    .iter()
+                    .map(|value| encoded_len(value) + FRAMING_BYTES)
+                    .sum::<usize>()
+        }
+        FIFOOperation::PriorityEnqueue(enqueue) => {
+            encoded_len(&enqueue.queue_key)
+                + enqueue
+                    .items
+                    .iter()
+                    .map(|(_, value)| encoded_len(value) + FRAMING_BYTES)
+                    .sum::<usize>()
+        }
+        FIFOOperation::Ack(ack) => encoded_len(&ack.queue_key) + ack.handles.len() * FRAMING_BYTES,
+        FIFOOperation::Configure(configure) => {
+            encoded_len(&configure.queue_key)
+                + configure
+                    .dead_letter_queue
+                    .as_deref()
+                    .map_or(0, encoded_len)
+        }
+        FIFOOperation::Move(op) => encoded_len(&op.queue_key) + encoded_len(&op.target_key),
+        FIFOOperation::Dequeue(op) => encoded_len(&op.queue_key),
+        FIFOOperation::Reserve(op) => encoded_len(&op.queue_key),
+        FIFOOperation::Purge(op) => encoded_len(&op.queue_key),
+        FIFOOperation::CreateQueue(op) => encoded_len(&op.queue_key),
+        FIFOOperation::DeleteQueue(op) => encoded_len(&op.queue_key),
+        FIFOOperation::PriorityDequeue(op) => encoded_len(&op.queue_key),
+        FIFOOperation::UpdateQueue(op) => encoded_len(&op.queue_key),
+        FIFOOperation::PriorityUpdateQueue(op) => encoded_len(&op.queue_key),
+        FIFOOperation::PriorityPurge(op) => encoded_len(&op.queue_key),
+        FIFOOperation::PriorityDeleteQueue(op) => encoded_len(&op.queue_key),
+    };
+    payload + 4 * FRAMING_BYTES
+}
+
+fn txn_op_size(op: &TxnOperation) -> usize {
+    let payload = match op {
+        TxnOperation::Set(kvset) => encoded_len(&kvset.key) + encoded_len(&kvset.value),
+        TxnOperation::Cas(kvcas) => encoded_len(&kvcas.key) + encoded_len(&kvcas.value),
+        TxnOperation::PutIfAbsent(kvput) => encoded_len(&kvput.key) + encoded_len(&kvput.value),
+        TxnOperation::Del(kvdel) => encoded_len(&kvdel.key),
+        TxnOperation::Incr(kvincr) => encoded_len(&kvincr.key),
+        TxnOperation::Enqueue(enqueue) => {
+            encoded_len(&enqueue.queue_key)
+                + enqueue
+                    .values
+                    .iter()
+                    .map(|value| encoded_len(value) + FRAMING_BYTES)
+                    .sum::<usize>()
+        }
+        TxnOperation::PriorityEnqueue(enqueue) => {
+            encoded_len(&enqueue.queue_key)
+                + enqueue
+                    .items
+                    .iter()
+                    .map(|(_, value)| encoded_len(value) + FRAMING_BYTES)
+                    .sum::<usize>()
+        }
+    };
+    payload + 4 * FRAMING_BYTES
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::fifo::FIFOEnqueue;
+    use crate::raft::store::kv::{KVCas, KVSet};
+    use crate::raft::store::txn::Txn;
+
+    fn set(key: &[u8], value: usize) -> KVOperation {
+        KVOperation::Set(KVSet {
+            key: key.to_vec(),
+            value: vec![0xFF; value],
+            return_previous: true,
+            blob: false,
+        })
+    }
+
+    #[test]
+    fn estimated_request_sizes_are_never_below_the_encoded_size() {
+        let ops = vec![
+            RequestOperation::KV(set(&[0xFE, 0xFF, 0x00, 0xFD], 300)),
+            RequestOperation::KV(KVOperation::Batch(vec![set(b"a", 1), set(b"b", 70_000)])),
+            RequestOperation::FIFO(FIFOOperation::Enqueue(FIFOEnqueue {
+                queue_key: b"queue".to_vec(),
+                values: vec![vec![0x80; 10], vec![0xFF; 200], Vec::new()],
+                deliver_at_ms: Some(u64::MAX),
+                delay_ms: None,
+            })),
+            RequestOperation::Txn(Txn {
+                compare: vec![TxnCompare::Value {
+                    key: b"key".to_vec(),
+                    value: Some(vec![0xFF; 40]),
+                }],
+                ops: vec![
+                    TxnOperation::Cas(KVCas {
+                        key: b"key".to_vec(),
+                        expected_revision: u64::MAX,
+                        value: vec![0xFF; 5000],
+                        return_previous: true,
+                        blob: false,
+                    }),
+                    TxnOperation::Enqueue(FIFOEnqueue {
+                        queue_key: b"outbox".to_vec(),
+                        values: vec![vec![0xFF; 100]],
+                        deliver_at_ms: None,
+                        delay_ms: Some(u64::MAX),
+                    }),
+                ],
+            }),
+        ];
+        for op in &ops {
+            let encoded = rmp_serde::to_vec(op).unwrap().len();
+            assert!(request_size(op) >= encoded, "{op:?}");
+        }
+    }
+
+    #[test]
+    fn values_and_requests_above_the_limits_are_rejected() {
+        let limits = Limits {
+            max_value_bytes: 100,
+            max_request_bytes: 250,
+            blob_chunk_bytes: Some(1000),
+        };
+        assert!(limits.check(&RequestOperation::KV(set(b"a", 100))).is_ok());
+        assert!(matches!(
+            limits.check(&RequestOperation::KV(set(b"a", 101))),
+            Err(SizeLimitError::ValueTooLarge {
+                size: 101,
+                limit: 100
+            })
+        ));
+        let batch = KVOperation::Batch(vec![set(b"a", 100), set(b"b", 100)]);
+        assert!(matches!(
+            limits.check(&RequestOperation::KV(batch)),
+            Err(SizeLimitError::RequestTooLarge { limit: 250, .. })
+        ));
+        assert_eq!(limits.blob_chunk_bytes(), Some(100));
+    }
+}
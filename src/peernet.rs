@@ -6,6 +6,7 @@ use std::sync::Arc;
 use std::sync::atomic::AtomicU64;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, WriteHalf};
 use tokio::sync::Mutex;
+use tokio::sync::oneshot;
 use tokio::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -148,6 +149,8 @@ enum PeerConnectionSignal<T: HStream> {
     ConRequest(PeerConnectionRequest<T>),
 }
 
+type PendingResponses = Arc<std::sync::Mutex<HashMap<u64, oneshot::Sender<Vec<u8>>>>>;
+
 pub struct PeerConnection<T: HStream, TS: HStartable<T>> {
     local_port: u16,
     peer_port: u16,
@@ -156,6 +159,9 @@ pub struct PeerConnection<T: HStream, TS: HStartable<T>> {
     req_id: AtomicU64,
     write_stream: Mutex<Option<WriteHalf<T>>>,
     read_channel: tokio::sync::broadcast::Sender<RecvMessage>,
+    // Responses are routed directly to the waiting `req_res` call, so any number of requests
+    // can be in flight without lagging the broadcast channel.
+    pending_responses: PendingResponses,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -187,7 +193,8 @@ where
             starter,
             req_id: AtomicU64::new(0),
             write_stream: Mutex::new(None),
-            read_channel: tokio::sync::broadcast::channel(16).0,
+            read_channel: tokio::sync::broadcast::channel(1024).0,
+            pending_responses: Arc::new(std::sync::Mutex::new(HashMap::new())),
         });
 
         let con_clone = con.clone();
@@ -214,13 +221,11 @@ where
         }
     }
 
-    pub async fn send_request(
-        self: Arc<Self>,
+    async fn write_request(
+        &self,
+        req_id: u64,
         data: Vec<u8>,
-    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
-        let req_id = self
-            .req_id
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let msg = Message::Req {
             req_id,
             payload: data,
@@ -235,7 +240,7 @@ where
         } else {
             return Err(format!("No active connection {}", self.peer_port).into());
         }
-        Ok(req_id)
+        Ok(())
     }
 
     pub async fn send_response(
@@ -264,22 +269,36 @@ where
         self: Arc<Self>,
         data: Vec<u8>,
     ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
-        let mut rec = self.read_channel.subscribe();
-        let req_id = self.send_request(data).await?;
-        loop {
-            let msg = tokio::time::timeout(Duration::from_secs(10), rec.recv()).await?;
-            match msg {
-                Ok(RecvMessage::Res { res_id, payload }) if res_id == req_id => {
-                    return Ok(payload);
-                }
-                Err(e) => {
-                    return Err(format!("Receive error: {}", e).into());
-                }
-                _ => {
-                    // Ignore other messages
-                }
-            }
-        }
+        self.req_res_with_timeout(data, Duration::from_secs(10))
+            .await
+    }
+
+    /// Like `req_res`, waiting up to `timeout` for the response, for requests that the peer
+    /// holds on to, such as long polls.
+    pub async fn req_res_with_timeout(
+        self: Arc<Self>,
+        data: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let req_id = self
+            .req_id
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+        // Register before sending so a fast response can't be missed
+        let (tx, rx) = oneshot::channel();
+        self.pending_responses.lock().unwrap().insert(req_id, tx);
+
+        let result = match self.write_request(req_id, data).await {
+            Ok(()) => match tokio::time::timeout(timeout, rx).await {
+                Ok(Ok(payload)) => Ok(payload),
+                Ok(Err(_)) => Err("Connection closed before response".into()),
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e),
+        };
+
+        self.pending_responses.lock().unwrap().remove(&req_id);
+        result
     }
 
     pub fn get_read_channel(self: Arc<Self>) -> tokio::sync::broadcast::Receiver<RecvMessage> {
@@ -364,6 +383,7 @@ where
 
             let mut read_handle = {
                 let read_channel = self.read_channel.clone();
+                let pending_responses = self.pending_responses.clone();
                 let local_port = self.local_port;
                 let peer_port = peer_port;
                 tokio::spawn(async move {
@@ -395,7 +415,16 @@ where
                                     res_id,
                                     payload.len()
                                 );
-                                let _ = read_channel.send(RecvMessage::Res { res_id, payload });
+                                let waiter = pending_responses.lock().unwrap().remove(&res_id);
+                                match waiter {
+                                    Some(tx) => {
+                                        let _ = tx.send(payload);
+                                    }
+                                    None => {
+                                        let _ =
+                                            read_channel.send(RecvMessage::Res { res_id, payload });
+                                    }
+                                }
                             }
                             Ok(_) => {
                                 eprintln!(
@@ -444,6 +473,8 @@ where
                         self.local_port, peer_port
                     );
                     self.write_stream.lock().await.take();
+                    // Fail in-flight requests instead of letting them run into the timeout
+                    self.pending_responses.lock().unwrap().clear();
                     continue;
                 }
             }
@@ -1,7 +1,11 @@
+use std::ops::Bound;
+
 use openraft::{LogId, ReadPolicy as OpenraftReadPolicy};
 use serde::{Deserialize, Serialize};
 
-use crate::raft::{NodeId, TypeConfig};
+use crate::raft::store::fifo::common::QueueStats;
+use crate::raft::store::kv::StoredValue;
+use crate::raft::{NodeId, SessionToken, TypeConfig};
 
 /// Copied from openraft as it doesn't implement Deserialize/Serialize.
 /// Policy that determines how to handle read operations in a Raft cluster.
@@ -44,8 +48,105 @@ pub enum RequestType {
     AppendEntriesRequest(Vec<u8>),
     InstallSnapshotRequest(Vec<u8>),
     VoteRequest(Vec<u8>),
+    /// Write forwarded by a follower. The leader proposes the writes of one connection in the
+    /// order it receives them, after checking them against its own size limits, and answers
+    /// with a `ForwardedWriteResult`.
     AppRequest(crate::raft::Request),
-    Linearizer { read_policy: ReadPolicy },
+    Linearizer {
+        read_policy: ReadPolicy,
+    },
+    /// Read executed by the leader against its own state machine. When `read_policy` is set,
+    /// the leader linearizes with it before reading, and with `after` it first waits until it
+    /// has applied that write.
+    Read {
+        query: ReadQuery,
+        read_policy: Option<ReadPolicy>,
+        #[serde(default)]
+        after: Option<SessionToken>,
+    },
+    /// FIFO dequeue or reserve that the leader retries whenever items are appended to the
+    /// queue, until it returns items or `timeout_ms` passes, or an enqueue into a full blocking
+    /// queue that it retries whenever items leave the queue. Answered like `AppRequest`.
+    WaitOnQueue {
+        request: crate::raft::Request,
+        timeout_ms: u64,
+    },
+    /// Write of a follower's pipeline. Unlike `AppRequest`, the leader proposes it concurrently
+    /// with the other requests of the connection, so it is not ordered against them. Answered
+    /// like `AppRequest`.
+    PipelinedAppRequest(crate::raft::Request),
+}
+
+/// Answer of the leader to a forwarded write. The outer error is a write rejected by the
+/// leader's size limits before it was proposed.
+pub type ForwardedWriteResult = Result<
+    Result<
+        openraft::raft::ClientWriteResponse<crate::raft::TypeConfig>,
+        openraft::error::ClientWriteError<crate::raft::TypeConfig>,
+    >,
+    crate::limits::SizeLimitError,
+>;
+
+/// A read of the state machine that can be executed locally or forwarded to the leader.
+/// Blob values are returned reassembled from chunks read in the same snapshot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReadQuery {
+    Get {
+        key: Vec<u8>,
+        at_revision: Option<u64>,
+    },
+    GetMany {
+        keys: Vec<Vec<u8>>,
+    },
+    Scan {
+        prefix: Vec<u8>,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    },
+    /// Keys whose indexed value falls between `start` and `end`, given as encoded index values
+    Index {
+        name: String,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    },
+    /// Up to `limit` items from the head of a FIFO queue, without removing them
+    QueuePeek {
+        queue_key: Vec<u8>,
+        limit: usize,
+    },
+    QueueStats {
+        queue_key: Vec<u8>,
+    },
+    /// Keys of every FIFO queue that was ever written to
+    ListQueues,
+    /// Up to `limit` items of a priority queue, highest priority first, without removing them
+    PriorityQueuePeek {
+        queue_key: Vec<u8>,
+        limit: usize,
+    },
+    PriorityQueueStats {
+        queue_key: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReadQueryResult {
+    Value(Option<StoredValue>),
+    Values(Vec<Option<StoredValue>>),
+    Entries(Vec<(Vec<u8>, StoredValue)>),
+    Items(Vec<Vec<u8>>),
+    QueueStats(QueueStats),
+    Queues(Vec<Vec<u8>>),
+    /// Items of a priority queue with their priorities
+    PriorityItems(Vec<(u32, Vec<u8>)>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReadQueryError {
+    Compacted { compacted_revision: u64 },
+    Other(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
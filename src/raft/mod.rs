@@ -5,8 +5,10 @@ use serde::{Deserialize, Serialize};
 pub mod store;
 pub use store::fifo::FIFOOperation;
 pub use store::kv::KVOperation;
+pub use store::txn::Txn;
 
 use crate::raft::store::fifo::FIFOResponse;
+use crate::raft::store::txn::TxnResponse;
 
 pub type NodeId = u64;
 
@@ -21,12 +23,18 @@ pub struct Request {
     pub client_id: NodeId,
     pub seq_id: Option<u64>,
     pub op: RequestOperation,
+    /// Leader's wall clock when the entry was proposed, in milliseconds since the Unix epoch.
+    /// Operations that depend on time read it from here, so every node applies them the same.
+    #[serde(default)]
+    pub time_ms: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum RequestOperation {
     KV(KVOperation),
     FIFO(FIFOOperation),
+    /// KV and FIFO operations applied together, e.g. a write and the enqueue announcing it
+    Txn(Txn),
 }
 
 impl fmt::Display for RequestOperation {
@@ -34,6 +42,7 @@ impl fmt::Display for RequestOperation {
         match &self {
             RequestOperation::KV(kv_op) => write!(f, "{}", kv_op),
             RequestOperation::FIFO(fifo_op) => write!(f, "{}", fifo_op),
+            RequestOperation::Txn(txn) => write!(f, "{}", txn),
         }
     }
 }
@@ -89,24 +98,114 @@ pub enum ResponseResult {
     Empty,
     KV(KVResponse),
     FIFO(FIFOResponse),
+    Txn(TxnResponse),
+}
+
+/// Causality token of a write: the index of the raft log entry that applied it. Passing it to
+/// a read's `after` makes any node wait until it has applied the write before serving the read.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SessionToken {
+    pub(crate) index: u64,
+}
+
+impl SessionToken {
+    pub(crate) fn from_write(response: &openraft::raft::ClientWriteResponse<TypeConfig>) -> Self {
+        Self {
+            index: response.log_id().index(),
+        }
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SetResponse {
     pub prev_value: Option<Vec<u8>>,
+    /// Cluster-wide revision of the write. Revisions increase monotonically across all keys.
     pub revision: u64,
+    /// Causality token of the write, filled in by the writing client
+    #[serde(default)]
+    pub token: SessionToken,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PutIfAbsentResponse {
+    /// Whether the value was stored. If not, the key already existed and was left untouched.
+    pub inserted: bool,
+    /// Value already stored at the key, when nothing was inserted
+    pub existing: Option<Vec<u8>>,
+    /// Revision of the stored value, either the new one or the existing one
+    pub revision: u64,
+    /// Causality token of the write, filled in by the writing client
+    #[serde(default)]
+    pub token: SessionToken,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteResponse {
+    pub existed: bool,
+    /// Deleted value, if it was requested
+    pub prev_value: Option<Vec<u8>>,
+    /// Revision of the key before the delete, 0 if it did not exist
+    pub prev_revision: u64,
+    /// Cluster-wide revision of the delete, `None` if nothing was deleted
+    pub revision: Option<u64>,
+    /// Causality token of the write, filled in by the writing client
+    #[serde(default)]
+    pub token: SessionToken,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum KVResponse {
     Set(SetResponse),
+    /// `success` is false when the expected revision did not match; nothing was deleted then
     Del {
-        existed: bool,
+        success: bool,
+        response: DeleteResponse,
     },
     Cas {
         success: bool,
         response: SetResponse,
     },
+    Compact {
+        compacted_revision: u64,
+    },
+    /// One response per operation of a `KVOperation::Batch`, in order
+    Batch(Vec<KVResponse>),
+    Incr(IncrResult),
+    /// Number of deleted keys, if it was requested
+    DeleteRange {
+        deleted: Option<u64>,
+    },
+    PutIfAbsent(PutIfAbsentResponse),
+    /// Number of keys added to the new index
+    CreateIndex {
+        indexed: u64,
+    },
+    DropIndex {
+        existed: bool,
+    },
+    /// A blob manifest referenced chunks that are no longer stored; nothing was written
+    MissingBlobChunks,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum IncrResult {
+    Applied {
+        value: i64,
+        revision: u64,
+    },
+    /// The new value would leave the requested bounds or overflow; nothing was written
+    OutOfRange {
+        value: i64,
+        revision: u64,
+    },
+    /// The stored value is not a MessagePack integer; nothing was written
+    NotAnInteger {
+        revision: u64,
+    },
 }
 
 openraft::declare_raft_types!(
@@ -1,24 +1,1308 @@
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io;
+use std::sync::Arc;
+use std::sync::Mutex;
 
+use rocksdb::DB;
 use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+use crate::raft::store::common::{deserialize, get_cf_handle, rocksdb_err_to_io, serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct QueueMeta {
     pub head: u64, // last popped index
     pub tail: u64, // last pushed index
+    /// Total data size of the waiting items. Only counts items pushed since it was recorded.
+    #[serde(default)]
+    pub bytes: u64,
+    /// Number of items scheduled for later delivery
+    #[serde(default)]
+    pub delayed: u64,
+    /// Total data size of the items scheduled for later delivery. Only counts items scheduled
+    /// since it was recorded.
+    #[serde(default)]
+    pub delayed_bytes: u64,
+    /// Last sequence number given to a scheduled item
+    #[serde(default)]
+    pub delayed_seq: u64,
+    /// Number of reserved items that are not acknowledged yet. Only counts items reserved since
+    /// it was recorded.
+    #[serde(default)]
+    pub inflight: u64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct QueueItem {
     pub index: u64,
     pub data: Vec<u8>,
+    /// Leader time at which the item was appended, 0 if unknown
+    #[serde(default)]
+    pub enqueued_ms: u64,
+}
+
+/// Counters and age of a queue, as returned by `DistFIFO::stats`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct QueueStats {
+    /// Items waiting to be delivered
+    pub length: u64,
+    /// Total data size of the waiting items
+    #[serde(default)]
+    pub bytes: u64,
+    /// Reserved items that are not acknowledged yet
+    pub in_flight: u64,
+    /// Items scheduled for later delivery that are not due yet
+    #[serde(default)]
+    pub delayed: u64,
+    /// Items ever appended to the queue
+    pub enqueued_total: u64,
+    /// Items ever taken from the head of the queue, whether dequeued, reserved, moved or purged
+    pub dequeued_total: u64,
+    /// Leader time at which the oldest waiting item was appended, in milliseconds since the
+    /// Unix epoch
+    pub oldest_enqueued_ms: Option<u64>,
+}
+
+impl QueueStats {
+    /// Time the oldest waiting item has spent in the queue.
+    pub fn oldest_item_age(&self) -> Option<std::time::Duration> {
+        self.oldest_enqueued_ms.map(|enqueued_ms| {
+            std::time::Duration::from_millis(crate::util::now_ms().saturating_sub(enqueued_ms))
+        })
+    }
+}
+
+/// What an enqueue does when the new items do not fit within `max_length` or `max_bytes`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DropPolicy {
+    /// Reject the whole enqueue
+    #[default]
+    Reject,
+    /// Accept the new items and drop items from the head until the queue fits again. Priority
+    /// queues drop their oldest items whatever their priority.
+    DropOldest,
+    /// Hold the producer until consumers have made room. Enqueues that could never fit are
+    /// rejected.
+    Block,
+}
+
+/// Per-queue limits and delivery policy, stored in `fifo_queue_config`, or in `pqueue_config`
+/// for priority queues, which have no reservations and so no dead-letter policy.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct QueueConfig {
+    /// Deliveries after which an unacknowledged item is dead-lettered instead of redelivered
+    pub max_deliveries: Option<u32>,
+    /// Queue that dead-lettered items are moved to. Without one they are dropped.
+    pub dead_letter_queue: Option<Vec<u8>>,
+    /// Most items the queue holds, counting scheduled ones but not in-flight ones
+    #[serde(default)]
+    pub max_length: Option<u64>,
+    /// Largest total data size of the items the queue holds, counting scheduled ones but not
+    /// in-flight ones
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// Time after which a waiting item expires and is dropped instead of delivered
+    #[serde(default)]
+    pub message_ttl_ms: Option<u64>,
+    #[serde(default)]
+    pub drop_policy: DropPolicy,
+}
+
+impl QueueConfig {
+    /// Whether a queue holding `length` items of `bytes` in total is within the limits.
+    pub fn fits(&self, length: u64, bytes: u64) -> bool {
+        self.max_length
+            .is_none_or(|max_length| length <= max_length)
+            && self.max_bytes.is_none_or(|max_bytes| bytes <= max_bytes)
+    }
+}
+
+/// Item scheduled for delivery at `deliver_at_ms`, kept in `fifo_delayed` until then.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DelayedItem {
+    pub deliver_at_ms: u64,
+    pub seq: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct PriorityQueueMeta {
+    /// Number of waiting items
+    pub length: u64,
+    /// Last sequence number given to an item, which orders items of the same priority
+    pub seq: u64,
+    /// Total data size of the waiting items
+    #[serde(default)]
+    pub bytes: u64,
+}
+
+/// Item of a priority queue, stored in `pqueue_data`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PriorityItem {
+    pub data: Vec<u8>,
+    /// Leader time at which the item was enqueued
+    pub enqueued_ms: u64,
+}
+
+/// Item handed to a consumer that has not been acknowledged yet. It becomes visible again once
+/// `deadline_ms` has passed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InFlightItem {
+    pub index: u64,
+    pub data: Vec<u8>,
+    pub deadline_ms: u64,
+    /// Number of times the item has been delivered
+    pub deliveries: u32,
 }
 
 pub struct FIFOOverlayQueue {
     pub meta: QueueMeta,
-    pub items: Vec<QueueItem>,
+    /// Items appended in this batch and still queued, by index
+    pub items: BTreeMap<u64, QueueItem>,
+    /// In-flight changes of this batch: None = acknowledged, Some(item) = written
+    pub inflight: BTreeMap<u64, Option<InFlightItem>>,
+    /// Scheduled item changes of this batch by due time and sequence: None = delivered,
+    /// Some(item) = scheduled
+    pub delayed: BTreeMap<(u64, u64), Option<DelayedItem>>,
+    pub config: QueueConfig,
+    /// Whether the queue has metadata, in the database or in this batch
+    pub exists: bool,
+    /// Whether items left the head of the queue or its limits changed in this batch, which can
+    /// make room for blocked producers
+    pub popped: bool,
+    /// State before the running transaction first touched the queue
+    undo: Option<QueueUndo>,
+}
+
+/// State of a queue before a transaction changed it, restored if the transaction declines.
+/// Transactions only enqueue, which never changes in-flight items.
+struct QueueUndo {
+    meta: QueueMeta,
+    config: QueueConfig,
+    exists: bool,
+    popped: bool,
+    /// Pending items the transaction removed from the head
+    items: Vec<(u64, QueueItem)>,
+    /// Earlier pending state of the scheduled items the transaction changed, oldest change last
+    delayed: Vec<((u64, u64), Option<Option<DelayedItem>>)>,
+}
+
+pub struct PriorityOverlayQueue {
+    pub meta: PriorityQueueMeta,
+    pub config: QueueConfig,
+    /// Whether the queue has metadata in the database or this batch
+    pub exists: bool,
+    /// Item changes of this batch by item key suffix: None = removed, Some(item) = enqueued
+    pub items: BTreeMap<Vec<u8>, Option<PriorityItem>>,
+    /// Whether items left the queue or its limits changed in this batch, which can make room
+    /// for blocked producers
+    pub popped: bool,
+    /// State before the running transaction first touched the queue
+    undo: Option<PriorityQueueUndo>,
+}
+
+/// State of a priority queue before a transaction changed it, restored if the transaction
+/// declines.
+struct PriorityQueueUndo {
+    meta: PriorityQueueMeta,
+    config: QueueConfig,
+    exists: bool,
+    popped: bool,
+    /// Earlier pending state of the items the transaction changed, oldest change last
+    items: Vec<(Vec<u8>, Option<Option<PriorityItem>>)>,
 }
 
+#[derive(Default)]
 pub struct FIFOOverlay {
     pub meta: HashMap<Vec<u8>, FIFOOverlayQueue>,
+    pub priority: HashMap<Vec<u8>, PriorityOverlayQueue>,
+    /// Queues that got new items in this batch, to notify once the batch is written
+    pub pushed: HashSet<Vec<u8>>,
+    /// Changes of the running transaction, see `begin_txn`
+    txn: Option<FIFOTxn>,
+}
+
+/// Queues touched by a transaction, whose changes are undone if it declines
+#[derive(Default)]
+struct FIFOTxn {
+    queues: Vec<Vec<u8>>,
+    priority: Vec<Vec<u8>>,
+    /// Queues the transaction added to `pushed`
+    pushed: Vec<Vec<u8>>,
+}
+
+/// Wakes clients waiting on a queue once the state machine has changed it: consumers waiting
+/// for items, or producers waiting for room.
+#[derive(Debug, Clone, Default)]
+pub struct QueueNotifier {
+    waiters: Arc<Mutex<HashMap<Vec<u8>, Arc<Notify>>>>,
+}
+
+impl QueueNotifier {
+    /// Notification handle of the queue. Create the `notified` future before checking the queue
+    /// so that no append is missed.
+    pub fn subscribe(&self, queue_key: &[u8]) -> Arc<Notify> {
+        self.waiters
+            .lock()
+            .unwrap()
+            .entry(queue_key.to_vec())
+            .or_default()
+            .clone()
+    }
+
+    pub fn notify(&self, queue_key: &[u8]) {
+        let mut waiters = self.waiters.lock().unwrap();
+        if let Some(notify) = waiters.get(queue_key) {
+            // Nobody but the map holds the handle anymore
+            if Arc::strong_count(notify) == 1 {
+                waiters.remove(queue_key);
+            } else {
+                notify.notify_waiters();
+            }
+        }
+    }
+}
+
+/// Key of the item at `index` of the queue, in both `fifo_queue_data` and `fifo_inflight`.
+pub fn item_key(queue_key: &[u8], index: u64) -> Vec<u8> {
+    let mut item_key = queue_key.to_vec();
+    item_key.extend_from_slice(&index.to_be_bytes());
+    item_key
+}
+
+/// Key of an in-flight item in `fifo_inflight_deadlines`: `queue_key | deadline_ms | index`.
+/// The in-flight items of a queue sort by deadline, so the expired ones are a prefix.
+pub fn inflight_deadline_key(queue_key: &[u8], deadline_ms: u64, index: u64) -> Vec<u8> {
+    let mut deadline_key = queue_key.to_vec();
+    deadline_key.extend_from_slice(&deadline_ms.to_be_bytes());
+    deadline_key.extend_from_slice(&index.to_be_bytes());
+    deadline_key
+}
+
+/// Key of a scheduled item in `fifo_delayed`: `queue_key | deliver_at_ms | seq`. The items of a
+/// queue sort by due time, and items due at the same time in the order they were scheduled.
+pub fn delayed_key(queue_key: &[u8], deliver_at_ms: u64, seq: u64) -> Vec<u8> {
+    let mut delayed_key = queue_key.to_vec();
+    delayed_key.extend_from_slice(&deliver_at_ms.to_be_bytes());
+    delayed_key.extend_from_slice(&seq.to_be_bytes());
+    delayed_key
+}
+
+/// Key of an item in `pqueue_data`: `queue_key | !priority | seq`. Inverting the priority sorts
+/// the highest priority first, and the sequence number keeps items of the same priority in
+/// enqueue order.
+pub fn priority_item_key(queue_key: &[u8], priority: u32, seq: u64) -> Vec<u8> {
+    let mut priority_item_key = queue_key.to_vec();
+    priority_item_key.extend_from_slice(&(!priority).to_be_bytes());
+    priority_item_key.extend_from_slice(&seq.to_be_bytes());
+    priority_item_key
+}
+
+/// Key of an item in `pqueue_order`: `queue_key | seq`, mapped to its priority. The items of a
+/// queue sort by enqueue order there, so the oldest ones are a prefix.
+pub fn priority_order_key(queue_key: &[u8], seq: u64) -> Vec<u8> {
+    let mut priority_order_key = queue_key.to_vec();
+    priority_order_key.extend_from_slice(&seq.to_be_bytes());
+    priority_order_key
+}
+
+/// Priority and sequence number of an item from its `pqueue_data` key suffix.
+pub fn parse_priority_suffix(suffix: &[u8]) -> (u32, u64) {
+    (
+        !u32::from_be_bytes(suffix[..4].try_into().unwrap()),
+        u64::from_be_bytes(suffix[4..12].try_into().unwrap()),
+    )
+}
+
+impl FIFOOverlay {
+    /// Pending state of the queue, loading its metadata and config from the database on first
+    /// use.
+    pub fn queue(&mut self, db: &DB, queue_key: &[u8]) -> Result<&mut FIFOOverlayQueue, io::Error> {
+        if !self.meta.contains_key(queue_key) {
+            let fifo_queue_meta = get_cf_handle(db, "fifo_queue_meta")?;
+            let fifo_queue_config = get_cf_handle(db, "fifo_queue_config")?;
+            let stored_meta = db
+                .get_cf(fifo_queue_meta, queue_key)
+                .map_err(rocksdb_err_to_io)?;
+            let exists = stored_meta.is_some();
+            let meta = match stored_meta {
+                Some(bytes) => deserialize(&bytes)?,
+                None => QueueMeta::default(),
+            };
+            let config = match db
+                .get_cf(fifo_queue_config, queue_key)
+                .map_err(rocksdb_err_to_io)?
+            {
+                Some(bytes) => deserialize(&bytes)?,
+                None => QueueConfig::default(),
+            };
+            self.meta.insert(
+                queue_key.to_vec(),
+                FIFOOverlayQueue {
+                    meta,
+                    items: BTreeMap::new(),
+                    inflight: BTreeMap::new(),
+                    delayed: BTreeMap::new(),
+                    config,
+                    exists,
+                    popped: false,
+                    undo: None,
+                },
+            );
+        }
+
+        let overlay_queue = self.meta.get_mut(queue_key).unwrap();
+        if let Some(txn) = self.txn.as_mut().filter(|_| overlay_queue.undo.is_none()) {
+            overlay_queue.undo = Some(QueueUndo {
+                meta: overlay_queue.meta,
+                config: overlay_queue.config.clone(),
+                exists: overlay_queue.exists,
+                popped: overlay_queue.popped,
+                items: Vec::new(),
+                delayed: Vec::new(),
+            });
+            txn.queues.push(queue_key.to_vec());
+        }
+        Ok(overlay_queue)
+    }
+
+    /// Pending state of the priority queue, loading its metadata and config from the database on
+    /// first use.
+    pub fn priority_queue(
+        &mut self,
+        db: &DB,
+        queue_key: &[u8],
+    ) -> Result<&mut PriorityOverlayQueue, io::Error> {
+        if !self.priority.contains_key(queue_key) {
+            let pqueue_meta = get_cf_handle(db, "pqueue_meta")?;
+            let pqueue_config = get_cf_handle(db, "pqueue_config")?;
+            let stored_meta = db
+                .get_cf(pqueue_meta, queue_key)
+                .map_err(rocksdb_err_to_io)?;
+            let exists = stored_meta.is_some();
+            let meta = match stored_meta {
+                Some(bytes) => deserialize(&bytes)?,
+                None => PriorityQueueMeta::default(),
+            };
+            let config = match db
+                .get_cf(pqueue_config, queue_key)
+                .map_err(rocksdb_err_to_io)?
+            {
+                Some(bytes) => deserialize(&bytes)?,
+                None => QueueConfig::default(),
+            };
+            self.priority.insert(
+                queue_key.to_vec(),
+                PriorityOverlayQueue {
+                    meta,
+                    config,
+                    exists,
+                    items: BTreeMap::new(),
+                    popped: false,
+                    undo: None,
+                },
+            );
+        }
+
+        let overlay_queue = self.priority.get_mut(queue_key).unwrap();
+        if let Some(txn) = self.txn.as_mut().filter(|_| overlay_queue.undo.is_none()) {
+            overlay_queue.undo = Some(PriorityQueueUndo {
+                meta: overlay_queue.meta,
+                config: overlay_queue.config.clone(),
+                exists: overlay_queue.exists,
+                popped: overlay_queue.popped,
+                items: Vec::new(),
+            });
+            txn.priority.push(queue_key.to_vec());
+        }
+        Ok(overlay_queue)
+    }
+
+    /// Record that `queue_key` got new items, to notify its consumers once the batch is written.
+    pub fn mark_pushed(&mut self, queue_key: &[u8]) {
+        let inserted = self.pushed.insert(queue_key.to_vec());
+        if let Some(txn) = self.txn.as_mut().filter(|_| inserted) {
+            txn.pushed.push(queue_key.to_vec());
+        }
+    }
+
+    /// Start recording the changes of a transaction, so that `rollback_txn` can undo them. The
+    /// cost of either outcome is proportional to what the transaction changed.
+    pub fn begin_txn(&mut self) {
+        self.txn = Some(FIFOTxn::default());
+    }
+
+    /// Keep the changes of the running transaction.
+    pub fn commit_txn(&mut self) {
+        let Some(txn) = self.txn.take() else {
+            return;
+        };
+        for queue_key in txn.queues {
+            if let Some(overlay_queue) = self.meta.get_mut(&queue_key) {
+                overlay_queue.undo = None;
+            }
+        }
+        for queue_key in txn.priority {
+            if let Some(overlay_queue) = self.priority.get_mut(&queue_key) {
+                overlay_queue.undo = None;
+            }
+        }
+    }
+
+    /// Undo the changes of the running transaction.
+    pub fn rollback_txn(&mut self) {
+        let Some(txn) = self.txn.take() else {
+            return;
+        };
+        for queue_key in txn.queues {
+            if let Some(overlay_queue) = self.meta.get_mut(&queue_key) {
+                overlay_queue.rollback();
+            }
+        }
+        for queue_key in txn.priority {
+            if let Some(overlay_queue) = self.priority.get_mut(&queue_key) {
+                overlay_queue.rollback();
+            }
+        }
+        for queue_key in txn.pushed {
+            self.pushed.remove(&queue_key);
+        }
+    }
+
+    /// Append `values` to the tail of the queue at `now_ms` and return its new metadata.
+    pub fn push(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+        values: impl IntoIterator<Item = Vec<u8>>,
+        now_ms: u64,
+    ) -> Result<QueueMeta, io::Error> {
+        let fifo_queue_meta = get_cf_handle(db, "fifo_queue_meta")?;
+        let fifo_queue_data = get_cf_handle(db, "fifo_queue_data")?;
+
+        let overlay_queue = self.queue(db, queue_key)?;
+        for value in values {
+            overlay_queue.meta.tail += 1;
+            overlay_queue.meta.bytes += value.len() as u64;
+            let item = QueueItem {
+                index: overlay_queue.meta.tail,
+                data: value,
+                enqueued_ms: now_ms,
+            };
+            batch.put_cf(
+                fifo_queue_data,
+                item_key(queue_key, item.index),
+                serialize(&item)?,
+            );
+            overlay_queue.items.insert(item.index, item);
+        }
+
+        batch.put_cf(fifo_queue_meta, queue_key, serialize(&overlay_queue.meta)?);
+        overlay_queue.exists = true;
+        let meta = overlay_queue.meta;
+        self.mark_pushed(queue_key);
+        Ok(meta)
+    }
+
+    /// Store `values` in `fifo_delayed` until `deliver_at_ms` and return the queue's new
+    /// metadata. They stay invisible to consumers until `promote_due` moves them to the tail.
+    pub fn schedule(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+        values: impl IntoIterator<Item = Vec<u8>>,
+        deliver_at_ms: u64,
+    ) -> Result<QueueMeta, io::Error> {
+        let fifo_queue_meta = get_cf_handle(db, "fifo_queue_meta")?;
+        let fifo_delayed = get_cf_handle(db, "fifo_delayed")?;
+
+        let overlay_queue = self.queue(db, queue_key)?;
+        for value in values {
+            overlay_queue.meta.delayed_seq += 1;
+            overlay_queue.meta.delayed += 1;
+            overlay_queue.meta.delayed_bytes += value.len() as u64;
+            let item = DelayedItem {
+                deliver_at_ms,
+                seq: overlay_queue.meta.delayed_seq,
+                data: value,
+            };
+            batch.put_cf(
+                fifo_delayed,
+                delayed_key(queue_key, deliver_at_ms, item.seq),
+                serialize(&item)?,
+            );
+            overlay_queue.set_delayed((deliver_at_ms, item.seq), Some(item));
+        }
+
+        batch.put_cf(fifo_queue_meta, queue_key, serialize(&overlay_queue.meta)?);
+        overlay_queue.exists = true;
+        Ok(overlay_queue.meta)
+    }
+
+    /// Move the scheduled items that are due at `now_ms` to the tail of the queue, in due-time
+    /// order. `now_ms` is the leader time committed with the entry, so every replica promotes
+    /// the same items. Scheduled items already count against the limits, so promoting them can
+    /// only exceed limits that were lowered since; the `DropOldest` policy then drops from the
+    /// head as an enqueue would, and the other policies keep the items they already accepted.
+    pub fn promote_due(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+        now_ms: u64,
+    ) -> Result<u64, io::Error> {
+        let fifo_delayed = get_cf_handle(db, "fifo_delayed")?;
+
+        let overlay_queue = self.queue(db, queue_key)?;
+        if overlay_queue.meta.delayed == 0 {
+            return Ok(0);
+        }
+
+        let due = overlay_queue.delayed_items(db, queue_key, now_ms)?;
+        for item in &due {
+            batch.delete_cf(
+                fifo_delayed,
+                delayed_key(queue_key, item.deliver_at_ms, item.seq),
+            );
+            overlay_queue.set_delayed((item.deliver_at_ms, item.seq), None);
+            overlay_queue.meta.delayed = overlay_queue.meta.delayed.saturating_sub(1);
+            overlay_queue.meta.delayed_bytes = overlay_queue
+                .meta
+                .delayed_bytes
+                .saturating_sub(item.data.len() as u64);
+        }
+
+        let promoted = due.len() as u64;
+        if promoted > 0 {
+            self.push(
+                db,
+                batch,
+                queue_key,
+                due.into_iter().map(|item| item.data),
+                now_ms,
+            )?;
+
+            let overlay_queue = self.queue(db, queue_key)?;
+            if overlay_queue.config.drop_policy == DropPolicy::DropOldest
+                && overlay_queue.drop_excess(db, batch, queue_key)? > 0
+            {
+                let fifo_queue_meta = get_cf_handle(db, "fifo_queue_meta")?;
+                batch.put_cf(fifo_queue_meta, queue_key, serialize(&overlay_queue.meta)?);
+            }
+        }
+        Ok(promoted)
+    }
+
+    /// Append dead-lettered `values` to `queue_key` within the queue's limits. With the
+    /// `DropOldest` policy the oldest items make room; otherwise the values that no longer fit
+    /// are dropped, since applying an entry can neither wait nor fail. Returns the number of
+    /// appended values.
+    pub fn push_dead_letters(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+        values: Vec<Vec<u8>>,
+        now_ms: u64,
+    ) -> Result<u64, io::Error> {
+        let fifo_queue_meta = get_cf_handle(db, "fifo_queue_meta")?;
+
+        self.promote_due(db, batch, queue_key, now_ms)?;
+        let overlay_queue = self.queue(db, queue_key)?;
+        if overlay_queue.drop_expired(db, batch, queue_key, now_ms)? > 0 {
+            batch.put_cf(fifo_queue_meta, queue_key, serialize(&overlay_queue.meta)?);
+        }
+
+        let drop_oldest = overlay_queue.config.drop_policy == DropPolicy::DropOldest;
+        let mut accepted = Vec::with_capacity(values.len());
+        let mut bytes = 0;
+        for value in values {
+            if !drop_oldest
+                && !overlay_queue.fits(accepted.len() as u64 + 1, bytes + value.len() as u64)
+            {
+                break;
+            }
+            bytes += value.len() as u64;
+            accepted.push(value);
+        }
+
+        let appended = accepted.len() as u64;
+        if appended == 0 {
+            return Ok(0);
+        }
+        self.push(db, batch, queue_key, accepted, now_ms)?;
+        if drop_oldest {
+            let overlay_queue = self.queue(db, queue_key)?;
+            if overlay_queue.drop_excess(db, batch, queue_key)? > 0 {
+                batch.put_cf(fifo_queue_meta, queue_key, serialize(&overlay_queue.meta)?);
+            }
+        }
+        Ok(appended)
+    }
+}
+
+impl PriorityOverlayQueue {
+    /// Record a pending item change: `None` for a removed item, `Some(item)` for an enqueued one.
+    fn set_item(&mut self, suffix: Vec<u8>, item: Option<PriorityItem>) {
+        let previous = self.items.insert(suffix.clone(), item);
+        if let Some(undo) = &mut self.undo {
+            undo.items.push((suffix, previous));
+        }
+    }
+
+    fn rollback(&mut self) {
+        let Some(undo) = self.undo.take() else {
+            return;
+        };
+        for (suffix, previous) in undo.items.into_iter().rev() {
+            match previous {
+                Some(previous) => self.items.insert(suffix, previous),
+                None => self.items.remove(&suffix),
+            };
+        }
+        self.meta = undo.meta;
+        self.config = undo.config;
+        self.exists = undo.exists;
+        self.popped = undo.popped;
+    }
+
+    /// Whether `new_length` more items of `new_bytes` in total fit within the queue's limits.
+    pub fn fits(&self, new_length: u64, new_bytes: u64) -> bool {
+        self.config
+            .fits(self.meta.length + new_length, self.meta.bytes + new_bytes)
+    }
+
+    /// Add an item with the next sequence number. The caller writes the metadata.
+    pub fn push(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+        priority: u32,
+        data: Vec<u8>,
+        now_ms: u64,
+    ) -> Result<(), io::Error> {
+        let pqueue_data = get_cf_handle(db, "pqueue_data")?;
+        let pqueue_order = get_cf_handle(db, "pqueue_order")?;
+
+        self.meta.seq += 1;
+        self.meta.length += 1;
+        self.meta.bytes += data.len() as u64;
+        let item = PriorityItem {
+            data,
+            enqueued_ms: now_ms,
+        };
+        let item_key = priority_item_key(queue_key, priority, self.meta.seq);
+        batch.put_cf(pqueue_data, &item_key, serialize(&item)?);
+        batch.put_cf(
+            pqueue_order,
+            priority_order_key(queue_key, self.meta.seq),
+            priority.to_be_bytes(),
+        );
+        self.exists = true;
+        self.set_item(item_key[queue_key.len()..].to_vec(), Some(item));
+        Ok(())
+    }
+
+    /// Remove the item with key suffix `suffix`. The caller writes the metadata.
+    pub fn remove(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+        suffix: Vec<u8>,
+        item: &PriorityItem,
+    ) -> Result<(), io::Error> {
+        let pqueue_data = get_cf_handle(db, "pqueue_data")?;
+        let pqueue_order = get_cf_handle(db, "pqueue_order")?;
+
+        let (_, seq) = parse_priority_suffix(&suffix);
+        let mut item_key = queue_key.to_vec();
+        item_key.extend_from_slice(&suffix);
+        batch.delete_cf(pqueue_data, &item_key);
+        batch.delete_cf(pqueue_order, priority_order_key(queue_key, seq));
+        self.meta.length = self.meta.length.saturating_sub(1);
+        self.meta.bytes = self.meta.bytes.saturating_sub(item.data.len() as u64);
+        self.popped = true;
+        self.set_item(suffix, None);
+        Ok(())
+    }
+
+    /// Up to `count` items from the front of the priority queue, highest priority first, as
+    /// `(key suffix, item)` pairs, including the pending ones.
+    pub fn first_items(
+        &self,
+        db: &DB,
+        queue_key: &[u8],
+        count: usize,
+    ) -> Result<Vec<(Vec<u8>, PriorityItem)>, io::Error> {
+        let pqueue_data = get_cf_handle(db, "pqueue_data")?;
+
+        // Stored items that are still there, enough to fill `count` on their own
+        let mut items = BTreeMap::new();
+        for item in db.iterator_cf(
+            pqueue_data,
+            rocksdb::IteratorMode::From(queue_key, rocksdb::Direction::Forward),
+        ) {
+            if items.len() >= count {
+                break;
+            }
+            let (key, value) = item.map_err(rocksdb_err_to_io)?;
+            if !key.starts_with(queue_key) {
+                break;
+            }
+            // Skip the items of other queues whose key starts with this queue's key
+            if key.len() != queue_key.len() + 12 {
+                continue;
+            }
+            let suffix = key[queue_key.len()..].to_vec();
+            if self.items.contains_key(&suffix) {
+                continue;
+            }
+            items.insert(suffix, deserialize::<PriorityItem>(&value)?);
+        }
+        for (suffix, pending) in &self.items {
+            if let Some(item) = pending {
+                items.insert(suffix.clone(), item.clone());
+            }
+        }
+        Ok(items.into_iter().take(count).collect())
+    }
+
+    /// Remove items oldest first for as long as `should_drop` returns true for the queue metadata
+    /// and the next item. Returns the number of removed items. The caller writes the metadata.
+    pub fn drop_oldest(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+        mut should_drop: impl FnMut(&PriorityQueueMeta, &PriorityItem) -> bool,
+    ) -> Result<u64, io::Error> {
+        let pqueue_data = get_cf_handle(db, "pqueue_data")?;
+        let pqueue_order = get_cf_handle(db, "pqueue_order")?;
+
+        let mut dropped = 0;
+        // Stored items come first: the pending ones got the newer sequence numbers
+        for entry in db.iterator_cf(
+            pqueue_order,
+            rocksdb::IteratorMode::From(queue_key, rocksdb::Direction::Forward),
+        ) {
+            if self.meta.length == 0 {
+                return Ok(dropped);
+            }
+            let (key, value) = entry.map_err(rocksdb_err_to_io)?;
+            if !key.starts_with(queue_key) {
+                break;
+            }
+            // Skip the items of other queues whose key starts with this queue's key
+            if key.len() != queue_key.len() + 8 || value.len() != 4 {
+                continue;
+            }
+            let seq = u64::from_be_bytes(key[queue_key.len()..].try_into().unwrap());
+            let priority = u32::from_be_bytes(value.as_ref().try_into().unwrap());
+            let item_key = priority_item_key(queue_key, priority, seq);
+            let suffix = item_key[queue_key.len()..].to_vec();
+            if self.items.contains_key(&suffix) {
+                continue;
+            }
+            // Missing items are gone already and are dropped along
+            let item = match db
+                .get_cf(pqueue_data, &item_key)
+                .map_err(rocksdb_err_to_io)?
+            {
+                Some(bytes) => deserialize::<PriorityItem>(&bytes)?,
+                None => PriorityItem {
+                    data: Vec::new(),
+                    enqueued_ms: 0,
+                },
+            };
+            if !should_drop(&self.meta, &item) {
+                return Ok(dropped);
+            }
+            self.remove(db, batch, queue_key, suffix, &item)?;
+            dropped += 1;
+        }
+
+        let mut pending: Vec<(Vec<u8>, PriorityItem)> = self
+            .items
+            .iter()
+            .filter_map(|(suffix, item)| Some((suffix.clone(), item.clone()?)))
+            .collect();
+        pending.sort_by_key(|(suffix, _)| parse_priority_suffix(suffix).1);
+        for (suffix, item) in pending {
+            if self.meta.length == 0 || !should_drop(&self.meta, &item) {
+                break;
+            }
+            self.remove(db, batch, queue_key, suffix, &item)?;
+            dropped += 1;
+        }
+        Ok(dropped)
+    }
+
+    /// Drop the oldest items until the queue is within its limits again, as the `DropOldest`
+    /// policy does. The caller writes the metadata.
+    pub fn drop_excess(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+    ) -> Result<u64, io::Error> {
+        let config = self.config.clone();
+        self.drop_oldest(db, batch, queue_key, |meta, _| {
+            !config.fits(meta.length, meta.bytes)
+        })
+    }
+
+    /// Drop the items that waited longer than the queue's message TTL at `now_ms`. Items are
+    /// examined in enqueue order and the first one that has not expired ends the scan. The
+    /// caller writes the metadata.
+    pub fn drop_expired(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+        now_ms: u64,
+    ) -> Result<u64, io::Error> {
+        let Some(ttl_ms) = self.config.message_ttl_ms else {
+            return Ok(0);
+        };
+        self.drop_oldest(db, batch, queue_key, |_, item| {
+            item.enqueued_ms.saturating_add(ttl_ms) <= now_ms
+        })
+    }
+}
+
+impl FIFOOverlayQueue {
+    /// Record a pending scheduled item change: `None` for a delivered item, `Some(item)` for a
+    /// scheduled one.
+    fn set_delayed(&mut self, due: (u64, u64), item: Option<DelayedItem>) {
+        let previous = self.delayed.insert(due, item);
+        if let Some(undo) = &mut self.undo {
+            undo.delayed.push((due, previous));
+        }
+    }
+
+    fn rollback(&mut self) {
+        let Some(undo) = self.undo.take() else {
+            return;
+        };
+        // Items pushed by the transaction come after the tail it started from
+        self.items.split_off(&(undo.meta.tail + 1));
+        self.items.extend(
+            undo.items
+                .into_iter()
+                .filter(|(index, _)| *index <= undo.meta.tail),
+        );
+        for (due, previous) in undo.delayed.into_iter().rev() {
+            match previous {
+                Some(previous) => self.delayed.insert(due, previous),
+                None => self.delayed.remove(&due),
+            };
+        }
+        self.meta = undo.meta;
+        self.config = undo.config;
+        self.exists = undo.exists;
+        self.popped = undo.popped;
+    }
+
+    /// Number of items waiting in the queue
+    pub fn length(&self) -> u64 {
+        self.meta.tail.saturating_sub(self.meta.head)
+    }
+
+    /// Number of items the queue holds against its limits: the waiting and the scheduled ones
+    pub fn held_length(&self) -> u64 {
+        self.length() + self.meta.delayed
+    }
+
+    /// Total data size of the items the queue holds against its limits
+    pub fn held_bytes(&self) -> u64 {
+        self.meta.bytes + self.meta.delayed_bytes
+    }
+
+    /// Whether `new_length` more items of `new_bytes` in total fit within the queue's limits.
+    pub fn fits(&self, new_length: u64, new_bytes: u64) -> bool {
+        self.config.fits(
+            self.held_length() + new_length,
+            self.held_bytes() + new_bytes,
+        )
+    }
+
+    /// Drop items from the head until the queue is within its limits again, as the `DropOldest`
+    /// policy does. Scheduled items count against the limits but are never dropped. The caller
+    /// writes the metadata.
+    pub fn drop_excess(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+    ) -> Result<u64, io::Error> {
+        // Count the items to drop from the head until the queue is within its limits again
+        let mut length = self.held_length();
+        let mut bytes = self.held_bytes();
+        let mut excess = 0;
+        while excess < self.length() && !self.config.fits(length, bytes) {
+            let index = self.meta.head + excess + 1;
+            let size = self
+                .item(db, queue_key, index)?
+                .map_or(0, |item| item.data.len() as u64);
+            bytes = bytes.saturating_sub(size);
+            length -= 1;
+            excess += 1;
+        }
+        self.drop_head(db, batch, queue_key, excess)
+    }
+
+    /// Queued item at `index`, checking the pending state before the database. `None` if the
+    /// item is gone, which the callers treat as already removed.
+    pub fn item(
+        &self,
+        db: &DB,
+        queue_key: &[u8],
+        index: u64,
+    ) -> Result<Option<QueueItem>, io::Error> {
+        if let Some(item) = self.items.get(&index) {
+            return Ok(Some(item.clone()));
+        }
+        // Items of this batch are all in the overlay
+        if index <= self.meta.head {
+            return Ok(None);
+        }
+
+        let fifo_queue_data = get_cf_handle(db, "fifo_queue_data")?;
+        match db
+            .get_cf(fifo_queue_data, item_key(queue_key, index))
+            .map_err(rocksdb_err_to_io)?
+        {
+            Some(bytes) => Ok(Some(deserialize::<QueueItem>(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Take the item at the head of the queue, deleting it from `fifo_queue_data`. Returns
+    /// `None`, still moving the head past it, if the item is missing. The caller checks that the
+    /// queue is not empty and writes the metadata.
+    pub fn pop(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+    ) -> Result<Option<QueueItem>, io::Error> {
+        let fifo_queue_data = get_cf_handle(db, "fifo_queue_data")?;
+
+        let index = self.meta.head + 1;
+        let item = match self.items.remove(&index) {
+            Some(item) => {
+                if let Some(undo) = &mut self.undo {
+                    undo.items.push((index, item.clone()));
+                }
+                Some(item)
+            }
+            None => self.item(db, queue_key, index)?,
+        };
+        batch.delete_cf(fifo_queue_data, item_key(queue_key, index));
+        self.meta.head = index;
+        if let Some(item) = &item {
+            self.meta.bytes = self.meta.bytes.saturating_sub(item.data.len() as u64);
+        }
+        self.popped = true;
+        Ok(item)
+    }
+
+    /// Delete up to `count` items from the head of the queue with a single range deletion. The
+    /// caller writes the metadata.
+    pub fn drop_head(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+        count: u64,
+    ) -> Result<u64, io::Error> {
+        let fifo_queue_data = get_cf_handle(db, "fifo_queue_data")?;
+
+        let dropped = count.min(self.length());
+        if dropped == 0 {
+            return Ok(0);
+        }
+
+        if dropped == self.length() {
+            self.meta.bytes = 0;
+        } else {
+            for index in self.meta.head + 1..=self.meta.head + dropped {
+                let size = self
+                    .item(db, queue_key, index)?
+                    .map_or(0, |item| item.data.len() as u64);
+                self.meta.bytes = self.meta.bytes.saturating_sub(size);
+            }
+        }
+        batch.delete_range_cf(
+            fifo_queue_data,
+            item_key(queue_key, self.meta.head + 1),
+            item_key(queue_key, self.meta.head + dropped + 1),
+        );
+        self.meta.head += dropped;
+        let kept = self.items.split_off(&(self.meta.head + 1));
+        let dropped_items = std::mem::replace(&mut self.items, kept);
+        if let Some(undo) = &mut self.undo {
+            undo.items.extend(dropped_items);
+        }
+        self.popped = true;
+        Ok(dropped)
+    }
+
+    /// Drop the items at the head of the queue that have outlived the queue's message TTL at
+    /// `now_ms`. Items expire in order, so the scan stops at the first live one. The caller
+    /// writes the metadata.
+    pub fn drop_expired(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+        now_ms: u64,
+    ) -> Result<u64, io::Error> {
+        let Some(ttl_ms) = self.config.message_ttl_ms else {
+            return Ok(0);
+        };
+
+        let mut expired = 0;
+        while expired < self.length() {
+            // Missing items are gone already and are dropped with the expired ones
+            if let Some(item) = self.item(db, queue_key, self.meta.head + expired + 1)? {
+                // Items from before enqueue times were recorded never expire
+                if item.enqueued_ms == 0 || item.enqueued_ms.saturating_add(ttl_ms) > now_ms {
+                    break;
+                }
+            }
+            expired += 1;
+        }
+        self.drop_head(db, batch, queue_key, expired)
+    }
+
+    /// In-flight item at `index`, checking the pending state before the database.
+    pub fn inflight_item(
+        &self,
+        db: &DB,
+        queue_key: &[u8],
+        index: u64,
+    ) -> Result<Option<InFlightItem>, io::Error> {
+        if let Some(pending) = self.inflight.get(&index) {
+            return Ok(pending.clone());
+        }
+
+        let fifo_inflight = get_cf_handle(db, "fifo_inflight")?;
+        match db
+            .get_cf(fifo_inflight, item_key(queue_key, index))
+            .map_err(rocksdb_err_to_io)?
+        {
+            Some(bytes) => Ok(Some(deserialize::<InFlightItem>(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Write `item` to the in-flight set and the deadline index. `previous_deadline_ms` is the
+    /// deadline of the entry it replaces, if any. The caller writes the metadata.
+    pub fn put_inflight(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+        previous_deadline_ms: Option<u64>,
+        item: InFlightItem,
+    ) -> Result<(), io::Error> {
+        let fifo_inflight = get_cf_handle(db, "fifo_inflight")?;
+        let fifo_inflight_deadlines = get_cf_handle(db, "fifo_inflight_deadlines")?;
+
+        match previous_deadline_ms {
+            Some(previous_deadline_ms) => batch.delete_cf(
+                fifo_inflight_deadlines,
+                inflight_deadline_key(queue_key, previous_deadline_ms, item.index),
+            ),
+            None => self.meta.inflight += 1,
+        }
+        batch.put_cf(
+            fifo_inflight_deadlines,
+            inflight_deadline_key(queue_key, item.deadline_ms, item.index),
+            [],
+        );
+        batch.put_cf(
+            fifo_inflight,
+            item_key(queue_key, item.index),
+            serialize(&item)?,
+        );
+        self.inflight.insert(item.index, Some(item));
+        Ok(())
+    }
+
+    /// Delete `item` from the in-flight set and the deadline index. The caller writes the
+    /// metadata.
+    pub fn remove_inflight(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        queue_key: &[u8],
+        item: &InFlightItem,
+    ) -> Result<(), io::Error> {
+        let fifo_inflight = get_cf_handle(db, "fifo_inflight")?;
+        let fifo_inflight_deadlines = get_cf_handle(db, "fifo_inflight_deadlines")?;
+
+        batch.delete_cf(
+            fifo_inflight_deadlines,
+            inflight_deadline_key(queue_key, item.deadline_ms, item.index),
+        );
+        batch.delete_cf(fifo_inflight, item_key(queue_key, item.index));
+        self.inflight.insert(item.index, None);
+        self.meta.inflight = self.meta.inflight.saturating_sub(1);
+        Ok(())
+    }
+
+    /// In-flight items of the queue whose deadline is at or before `now_ms`, oldest deadline
+    /// first, including the pending ones. Only the expired prefix of the deadline index is read.
+    pub fn expired_inflight_items(
+        &self,
+        db: &DB,
+        queue_key: &[u8],
+        now_ms: u64,
+    ) -> Result<Vec<InFlightItem>, io::Error> {
+        let fifo_inflight_deadlines = get_cf_handle(db, "fifo_inflight_deadlines")?;
+
+        let mut expired = BTreeMap::new();
+        for entry in db.iterator_cf(
+            fifo_inflight_deadlines,
+            rocksdb::IteratorMode::From(queue_key, rocksdb::Direction::Forward),
+        ) {
+            let (key, _) = entry.map_err(rocksdb_err_to_io)?;
+            if !key.starts_with(queue_key) {
+                break;
+            }
+            // Skip the entries of other queues whose key starts with this queue's key
+            if key.len() != queue_key.len() + 16 {
+                continue;
+            }
+            let suffix = &key[queue_key.len()..];
+            let deadline_ms = u64::from_be_bytes(suffix[..8].try_into().unwrap());
+            let index = u64::from_be_bytes(suffix[8..].try_into().unwrap());
+            if deadline_ms > now_ms {
+                break;
+            }
+            // Items changed in this batch are taken from the pending state below
+            if self.inflight.contains_key(&index) {
+                continue;
+            }
+            if let Some(item) = self.inflight_item(db, queue_key, index)? {
+                expired.insert((deadline_ms, index), item);
+            }
+        }
+        for (index, pending) in &self.inflight {
+            if let Some(item) = pending {
+                if item.deadline_ms <= now_ms {
+                    expired.insert((item.deadline_ms, *index), item.clone());
+                }
+            }
+        }
+        Ok(expired.into_values().collect())
+    }
+
+    /// Scheduled items of the queue due at or before `due_by_ms`, in due-time order, including
+    /// the pending ones.
+    pub fn delayed_items(
+        &self,
+        db: &DB,
+        queue_key: &[u8],
+        due_by_ms: u64,
+    ) -> Result<Vec<DelayedItem>, io::Error> {
+        let fifo_delayed = get_cf_handle(db, "fifo_delayed")?;
+
+        let mut items = BTreeMap::new();
+        for item in db.iterator_cf(
+            fifo_delayed,
+            rocksdb::IteratorMode::From(queue_key, rocksdb::Direction::Forward),
+        ) {
+            let (key, value) = item.map_err(rocksdb_err_to_io)?;
+            if !key.starts_with(queue_key) {
+                break;
+            }
+            // Skip the items of other queues whose key starts with this queue's key
+            if key.len() != queue_key.len() + 16 {
+                continue;
+            }
+            let item = deserialize::<DelayedItem>(&value)?;
+            if item.deliver_at_ms > due_by_ms {
+                break;
+            }
+            items.insert((item.deliver_at_ms, item.seq), item);
+        }
+        for (due, pending) in self.delayed.range(..=(due_by_ms, u64::MAX)) {
+            match pending {
+                Some(item) => items.insert(*due, item.clone()),
+                None => items.remove(due),
+            };
+        }
+        Ok(items.into_values().collect())
+    }
+
+    /// Every in-flight item of the queue in index order, including the pending ones.
+    pub fn inflight_items(
+        &self,
+        db: &DB,
+        queue_key: &[u8],
+    ) -> Result<Vec<InFlightItem>, io::Error> {
+        let fifo_inflight = get_cf_handle(db, "fifo_inflight")?;
+
+        let mut items = BTreeMap::new();
+        for item in db.iterator_cf(
+            fifo_inflight,
+            rocksdb::IteratorMode::From(queue_key, rocksdb::Direction::Forward),
+        ) {
+            let (key, value) = item.map_err(rocksdb_err_to_io)?;
+            if !key.starts_with(queue_key) {
+                break;
+            }
+            // Skip the items of other queues whose key starts with this queue's key
+            if key.len() != queue_key.len() + 8 {
+                continue;
+            }
+            let item = deserialize::<InFlightItem>(&value)?;
+            items.insert(item.index, item);
+        }
+        for (index, pending) in &self.inflight {
+            match pending {
+                Some(item) => items.insert(*index, item.clone()),
+                None => items.remove(index),
+            };
+        }
+        Ok(items.into_values().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn notify_wakes_the_futures_created_before_it() {
+        let notifier = QueueNotifier::default();
+        let notify = notifier.subscribe(b"q");
+        let notified = notify.notified();
+        let other_queue = notifier.subscribe(b"other");
+        let not_notified = other_queue.notified();
+
+        notifier.notify(b"q");
+        assert!(notified.now_or_never().is_some());
+        assert!(not_notified.now_or_never().is_none());
+    }
+
+    #[test]
+    fn notify_forgets_queues_nobody_waits_on() {
+        let notifier = QueueNotifier::default();
+        drop(notifier.subscribe(b"q"));
+        notifier.notify(b"q");
+        notifier.notify(b"never-subscribed");
+        assert!(notifier.waiters.lock().unwrap().is_empty());
+    }
 }
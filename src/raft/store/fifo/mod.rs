@@ -1,18 +1,41 @@
+pub mod operation_ack;
+pub mod operation_configure;
+pub mod operation_create_queue;
+pub mod operation_delete_queue;
 pub mod operation_dequeue;
 pub mod operation_enqueue;
+pub mod operation_move;
+pub mod operation_priority_delete_queue;
+pub mod operation_priority_dequeue;
+pub mod operation_priority_enqueue;
+pub mod operation_priority_purge;
+pub mod operation_priority_update_queue;
+pub mod operation_purge;
+pub mod operation_reserve;
+pub mod operation_update_queue;
 
 pub mod common;
 
 use std::fmt;
+use std::sync::Arc;
 
+use rocksdb::DB;
 use serde::{Deserialize, Serialize};
 
-use crate::raft::store::fifo::common::QueueMeta;
+use crate::raft::store::fifo::common::{DropPolicy, FIFOOverlay, QueueConfig, QueueMeta};
 
+/// Append values to the queue, or schedule them for later delivery. A delay is added to the
+/// leader time of the entry, so the due time is fixed when the entry is committed.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FIFOEnqueue {
     pub queue_key: Vec<u8>,
     pub values: Vec<Vec<u8>>,
+    /// Deliver at this time, in milliseconds since the Unix epoch
+    #[serde(default)]
+    pub deliver_at_ms: Option<u64>,
+    /// Deliver this many milliseconds after the leader received the enqueue
+    #[serde(default)]
+    pub delay_ms: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -21,21 +44,352 @@ pub struct FIFODequeue {
     pub count: usize,
 }
 
+/// Hand out up to `count` items, keeping them in flight until they are acknowledged. Items
+/// whose visibility timeout expired are delivered again before new ones.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FIFOReserve {
+    pub queue_key: Vec<u8>,
+    pub count: usize,
+    pub visibility_timeout_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FIFOAck {
+    pub queue_key: Vec<u8>,
+    pub handles: Vec<ReceiptHandle>,
+}
+
+/// Set the dead-letter policy of the queue, keeping its other settings. `None` clears a field.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FIFOConfigure {
+    pub queue_key: Vec<u8>,
+    pub max_deliveries: Option<u32>,
+    pub dead_letter_queue: Option<Vec<u8>>,
+}
+
+/// Create an empty queue with the given limits, unless it already exists
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FIFOCreateQueue {
+    pub queue_key: Vec<u8>,
+    pub config: QueueConfig,
+}
+
+/// Replace the limits, message TTL and drop policy of a queue, creating it if needed. The
+/// dead-letter policy is left to `FIFOConfigure`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FIFOUpdateQueue {
+    pub queue_key: Vec<u8>,
+    pub max_length: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub message_ttl_ms: Option<u64>,
+    pub drop_policy: DropPolicy,
+}
+
+/// Remove the queue with its items, in-flight items, metadata and config
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FIFODeleteQueue {
+    pub queue_key: Vec<u8>,
+}
+
+/// Move up to `count` items, or all of them, from the head of one queue to the tail of another
+/// in a single entry, so that no item is lost or duplicated in between
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FIFOMove {
+    pub queue_key: Vec<u8>,
+    pub target_key: Vec<u8>,
+    pub count: Option<usize>,
+    /// Include the moved items in the response, not just their number
+    #[serde(default)]
+    pub return_items: bool,
+    /// Append to the target queue even beyond its limits
+    #[serde(default)]
+    pub ignore_limits: bool,
+}
+
+/// Delete every queued item. In-flight items are left alone.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FIFOPurge {
+    pub queue_key: Vec<u8>,
+}
+
+/// Add `(priority, value)` items to a priority queue
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FIFOPriorityEnqueue {
+    pub queue_key: Vec<u8>,
+    pub items: Vec<(u32, Vec<u8>)>,
+}
+
+/// Remove up to `count` items from a priority queue, highest priority first and in enqueue
+/// order within a priority
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FIFOPriorityDequeue {
+    pub queue_key: Vec<u8>,
+    pub count: usize,
+}
+
+/// Identifies one delivery of a reserved item. A handle from an earlier delivery no longer
+/// acknowledges the item once it has been delivered again.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReceiptHandle {
+    pub(crate) index: u64,
+    pub(crate) delivery: u32,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum FIFOOperation {
     Enqueue(FIFOEnqueue),
+    /// Remove items for good; consumers that crash before processing them lose them
     Dequeue(FIFODequeue),
+    Reserve(FIFOReserve),
+    Ack(FIFOAck),
+    Configure(FIFOConfigure),
+    Move(FIFOMove),
+    Purge(FIFOPurge),
+    CreateQueue(FIFOCreateQueue),
+    DeleteQueue(FIFODeleteQueue),
+    PriorityEnqueue(FIFOPriorityEnqueue),
+    PriorityDequeue(FIFOPriorityDequeue),
+    UpdateQueue(FIFOUpdateQueue),
+    /// `UpdateQueue` for a priority queue
+    PriorityUpdateQueue(FIFOUpdateQueue),
+    /// `Purge` for a priority queue
+    PriorityPurge(FIFOPurge),
+    /// `DeleteQueue` for a priority queue
+    PriorityDeleteQueue(FIFODeleteQueue),
 }
 
 impl fmt::Display for FIFOOperation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self {
-            FIFOOperation::Enqueue(FIFOEnqueue { values, .. }) => {
-                write!(f, "Enqueue {{ values: Vec<Vec<u8>>[{}] }}", values.len(),)
+            FIFOOperation::Enqueue(FIFOEnqueue {
+                values,
+                deliver_at_ms,
+                delay_ms,
+                ..
+            }) => {
+                write!(
+                    f,
+                    "Enqueue {{ values: Vec<Vec<u8>>[{}], deliver_at_ms: {:?}, delay_ms: {:?} }}",
+                    values.len(),
+                    deliver_at_ms,
+                    delay_ms
+                )
             }
             FIFOOperation::Dequeue(FIFODequeue { count, .. }) => {
                 write!(f, "Dequeue {{ count: {} }}", count)
             }
+            FIFOOperation::Reserve(FIFOReserve {
+                count,
+                visibility_timeout_ms,
+                ..
+            }) => {
+                write!(
+                    f,
+                    "Reserve {{ count: {}, visibility_timeout_ms: {} }}",
+                    count, visibility_timeout_ms
+                )
+            }
+            FIFOOperation::Ack(FIFOAck { handles, .. }) => {
+                write!(
+                    f,
+                    "Ack {{ handles: Vec<ReceiptHandle>[{}] }}",
+                    handles.len()
+                )
+            }
+            FIFOOperation::Configure(FIFOConfigure { max_deliveries, .. }) => {
+                write!(f, "Configure {{ max_deliveries: {:?} }}", max_deliveries)
+            }
+            FIFOOperation::Move(FIFOMove { count, .. }) => {
+                write!(f, "Move {{ count: {:?} }}", count)
+            }
+            FIFOOperation::Purge(_) => write!(f, "Purge"),
+            FIFOOperation::CreateQueue(FIFOCreateQueue { config, .. }) => {
+                write!(
+                    f,
+                    "CreateQueue {{ max_length: {:?}, max_bytes: {:?}, message_ttl_ms: {:?}, drop_policy: {:?} }}",
+                    config.max_length, config.max_bytes, config.message_ttl_ms, config.drop_policy
+                )
+            }
+            FIFOOperation::DeleteQueue(_) => write!(f, "DeleteQueue"),
+            FIFOOperation::PriorityEnqueue(FIFOPriorityEnqueue { items, .. }) => {
+                write!(
+                    f,
+                    "PriorityEnqueue {{ items: Vec<(u32, Vec<u8>)>[{}] }}",
+                    items.len()
+                )
+            }
+            FIFOOperation::PriorityDequeue(FIFOPriorityDequeue { count, .. }) => {
+                write!(f, "PriorityDequeue {{ count: {} }}", count)
+            }
+            FIFOOperation::UpdateQueue(FIFOUpdateQueue {
+                max_length,
+                max_bytes,
+                message_ttl_ms,
+                drop_policy,
+                ..
+            }) => {
+                write!(
+                    f,
+                    "UpdateQueue {{ max_length: {:?}, max_bytes: {:?}, message_ttl_ms: {:?}, drop_policy: {:?} }}",
+                    max_length, max_bytes, message_ttl_ms, drop_policy
+                )
+            }
+            FIFOOperation::PriorityUpdateQueue(FIFOUpdateQueue {
+                max_length,
+                max_bytes,
+                message_ttl_ms,
+                drop_policy,
+                ..
+            }) => {
+                write!(
+                    f,
+                    "PriorityUpdateQueue {{ max_length: {:?}, max_bytes: {:?}, message_ttl_ms: {:?}, drop_policy: {:?} }}",
+                    max_length, max_bytes, message_ttl_ms, drop_policy
+                )
+            }
+            FIFOOperation::PriorityPurge(_) => write!(f, "PriorityPurge"),
+            FIFOOperation::PriorityDeleteQueue(_) => write!(f, "PriorityDeleteQueue"),
+        }
+    }
+}
+
+/// Apply a single FIFO operation to the state machine batch. `now_ms` is the leader time of the
+/// entry.
+pub fn apply_fifo_operation(
+    op: FIFOOperation,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    now_ms: u64,
+    pending_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    match op {
+        FIFOOperation::Enqueue(enqueue_op) => operation_enqueue::operation_enqueue(
+            enqueue_op,
+            db,
+            client_id,
+            seq_id,
+            now_ms,
+            pending_state,
+            batch,
+        ),
+        FIFOOperation::Dequeue(dequeue_op) => operation_dequeue::operation_dequeue(
+            dequeue_op,
+            db,
+            client_id,
+            seq_id,
+            now_ms,
+            pending_state,
+            batch,
+        ),
+        FIFOOperation::Reserve(reserve_op) => operation_reserve::operation_reserve(
+            reserve_op,
+            db,
+            client_id,
+            seq_id,
+            now_ms,
+            pending_state,
+            batch,
+        ),
+        FIFOOperation::Ack(ack_op) => {
+            operation_ack::operation_ack(ack_op, db, client_id, seq_id, pending_state, batch)
+        }
+        FIFOOperation::Configure(configure_op) => operation_configure::operation_configure(
+            configure_op,
+            db,
+            client_id,
+            seq_id,
+            pending_state,
+            batch,
+        ),
+        FIFOOperation::Move(move_op) => operation_move::operation_move(
+            move_op,
+            db,
+            client_id,
+            seq_id,
+            now_ms,
+            pending_state,
+            batch,
+        ),
+        FIFOOperation::Purge(purge_op) => {
+            operation_purge::operation_purge(purge_op, db, client_id, seq_id, pending_state, batch)
+        }
+        FIFOOperation::CreateQueue(create_op) => operation_create_queue::operation_create_queue(
+            create_op,
+            db,
+            client_id,
+            seq_id,
+            pending_state,
+            batch,
+        ),
+        FIFOOperation::DeleteQueue(delete_op) => operation_delete_queue::operation_delete_queue(
+            delete_op,
+            db,
+            client_id,
+            seq_id,
+            pending_state,
+            batch,
+        ),
+        FIFOOperation::PriorityEnqueue(enqueue_op) => {
+            operation_priority_enqueue::operation_priority_enqueue(
+                enqueue_op,
+                db,
+                client_id,
+                seq_id,
+                now_ms,
+                pending_state,
+                batch,
+            )
+        }
+        FIFOOperation::PriorityDequeue(dequeue_op) => {
+            operation_priority_dequeue::operation_priority_dequeue(
+                dequeue_op,
+                db,
+                client_id,
+                seq_id,
+                now_ms,
+                pending_state,
+                batch,
+            )
+        }
+        FIFOOperation::UpdateQueue(update_op) => operation_update_queue::operation_update_queue(
+            update_op,
+            db,
+            client_id,
+            seq_id,
+            pending_state,
+            batch,
+        ),
+        FIFOOperation::PriorityUpdateQueue(update_op) => {
+            operation_priority_update_queue::operation_priority_update_queue(
+                update_op,
+                db,
+                client_id,
+                seq_id,
+                pending_state,
+                batch,
+            )
+        }
+        FIFOOperation::PriorityPurge(purge_op) => {
+            operation_priority_purge::operation_priority_purge(
+                purge_op,
+                db,
+                client_id,
+                seq_id,
+                pending_state,
+                batch,
+            )
+        }
+        FIFOOperation::PriorityDeleteQueue(delete_op) => {
+            operation_priority_delete_queue::operation_priority_delete_queue(
+                delete_op,
+                db,
+                client_id,
+                seq_id,
+                pending_state,
+                batch,
+            )
         }
     }
 }
@@ -50,8 +404,68 @@ pub struct DequeueResponse {
     pub items: Vec<Vec<u8>>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReservedItem {
+    pub handle: ReceiptHandle,
+    pub data: Vec<u8>,
+    pub deliveries: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReserveResponse {
+    pub queue_meta: QueueMeta,
+    pub items: Vec<ReservedItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AckResponse {
+    /// Number of handles that acknowledged an in-flight item
+    pub acked: usize,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum FIFOResponse {
     Enqueue(EnqueueResponse),
     Dequeue(DequeueResponse),
+    Reserve(ReserveResponse),
+    Ack(AckResponse),
+    Configure,
+    /// Number of items moved to the target queue, and the items themselves if requested
+    Move {
+        moved: u64,
+        items: Vec<Vec<u8>>,
+    },
+    /// Number of items deleted
+    Purge {
+        purged: u64,
+    },
+    /// False if the queue already existed and was left unchanged
+    CreateQueue {
+        created: bool,
+    },
+    DeleteQueue {
+        existed: bool,
+    },
+    /// Number of items in the priority queue after the enqueue
+    PriorityEnqueue {
+        length: u64,
+    },
+    /// Dequeued `(priority, value)` items
+    PriorityDequeue {
+        items: Vec<(u32, Vec<u8>)>,
+    },
+    /// The enqueue or move was refused because the items do not fit within the queue's limits. With
+    /// `block`, the producer waits for room and retries. `length` and `bytes` include the
+    /// scheduled items.
+    QueueFull {
+        length: u64,
+        bytes: u64,
+        max_length: Option<u64>,
+        max_bytes: Option<u64>,
+        block: bool,
+    },
+    /// False if the queue did not exist and was created
+    UpdateQueue {
+        existed: bool,
+    },
 }
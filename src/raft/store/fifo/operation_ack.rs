@@ -0,0 +1,49 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    Response, ResponseResult,
+    store::{
+        common::{get_cf_handle, serialize},
+        fifo::{AckResponse, FIFOAck, FIFOResponse, common::FIFOOverlay},
+    },
+};
+
+/// Delete the in-flight items of the given receipt handles. Handles of items that were already
+/// acknowledged, or delivered again since, are ignored.
+pub fn operation_ack(
+    op: FIFOAck,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let key_bytes = op.queue_key.clone();
+    let fifo_queue_meta = get_cf_handle(&db, "fifo_queue_meta")?;
+
+    let overlay_queue = pending_state.queue(&db, &key_bytes)?;
+
+    let mut acked = 0;
+    for handle in op.handles {
+        let current = overlay_queue.inflight_item(&db, &key_bytes, handle.index)?;
+        if let Some(item) = current.filter(|item| item.deliveries == handle.delivery) {
+            overlay_queue.remove_inflight(&db, batch, &key_bytes, &item)?;
+            acked += 1;
+        }
+    }
+    if acked > 0 {
+        batch.put_cf(
+            fifo_queue_meta,
+            &key_bytes,
+            &serialize(&overlay_queue.meta)?,
+        );
+    }
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::FIFO(FIFOResponse::Ack(AckResponse { acked })),
+    })
+}
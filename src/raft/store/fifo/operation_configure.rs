@@ -0,0 +1,151 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    Response, ResponseResult,
+    store::{
+        common::{get_cf_handle, serialize},
+        fifo::{FIFOConfigure, FIFOResponse, common::FIFOOverlay},
+    },
+};
+
+/// Replace the dead-letter policy of the queue, clearing the fields that are `None`. It applies
+/// from the next reservation on.
+pub fn operation_configure(
+    op: FIFOConfigure,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let fifo_queue_config = get_cf_handle(&db, "fifo_queue_config")?;
+
+    let overlay_queue = pending_state.queue(&db, &op.queue_key)?;
+    overlay_queue.config.max_deliveries = op.max_deliveries;
+    overlay_queue.config.dead_letter_queue = op.dead_letter_queue;
+    batch.put_cf(
+        fifo_queue_config,
+        &op.queue_key,
+        serialize(&overlay_queue.config)?,
+    );
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::FIFO(FIFOResponse::Configure),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::fifo::common::DropPolicy;
+    use crate::raft::store::fifo::{FIFOOperation, FIFOReserve, FIFOUpdateQueue};
+    use crate::raft::store::test_util::{TestBatch, TestDb, enqueue};
+
+    fn configure(batch: &mut TestBatch, max_deliveries: Option<u32>, dead_letter_queue: bool) {
+        let op = FIFOOperation::Configure(FIFOConfigure {
+            queue_key: b"q".to_vec(),
+            max_deliveries,
+            dead_letter_queue: dead_letter_queue.then(|| b"dlq".to_vec()),
+        });
+        batch.fifo(op, 0);
+    }
+
+    /// Reserve one item of `q` with a visibility timeout of 100ms, returning its delivery count
+    fn reserve(batch: &mut TestBatch, now_ms: u64) -> Option<u32> {
+        let op = FIFOOperation::Reserve(FIFOReserve {
+            queue_key: b"q".to_vec(),
+            count: 1,
+            visibility_timeout_ms: 100,
+        });
+        match batch.fifo(op, now_ms) {
+            ResponseResult::FIFO(FIFOResponse::Reserve(response)) => {
+                response.items.first().map(|item| item.deliveries)
+            }
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    fn limit_dead_letter_queue(batch: &mut TestBatch, drop_policy: DropPolicy) {
+        let op = FIFOOperation::UpdateQueue(FIFOUpdateQueue {
+            queue_key: b"dlq".to_vec(),
+            max_length: Some(1),
+            max_bytes: None,
+            message_ttl_ms: None,
+            drop_policy,
+        });
+        batch.fifo(op, 0);
+    }
+
+    #[tokio::test]
+    async fn items_out_of_deliveries_are_dead_lettered_by_the_next_reservation() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.fifo(enqueue(b"q", &[b"a"]), 0);
+        configure(&mut batch, Some(2), true);
+        assert_eq!(reserve(&mut batch, 0), Some(1));
+        assert_eq!(reserve(&mut batch, 100), Some(2));
+        // Still in flight after its last timeout until a reservation finds it expired
+        assert_eq!(
+            batch.fifo.queue(&test_db.db(), b"q").unwrap().meta.inflight,
+            1
+        );
+        assert_eq!(reserve(&mut batch, 200), None);
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        assert_eq!(
+            sm.peek_queue(b"dlq".to_vec(), 10).await.unwrap(),
+            vec![b"a".to_vec()]
+        );
+        assert_eq!(sm.queue_stats(b"q".to_vec()).await.unwrap().in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn dead_letters_respect_the_limits_of_the_dead_letter_queue() {
+        for (drop_policy, expected) in [
+            (DropPolicy::Reject, b"old"),
+            (DropPolicy::Block, b"old"),
+            (DropPolicy::DropOldest, b"new"),
+        ] {
+            let test_db = TestDb::new();
+            let mut batch = test_db.batch();
+            limit_dead_letter_queue(&mut batch, drop_policy);
+            batch.fifo(enqueue(b"dlq", &[b"old"]), 0);
+            batch.fifo(enqueue(b"q", &[b"new"]), 0);
+            configure(&mut batch, Some(1), true);
+            batch.write();
+
+            let mut batch = test_db.batch();
+            assert_eq!(reserve(&mut batch, 0), Some(1));
+            assert_eq!(reserve(&mut batch, 100), None);
+            batch.write();
+
+            let sm = test_db.state_machine().await;
+            assert_eq!(
+                sm.peek_queue(b"dlq".to_vec(), 10).await.unwrap(),
+                vec![expected.to_vec()],
+                "{drop_policy:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn without_a_dead_letter_queue_items_are_dropped_and_clearing_the_policy_redelivers() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.fifo(enqueue(b"q", &[b"a", b"b"]), 0);
+        configure(&mut batch, Some(1), false);
+        assert_eq!(reserve(&mut batch, 0), Some(1));
+        assert_eq!(reserve(&mut batch, 100), Some(1));
+        let overlay_queue = batch.fifo.queue(&test_db.db(), b"q").unwrap();
+        assert_eq!(overlay_queue.meta.inflight, 1);
+
+        configure(&mut batch, None, false);
+        assert_eq!(reserve(&mut batch, 200), Some(2));
+        assert_eq!(reserve(&mut batch, 300), Some(3));
+    }
+}
@@ -0,0 +1,116 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    Response, ResponseResult,
+    store::{
+        common::{get_cf_handle, serialize},
+        fifo::{FIFOCreateQueue, FIFOResponse, common::FIFOOverlay},
+    },
+};
+
+/// Write the metadata and config of a new, empty queue. An existing queue, including one that
+/// came into existence through an enqueue, keeps its items and config.
+pub fn operation_create_queue(
+    op: FIFOCreateQueue,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let fifo_queue_meta = get_cf_handle(&db, "fifo_queue_meta")?;
+    let fifo_queue_config = get_cf_handle(&db, "fifo_queue_config")?;
+
+    let overlay_queue = pending_state.queue(&db, &op.queue_key)?;
+    let created = !overlay_queue.exists;
+    if created {
+        batch.put_cf(
+            fifo_queue_meta,
+            &op.queue_key,
+            serialize(&overlay_queue.meta)?,
+        );
+        batch.put_cf(fifo_queue_config, &op.queue_key, serialize(&op.config)?);
+        overlay_queue.config = op.config;
+        overlay_queue.exists = true;
+    }
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::FIFO(FIFOResponse::CreateQueue { created }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::fifo::FIFOOperation;
+    use crate::raft::store::fifo::common::QueueConfig;
+    use crate::raft::store::test_util::{TestBatch, TestDb, enqueue};
+
+    fn create(batch: &mut TestBatch, queue_key: &[u8], config: QueueConfig) -> bool {
+        let op = FIFOOperation::CreateQueue(FIFOCreateQueue {
+            queue_key: queue_key.to_vec(),
+            config,
+        });
+        match batch.fifo(op, 0) {
+            ResponseResult::FIFO(FIFOResponse::CreateQueue { created }) => created,
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    fn max_length(max_length: u64) -> QueueConfig {
+        QueueConfig {
+            max_length: Some(max_length),
+            ..QueueConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn existing_queues_keep_their_items_and_config() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        assert!(create(&mut batch, b"q", max_length(1)));
+        assert!(!create(&mut batch, b"q", max_length(5)));
+        batch.fifo(enqueue(b"q", &[b"a"]), 0);
+        assert!(matches!(
+            batch.fifo(enqueue(b"q", &[b"b"]), 0),
+            ResponseResult::FIFO(FIFOResponse::QueueFull { .. })
+        ));
+
+        batch.fifo(enqueue(b"implicit", &[b"a"]), 0);
+        assert!(!create(&mut batch, b"implicit", max_length(0)));
+        batch.write();
+
+        let mut batch = test_db.batch();
+        assert!(!create(&mut batch, b"q", max_length(5)));
+        assert_eq!(batch.dequeue(b"implicit", 10, 0), vec![b"a".to_vec()]);
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        assert_eq!(
+            sm.list_queues().await.unwrap(),
+            vec![b"implicit".to_vec(), b"q".to_vec()]
+        );
+        assert_eq!(sm.queue_stats(b"q".to_vec()).await.unwrap().length, 1);
+    }
+
+    #[test]
+    fn items_expire_after_the_message_ttl() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        let config = QueueConfig {
+            message_ttl_ms: Some(100),
+            ..QueueConfig::default()
+        };
+        create(&mut batch, b"q", config);
+        batch.fifo(enqueue(b"q", &[b"a"]), 1000);
+        batch.fifo(enqueue(b"q", &[b"b"]), 1050);
+        batch.write();
+
+        let mut batch = test_db.batch();
+        assert_eq!(batch.dequeue(b"q", 10, 1100), vec![b"b".to_vec()]);
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    Response, ResponseResult,
+    store::{
+        common::get_cf_handle,
+        fifo::{
+            FIFODeleteQueue, FIFOResponse,
+            common::{FIFOOverlay, QueueConfig, QueueMeta, delayed_key},
+        },
+    },
+};
+
+/// Delete the queued items with a single range deletion, then the in-flight and scheduled items,
+/// metadata and config. Handles of the deleted in-flight items no longer acknowledge anything, and a later
+/// enqueue starts a fresh queue with default settings.
+pub fn operation_delete_queue(
+    op: FIFODeleteQueue,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let key_bytes = op.queue_key.clone();
+    let fifo_queue_meta = get_cf_handle(&db, "fifo_queue_meta")?;
+    let fifo_queue_config = get_cf_handle(&db, "fifo_queue_config")?;
+    let fifo_delayed = get_cf_handle(&db, "fifo_delayed")?;
+
+    let overlay_queue = pending_state.queue(&db, &key_bytes)?;
+    let existed = overlay_queue.exists;
+
+    let length = overlay_queue.length();
+    overlay_queue.drop_head(&db, batch, &key_bytes, length)?;
+
+    // In-flight and scheduled keys of other queues can share this queue's prefix, so no range
+    // deletion here
+    for item in overlay_queue.inflight_items(&db, &key_bytes)? {
+        overlay_queue.remove_inflight(&db, batch, &key_bytes, &item)?;
+    }
+    for item in overlay_queue.delayed_items(&db, &key_bytes, u64::MAX)? {
+        batch.delete_cf(
+            fifo_delayed,
+            delayed_key(&key_bytes, item.deliver_at_ms, item.seq),
+        );
+        overlay_queue
+            .delayed
+            .insert((item.deliver_at_ms, item.seq), None);
+    }
+
+    batch.delete_cf(fifo_queue_meta, &key_bytes);
+    batch.delete_cf(fifo_queue_config, &key_bytes);
+    overlay_queue.meta = QueueMeta::default();
+    overlay_queue.config = QueueConfig::default();
+    overlay_queue.exists = false;
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::FIFO(FIFOResponse::DeleteQueue { existed }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::fifo::common::DropPolicy;
+    use crate::raft::store::fifo::{
+        FIFOAck, FIFOEnqueue, FIFOOperation, FIFOReserve, FIFOUpdateQueue,
+    };
+    use crate::raft::store::test_util::{TestBatch, TestDb, enqueue};
+
+    fn delete(batch: &mut TestBatch, queue_key: &[u8]) -> bool {
+        let op = FIFOOperation::DeleteQueue(FIFODeleteQueue {
+            queue_key: queue_key.to_vec(),
+        });
+        match batch.fifo(op, 0) {
+            ResponseResult::FIFO(FIFOResponse::DeleteQueue { existed }) => existed,
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    /// Fill `queue_key` with a waiting, an in-flight and a scheduled item, limited to 3 items
+    fn fill(batch: &mut TestBatch, queue_key: &[u8]) -> FIFOOperation {
+        let update = FIFOOperation::UpdateQueue(FIFOUpdateQueue {
+            queue_key: queue_key.to_vec(),
+            max_length: Some(3),
+            max_bytes: None,
+            message_ttl_ms: None,
+            drop_policy: DropPolicy::Reject,
+        });
+        batch.fifo(update, 0);
+        batch.fifo(enqueue(queue_key, &[b"a", b"b"]), 0);
+        let scheduled = FIFOOperation::Enqueue(FIFOEnqueue {
+            queue_key: queue_key.to_vec(),
+            values: vec![b"c".to_vec()],
+            deliver_at_ms: Some(1000),
+            delay_ms: None,
+        });
+        batch.fifo(scheduled, 0);
+        let reserve = FIFOOperation::Reserve(FIFOReserve {
+            queue_key: queue_key.to_vec(),
+            count: 1,
+            visibility_timeout_ms: 100,
+        });
+        let handle = match batch.fifo(reserve, 0) {
+            ResponseResult::FIFO(FIFOResponse::Reserve(response)) => response.items[0].handle,
+            res => panic!("unexpected response {res:?}"),
+        };
+        FIFOOperation::Ack(FIFOAck {
+            queue_key: queue_key.to_vec(),
+            handles: vec![handle],
+        })
+    }
+
+    #[tokio::test]
+    async fn delete_removes_every_item_and_the_config() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        let ack = fill(&mut batch, b"q");
+        // Keys of this queue start with the deleted queue's key
+        fill(&mut batch, b"qq");
+        batch.write();
+
+        let mut batch = test_db.batch();
+        assert!(delete(&mut batch, b"q"));
+        assert!(batch.dequeue(b"q", 10, 2000).is_empty());
+        match batch.fifo(ack, 0) {
+            ResponseResult::FIFO(FIFOResponse::Ack(response)) => assert_eq!(response.acked, 0),
+            res => panic!("unexpected response {res:?}"),
+        }
+        assert!(!delete(&mut batch, b"q"));
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        assert_eq!(sm.list_queues().await.unwrap(), vec![b"qq".to_vec()]);
+        let stats = sm.queue_stats(b"qq".to_vec()).await.unwrap();
+        assert_eq!((stats.length, stats.in_flight, stats.delayed), (1, 1, 1));
+        assert!(!sm.has_deliverable(b"q".to_vec(), true, 2000).await.unwrap());
+
+        // A later enqueue starts over without the old limits
+        let mut batch = test_db.batch();
+        batch.fifo(enqueue(b"q", &[b"1", b"2", b"3", b"4"]), 0);
+        assert_eq!(batch.dequeue(b"q", 1, 2000), vec![b"1".to_vec()]);
+        batch.write();
+        let stats = sm.queue_stats(b"q".to_vec()).await.unwrap();
+        assert_eq!((stats.length, stats.in_flight, stats.delayed), (3, 0, 0));
+        assert_eq!(stats.enqueued_total, 4);
+    }
+}
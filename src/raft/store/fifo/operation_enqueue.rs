@@ -5,68 +5,350 @@ use rocksdb::DB;
 use crate::raft::{
     Response, ResponseResult,
     store::{
-        common::{deserialize, get_cf_handle, rocksdb_err_to_io, serialize},
+        common::{get_cf_handle, serialize},
         fifo::{
             EnqueueResponse, FIFOEnqueue, FIFOResponse,
-            common::{FIFOOverlay, FIFOOverlayQueue, QueueMeta},
+            common::{DropPolicy, FIFOOverlay},
         },
     },
 };
 
+/// Append the values to the tail of the queue, or schedule them if they are not due yet at
+/// `now_ms`. Due scheduled items are promoted first, so they keep their place ahead of the new
+/// ones, and expired items are dropped, so they do not count against the limits. The limits are
+/// checked against the length and byte counts kept in `QueueMeta`, scheduled items included, so
+/// every replica reaches the same decision. A queue whose limits the new items
+/// would exceed rejects the whole enqueue, drops its oldest items, or asks the producer to wait,
+/// depending on its drop policy.
 pub fn operation_enqueue(
     op: FIFOEnqueue,
     db: Arc<DB>,
     client_id: u64,
     seq_id: Option<u64>,
+    now_ms: u64,
     pending_state: &mut FIFOOverlay,
     batch: &mut rocksdb::WriteBatchWithTransaction<false>,
 ) -> Result<crate::raft::Response, std::io::Error> {
     let key_bytes = op.queue_key.clone();
     let fifo_queue_meta = get_cf_handle(&db, "fifo_queue_meta")?;
-    let fifo_queue_data = get_cf_handle(&db, "fifo_queue_data")?;
-
-    let overlay_queue = pending_state
-        .meta
-        .entry(key_bytes.clone())
-        .or_insert_with(|| {
-            // Only load from DB if not already in overlay
-            let meta = match db.get_cf(&fifo_queue_meta, &key_bytes) {
-                Ok(Some(value_bytes)) => match deserialize(&value_bytes) {
-                    Ok(m) => m,
-                    Err(_) => QueueMeta { head: 0, tail: 0 },
-                },
-                _ => QueueMeta { head: 0, tail: 0 },
-            };
-            FIFOOverlayQueue {
-                meta,
-                items: vec![],
-            }
+
+    let deliver_at_ms = op
+        .deliver_at_ms
+        .or(op.delay_ms.map(|delay_ms| now_ms.saturating_add(delay_ms)))
+        .filter(|deliver_at_ms| *deliver_at_ms > now_ms);
+
+    pending_state.promote_due(&db, batch, &key_bytes, now_ms)?;
+
+    let overlay_queue = pending_state.queue(&db, &key_bytes)?;
+    if overlay_queue.drop_expired(&db, batch, &key_bytes, now_ms)? > 0 {
+        batch.put_cf(
+            fifo_queue_meta,
+            &key_bytes,
+            &serialize(&overlay_queue.meta)?,
+        );
+    }
+
+    let max_length = overlay_queue.config.max_length;
+    let max_bytes = overlay_queue.config.max_bytes;
+    let drop_policy = overlay_queue.config.drop_policy;
+
+    let new_length = op.values.len() as u64;
+    let new_bytes: u64 = op.values.iter().map(|value| value.len() as u64).sum();
+
+    if drop_policy != DropPolicy::DropOldest && !overlay_queue.fits(new_length, new_bytes) {
+        return Ok(Response::Result {
+            client_id,
+            seq_id,
+            res: ResponseResult::FIFO(FIFOResponse::QueueFull {
+                length: overlay_queue.held_length(),
+                bytes: overlay_queue.held_bytes(),
+                max_length,
+                max_bytes,
+                // Waiting only helps if the items fit into an empty queue
+                block: drop_policy == DropPolicy::Block
+                    && overlay_queue.config.fits(new_length, new_bytes),
+            }),
         });
+    }
 
-    for value in op.values.iter() {
-        overlay_queue.meta.tail += 1;
-        let item = crate::raft::store::fifo::common::QueueItem {
-            index: overlay_queue.meta.tail,
-            data: value.clone(),
-        };
-        let item_bytes = serialize(&item)?;
-        let mut item_key = key_bytes.clone();
-        item_key.extend_from_slice(&overlay_queue.meta.tail.to_be_bytes());
-        batch.put_cf(fifo_queue_data, &item_key, &item_bytes);
-        overlay_queue.items.push(item);
+    // Scheduled items are checked against the limits now, but only join the queue once due
+    if let Some(deliver_at_ms) = deliver_at_ms {
+        let queue_meta =
+            pending_state.schedule(&db, batch, &key_bytes, op.values, deliver_at_ms)?;
+        return Ok(Response::Result {
+            client_id,
+            seq_id,
+            res: ResponseResult::FIFO(FIFOResponse::Enqueue(EnqueueResponse { queue_meta })),
+        });
     }
 
-    batch.put_cf(
-        fifo_queue_meta,
-        &key_bytes,
-        &serialize(&overlay_queue.meta)?,
-    );
+    let mut queue_meta = pending_state.push(&db, batch, &key_bytes, op.values, now_ms)?;
+
+    if drop_policy == DropPolicy::DropOldest {
+        let overlay_queue = pending_state.queue(&db, &key_bytes)?;
+
+        if overlay_queue.drop_excess(&db, batch, &key_bytes)? > 0 {
+            batch.put_cf(
+                fifo_queue_meta,
+                &key_bytes,
+                &serialize(&overlay_queue.meta)?,
+            );
+            queue_meta = overlay_queue.meta;
+        }
+    }
 
     Ok(Response::Result {
         client_id: client_id,
         seq_id: seq_id,
-        res: ResponseResult::FIFO(FIFOResponse::Enqueue(EnqueueResponse {
-            queue_meta: overlay_queue.meta,
-        })),
+        res: ResponseResult::FIFO(FIFOResponse::Enqueue(EnqueueResponse { queue_meta })),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::fifo::common::item_key;
+    use crate::raft::store::fifo::{FIFOOperation, FIFOUpdateQueue};
+    use crate::raft::store::test_util::{TestBatch, TestDb, enqueue};
+
+    fn limit(
+        batch: &mut TestBatch,
+        max_length: Option<u64>,
+        max_bytes: Option<u64>,
+        drop_policy: DropPolicy,
+    ) {
+        let op = FIFOOperation::UpdateQueue(FIFOUpdateQueue {
+            queue_key: b"q".to_vec(),
+            max_length,
+            max_bytes,
+            message_ttl_ms: None,
+            drop_policy,
+        });
+        batch.fifo(op, 0);
+    }
+
+    /// `block` of the `QueueFull` response, or `None` if the enqueue was accepted
+    fn enqueue_blocked(batch: &mut TestBatch, values: &[&[u8]]) -> Option<bool> {
+        match batch.fifo(enqueue(b"q", values), 0) {
+            ResponseResult::FIFO(FIFOResponse::Enqueue(_)) => None,
+            ResponseResult::FIFO(FIFOResponse::QueueFull { block, .. }) => Some(block),
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn full_queues_reject_the_whole_enqueue() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        limit(&mut batch, Some(2), Some(5), DropPolicy::Reject);
+        batch.fifo(enqueue(b"q", &[b"a"]), 0);
+        match batch.fifo(enqueue(b"q", &[b"b", b"c"]), 0) {
+            ResponseResult::FIFO(FIFOResponse::QueueFull {
+                length,
+                bytes,
+                max_length,
+                max_bytes,
+                block,
+            }) => {
+                assert_eq!((length, bytes), (1, 1));
+                assert_eq!((max_length, max_bytes), (Some(2), Some(5)));
+                assert!(!block);
+            }
+            res => panic!("unexpected response {res:?}"),
+        }
+        assert_eq!(enqueue_blocked(&mut batch, &[b"bbbbb"]), Some(false));
+        assert_eq!(enqueue_blocked(&mut batch, &[b"bbbb"]), None);
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        assert_eq!(
+            sm.peek_queue(b"q".to_vec(), 10).await.unwrap(),
+            vec![b"a".to_vec(), b"bbbb".to_vec()]
+        );
+        assert_eq!(sm.queue_stats(b"q".to_vec()).await.unwrap().bytes, 5);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_makes_room_across_batches() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        limit(&mut batch, Some(2), None, DropPolicy::DropOldest);
+        batch.fifo(enqueue(b"q", &[b"a", b"b"]), 0);
+        batch.write();
+
+        // Drops stored items, then an item of this batch
+        let mut batch = test_db.batch();
+        assert_eq!(enqueue_blocked(&mut batch, &[b"c"]), None);
+        assert_eq!(enqueue_blocked(&mut batch, &[b"d", b"e"]), None);
+        assert_eq!(batch.dequeue(b"q", 1, 0), vec![b"d".to_vec()]);
+        // More items than the queue holds keep the newest ones
+        assert_eq!(enqueue_blocked(&mut batch, &[b"x", b"y", b"z"]), None);
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        assert_eq!(
+            sm.peek_queue(b"q".to_vec(), 10).await.unwrap(),
+            vec![b"y".to_vec(), b"z".to_vec()]
+        );
+        let stats = sm.queue_stats(b"q".to_vec()).await.unwrap();
+        assert_eq!((stats.length, stats.bytes), (2, 2));
+    }
+
+    #[test]
+    fn blocked_producers_wait_only_for_items_that_can_fit() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        limit(&mut batch, None, Some(4), DropPolicy::Block);
+        assert_eq!(enqueue_blocked(&mut batch, &[b"aaa"]), None);
+        assert_eq!(enqueue_blocked(&mut batch, &[b"bb"]), Some(true));
+        assert_eq!(enqueue_blocked(&mut batch, &[b"bbbbb"]), Some(false));
+        batch.dequeue(b"q", 1, 0);
+        assert_eq!(enqueue_blocked(&mut batch, &[b"bb"]), None);
+    }
+
+    #[tokio::test]
+    async fn missing_items_count_as_removed() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.fifo(enqueue(b"q", &[b"a", b"b", b"c"]), 0);
+        batch.write();
+
+        let db = test_db.db();
+        let fifo_queue_data = get_cf_handle(&db, "fifo_queue_data").unwrap();
+        db.delete_cf(fifo_queue_data, item_key(b"q", 2)).unwrap();
+
+        let mut batch = test_db.batch();
+        limit(&mut batch, Some(2), None, DropPolicy::DropOldest);
+        assert_eq!(enqueue_blocked(&mut batch, &[b"d"]), None);
+        batch.write();
+        let sm = test_db.state_machine().await;
+        assert_eq!(
+            sm.peek_queue(b"q".to_vec(), 10).await.unwrap(),
+            vec![b"c".to_vec(), b"d".to_vec()]
+        );
+
+        db.delete_cf(fifo_queue_data, item_key(b"q", 3)).unwrap();
+        let mut batch = test_db.batch();
+        assert_eq!(batch.dequeue(b"q", 10, 0), vec![b"d".to_vec()]);
+        batch.write();
+        assert_eq!(sm.queue_stats(b"q".to_vec()).await.unwrap().length, 0);
+    }
+
+    fn schedule(batch: &mut TestBatch, values: &[&[u8]], deliver_at_ms: u64, now_ms: u64) {
+        let op = FIFOOperation::Enqueue(FIFOEnqueue {
+            queue_key: b"q".to_vec(),
+            values: values.iter().map(|value| value.to_vec()).collect(),
+            deliver_at_ms: Some(deliver_at_ms),
+            delay_ms: None,
+        });
+        match batch.fifo(op, now_ms) {
+            ResponseResult::FIFO(FIFOResponse::Enqueue(_)) => {}
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn scheduled_items_join_the_queue_in_due_order_once_due() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        schedule(&mut batch, &[b"late"], 2000, 0);
+        schedule(&mut batch, &[b"early"], 1000, 0);
+        let delayed = FIFOOperation::Enqueue(FIFOEnqueue {
+            queue_key: b"q".to_vec(),
+            values: vec![b"delayed".to_vec()],
+            deliver_at_ms: None,
+            delay_ms: Some(1000),
+        });
+        batch.fifo(delayed, 500);
+        // Already due, so appended right away
+        schedule(&mut batch, &[b"now"], 100, 500);
+        assert_eq!(batch.dequeue(b"q", 10, 999), vec![b"now".to_vec()]);
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        assert_eq!(
+            sm.next_due_ms(b"q".to_vec(), false).await.unwrap(),
+            Some(1000)
+        );
+        assert!(!sm.has_deliverable(b"q".to_vec(), false, 999).await.unwrap());
+        assert!(
+            sm.has_deliverable(b"q".to_vec(), false, 1000)
+                .await
+                .unwrap()
+        );
+        let stats = sm.queue_stats(b"q".to_vec()).await.unwrap();
+        assert_eq!((stats.length, stats.delayed), (0, 3));
+
+        // Due items are promoted ahead of the enqueue that finds them
+        let mut batch = test_db.batch();
+        batch.fifo(enqueue(b"q", &[b"new"]), 2000);
+        assert_eq!(
+            batch.dequeue(b"q", 10, 2000),
+            vec![
+                b"early".to_vec(),
+                b"delayed".to_vec(),
+                b"late".to_vec(),
+                b"new".to_vec()
+            ]
+        );
+        batch.write();
+        assert_eq!(sm.next_due_ms(b"q".to_vec(), false).await.unwrap(), None);
+    }
+
+    #[test]
+    fn scheduled_items_count_against_the_limits() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        limit(&mut batch, Some(2), Some(3), DropPolicy::Reject);
+        batch.fifo(enqueue(b"q", &[b"a"]), 0);
+        schedule(&mut batch, &[b"bb"], 1000, 0);
+        match batch.fifo(enqueue(b"q", &[b"c"]), 0) {
+            ResponseResult::FIFO(FIFOResponse::QueueFull { length, bytes, .. }) => {
+                assert_eq!((length, bytes), (2, 3));
+            }
+            res => panic!("unexpected response {res:?}"),
+        }
+        let op = FIFOOperation::Enqueue(FIFOEnqueue {
+            queue_key: b"q".to_vec(),
+            values: vec![b"c".to_vec()],
+            deliver_at_ms: Some(1000),
+            delay_ms: None,
+        });
+        assert!(matches!(
+            batch.fifo(op, 0),
+            ResponseResult::FIFO(FIFOResponse::QueueFull { .. })
+        ));
+
+        // Promoting them does not change what the queue holds
+        assert_eq!(batch.dequeue(b"q", 1, 1000), vec![b"a".to_vec()]);
+        assert_eq!(enqueue_blocked(&mut batch, &[b"c"]), None);
+        assert_eq!(enqueue_blocked(&mut batch, &[b"d"]), Some(false));
+    }
+
+    #[tokio::test]
+    async fn promotion_beyond_lowered_limits_applies_the_drop_policy() {
+        for (drop_policy, expected) in [
+            (DropPolicy::DropOldest, &[b"b", b"c"][..]),
+            (DropPolicy::Reject, &[b"a", b"b", b"c"][..]),
+        ] {
+            let test_db = TestDb::new();
+            let mut batch = test_db.batch();
+            batch.fifo(enqueue(b"q", &[b"a", b"b"]), 0);
+            schedule(&mut batch, &[b"c"], 1000, 0);
+            batch.write();
+
+            let mut batch = test_db.batch();
+            limit(&mut batch, Some(2), None, drop_policy);
+            assert!(batch.dequeue(b"q", 0, 1000).is_empty());
+            batch.write();
+
+            let sm = test_db.state_machine().await;
+            let expected: Vec<_> = expected.iter().map(|value| value.to_vec()).collect();
+            assert_eq!(
+                sm.peek_queue(b"q".to_vec(), 10).await.unwrap(),
+                expected,
+                "{drop_policy:?}"
+            );
+        }
+    }
+}
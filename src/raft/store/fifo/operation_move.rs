@@ -0,0 +1,274 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    Response, ResponseResult,
+    store::{
+        common::{get_cf_handle, serialize},
+        fifo::{
+            FIFOMove, FIFOResponse,
+            common::{DropPolicy, FIFOOverlay},
+        },
+    },
+};
+
+/// Move items from the head of `queue_key` to the tail of `target_key`, keeping their order.
+/// Both queues change in the same entry and through the same overlay, so later entries of the
+/// batch see the moved items in the target queue. Both queues promote their due items and drop
+/// their expired ones first, like a dequeue and an enqueue. Unless `ignore_limits` is set, the
+/// items must fit within the target queue's limits as an enqueue's would: with the `DropOldest`
+/// policy the target drops its oldest items, otherwise nothing is moved and the response is
+/// `QueueFull`. A move never waits for room.
+pub fn operation_move(
+    op: FIFOMove,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    now_ms: u64,
+    pending_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let key_bytes = op.queue_key.clone();
+    let fifo_queue_meta = get_cf_handle(&db, "fifo_queue_meta")?;
+
+    pending_state.promote_due(&db, batch, &key_bytes, now_ms)?;
+    let overlay_queue = pending_state.queue(&db, &key_bytes)?;
+    overlay_queue.drop_expired(&db, batch, &key_bytes, now_ms)?;
+
+    let available_items = overlay_queue.length() as usize;
+    let items_to_move = op
+        .count
+        .map_or(available_items, |count| count.min(available_items));
+
+    // A queue moving items to itself keeps its size
+    let check_limits = !op.ignore_limits && op.target_key != key_bytes;
+    let mut drop_oldest = false;
+    if check_limits && items_to_move > 0 {
+        let mut new_bytes = 0;
+        for index in overlay_queue.meta.head + 1..=overlay_queue.meta.head + items_to_move as u64 {
+            new_bytes += overlay_queue
+                .item(&db, &key_bytes, index)?
+                .map_or(0, |item| item.data.len() as u64);
+        }
+        batch.put_cf(
+            fifo_queue_meta,
+            &key_bytes,
+            &serialize(&overlay_queue.meta)?,
+        );
+
+        pending_state.promote_due(&db, batch, &op.target_key, now_ms)?;
+        let target_queue = pending_state.queue(&db, &op.target_key)?;
+        if target_queue.drop_expired(&db, batch, &op.target_key, now_ms)? > 0 {
+            batch.put_cf(
+                fifo_queue_meta,
+                &op.target_key,
+                &serialize(&target_queue.meta)?,
+            );
+        }
+
+        drop_oldest = target_queue.config.drop_policy == DropPolicy::DropOldest;
+        if !drop_oldest && !target_queue.fits(items_to_move as u64, new_bytes) {
+            return Ok(Response::Result {
+                client_id,
+                seq_id,
+                res: ResponseResult::FIFO(FIFOResponse::QueueFull {
+                    length: target_queue.held_length(),
+                    bytes: target_queue.held_bytes(),
+                    max_length: target_queue.config.max_length,
+                    max_bytes: target_queue.config.max_bytes,
+                    block: false,
+                }),
+            });
+        }
+    }
+
+    let overlay_queue = pending_state.queue(&db, &key_bytes)?;
+    let mut values = Vec::with_capacity(items_to_move);
+    for _ in 0..items_to_move {
+        if let Some(item) = overlay_queue.pop(&db, batch, &key_bytes)? {
+            values.push(item.data);
+        }
+    }
+
+    batch.put_cf(
+        fifo_queue_meta,
+        &key_bytes,
+        &serialize(&overlay_queue.meta)?,
+    );
+
+    let moved = values.len() as u64;
+    let items = if op.return_items {
+        values.clone()
+    } else {
+        Vec::new()
+    };
+    if !values.is_empty() {
+        pending_state.push(&db, batch, &op.target_key, values, now_ms)?;
+        if drop_oldest {
+            let target_queue = pending_state.queue(&db, &op.target_key)?;
+            if target_queue.drop_excess(&db, batch, &op.target_key)? > 0 {
+                batch.put_cf(
+                    fifo_queue_meta,
+                    &op.target_key,
+                    &serialize(&target_queue.meta)?,
+                );
+            }
+        }
+    }
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::FIFO(FIFOResponse::Move { moved, items }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::fifo::{FIFOOperation, FIFOUpdateQueue};
+    use crate::raft::store::test_util::{TestBatch, TestDb, enqueue};
+
+    fn move_items(
+        batch: &mut TestBatch,
+        target_key: &[u8],
+        count: Option<usize>,
+        return_items: bool,
+        ignore_limits: bool,
+    ) -> ResponseResult {
+        let op = FIFOOperation::Move(FIFOMove {
+            queue_key: b"q".to_vec(),
+            target_key: target_key.to_vec(),
+            count,
+            return_items,
+            ignore_limits,
+        });
+        batch.fifo(op, 0)
+    }
+
+    /// Number of moved items, or `None` if the target queue is full
+    fn moved(response: ResponseResult) -> Option<u64> {
+        match response {
+            ResponseResult::FIFO(FIFOResponse::Move { moved, .. }) => Some(moved),
+            ResponseResult::FIFO(FIFOResponse::QueueFull { block, .. }) => {
+                assert!(!block);
+                None
+            }
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    fn limit(batch: &mut TestBatch, queue_key: &[u8], drop_policy: DropPolicy) {
+        let op = FIFOOperation::UpdateQueue(FIFOUpdateQueue {
+            queue_key: queue_key.to_vec(),
+            max_length: Some(2),
+            max_bytes: None,
+            message_ttl_ms: None,
+            drop_policy,
+        });
+        batch.fifo(op, 0);
+    }
+
+    #[tokio::test]
+    async fn moved_items_keep_their_order_and_are_visible_in_the_same_batch() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.fifo(enqueue(b"q", &[b"a", b"b", b"c"]), 0);
+        batch.write();
+
+        let mut batch = test_db.batch();
+        batch.fifo(enqueue(b"q", &[b"d"]), 0);
+        batch.fifo(enqueue(b"target", &[b"x"]), 0);
+        match move_items(&mut batch, b"target", Some(2), true, false) {
+            ResponseResult::FIFO(FIFOResponse::Move { moved, items }) => {
+                assert_eq!(moved, 2);
+                assert_eq!(items, vec![b"a".to_vec(), b"b".to_vec()]);
+            }
+            res => panic!("unexpected response {res:?}"),
+        }
+        match move_items(&mut batch, b"target", None, false, false) {
+            ResponseResult::FIFO(FIFOResponse::Move { moved, items }) => {
+                assert_eq!(moved, 2);
+                assert!(items.is_empty());
+            }
+            res => panic!("unexpected response {res:?}"),
+        }
+        assert_eq!(
+            batch.dequeue(b"target", 2, 0),
+            vec![b"x".to_vec(), b"a".to_vec()]
+        );
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        assert_eq!(sm.queue_stats(b"q".to_vec()).await.unwrap().length, 0);
+        assert_eq!(
+            sm.peek_queue(b"target".to_vec(), 10).await.unwrap(),
+            vec![b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]
+        );
+    }
+
+    #[test]
+    fn moves_into_a_full_queue_follow_its_drop_policy() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.fifo(enqueue(b"q", &[b"a", b"b", b"c"]), 0);
+        limit(&mut batch, b"reject", DropPolicy::Reject);
+        // Moves never wait, so blocking queues reject them
+        limit(&mut batch, b"block", DropPolicy::Block);
+        limit(&mut batch, b"drop", DropPolicy::DropOldest);
+        for target_key in [b"reject".as_slice(), b"block", b"drop"] {
+            batch.fifo(enqueue(target_key, &[b"t"]), 0);
+        }
+        batch.write();
+
+        let mut batch = test_db.batch();
+        assert_eq!(
+            moved(move_items(&mut batch, b"reject", None, false, false)),
+            None
+        );
+        assert_eq!(
+            moved(move_items(&mut batch, b"block", Some(2), false, false)),
+            None
+        );
+        assert_eq!(
+            moved(move_items(&mut batch, b"reject", Some(1), false, false)),
+            Some(1)
+        );
+        assert_eq!(
+            moved(move_items(&mut batch, b"drop", None, false, false)),
+            Some(2)
+        );
+        assert_eq!(
+            batch.dequeue(b"reject", 10, 0),
+            vec![b"t".to_vec(), b"a".to_vec()]
+        );
+        assert_eq!(
+            batch.dequeue(b"drop", 10, 0),
+            vec![b"b".to_vec(), b"c".to_vec()]
+        );
+    }
+
+    #[test]
+    fn ignore_limits_and_moves_within_a_queue_bypass_the_limits() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        limit(&mut batch, b"q", DropPolicy::Reject);
+        limit(&mut batch, b"target", DropPolicy::Reject);
+        batch.fifo(enqueue(b"q", &[b"a", b"b"]), 0);
+        batch.fifo(enqueue(b"target", &[b"t", b"u"]), 0);
+
+        assert_eq!(
+            moved(move_items(&mut batch, b"q", Some(1), false, false)),
+            Some(1)
+        );
+        assert_eq!(
+            moved(move_items(&mut batch, b"target", None, false, true)),
+            Some(2)
+        );
+        assert_eq!(
+            batch.dequeue(b"target", 10, 0),
+            vec![b"t".to_vec(), b"u".to_vec(), b"b".to_vec(), b"a".to_vec()]
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    Response, ResponseResult,
+    store::{
+        common::get_cf_handle,
+        fifo::{
+            FIFODeleteQueue, FIFOResponse,
+            common::{FIFOOverlay, PriorityQueueMeta, QueueConfig},
+        },
+    },
+};
+
+/// Delete the items of the priority queue one by one, then its metadata and config. A later
+/// enqueue starts a fresh queue with default settings.
+pub fn operation_priority_delete_queue(
+    op: FIFODeleteQueue,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let key_bytes = op.queue_key.clone();
+    let pqueue_meta = get_cf_handle(&db, "pqueue_meta")?;
+    let pqueue_config = get_cf_handle(&db, "pqueue_config")?;
+
+    let overlay_queue = pending_state.priority_queue(&db, &key_bytes)?;
+    let existed = overlay_queue.exists;
+
+    overlay_queue.drop_oldest(&db, batch, &key_bytes, |_, _| true)?;
+
+    batch.delete_cf(pqueue_meta, &key_bytes);
+    batch.delete_cf(pqueue_config, &key_bytes);
+    overlay_queue.meta = PriorityQueueMeta::default();
+    overlay_queue.config = QueueConfig::default();
+    overlay_queue.exists = false;
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::FIFO(FIFOResponse::DeleteQueue { existed }),
+    })
+}
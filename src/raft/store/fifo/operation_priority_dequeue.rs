@@ -0,0 +1,51 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    Response, ResponseResult,
+    store::{
+        common::{get_cf_handle, serialize},
+        fifo::{
+            FIFOPriorityDequeue, FIFOResponse,
+            common::{FIFOOverlay, parse_priority_suffix},
+        },
+    },
+};
+
+/// Remove the first `count` items in key order, which is highest priority first and enqueue
+/// order within a priority. Expired items are dropped first instead of being delivered.
+pub fn operation_priority_dequeue(
+    op: FIFOPriorityDequeue,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    now_ms: u64,
+    pending_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let key_bytes = op.queue_key.clone();
+    let pqueue_meta = get_cf_handle(&db, "pqueue_meta")?;
+
+    let overlay_queue = pending_state.priority_queue(&db, &key_bytes)?;
+    let mut changed = overlay_queue.drop_expired(&db, batch, &key_bytes, now_ms)? > 0;
+
+    let mut items = Vec::new();
+    if overlay_queue.meta.length > 0 {
+        for (suffix, item) in overlay_queue.first_items(&db, &key_bytes, op.count)? {
+            let (priority, _) = parse_priority_suffix(&suffix);
+            overlay_queue.remove(&db, batch, &key_bytes, suffix, &item)?;
+            items.push((priority, item.data));
+        }
+        changed |= !items.is_empty();
+    }
+    if changed {
+        batch.put_cf(pqueue_meta, &key_bytes, serialize(&overlay_queue.meta)?);
+    }
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::FIFO(FIFOResponse::PriorityDequeue { items }),
+    })
+}
@@ -0,0 +1,260 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    Response, ResponseResult,
+    store::{
+        common::{get_cf_handle, serialize},
+        fifo::{
+            FIFOPriorityEnqueue, FIFOResponse,
+            common::{DropPolicy, FIFOOverlay},
+        },
+    },
+};
+
+/// Store every item under its priority and the next sequence number of the queue. Expired items
+/// are dropped first, so they do not count against the limits, and the limits and drop policy
+/// apply as for a FIFO enqueue, except that `DropOldest` drops the oldest items whatever their
+/// priority.
+pub fn operation_priority_enqueue(
+    op: FIFOPriorityEnqueue,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    now_ms: u64,
+    pending_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let key_bytes = op.queue_key.clone();
+    let pqueue_meta = get_cf_handle(&db, "pqueue_meta")?;
+
+    let overlay_queue = pending_state.priority_queue(&db, &key_bytes)?;
+    if overlay_queue.drop_expired(&db, batch, &key_bytes, now_ms)? > 0 {
+        batch.put_cf(pqueue_meta, &key_bytes, serialize(&overlay_queue.meta)?);
+    }
+
+    let max_length = overlay_queue.config.max_length;
+    let max_bytes = overlay_queue.config.max_bytes;
+    let drop_policy = overlay_queue.config.drop_policy;
+
+    let new_length = op.items.len() as u64;
+    let new_bytes: u64 = op.items.iter().map(|(_, value)| value.len() as u64).sum();
+
+    if drop_policy != DropPolicy::DropOldest && !overlay_queue.fits(new_length, new_bytes) {
+        return Ok(Response::Result {
+            client_id,
+            seq_id,
+            res: ResponseResult::FIFO(FIFOResponse::QueueFull {
+                length: overlay_queue.meta.length,
+                bytes: overlay_queue.meta.bytes,
+                max_length,
+                max_bytes,
+                // Waiting only helps if the items fit into an empty queue
+                block: drop_policy == DropPolicy::Block
+                    && overlay_queue.config.fits(new_length, new_bytes),
+            }),
+        });
+    }
+
+    let pushed = !op.items.is_empty();
+    for (priority, value) in op.items {
+        overlay_queue.push(&db, batch, &key_bytes, priority, value, now_ms)?;
+    }
+    if drop_policy == DropPolicy::DropOldest {
+        overlay_queue.drop_excess(&db, batch, &key_bytes)?;
+    }
+
+    batch.put_cf(pqueue_meta, &key_bytes, serialize(&overlay_queue.meta)?);
+    let length = overlay_queue.meta.length;
+
+    if pushed {
+        pending_state.mark_pushed(&key_bytes);
+    }
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::FIFO(FIFOResponse::PriorityEnqueue { length }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::fifo::{
+        FIFODeleteQueue, FIFOOperation, FIFOPriorityDequeue, FIFOPurge, FIFOUpdateQueue,
+    };
+    use crate::raft::store::test_util::{TestBatch, TestDb};
+
+    /// Enqueue `items` to `queue_key`, returning the queue length or `None` if it is full
+    fn priority_enqueue(
+        batch: &mut TestBatch,
+        queue_key: &[u8],
+        items: &[(u32, &[u8])],
+        now_ms: u64,
+    ) -> Option<u64> {
+        let op = FIFOOperation::PriorityEnqueue(FIFOPriorityEnqueue {
+            queue_key: queue_key.to_vec(),
+            items: items
+                .iter()
+                .map(|(priority, value)| (*priority, value.to_vec()))
+                .collect(),
+        });
+        match batch.fifo(op, now_ms) {
+            ResponseResult::FIFO(FIFOResponse::PriorityEnqueue { length }) => Some(length),
+            ResponseResult::FIFO(FIFOResponse::QueueFull { .. }) => None,
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    fn priority_dequeue(batch: &mut TestBatch, count: usize, now_ms: u64) -> Vec<(u32, Vec<u8>)> {
+        let op = FIFOOperation::PriorityDequeue(FIFOPriorityDequeue {
+            queue_key: b"q".to_vec(),
+            count,
+        });
+        match batch.fifo(op, now_ms) {
+            ResponseResult::FIFO(FIFOResponse::PriorityDequeue { items }) => items,
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    fn limit(
+        batch: &mut TestBatch,
+        max_length: Option<u64>,
+        message_ttl_ms: Option<u64>,
+        drop_policy: DropPolicy,
+    ) {
+        let op = FIFOOperation::PriorityUpdateQueue(FIFOUpdateQueue {
+            queue_key: b"q".to_vec(),
+            max_length,
+            max_bytes: None,
+            message_ttl_ms,
+            drop_policy,
+        });
+        batch.fifo(op, 0);
+    }
+
+    #[tokio::test]
+    async fn items_leave_by_priority_then_enqueue_order() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        priority_enqueue(&mut batch, b"q", &[(1, b"a"), (5, b"b")], 1000);
+        // Keys of this queue start with the key of `q`
+        priority_enqueue(&mut batch, b"qq", &[(7, b"x")], 1000);
+        batch.write();
+
+        let mut batch = test_db.batch();
+        let length = priority_enqueue(&mut batch, b"q", &[(5, b"c"), (1, b"d"), (9, b"e")], 2000);
+        assert_eq!(length, Some(5));
+        assert_eq!(
+            priority_dequeue(&mut batch, 2, 2000),
+            vec![(9, b"e".to_vec()), (5, b"b".to_vec())]
+        );
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        assert_eq!(
+            sm.peek_priority_queue(b"q".to_vec(), 10).await.unwrap(),
+            vec![(5, b"c".to_vec()), (1, b"a".to_vec()), (1, b"d".to_vec())]
+        );
+        let stats = sm.priority_queue_stats(b"q".to_vec()).await.unwrap();
+        assert_eq!((stats.length, stats.bytes), (3, 3));
+        assert_eq!(stats.oldest_enqueued_ms, Some(1000));
+    }
+
+    #[tokio::test]
+    async fn limits_drop_the_oldest_items_whatever_their_priority() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        limit(&mut batch, Some(2), None, DropPolicy::DropOldest);
+        priority_enqueue(&mut batch, b"q", &[(9, b"a")], 0);
+        batch.write();
+
+        // Drops a stored item, then an item of this batch
+        let mut batch = test_db.batch();
+        priority_enqueue(&mut batch, b"q", &[(1, b"b")], 0);
+        assert_eq!(priority_enqueue(&mut batch, b"q", &[(5, b"c")], 0), Some(2));
+        assert_eq!(priority_enqueue(&mut batch, b"q", &[(0, b"d")], 0), Some(2));
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        assert_eq!(
+            sm.peek_priority_queue(b"q".to_vec(), 10).await.unwrap(),
+            vec![(5, b"c".to_vec()), (0, b"d".to_vec())]
+        );
+
+        let mut batch = test_db.batch();
+        limit(&mut batch, Some(2), None, DropPolicy::Reject);
+        assert_eq!(priority_enqueue(&mut batch, b"q", &[(9, b"e")], 0), None);
+        assert_eq!(priority_dequeue(&mut batch, 1, 0), vec![(5, b"c".to_vec())]);
+        assert_eq!(priority_enqueue(&mut batch, b"q", &[(9, b"e")], 0), Some(2));
+    }
+
+    #[test]
+    fn expired_items_are_dropped_instead_of_delivered() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        limit(&mut batch, Some(2), Some(100), DropPolicy::Reject);
+        priority_enqueue(&mut batch, b"q", &[(9, b"a")], 1000);
+        priority_enqueue(&mut batch, b"q", &[(1, b"b")], 1050);
+        batch.write();
+
+        // The expired item makes room before the limits are checked
+        let mut batch = test_db.batch();
+        assert_eq!(
+            priority_enqueue(&mut batch, b"q", &[(1, b"c")], 1100),
+            Some(2)
+        );
+        assert_eq!(
+            priority_dequeue(&mut batch, 10, 1100),
+            vec![(1, b"b".to_vec()), (1, b"c".to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    async fn purge_and_delete_remove_only_this_queue() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        limit(&mut batch, Some(3), None, DropPolicy::Reject);
+        priority_enqueue(&mut batch, b"q", &[(1, b"a"), (2, b"b")], 0);
+        priority_enqueue(&mut batch, b"qq", &[(1, b"x")], 0);
+        batch.write();
+
+        let mut batch = test_db.batch();
+        priority_enqueue(&mut batch, b"q", &[(3, b"c")], 0);
+        let purge = FIFOOperation::PriorityPurge(FIFOPurge {
+            queue_key: b"q".to_vec(),
+        });
+        match batch.fifo(purge, 0) {
+            ResponseResult::FIFO(FIFOResponse::Purge { purged }) => assert_eq!(purged, 3),
+            res => panic!("unexpected response {res:?}"),
+        }
+        assert!(priority_dequeue(&mut batch, 10, 0).is_empty());
+        // Purging keeps the limits
+        let items: [(u32, &[u8]); 4] = [(1, b"1"), (1, b"2"), (1, b"3"), (1, b"4")];
+        assert_eq!(priority_enqueue(&mut batch, b"q", &items, 0), None);
+        priority_enqueue(&mut batch, b"q", &items[..3], 0);
+
+        let delete = FIFOOperation::PriorityDeleteQueue(FIFODeleteQueue {
+            queue_key: b"q".to_vec(),
+        });
+        match batch.fifo(delete, 0) {
+            ResponseResult::FIFO(FIFOResponse::DeleteQueue { existed }) => assert!(existed),
+            res => panic!("unexpected response {res:?}"),
+        }
+        // Deleting drops them
+        assert_eq!(priority_enqueue(&mut batch, b"q", &items, 0), Some(4));
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        assert_eq!(
+            sm.priority_queue_stats(b"q".to_vec()).await.unwrap().length,
+            4
+        );
+        assert_eq!(
+            sm.peek_priority_queue(b"qq".to_vec(), 10).await.unwrap(),
+            vec![(1, b"x".to_vec())]
+        );
+    }
+}
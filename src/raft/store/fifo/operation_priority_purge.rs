@@ -0,0 +1,37 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    Response, ResponseResult,
+    store::{
+        common::{get_cf_handle, serialize},
+        fifo::{FIFOPurge, FIFOResponse, common::FIFOOverlay},
+    },
+};
+
+/// Delete every item of the priority queue, keeping the queue and its settings. Item keys of
+/// other queues can share this queue's prefix, so the items are deleted one by one.
+pub fn operation_priority_purge(
+    op: FIFOPurge,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let key_bytes = op.queue_key.clone();
+    let pqueue_meta = get_cf_handle(&db, "pqueue_meta")?;
+
+    let overlay_queue = pending_state.priority_queue(&db, &key_bytes)?;
+    let purged = overlay_queue.drop_oldest(&db, batch, &key_bytes, |_, _| true)?;
+    if purged > 0 {
+        batch.put_cf(pqueue_meta, &key_bytes, serialize(&overlay_queue.meta)?);
+    }
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::FIFO(FIFOResponse::Purge { purged }),
+    })
+}
@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    Response, ResponseResult,
+    store::{
+        common::{get_cf_handle, serialize},
+        fifo::{FIFOResponse, FIFOUpdateQueue, common::FIFOOverlay},
+    },
+};
+
+/// Replace the limits, message TTL and drop policy of the priority queue, and create the queue
+/// if it does not exist yet. Items the queue already holds are kept even if they exceed lowered
+/// limits; the new limits apply from the next enqueue on.
+pub fn operation_priority_update_queue(
+    op: FIFOUpdateQueue,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let pqueue_meta = get_cf_handle(&db, "pqueue_meta")?;
+    let pqueue_config = get_cf_handle(&db, "pqueue_config")?;
+
+    let overlay_queue = pending_state.priority_queue(&db, &op.queue_key)?;
+    let existed = overlay_queue.exists;
+    if !existed {
+        batch.put_cf(pqueue_meta, &op.queue_key, serialize(&overlay_queue.meta)?);
+        overlay_queue.exists = true;
+    }
+    overlay_queue.config.max_length = op.max_length;
+    overlay_queue.config.max_bytes = op.max_bytes;
+    overlay_queue.config.message_ttl_ms = op.message_ttl_ms;
+    overlay_queue.config.drop_policy = op.drop_policy;
+    batch.put_cf(
+        pqueue_config,
+        &op.queue_key,
+        serialize(&overlay_queue.config)?,
+    );
+    // Raised limits or a new drop policy can let blocked producers through
+    overlay_queue.popped = true;
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::FIFO(FIFOResponse::UpdateQueue { existed }),
+    })
+}
@@ -0,0 +1,87 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    Response, ResponseResult,
+    store::{
+        common::{get_cf_handle, serialize},
+        fifo::{FIFOPurge, FIFOResponse, common::FIFOOverlay},
+    },
+};
+
+/// Delete every queued item with a single range deletion by moving `head` up to `tail`.
+pub fn operation_purge(
+    op: FIFOPurge,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let key_bytes = op.queue_key.clone();
+    let fifo_queue_meta = get_cf_handle(&db, "fifo_queue_meta")?;
+
+    let overlay_queue = pending_state.queue(&db, &key_bytes)?;
+
+    let length = overlay_queue.length();
+    let purged = overlay_queue.drop_head(&db, batch, &key_bytes, length)?;
+    if purged > 0 {
+        batch.put_cf(
+            fifo_queue_meta,
+            &key_bytes,
+            &serialize(&overlay_queue.meta)?,
+        );
+    }
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::FIFO(FIFOResponse::Purge { purged }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::fifo::FIFOOperation;
+    use crate::raft::store::test_util::{TestBatch, TestDb, enqueue};
+
+    fn purge(batch: &mut TestBatch) -> u64 {
+        let op = FIFOOperation::Purge(FIFOPurge {
+            queue_key: b"q".to_vec(),
+        });
+        match batch.fifo(op, 0) {
+            ResponseResult::FIFO(FIFOResponse::Purge { purged }) => purged,
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn purge_removes_the_waiting_items_and_keeps_the_counters() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        assert_eq!(purge(&mut batch), 0);
+        assert_eq!(batch.batch.len(), 0);
+
+        batch.fifo(enqueue(b"q", &[b"a", b"b"]), 0);
+        assert_eq!(purge(&mut batch), 2);
+        assert!(batch.dequeue(b"q", 10, 0).is_empty());
+        batch.fifo(enqueue(b"q", &[b"ccc"]), 0);
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        assert_eq!(
+            sm.peek_queue(b"q".to_vec(), 10).await.unwrap(),
+            vec![b"ccc".to_vec()]
+        );
+        let stats = sm.queue_stats(b"q".to_vec()).await.unwrap();
+        assert_eq!((stats.length, stats.bytes), (1, 3));
+        assert_eq!((stats.enqueued_total, stats.dequeued_total), (3, 2));
+
+        let mut batch = test_db.batch();
+        assert_eq!(purge(&mut batch), 1);
+        batch.write();
+        assert_eq!(sm.queue_stats(b"q".to_vec()).await.unwrap().length, 0);
+    }
+}
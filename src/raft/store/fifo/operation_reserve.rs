@@ -0,0 +1,222 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    Response, ResponseResult,
+    store::{
+        common::{get_cf_handle, serialize},
+        fifo::{
+            FIFOReserve, FIFOResponse, ReceiptHandle, ReserveResponse, ReservedItem,
+            common::{FIFOOverlay, InFlightItem, QueueItem},
+        },
+    },
+};
+
+/// Move up to `count` items to the in-flight set with a deadline of `now_ms` plus the
+/// visibility timeout. Expired in-flight items are delivered again first, oldest first, and
+/// then new items are taken from the head of the queue, after promoting the scheduled items
+/// that are due. Expired items that reached the queue's `max_deliveries` are moved to its
+/// dead-letter queue within that queue's limits, or dropped if it has none. Dead-lettering only
+/// happens here, so an item waits in flight until the next reservation finds it expired.
+pub fn operation_reserve(
+    op: FIFOReserve,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    now_ms: u64,
+    pending_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let key_bytes = op.queue_key.clone();
+    let fifo_queue_meta = get_cf_handle(&db, "fifo_queue_meta")?;
+
+    pending_state.promote_due(&db, batch, &key_bytes, now_ms)?;
+    let overlay_queue = pending_state.queue(&db, &key_bytes)?;
+    let stored_meta = overlay_queue.meta;
+    let deadline_ms = now_ms.saturating_add(op.visibility_timeout_ms);
+
+    let mut reserved = Vec::with_capacity(op.count);
+
+    // Redeliver items whose visibility timeout expired, and dead-letter the ones that have
+    // used up their delivery attempts
+    let max_deliveries = overlay_queue.config.max_deliveries;
+    let mut dead_letters = Vec::new();
+    for mut item in overlay_queue.expired_inflight_items(&db, &key_bytes, now_ms)? {
+        if max_deliveries.is_some_and(|max_deliveries| item.deliveries >= max_deliveries) {
+            overlay_queue.remove_inflight(&db, batch, &key_bytes, &item)?;
+            dead_letters.push(item.data);
+            continue;
+        }
+        if reserved.len() >= op.count {
+            continue;
+        }
+
+        let previous_deadline_ms = item.deadline_ms;
+        item.deadline_ms = deadline_ms;
+        item.deliveries += 1;
+        reserved.push(ReservedItem {
+            handle: ReceiptHandle {
+                index: item.index,
+                delivery: item.deliveries,
+            },
+            data: item.data.clone(),
+            deliveries: item.deliveries,
+        });
+        overlay_queue.put_inflight(&db, batch, &key_bytes, Some(previous_deadline_ms), item)?;
+    }
+
+    // Then take new items from the head of the queue, skipping the expired ones
+    overlay_queue.drop_expired(&db, batch, &key_bytes, now_ms)?;
+    while reserved.len() < op.count && overlay_queue.meta.head < overlay_queue.meta.tail {
+        let Some(QueueItem { index, data, .. }) = overlay_queue.pop(&db, batch, &key_bytes)? else {
+            continue;
+        };
+
+        let item = InFlightItem {
+            index,
+            data,
+            deadline_ms,
+            deliveries: 1,
+        };
+        reserved.push(ReservedItem {
+            handle: ReceiptHandle { index, delivery: 1 },
+            data: item.data.clone(),
+            deliveries: 1,
+        });
+        overlay_queue.put_inflight(&db, batch, &key_bytes, None, item)?;
+    }
+
+    // An empty poll leaves the metadata unchanged
+    if overlay_queue.meta != stored_meta {
+        batch.put_cf(
+            fifo_queue_meta,
+            &key_bytes,
+            &serialize(&overlay_queue.meta)?,
+        );
+    }
+    let queue_meta = overlay_queue.meta;
+
+    // Dead-lettered items move to the dead-letter queue in this same entry
+    if let Some(dead_letter_queue) = overlay_queue.config.dead_letter_queue.clone() {
+        if !dead_letters.is_empty() {
+            pending_state.push_dead_letters(
+                &db,
+                batch,
+                &dead_letter_queue,
+                dead_letters,
+                now_ms,
+            )?;
+        }
+    }
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::FIFO(FIFOResponse::Reserve(ReserveResponse {
+            queue_meta,
+            items: reserved,
+        })),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::fifo::{FIFOAck, FIFOOperation};
+    use crate::raft::store::test_util::{TestBatch, TestDb, enqueue};
+
+    fn reserve(batch: &mut TestBatch, count: usize, now_ms: u64) -> Vec<ReservedItem> {
+        let op = FIFOOperation::Reserve(FIFOReserve {
+            queue_key: b"q".to_vec(),
+            count,
+            visibility_timeout_ms: 100,
+        });
+        match batch.fifo(op, now_ms) {
+            ResponseResult::FIFO(FIFOResponse::Reserve(response)) => response.items,
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    fn ack(batch: &mut TestBatch, handles: Vec<ReceiptHandle>) -> usize {
+        let op = FIFOOperation::Ack(FIFOAck {
+            queue_key: b"q".to_vec(),
+            handles,
+        });
+        match batch.fifo(op, 0) {
+            ResponseResult::FIFO(FIFOResponse::Ack(response)) => response.acked,
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    fn data(items: &[ReservedItem]) -> Vec<(Vec<u8>, u32)> {
+        items
+            .iter()
+            .map(|item| (item.data.clone(), item.deliveries))
+            .collect()
+    }
+
+    #[test]
+    fn reserved_items_stay_hidden_until_their_timeout_expires() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.fifo(enqueue(b"q", &[b"a", b"b", b"c"]), 1000);
+
+        let first = reserve(&mut batch, 2, 1000);
+        assert_eq!(data(&first), vec![(b"a".to_vec(), 1), (b"b".to_vec(), 1)]);
+        assert_eq!(
+            data(&reserve(&mut batch, 5, 1050)),
+            vec![(b"c".to_vec(), 1)]
+        );
+        assert!(reserve(&mut batch, 5, 1099).is_empty());
+
+        assert_eq!(ack(&mut batch, vec![first[0].handle]), 1);
+        // Expired reservations come back oldest deadline first, with a new delivery count
+        let again = reserve(&mut batch, 5, 1200);
+        assert_eq!(data(&again), vec![(b"b".to_vec(), 2), (b"c".to_vec(), 2)]);
+
+        // The handle of the earlier delivery no longer acknowledges the item
+        assert_eq!(ack(&mut batch, vec![first[1].handle]), 0);
+        assert_eq!(ack(&mut batch, vec![again[0].handle, again[0].handle]), 1);
+        assert_eq!(
+            batch.fifo.queue(&test_db.db(), b"q").unwrap().meta.inflight,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn reservations_expire_across_batches() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.fifo(enqueue(b"q", &[b"a", b"b"]), 1000);
+        let first = reserve(&mut batch, 2, 1000);
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        let stats = sm.queue_stats(b"q".to_vec()).await.unwrap();
+        assert_eq!((stats.length, stats.in_flight), (0, 2));
+        assert_eq!(
+            sm.next_due_ms(b"q".to_vec(), true).await.unwrap(),
+            Some(1100)
+        );
+
+        let mut batch = test_db.batch();
+        assert_eq!(ack(&mut batch, vec![first[1].handle]), 1);
+        batch.write();
+
+        let mut batch = test_db.batch();
+        assert!(reserve(&mut batch, 5, 1099).is_empty());
+        assert_eq!(
+            data(&reserve(&mut batch, 5, 1100)),
+            vec![(b"a".to_vec(), 2)]
+        );
+        batch.write();
+
+        let stats = sm.queue_stats(b"q".to_vec()).await.unwrap();
+        assert_eq!((stats.length, stats.in_flight), (0, 1));
+        assert_eq!(
+            sm.next_due_ms(b"q".to_vec(), true).await.unwrap(),
+            Some(1200)
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    Response, ResponseResult,
+    store::{
+        common::{get_cf_handle, serialize},
+        fifo::{FIFOResponse, FIFOUpdateQueue, common::FIFOOverlay},
+    },
+};
+
+/// Replace the limits, message TTL and drop policy of the queue, keeping its dead-letter policy,
+/// and create the queue if it does not exist yet. Items the queue already holds are kept even if
+/// they exceed lowered limits; the new limits apply from the next enqueue on.
+pub fn operation_update_queue(
+    op: FIFOUpdateQueue,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let fifo_queue_meta = get_cf_handle(&db, "fifo_queue_meta")?;
+    let fifo_queue_config = get_cf_handle(&db, "fifo_queue_config")?;
+
+    let overlay_queue = pending_state.queue(&db, &op.queue_key)?;
+    let existed = overlay_queue.exists;
+    if !existed {
+        batch.put_cf(
+            fifo_queue_meta,
+            &op.queue_key,
+            serialize(&overlay_queue.meta)?,
+        );
+        overlay_queue.exists = true;
+    }
+    overlay_queue.config.max_length = op.max_length;
+    overlay_queue.config.max_bytes = op.max_bytes;
+    overlay_queue.config.message_ttl_ms = op.message_ttl_ms;
+    overlay_queue.config.drop_policy = op.drop_policy;
+    batch.put_cf(
+        fifo_queue_config,
+        &op.queue_key,
+        serialize(&overlay_queue.config)?,
+    );
+    // Raised limits or a new drop policy can let blocked producers through
+    overlay_queue.popped = true;
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::FIFO(FIFOResponse::UpdateQueue { existed }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::fifo::common::DropPolicy;
+    use crate::raft::store::fifo::{FIFOConfigure, FIFOOperation};
+    use crate::raft::store::test_util::{TestBatch, TestDb, enqueue};
+
+    fn update(batch: &mut TestBatch, max_length: Option<u64>) -> bool {
+        let op = FIFOOperation::UpdateQueue(FIFOUpdateQueue {
+            queue_key: b"q".to_vec(),
+            max_length,
+            max_bytes: None,
+            message_ttl_ms: None,
+            drop_policy: DropPolicy::Reject,
+        });
+        match batch.fifo(op, 0) {
+            ResponseResult::FIFO(FIFOResponse::UpdateQueue { existed }) => existed,
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn update_creates_the_queue_and_keeps_the_dead_letter_policy() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        assert!(!update(&mut batch, None));
+        let configure = FIFOOperation::Configure(FIFOConfigure {
+            queue_key: b"q".to_vec(),
+            max_deliveries: Some(3),
+            dead_letter_queue: Some(b"dlq".to_vec()),
+        });
+        batch.fifo(configure, 0);
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        assert_eq!(sm.list_queues().await.unwrap(), vec![b"q".to_vec()]);
+
+        let mut batch = test_db.batch();
+        assert!(update(&mut batch, Some(10)));
+        batch.write();
+
+        let mut batch = test_db.batch();
+        let config = &batch.fifo.queue(&test_db.db(), b"q").unwrap().config;
+        assert_eq!(config.max_length, Some(10));
+        assert_eq!(config.max_deliveries, Some(3));
+        assert_eq!(config.dead_letter_queue, Some(b"dlq".to_vec()));
+    }
+
+    #[test]
+    fn lowered_limits_keep_the_items_and_apply_to_the_next_enqueue() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.fifo(enqueue(b"q", &[b"a", b"b", b"c"]), 0);
+        update(&mut batch, Some(2));
+        assert!(batch.fifo.queue(&test_db.db(), b"q").unwrap().popped);
+        assert!(matches!(
+            batch.fifo(enqueue(b"q", &[b"d"]), 0),
+            ResponseResult::FIFO(FIFOResponse::QueueFull { length: 3, .. })
+        ));
+        batch.write();
+
+        let mut batch = test_db.batch();
+        assert_eq!(batch.dequeue(b"q", 10, 0).len(), 3);
+        update(&mut batch, None);
+        batch.fifo(enqueue(b"q", &[b"d", b"e", b"f"]), 0);
+        assert_eq!(batch.dequeue(b"q", 10, 0).len(), 3);
+    }
+}
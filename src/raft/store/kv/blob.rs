@@ -0,0 +1,185 @@
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::raft::store::kv::StoredValue;
+
+/// First byte of every blob chunk key. Plain keys starting with this byte are reserved.
+pub const BLOB_CHUNK_PREFIX: u8 = 0xFD;
+
+/// Stored in place of a large value, with `StoredValue::blob` set: the keys of its chunks, in
+/// order. Chunks are plain KV entries written before the manifest.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlobManifest {
+    pub size: u64,
+    pub chunks: Vec<Vec<u8>>,
+}
+
+impl BlobManifest {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        rmp_serde::from_slice(data).ok()
+    }
+}
+
+/// Content-addressed key of a blob chunk.
+pub fn chunk_key(chunk: &[u8]) -> Vec<u8> {
+    let mut key = vec![BLOB_CHUNK_PREFIX];
+    key.extend_from_slice(&Sha256::digest(chunk));
+    key
+}
+
+/// Replace the data of `stored` with the reassembled blob if it is a blob. Chunks are looked up
+/// with `get_chunk`, which must read the same snapshot as the manifest so that a concurrent
+/// overwrite can't release them in between.
+pub fn resolve_blob(
+    stored: StoredValue,
+    mut get_chunk: impl FnMut(&[u8]) -> Result<Option<StoredValue>, io::Error>,
+) -> Result<StoredValue, io::Error> {
+    if !stored.blob {
+        return Ok(stored);
+    }
+    let Some(manifest) = BlobManifest::parse(&stored.data) else {
+        return Err(io::Error::other("Blob manifest is corrupt"));
+    };
+
+    let mut data = Vec::with_capacity(manifest.size as usize);
+    for key in &manifest.chunks {
+        match get_chunk(key)? {
+            Some(chunk) if chunk_key(&chunk.data) == *key => data.extend(chunk.data),
+            _ => return Err(io::Error::other("Blob chunk is missing or corrupt")),
+        }
+    }
+    Ok(StoredValue {
+        data,
+        blob: false,
+        ..stored
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::kv::{KVDel, KVOperation, KVPutIfAbsent, KVSet};
+    use crate::raft::store::test_util::{TestBatch, TestDb};
+    use crate::raft::{KVResponse, ResponseResult};
+
+    fn write_chunk(batch: &mut TestBatch, chunk: &[u8]) -> Vec<u8> {
+        let key = chunk_key(chunk);
+        batch.kv(KVOperation::PutIfAbsent(KVPutIfAbsent {
+            key: key.clone(),
+            value: chunk.to_vec(),
+        }));
+        key
+    }
+
+    fn set_blob(batch: &mut TestBatch, key: &[u8], chunks: &[&[u8]]) -> ResponseResult {
+        let manifest = BlobManifest {
+            size: chunks.iter().map(|chunk| chunk.len() as u64).sum(),
+            chunks: chunks.iter().map(|chunk| chunk_key(chunk)).collect(),
+        };
+        batch.kv(KVOperation::Set(KVSet {
+            key: key.to_vec(),
+            value: rmp_serde::to_vec(&manifest).unwrap(),
+            return_previous: false,
+            blob: true,
+        }))
+    }
+
+    fn del(batch: &mut TestBatch, key: &[u8]) {
+        batch.kv(KVOperation::Del(KVDel {
+            key: key.to_vec(),
+            expected_revision: None,
+            return_previous: false,
+        }));
+    }
+
+    /// Reference count of the chunk and whether it is still stored
+    fn chunk_state(test_db: &TestDb, batch: &TestBatch, chunk: &[u8]) -> (u64, bool) {
+        let db = test_db.db();
+        let key = chunk_key(chunk);
+        (
+            batch.kv.blob_ref_count(&db, &key).unwrap(),
+            batch.kv.get(&db, &key).unwrap().is_some(),
+        )
+    }
+
+    #[tokio::test]
+    async fn blobs_are_reassembled_and_count_references_to_shared_chunks() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        write_chunk(&mut batch, b"aaa");
+        write_chunk(&mut batch, b"bbb");
+        set_blob(&mut batch, b"x", &[b"aaa", b"bbb"]);
+        set_blob(&mut batch, b"y", &[b"bbb"]);
+        assert_eq!(chunk_state(&test_db, &batch, b"bbb"), (2, true));
+        batch.write();
+
+        let batch = test_db.batch();
+        assert_eq!(chunk_state(&test_db, &batch, b"aaa"), (1, true));
+        assert_eq!(chunk_state(&test_db, &batch, b"bbb"), (2, true));
+
+        let sm = test_db.state_machine().await;
+        let x = sm.get_stored(b"x").await.unwrap().unwrap();
+        assert_eq!(x.data, b"aaabbb");
+        assert!(!x.blob);
+    }
+
+    #[test]
+    fn overwrites_and_deletes_release_chunks_no_blob_references() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        write_chunk(&mut batch, b"aaa");
+        write_chunk(&mut batch, b"bbb");
+        set_blob(&mut batch, b"x", &[b"aaa", b"bbb"]);
+        set_blob(&mut batch, b"y", &[b"bbb"]);
+        batch.write();
+
+        let mut batch = test_db.batch();
+        write_chunk(&mut batch, b"ccc");
+        set_blob(&mut batch, b"x", &[b"bbb", b"ccc"]);
+        assert_eq!(chunk_state(&test_db, &batch, b"aaa"), (0, false));
+        assert_eq!(chunk_state(&test_db, &batch, b"bbb"), (2, true));
+        del(&mut batch, b"y");
+        assert_eq!(chunk_state(&test_db, &batch, b"bbb"), (1, true));
+        batch.write();
+
+        let mut batch = test_db.batch();
+        del(&mut batch, b"x");
+        batch.write();
+
+        let batch = test_db.batch();
+        for chunk in [b"aaa", b"bbb", b"ccc"] {
+            assert_eq!(chunk_state(&test_db, &batch, chunk), (0, false));
+        }
+    }
+
+    #[test]
+    fn a_manifest_with_a_missing_chunk_is_refused() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        write_chunk(&mut batch, b"aaa");
+        assert!(matches!(
+            set_blob(&mut batch, b"x", &[b"aaa", b"bbb"]),
+            ResponseResult::KV(KVResponse::MissingBlobChunks)
+        ));
+        assert!(batch.kv.get(&test_db.db(), b"x").unwrap().is_none());
+        assert_eq!(chunk_state(&test_db, &batch, b"aaa"), (0, true));
+    }
+
+    #[test]
+    fn resolving_fails_when_a_chunk_is_gone() {
+        let manifest = BlobManifest {
+            size: 3,
+            chunks: vec![chunk_key(b"aaa")],
+        };
+        let stored = StoredValue {
+            revision: 1,
+            data: rmp_serde::to_vec(&manifest).unwrap(),
+            create_revision: 1,
+            version: 1,
+            blob: true,
+        };
+        assert!(resolve_blob(stored, |_| Ok(None)).is_err());
+    }
+}
@@ -1,8 +1,590 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io;
+
+use rocksdb::DB;
 use serde::{Deserialize, Serialize};
 
-/// Value stored in the state machine: (revision, data)
+use crate::raft::store::common::{deserialize, get_cf_handle, rocksdb_err_to_io, serialize};
+use crate::raft::store::kv::blob::BlobManifest;
+use crate::raft::store::kv::index::{IndexDefinition, index_entry_key};
+
+/// Value stored in the state machine.
+///
+/// `revision` is the cluster-wide revision of the last write to the key (etcd's `mod_revision`),
+/// `create_revision` is the revision at which the key was last created and `version` counts the
+/// writes since then. When `blob` is set, `data` is a `BlobManifest` and the value itself is
+/// stored in chunks.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StoredValue {
     pub revision: u64,
     pub data: Vec<u8>,
+    #[serde(default)]
+    pub create_revision: u64,
+    #[serde(default)]
+    pub version: u64,
+    #[serde(default)]
+    pub blob: bool,
+}
+
+/// Result of looking a key up at a past revision.
+#[derive(Debug, Clone)]
+pub enum HistoryRead {
+    Value(Option<StoredValue>),
+    Compacted { compacted_revision: u64 },
+}
+
+pub const KV_META_REVISION: &str = "revision";
+pub const KV_META_COMPACTED_REVISION: &str = "compacted_revision";
+pub const KV_META_COMPACTION: &str = "compaction";
+
+/// Compaction that is still dropping history, applied in bounded steps. Stored in
+/// `sm_kv_meta` between steps.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CompactionProgress {
+    /// Revision the history is compacted to
+    pub target: u64,
+    /// Last history key examined; the next step resumes after it
+    pub resume_after: Option<Vec<u8>>,
+    /// History key of the newest version at or below `target` seen for the key being
+    /// examined, and whether it is a tombstone
+    pub newest: Option<(Vec<u8>, bool)>,
+    /// Whether the history is done and the steps now collect unreferenced blob chunks
+    #[serde(default)]
+    pub collecting_chunks: bool,
+    /// Last blob chunk key examined
+    #[serde(default)]
+    pub chunks_after: Option<Vec<u8>>,
+}
+
+/// Revision counters of the KV store and whether a compaction is still in progress.
+#[derive(Debug, Clone)]
+pub struct CompactionState {
+    pub revision: u64,
+    pub compacted_revision: u64,
+    pub in_progress: bool,
+}
+
+/// Prefix of every history entry of `key`. The key length is included so that a key is never a
+/// prefix of another key's history.
+pub fn history_prefix(key: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(4 + key.len() + 8);
+    prefix.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(key);
+    prefix
+}
+
+/// History key of `key` at `revision`: `len(key) | key | revision`, all big endian.
+pub fn history_key(key: &[u8], revision: u64) -> Vec<u8> {
+    let mut history_key = history_prefix(key);
+    history_key.extend_from_slice(&revision.to_be_bytes());
+    history_key
+}
+
+/// Split a history key back into the user key and revision.
+pub fn parse_history_key(history_key: &[u8]) -> Option<(&[u8], u64)> {
+    let len = u32::from_be_bytes(history_key.get(..4)?.try_into().ok()?) as usize;
+    let key = history_key.get(4..4 + len)?;
+    let revision = u64::from_be_bytes(history_key.get(4 + len..)?.try_into().ok()?);
+    Some((key, revision))
+}
+
+/// Smallest key greater than every key starting with `prefix`, or an empty vector when there
+/// is none (the prefix is empty or all 0xFF).
+pub fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xFF {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    Vec::new()
+}
+
+/// Read a counter stored in `sm_kv_meta`, defaulting to 0.
+pub fn read_kv_meta(db: &DB, name: &str) -> Result<u64, io::Error> {
+    let sm_kv_meta = get_cf_handle(db, "sm_kv_meta")?;
+    match db.get_cf(sm_kv_meta, name).map_err(rocksdb_err_to_io)? {
+        Some(bytes) => Ok(deserialize::<u64>(&bytes)?),
+        None => Ok(0),
+    }
+}
+
+/// Compaction in progress stored in `sm_kv_meta`, if any.
+pub fn read_compaction(db: &DB) -> Result<Option<CompactionProgress>, io::Error> {
+    let sm_kv_meta = get_cf_handle(db, "sm_kv_meta")?;
+    match db
+        .get_cf(sm_kv_meta, KV_META_COMPACTION)
+        .map_err(rocksdb_err_to_io)?
+    {
+        Some(bytes) => Ok(Some(deserialize::<CompactionProgress>(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Tracks KV state changes within one `apply` batch for correct read-your-writes semantics.
+#[derive(Default)]
+pub struct KVOverlay {
+    /// None = deleted, Some(value) = written
+    pub values: HashMap<Vec<u8>, Option<StoredValue>>,
+    /// Cluster-wide revision, loaded lazily on the first write of the batch
+    pub revision: Option<u64>,
+    /// Index definitions by name, loaded lazily on the first write of the batch
+    pub indexes: Option<BTreeMap<String, IndexDefinition>>,
+    /// Reference counts of blob chunks changed in this batch
+    pub blob_refs: HashMap<Vec<u8>, u64>,
+    /// History entries written in this batch and whether each is a tombstone, or None for
+    /// the ones compaction removed
+    pub history: BTreeMap<Vec<u8>, Option<bool>>,
+    /// Compacted revision, loaded lazily on the first compaction of the batch
+    pub compacted_revision: Option<u64>,
+    /// Compaction in progress, loaded lazily on the first compaction of the batch
+    pub compaction: Option<Option<CompactionProgress>>,
+    /// Changes of the running transaction, see `begin_txn`
+    txn: Option<KVTxn>,
+}
+
+/// Earlier pending state of everything a transaction changed, oldest change first. Transactions
+/// only write keys, so indexes and compaction are left alone.
+#[derive(Default)]
+struct KVTxn {
+    revision: Option<u64>,
+    values: Vec<(Vec<u8>, Option<Option<StoredValue>>)>,
+    blob_refs: Vec<(Vec<u8>, Option<u64>)>,
+    history: Vec<(Vec<u8>, Option<Option<bool>>)>,
+}
+
+impl KVOverlay {
+    /// Current value of `key`, checking the pending state before the database.
+    pub fn get(&self, db: &DB, key: &[u8]) -> Result<Option<StoredValue>, io::Error> {
+        if let Some(pending) = self.values.get(key) {
+            return Ok(pending.clone());
+        }
+
+        let sm_data = get_cf_handle(db, "sm_data")?;
+        match db.get_cf(sm_data, key).map_err(rocksdb_err_to_io)? {
+            Some(bytes) => Ok(Some(deserialize::<StoredValue>(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Latest cluster-wide revision, including the ones allocated in this batch.
+    pub fn latest_revision(&self, db: &DB) -> Result<u64, io::Error> {
+        match self.revision {
+            Some(revision) => Ok(revision),
+            None => read_kv_meta(db, KV_META_REVISION),
+        }
+    }
+
+    /// Compacted revision, checking the pending state before the database.
+    pub fn compacted_revision(&self, db: &DB) -> Result<u64, io::Error> {
+        match self.compacted_revision {
+            Some(compacted_revision) => Ok(compacted_revision),
+            None => read_kv_meta(db, KV_META_COMPACTED_REVISION),
+        }
+    }
+
+    /// Compaction in progress, checking the pending state before the database.
+    pub fn compaction(&self, db: &DB) -> Result<Option<CompactionProgress>, io::Error> {
+        if let Some(compaction) = &self.compaction {
+            return Ok(compaction.clone());
+        }
+        read_compaction(db)
+    }
+
+    /// Record the version `value` of `key` at `revision` in the history.
+    fn put_history(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        key: &[u8],
+        revision: u64,
+        value: Option<&StoredValue>,
+    ) -> Result<(), io::Error> {
+        let sm_history = get_cf_handle(db, "sm_history")?;
+        let history_key = history_key(key, revision);
+        batch.put_cf(sm_history, &history_key, serialize(&value)?);
+        self.set_history(history_key, Some(value.is_none()));
+        Ok(())
+    }
+
+    /// Remove a history entry during compaction.
+    pub fn remove_history(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        history_key: &[u8],
+    ) -> Result<(), io::Error> {
+        let sm_history = get_cf_handle(db, "sm_history")?;
+        batch.delete_cf(sm_history, history_key);
+        self.set_history(history_key.to_vec(), None);
+        Ok(())
+    }
+
+    fn set_value(&mut self, key: Vec<u8>, value: Option<StoredValue>) {
+        let previous = self.values.insert(key.clone(), value);
+        if let Some(txn) = &mut self.txn {
+            txn.values.push((key, previous));
+        }
+    }
+
+    fn set_blob_refs(&mut self, chunk: Vec<u8>, count: u64) {
+        let previous = self.blob_refs.insert(chunk.clone(), count);
+        if let Some(txn) = &mut self.txn {
+            txn.blob_refs.push((chunk, previous));
+        }
+    }
+
+    fn set_history(&mut self, history_key: Vec<u8>, entry: Option<bool>) {
+        let previous = self.history.insert(history_key.clone(), entry);
+        if let Some(txn) = &mut self.txn {
+            txn.history.push((history_key, previous));
+        }
+    }
+
+    /// Start recording the changes of a transaction, so that `rollback_txn` can undo them.
+    pub fn begin_txn(&mut self) {
+        self.txn = Some(KVTxn {
+            revision: self.revision,
+            ..KVTxn::default()
+        });
+    }
+
+    /// Keep the changes of the running transaction.
+    pub fn commit_txn(&mut self) {
+        self.txn = None;
+    }
+
+    /// Undo the changes of the running transaction, newest first.
+    pub fn rollback_txn(&mut self) {
+        let Some(txn) = self.txn.take() else {
+            return;
+        };
+        self.revision = txn.revision;
+        for (key, previous) in txn.values.into_iter().rev() {
+            match previous {
+                Some(previous) => self.values.insert(key, previous),
+                None => self.values.remove(&key),
+            };
+        }
+        for (chunk, previous) in txn.blob_refs.into_iter().rev() {
+            match previous {
+                Some(previous) => self.blob_refs.insert(chunk, previous),
+                None => self.blob_refs.remove(&chunk),
+            };
+        }
+        for (history_key, previous) in txn.history.into_iter().rev() {
+            match previous {
+                Some(previous) => self.history.insert(history_key, previous),
+                None => self.history.remove(&history_key),
+            };
+        }
+    }
+
+    /// Allocate the next cluster-wide revision.
+    pub fn next_revision(&mut self, db: &DB) -> Result<u64, io::Error> {
+        let next = self.latest_revision(db)? + 1;
+        self.revision = Some(next);
+        Ok(next)
+    }
+
+    /// Write `data` to `key` at `revision`, recording the new version in the history. With
+    /// `blob`, `data` is a blob manifest and its chunks gain a reference; the chunks of a
+    /// replaced blob lose one.
+    #[allow(clippy::too_many_arguments)]
+    pub fn put(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        key: &[u8],
+        data: Vec<u8>,
+        blob: bool,
+        revision: u64,
+        current: Option<&StoredValue>,
+    ) -> Result<StoredValue, io::Error> {
+        let sm_data = get_cf_handle(db, "sm_data")?;
+
+        // Retain first, so that chunks shared with the replaced blob are never deleted
+        if blob {
+            self.retain_blob(db, batch, &data)?;
+        }
+
+        let stored_value = match current {
+            Some(current) => StoredValue {
+                revision,
+                data,
+                create_revision: current.create_revision,
+                version: current.version + 1,
+                blob,
+            },
+            None => StoredValue {
+                revision,
+                data,
+                create_revision: revision,
+                version: 1,
+                blob,
+            },
+        };
+
+        self.update_indexes(db, batch, key, current, Some(&stored_value))?;
+        // Release after indexing, which may still need the replaced blob's chunks
+        if let Some(current) = current.filter(|current| current.blob) {
+            self.release_blob(db, batch, &current.data, revision)?;
+        }
+        batch.put_cf(sm_data, key, serialize(&stored_value)?);
+        self.put_history(db, batch, key, revision, Some(&stored_value))?;
+        self.set_value(key.to_vec(), Some(stored_value.clone()));
+        Ok(stored_value)
+    }
+
+    /// Delete `key` at `revision`, recording a tombstone in the history.
+    pub fn delete(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        key: &[u8],
+        revision: u64,
+    ) -> Result<(), io::Error> {
+        let sm_data = get_cf_handle(db, "sm_data")?;
+
+        self.remove_from_indexes(db, batch, key)?;
+        self.release_if_blob(db, batch, key, revision)?;
+        batch.delete_cf(sm_data, key);
+        self.put_history(db, batch, key, revision, None)?;
+        self.set_value(key.to_vec(), None);
+        Ok(())
+    }
+
+    /// Delete `keys`, which must all exist and lie in `[start, end)`, at `revision`. The data is
+    /// removed with a single range deletion and each key gets its own history tombstone.
+    pub fn delete_range(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        start: &[u8],
+        end: &[u8],
+        keys: &[Vec<u8>],
+        revision: u64,
+    ) -> Result<(), io::Error> {
+        let sm_data = get_cf_handle(db, "sm_data")?;
+
+        batch.delete_range_cf(sm_data, start, end);
+        for key in keys {
+            self.remove_from_indexes(db, batch, key)?;
+            self.release_if_blob(db, batch, key, revision)?;
+            self.put_history(db, batch, key, revision, None)?;
+            self.set_value(key.clone(), None);
+        }
+        Ok(())
+    }
+
+    /// Whether every chunk of the blob manifest `data` is stored. A chunk written for this blob
+    /// may have been collected in between if the last blob sharing it was removed.
+    pub fn blob_chunks_present(&self, db: &DB, data: &[u8]) -> Result<bool, io::Error> {
+        let Some(manifest) = BlobManifest::parse(data) else {
+            return Ok(false);
+        };
+        for chunk in &manifest.chunks {
+            if self.get(db, chunk)?.is_none() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Number of stored blobs referencing `chunk`.
+    pub fn blob_ref_count(&self, db: &DB, chunk: &[u8]) -> Result<u64, io::Error> {
+        if let Some(count) = self.blob_refs.get(chunk) {
+            return Ok(*count);
+        }
+        let sm_blob_refs = get_cf_handle(db, "sm_blob_refs")?;
+        match db.get_cf(sm_blob_refs, chunk).map_err(rocksdb_err_to_io)? {
+            Some(bytes) => Ok(deserialize::<u64>(&bytes)?),
+            None => Ok(0),
+        }
+    }
+
+    /// Add a reference to every chunk of the blob manifest `data`.
+    fn retain_blob(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        data: &[u8],
+    ) -> Result<(), io::Error> {
+        let Some(manifest) = BlobManifest::parse(data) else {
+            return Ok(());
+        };
+        let sm_blob_refs = get_cf_handle(db, "sm_blob_refs")?;
+        for chunk in manifest.chunks {
+            let count = self.blob_ref_count(db, &chunk)? + 1;
+            batch.put_cf(sm_blob_refs, &chunk, serialize(&count)?);
+            self.set_blob_refs(chunk, count);
+        }
+        Ok(())
+    }
+
+    /// Drop a reference to every chunk of the blob manifest `data`, deleting the chunks that
+    /// no stored blob references anymore. Reads of the blob at older revisions fail once its
+    /// chunks are gone.
+    fn release_blob(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        data: &[u8],
+        revision: u64,
+    ) -> Result<(), io::Error> {
+        let Some(manifest) = BlobManifest::parse(data) else {
+            return Ok(());
+        };
+        let sm_blob_refs = get_cf_handle(db, "sm_blob_refs")?;
+        for chunk in manifest.chunks {
+            let count = self.blob_ref_count(db, &chunk)?.saturating_sub(1);
+            if count == 0 {
+                batch.delete_cf(sm_blob_refs, &chunk);
+                if self.get(db, &chunk)?.is_some() {
+                    self.delete(db, batch, &chunk, revision)?;
+                }
+            } else {
+                batch.put_cf(sm_blob_refs, &chunk, serialize(&count)?);
+            }
+            self.set_blob_refs(chunk, count);
+        }
+        Ok(())
+    }
+
+    /// Release the chunks of the value at `key` if it is a blob, as it is about to be deleted.
+    fn release_if_blob(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        key: &[u8],
+        revision: u64,
+    ) -> Result<(), io::Error> {
+        match self.get(db, key)? {
+            Some(current) if current.blob => self.release_blob(db, batch, &current.data, revision),
+            _ => Ok(()),
+        }
+    }
+
+    /// Index definitions, loading them from `sm_index_defs` on first use.
+    pub fn indexes(
+        &mut self,
+        db: &DB,
+    ) -> Result<&mut BTreeMap<String, IndexDefinition>, io::Error> {
+        if self.indexes.is_none() {
+            let sm_index_defs = get_cf_handle(db, "sm_index_defs")?;
+            let mut indexes = BTreeMap::new();
+            for item in db.iterator_cf(sm_index_defs, rocksdb::IteratorMode::Start) {
+                let (name, definition) = item.map_err(rocksdb_err_to_io)?;
+                indexes.insert(
+                    String::from_utf8_lossy(&name).into_owned(),
+                    deserialize::<IndexDefinition>(&definition)?,
+                );
+            }
+            self.indexes = Some(indexes);
+        }
+        Ok(self.indexes.get_or_insert_default())
+    }
+
+    /// Value of `stored` as the client wrote it, reassembling blobs from their chunks. `None`
+    /// when a chunk of the blob is missing.
+    pub fn indexed_data<'a>(
+        &self,
+        db: &DB,
+        stored: &'a StoredValue,
+    ) -> Result<Option<Cow<'a, [u8]>>, io::Error> {
+        if !stored.blob {
+            return Ok(Some(Cow::Borrowed(&stored.data)));
+        }
+        let Some(manifest) = BlobManifest::parse(&stored.data) else {
+            return Ok(None);
+        };
+        let mut data = Vec::with_capacity(manifest.size as usize);
+        for chunk in &manifest.chunks {
+            match self.get(db, chunk)? {
+                Some(chunk) => data.extend(chunk.data),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(Cow::Owned(data)))
+    }
+
+    /// Move the index entries of `key` from the values extracted from `old` to the ones
+    /// extracted from `new`. Blobs are reassembled only when an index covers the key.
+    pub fn update_indexes(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        key: &[u8],
+        old: Option<&StoredValue>,
+        new: Option<&StoredValue>,
+    ) -> Result<(), io::Error> {
+        let sm_index = get_cf_handle(db, "sm_index")?;
+        let definitions: Vec<(String, IndexDefinition)> = self
+            .indexes(db)?
+            .iter()
+            .filter(|(_, definition)| key.starts_with(&definition.key_prefix))
+            .map(|(name, definition)| (name.clone(), definition.clone()))
+            .collect();
+        if definitions.is_empty() {
+            return Ok(());
+        }
+        let old = old
+            .map(|stored| self.indexed_data(db, stored))
+            .transpose()?
+            .flatten();
+        let new = new
+            .map(|stored| self.indexed_data(db, stored))
+            .transpose()?
+            .flatten();
+
+        for (name, definition) in &definitions {
+            let old_value = old.as_deref().and_then(|data| definition.extract(data));
+            let new_value = new.as_deref().and_then(|data| definition.extract(data));
+            if old_value == new_value {
+                continue;
+            }
+            if let Some(value) = old_value {
+                batch.delete_cf(sm_index, index_entry_key(name, &value, key));
+            }
+            if let Some(value) = new_value {
+                batch.put_cf(sm_index, index_entry_key(name, &value, key), key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the index entries of `key`, which is about to be deleted.
+    fn remove_from_indexes(
+        &mut self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+        key: &[u8],
+    ) -> Result<(), io::Error> {
+        // Only look the current value up when some index covers the key
+        let indexed = self
+            .indexes(db)?
+            .values()
+            .any(|definition| key.starts_with(&definition.key_prefix));
+        if !indexed {
+            return Ok(());
+        }
+        let current = self.get(db, key)?;
+        self.update_indexes(db, batch, key, current.as_ref(), None)
+    }
+
+    /// Persist the revision counter if it moved during this batch.
+    pub fn flush(
+        &self,
+        db: &DB,
+        batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+    ) -> Result<(), io::Error> {
+        if let Some(revision) = self.revision {
+            let sm_kv_meta = get_cf_handle(db, "sm_kv_meta")?;
+            batch.put_cf(sm_kv_meta, KV_META_REVISION, serialize(&revision)?);
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+
+use crate::distkv::keyspace::OrderedKey;
+
+/// Secondary index over the values of the keys starting with `key_prefix`. The indexed value is
+/// the field found by following `field` through the MessagePack value: map keys by name and
+/// array elements by position.
+///
+/// Strings, binaries, booleans and integers that fit in an `i64` are indexed, with the same
+/// order-preserving encoding as `OrderedKey` for `String`, `Vec<u8>`, `bool` and `i64`. Values
+/// that are not MessagePack, lack the field or hold another type are left out of the index.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IndexDefinition {
+    pub key_prefix: Vec<u8>,
+    pub field: Vec<String>,
+}
+
+impl IndexDefinition {
+    pub fn new(key_prefix: impl AsRef<[u8]>, field: &[&str]) -> Self {
+        Self {
+            key_prefix: key_prefix.as_ref().to_vec(),
+            field: field.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    /// Encoded index value of `data`, if it has one.
+    pub fn extract(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let value = rmpv::decode::read_value(&mut &data[..]).ok()?;
+
+        let mut current = &value;
+        for name in &self.field {
+            current = match current {
+                rmpv::Value::Map(entries) => entries
+                    .iter()
+                    .find(|(key, _)| key.as_str() == Some(name.as_str()))
+                    .map(|(_, value)| value)?,
+                rmpv::Value::Array(items) => items.get(name.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+
+        let mut encoded = Vec::new();
+        match current {
+            rmpv::Value::String(string) => {
+                string.as_bytes().to_vec().write_ordered(&mut encoded);
+            }
+            rmpv::Value::Binary(bytes) => bytes.write_ordered(&mut encoded),
+            rmpv::Value::Boolean(boolean) => boolean.write_ordered(&mut encoded),
+            rmpv::Value::Integer(integer) => integer.as_i64()?.write_ordered(&mut encoded),
+            _ => return None,
+        }
+        Some(encoded)
+    }
+}
+
+/// Prefix of every entry of the index `name`. The name length is included so that an index
+/// name is never a prefix of another index's entries.
+pub fn index_prefix(name: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(4 + name.len());
+    prefix.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(name.as_bytes());
+    prefix
+}
+
+/// Entry key of `key` in the index `name`: `len(name) | name | value | key`. Encoded values are
+/// self-delimiting, so entries sort by value first and then by key. The entry's value is `key`.
+pub fn index_entry_key(name: &str, value: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut entry_key = index_prefix(name);
+    entry_key.extend_from_slice(value);
+    entry_key.extend_from_slice(key);
+    entry_key
+}
@@ -1,36 +1,164 @@
-mod common;
+pub mod blob;
+pub mod common;
+pub mod index;
+mod operation_batch;
 mod operation_cas;
+mod operation_compact;
+mod operation_create_index;
 mod operation_del;
+mod operation_delete_range;
+mod operation_drop_index;
+mod operation_incr;
+mod operation_put_if_absent;
 mod operation_set;
 
-pub use common::StoredValue;
+pub use common::{KVOverlay, StoredValue};
+pub use operation_batch::operation_batch;
 pub use operation_cas::operation_cas;
+pub use operation_compact::operation_compact;
+pub use operation_create_index::operation_create_index;
 pub use operation_del::operation_del;
+pub use operation_delete_range::operation_delete_range;
+pub use operation_drop_index::operation_drop_index;
+pub use operation_incr::operation_incr;
+pub use operation_put_if_absent::operation_put_if_absent;
 pub use operation_set::operation_set;
 use std::fmt;
+use std::sync::Arc;
 
+use rocksdb::DB;
 use serde::{Deserialize, Serialize};
 
+use crate::raft::store::kv::index::IndexDefinition;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct KVSet {
-    pub key: String,
+    pub key: Vec<u8>,
     pub value: Vec<u8>,
     pub return_previous: bool,
+    /// `value` is a blob manifest whose chunks are already stored
+    #[serde(default)]
+    pub blob: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct KVCas {
-    pub key: String,
+    pub key: Vec<u8>,
     pub expected_revision: u64,
     pub value: Vec<u8>,
     pub return_previous: bool,
+    /// `value` is a blob manifest whose chunks are already stored
+    #[serde(default)]
+    pub blob: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KVDel {
+    pub key: Vec<u8>,
+    /// Only delete if the key is at this revision (0 for a missing key)
+    pub expected_revision: Option<u64>,
+    pub return_previous: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KVPutIfAbsent {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KVIncr {
+    pub key: Vec<u8>,
+    pub delta: i64,
+    pub min: Option<i64>,
+    pub max: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum KVOperation {
     Set(KVSet),
-    Del { key: String },
+    Del(KVDel),
     Cas(KVCas),
+    Compact {
+        revision: u64,
+    },
+    /// Several operations applied in order within one raft entry
+    Batch(Vec<KVOperation>),
+    Incr(KVIncr),
+    /// Delete every key in `[start, end)`; an empty `end` means no upper bound. The number of
+    /// deleted keys is returned when `count` is set.
+    DeleteRange {
+        start: Vec<u8>,
+        end: Vec<u8>,
+        #[serde(default)]
+        count: bool,
+    },
+    /// Store the value only if the key does not exist
+    PutIfAbsent(KVPutIfAbsent),
+    /// Register the index `name`, replacing any index of that name, and index the existing keys
+    CreateIndex {
+        name: String,
+        definition: IndexDefinition,
+    },
+    DropIndex {
+        name: String,
+    },
+}
+
+/// Apply a single KV operation to the state machine batch.
+pub fn apply_kv_operation(
+    op: KVOperation,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut KVOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    match op {
+        KVOperation::Set(kvset) => {
+            operation_set(kvset, db, client_id, seq_id, pending_state, batch)
+        }
+        KVOperation::Del(kvdel) => {
+            operation_del(kvdel, db, client_id, seq_id, pending_state, batch)
+        }
+        KVOperation::Cas(kvcas) => {
+            operation_cas(kvcas, db, client_id, seq_id, pending_state, batch)
+        }
+        KVOperation::Compact { revision } => {
+            operation_compact(revision, db, client_id, seq_id, pending_state, batch)
+        }
+        KVOperation::Batch(ops) => {
+            operation_batch(ops, db, client_id, seq_id, pending_state, batch)
+        }
+        KVOperation::Incr(kvincr) => {
+            operation_incr(kvincr, db, client_id, seq_id, pending_state, batch)
+        }
+        KVOperation::DeleteRange { start, end, count } => operation_delete_range(
+            start,
+            end,
+            count,
+            db,
+            client_id,
+            seq_id,
+            pending_state,
+            batch,
+        ),
+        KVOperation::PutIfAbsent(kvput) => {
+            operation_put_if_absent(kvput, db, client_id, seq_id, pending_state, batch)
+        }
+        KVOperation::CreateIndex { name, definition } => operation_create_index(
+            name,
+            definition,
+            db,
+            client_id,
+            seq_id,
+            pending_state,
+            batch,
+        ),
+        KVOperation::DropIndex { name } => {
+            operation_drop_index(name, db, client_id, seq_id, pending_state, batch)
+        }
+    }
 }
 
 impl fmt::Display for KVOperation {
@@ -40,32 +168,94 @@ impl fmt::Display for KVOperation {
                 key,
                 value,
                 return_previous,
+                ..
             }) => {
                 write!(
                     f,
                     "Set {{ key: {}, value: Vec<u8>[{}], return_previous: {} }}",
-                    key,
+                    String::from_utf8_lossy(key),
                     value.len(),
                     return_previous
                 )
             }
-            KVOperation::Del { key } => {
-                write!(f, "Del {{ key: {} }}", key)
+            KVOperation::Del(KVDel {
+                key,
+                expected_revision,
+                return_previous,
+            }) => {
+                write!(
+                    f,
+                    "Del {{ key: {}, expected_revision: {:?}, return_previous: {} }}",
+                    String::from_utf8_lossy(key),
+                    expected_revision,
+                    return_previous
+                )
             }
             KVOperation::Cas(KVCas {
                 key,
                 expected_revision,
                 value,
                 return_previous,
+                ..
             }) => {
                 write!(
                     f,
                     "Cas {{ key: {}, expected_revision: {}, value: Vec<u8>[{}], return_previous: {} }}",
-                    key,
+                    String::from_utf8_lossy(key),
                     expected_revision,
                     value.len(),
                     return_previous
                 )
+            }
+            KVOperation::Compact { revision } => {
+                write!(f, "Compact {{ revision: {} }}", revision)
+            }
+            KVOperation::Batch(ops) => {
+                write!(f, "Batch {{ ops: Vec<KVOperation>[{}] }}", ops.len())
+            }
+            KVOperation::Incr(KVIncr {
+                key,
+                delta,
+                min,
+                max,
+            }) => {
+                write!(
+                    f,
+                    "Incr {{ key: {}, delta: {}, min: {:?}, max: {:?} }}",
+                    String::from_utf8_lossy(key),
+                    delta,
+                    min,
+                    max
+                )
+            }
+            KVOperation::DeleteRange { start, end, count } => {
+                write!(
+                    f,
+                    "DeleteRange {{ start: {}, end: {}, count: {} }}",
+                    String::from_utf8_lossy(start),
+                    String::from_utf8_lossy(end),
+                    count
+                )
+            }
+            KVOperation::PutIfAbsent(KVPutIfAbsent { key, value }) => {
+                write!(
+                    f,
+                    "PutIfAbsent {{ key: {}, value: Vec<u8>[{}] }}",
+                    String::from_utf8_lossy(key),
+                    value.len()
+                )
+            }
+            KVOperation::CreateIndex { name, definition } => {
+                write!(
+                    f,
+                    "CreateIndex {{ name: {}, key_prefix: {}, field: {:?} }}",
+                    name,
+                    String::from_utf8_lossy(&definition.key_prefix),
+                    definition.field
+                )
+            }
+            KVOperation::DropIndex { name } => {
+                write!(f, "DropIndex {{ name: {} }}", name)
             } // RequestOperation::FIFO(FIFOOperation::Enqueue { values }) => {
               //     format!("Enqueue {{ values: Vec<u8>[{}] }}", values.len())
               // }
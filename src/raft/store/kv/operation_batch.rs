@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    KVOperation, KVResponse, Response, ResponseResult,
+    store::kv::{apply_kv_operation, common::KVOverlay},
+};
+
+/// Apply `ops` in order within the same raft entry. Each operation sees the writes of the ones
+/// before it. A failed CAS does not abort the remaining operations.
+pub fn operation_batch(
+    ops: Vec<KVOperation>,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut KVOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let mut responses = Vec::with_capacity(ops.len());
+
+    for op in ops {
+        match apply_kv_operation(op, db.clone(), client_id, seq_id, pending_state, batch)? {
+            Response::Result {
+                res: ResponseResult::KV(response),
+                ..
+            } => responses.push(response),
+            _ => {
+                return Err(std::io::Error::other(
+                    "Unexpected response to batched KV operation",
+                ));
+            }
+        }
+    }
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::KV(KVResponse::Batch(responses)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::kv::KVCas;
+    use crate::raft::store::test_util::{TestDb, set};
+
+    #[test]
+    fn batched_operations_see_earlier_writes_and_a_failed_cas_does_not_abort() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        let cas = |expected_revision, value: &[u8]| {
+            KVOperation::Cas(KVCas {
+                key: b"a".to_vec(),
+                expected_revision,
+                value: value.to_vec(),
+                return_previous: false,
+                blob: false,
+            })
+        };
+        let responses = match batch.kv(KVOperation::Batch(vec![
+            set(b"a", b"1"),
+            cas(1, b"2"),
+            cas(1, b"3"),
+            set(b"b", b"1"),
+        ])) {
+            ResponseResult::KV(KVResponse::Batch(responses)) => responses,
+            res => panic!("unexpected response {res:?}"),
+        };
+        let cas_results: Vec<_> = responses[1..3]
+            .iter()
+            .map(|response| match response {
+                KVResponse::Cas { success, response } => (*success, response.revision),
+                response => panic!("unexpected response {response:?}"),
+            })
+            .collect();
+        assert_eq!(cas_results, vec![(true, 2), (false, 2)]);
+        assert!(matches!(&responses[3], KVResponse::Set(response) if response.revision == 3));
+        batch.write();
+
+        let batch = test_db.batch();
+        let a = batch.kv.get(&test_db.db(), b"a").unwrap().unwrap();
+        assert_eq!((a.data, a.revision), (b"2".to_vec(), 2));
+    }
+}
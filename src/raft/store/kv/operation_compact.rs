@@ -0,0 +1,445 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    KVResponse, Response, ResponseResult,
+    store::{
+        common::{deserialize, get_cf_handle, rocksdb_err_to_io, serialize},
+        kv::{
+            blob::BLOB_CHUNK_PREFIX,
+            common::{
+                CompactionProgress, KV_META_COMPACTED_REVISION, KV_META_COMPACTION, KVOverlay,
+                StoredValue, parse_history_key,
+            },
+        },
+    },
+};
+
+/// Most history entries or blob chunks examined by one compaction step
+const COMPACT_STEP_ENTRIES: usize = 1024;
+
+/// Drop history that is no longer visible to reads at or after `revision`.
+///
+/// For every key only the newest version at or below `revision` is kept, and it is dropped too
+/// when it is a tombstone. Reads of revisions below the compacted revision fail as soon as this
+/// is applied, but the history is dropped in steps of at most `COMPACT_STEP_ENTRIES` entries:
+/// every compact entry, including one that doesn't move the compacted revision, runs the next
+/// step of the compaction in progress. The leader proposes those steps until it is done.
+///
+/// Once the history is done, the steps delete the blob chunks written at or below `revision`
+/// that no stored blob references, such as the chunks of a blob whose manifest never landed.
+/// A blob write that is still in progress gets `MissingBlobChunks` and writes its chunks again.
+pub fn operation_compact(
+    revision: u64,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut KVOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let sm_kv_meta = get_cf_handle(&db, "sm_kv_meta")?;
+
+    let mut compacted_revision = pending_state.compacted_revision(&db)?;
+    let mut progress = pending_state.compaction(&db)?;
+
+    // Never compact past the latest revision, and never move the compacted revision backwards.
+    // A compaction in progress restarts from the first key with the new target, which is safe
+    // as it only removed entries that are hidden at the new target too.
+    let target = revision.min(pending_state.latest_revision(&db)?);
+    if target > compacted_revision {
+        compacted_revision = target;
+        batch.put_cf(sm_kv_meta, KV_META_COMPACTED_REVISION, serialize(&target)?);
+        pending_state.compacted_revision = Some(target);
+        progress = Some(CompactionProgress {
+            target,
+            resume_after: None,
+            newest: None,
+            collecting_chunks: false,
+            chunks_after: None,
+        });
+    }
+
+    if let Some(mut progress) = progress {
+        let progress = if !progress.collecting_chunks {
+            progress.collecting_chunks = compact_step(&db, batch, pending_state, &mut progress)?;
+            Some(progress)
+        } else {
+            match collect_chunks_step(&db, batch, pending_state, &mut progress)? {
+                true => None,
+                false => Some(progress),
+            }
+        };
+        match &progress {
+            Some(progress) => batch.put_cf(sm_kv_meta, KV_META_COMPACTION, serialize(progress)?),
+            None => batch.delete_cf(sm_kv_meta, KV_META_COMPACTION),
+        }
+        pending_state.compaction = Some(progress);
+    }
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::KV(KVResponse::Compact { compacted_revision }),
+    })
+}
+
+/// Examine up to `COMPACT_STEP_ENTRIES` history entries after the ones already examined, along
+/// with the entries written or removed earlier in this batch, and drop the hidden ones. Returns
+/// whether the whole history was examined.
+fn compact_step(
+    db: &DB,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+    pending_state: &mut KVOverlay,
+    progress: &mut CompactionProgress,
+) -> Result<bool, std::io::Error> {
+    let sm_history = get_cf_handle(db, "sm_history")?;
+    let target = progress.target;
+    let at_or_below_target = |history_key: &[u8]| {
+        parse_history_key(history_key).is_some_and(|(_, revision)| revision <= target)
+    };
+
+    // Entries at or below the target, mapped to whether they are tombstones
+    let mut entries = BTreeMap::new();
+    let mut examined = 0;
+    let mut last_examined = None;
+    let mut done = true;
+    let start = progress.resume_after.clone().unwrap_or_default();
+    for item in db.iterator_cf(
+        sm_history,
+        rocksdb::IteratorMode::From(&start, rocksdb::Direction::Forward),
+    ) {
+        let (history_key, value) = item.map_err(rocksdb_err_to_io)?;
+        if progress.resume_after.as_deref() == Some(history_key.as_ref()) {
+            continue;
+        }
+        if examined >= COMPACT_STEP_ENTRIES {
+            done = false;
+            break;
+        }
+        examined += 1;
+        if at_or_below_target(&history_key) {
+            let is_tombstone = deserialize::<Option<StoredValue>>(&value)?.is_none();
+            entries.insert(history_key.to_vec(), Some(is_tombstone));
+        }
+        last_examined = Some(history_key.to_vec());
+    }
+
+    // Entries of this batch aren't in the database yet, and the ones removed in it still are
+    let lower = match &progress.resume_after {
+        Some(resume_after) => Bound::Excluded(resume_after.clone()),
+        None => Bound::Unbounded,
+    };
+    let upper = match (&last_examined, done) {
+        (Some(last_examined), false) => Bound::Included(last_examined.clone()),
+        _ => Bound::Unbounded,
+    };
+    for (history_key, entry) in pending_state.history.range((lower, upper)) {
+        if at_or_below_target(history_key) {
+            entries.insert(history_key.clone(), *entry);
+        }
+    }
+
+    // History keys sort by user key, then by revision, so the versions of a key are adjacent
+    for (history_key, entry) in entries {
+        let Some(is_tombstone) = entry else {
+            continue;
+        };
+        let Some((key, _)) = parse_history_key(&history_key) else {
+            continue;
+        };
+        if let Some((newest_history_key, newest_is_tombstone)) = progress.newest.take() {
+            let same_key = parse_history_key(&newest_history_key)
+                .is_some_and(|(newest_key, _)| newest_key == key);
+            if same_key || newest_is_tombstone {
+                pending_state.remove_history(db, batch, &newest_history_key)?;
+            }
+        }
+        progress.newest = Some((history_key, is_tombstone));
+    }
+    if last_examined.is_some() {
+        progress.resume_after = last_examined;
+    }
+
+    if done {
+        if let Some((newest_history_key, true)) = progress.newest.take() {
+            pending_state.remove_history(db, batch, &newest_history_key)?;
+        }
+    }
+    Ok(done)
+}
+
+/// Examine up to `COMPACT_STEP_ENTRIES` blob chunks after the ones already examined and delete
+/// those written at or below the target that no stored blob references. Chunks written in this
+/// batch are newer than any blob they could have been orphaned by, so only stored ones are
+/// examined. Returns whether every chunk was examined.
+fn collect_chunks_step(
+    db: &DB,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+    pending_state: &mut KVOverlay,
+    progress: &mut CompactionProgress,
+) -> Result<bool, std::io::Error> {
+    let sm_data = get_cf_handle(db, "sm_data")?;
+
+    let mut chunks = Vec::new();
+    let mut done = true;
+    let start = progress
+        .chunks_after
+        .clone()
+        .unwrap_or_else(|| vec![BLOB_CHUNK_PREFIX]);
+    for item in db.iterator_cf(
+        sm_data,
+        rocksdb::IteratorMode::From(&start, rocksdb::Direction::Forward),
+    ) {
+        let (key, _) = item.map_err(rocksdb_err_to_io)?;
+        if key.first() != Some(&BLOB_CHUNK_PREFIX) {
+            break;
+        }
+        if progress.chunks_after.as_deref() == Some(key.as_ref()) {
+            continue;
+        }
+        if chunks.len() >= COMPACT_STEP_ENTRIES {
+            done = false;
+            break;
+        }
+        chunks.push(key.to_vec());
+    }
+
+    // Every chunk deleted by this step shares one revision
+    let mut delete_revision = None;
+    for chunk in &chunks {
+        // The value may have been removed or rewritten earlier in this batch
+        let Some(stored) = pending_state.get(db, chunk)? else {
+            continue;
+        };
+        if stored.revision > progress.target || pending_state.blob_ref_count(db, chunk)? > 0 {
+            continue;
+        }
+        let revision = match delete_revision {
+            Some(revision) => revision,
+            None => *delete_revision.insert(pending_state.next_revision(db)?),
+        };
+        pending_state.delete(db, batch, chunk, revision)?;
+    }
+    if let Some(last) = chunks.pop() {
+        progress.chunks_after = Some(last);
+    }
+    Ok(done)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::kv::blob::{BlobManifest, chunk_key};
+    use crate::raft::store::kv::common::HistoryRead;
+    use crate::raft::store::kv::{KVDel, KVOperation, KVPutIfAbsent, KVSet};
+    use crate::raft::store::test_util::{TestBatch, TestDb, set};
+
+    fn del(key: &[u8]) -> KVOperation {
+        KVOperation::Del(KVDel {
+            key: key.to_vec(),
+            expected_revision: None,
+            return_previous: false,
+        })
+    }
+
+    /// Apply compact entries until the compaction in progress is done
+    fn compact(batch: &mut TestBatch, revision: u64) -> u64 {
+        let mut compacted = 0;
+        for _ in 0..16 {
+            match batch.kv(KVOperation::Compact { revision }) {
+                ResponseResult::KV(KVResponse::Compact { compacted_revision }) => {
+                    compacted = compacted_revision
+                }
+                res => panic!("unexpected response {res:?}"),
+            }
+            if batch.kv.compaction.as_ref().is_some_and(Option::is_none) {
+                return compacted;
+            }
+        }
+        panic!("compaction did not finish");
+    }
+
+    fn history(test_db: &TestDb) -> Vec<(Vec<u8>, u64)> {
+        let db = test_db.db();
+        let sm_history = get_cf_handle(&db, "sm_history").unwrap();
+        db.iterator_cf(sm_history, rocksdb::IteratorMode::Start)
+            .map(|item| {
+                let (history_key, _) = item.unwrap();
+                let (key, revision) = parse_history_key(&history_key).unwrap();
+                (key.to_vec(), revision)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn revisions_are_shared_by_all_keys_and_history_keeps_every_version() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.kv(set(b"a", b"1"));
+        batch.kv(set(b"b", b"1"));
+        batch.kv(set(b"a", b"2"));
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        let a = sm.get_stored(b"a").await.unwrap().unwrap();
+        assert_eq!((a.revision, a.create_revision, a.version), (3, 1, 2));
+        assert_eq!(sm.get_stored(b"b").await.unwrap().unwrap().revision, 2);
+
+        for (revision, expected) in [(1, b"1"), (2, b"1"), (3, b"2")] {
+            match sm.get_at_revision(b"a", revision).await.unwrap() {
+                HistoryRead::Value(Some(stored)) => assert_eq!(stored.data, expected),
+                read => panic!("unexpected read at {revision}: {read:?}"),
+            }
+        }
+        assert!(matches!(
+            sm.get_at_revision(b"b", 1).await.unwrap(),
+            HistoryRead::Value(None)
+        ));
+    }
+
+    #[tokio::test]
+    async fn compaction_keeps_the_newest_version_at_the_target() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.kv(set(b"a", b"1"));
+        batch.kv(set(b"a", b"2"));
+        batch.kv(set(b"b", b"1"));
+        batch.kv(del(b"b"));
+        batch.kv(set(b"a", b"3"));
+        batch.write();
+
+        let mut batch = test_db.batch();
+        assert_eq!(compact(&mut batch, 4), 4);
+        batch.write();
+
+        assert_eq!(
+            history(&test_db),
+            vec![(b"a".to_vec(), 2), (b"a".to_vec(), 5)]
+        );
+        let sm = test_db.state_machine().await;
+        assert!(matches!(
+            sm.get_at_revision(b"a", 3).await.unwrap(),
+            HistoryRead::Compacted {
+                compacted_revision: 4
+            }
+        ));
+        match sm.get_at_revision(b"a", 4).await.unwrap() {
+            HistoryRead::Value(Some(stored)) => assert_eq!(stored.data, b"2"),
+            read => panic!("unexpected read: {read:?}"),
+        }
+        assert!(matches!(
+            sm.get_at_revision(b"b", 4).await.unwrap(),
+            HistoryRead::Value(None)
+        ));
+        assert!(!sm.compaction_state().await.unwrap().in_progress);
+    }
+
+    #[test]
+    fn compaction_sees_history_written_earlier_in_the_batch() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.kv(set(b"a", b"1"));
+        batch.kv(set(b"a", b"2"));
+        batch.kv(set(b"b", b"1"));
+        batch.kv(del(b"b"));
+        compact(&mut batch, 4);
+        batch.write();
+
+        assert_eq!(history(&test_db), vec![(b"a".to_vec(), 2)]);
+    }
+
+    #[test]
+    fn compaction_never_moves_back_or_past_the_latest_revision() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.kv(set(b"a", b"1"));
+        batch.kv(set(b"a", b"2"));
+        assert_eq!(compact(&mut batch, 10), 2);
+        assert_eq!(compact(&mut batch, 1), 2);
+        batch.write();
+
+        assert_eq!(history(&test_db), vec![(b"a".to_vec(), 2)]);
+    }
+
+    #[tokio::test]
+    async fn compaction_resumes_across_batches() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        for i in 0..COMPACT_STEP_ENTRIES {
+            let key = format!("key-{i:05}");
+            batch.kv(set(key.as_bytes(), b"1"));
+            batch.kv(set(key.as_bytes(), b"2"));
+        }
+        batch.write();
+
+        let target = 2 * COMPACT_STEP_ENTRIES as u64;
+        let mut batch = test_db.batch();
+        batch.kv(KVOperation::Compact { revision: target });
+        batch.write();
+        let sm = test_db.state_machine().await;
+        let state = sm.compaction_state().await.unwrap();
+        assert_eq!(state.compacted_revision, target);
+        assert!(state.in_progress);
+
+        // A write between the steps is left alone, as it is above the target
+        let mut batch = test_db.batch();
+        batch.kv(set(b"key-00000", b"3"));
+        batch.write();
+
+        for _ in 0..4 {
+            let mut batch = test_db.batch();
+            batch.kv(KVOperation::Compact { revision: target });
+            batch.write();
+        }
+        assert!(!sm.compaction_state().await.unwrap().in_progress);
+
+        let history = history(&test_db);
+        assert_eq!(history.len(), COMPACT_STEP_ENTRIES + 1);
+        assert_eq!(history[0], (b"key-00000".to_vec(), 2));
+        assert_eq!(history[1], (b"key-00000".to_vec(), target + 1));
+        assert!(history[2..].iter().all(|(_, revision)| revision % 2 == 0));
+    }
+
+    #[test]
+    fn compaction_collects_chunks_no_blob_references() {
+        let put_chunk = |batch: &mut TestBatch, chunk: &[u8]| {
+            batch.kv(KVOperation::PutIfAbsent(KVPutIfAbsent {
+                key: chunk_key(chunk),
+                value: chunk.to_vec(),
+            }));
+        };
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        put_chunk(&mut batch, b"kept");
+        put_chunk(&mut batch, b"orphan");
+        let manifest = BlobManifest {
+            size: 4,
+            chunks: vec![chunk_key(b"kept")],
+        };
+        batch.kv(KVOperation::Set(KVSet {
+            key: b"blob".to_vec(),
+            value: rmp_serde::to_vec(&manifest).unwrap(),
+            return_previous: false,
+            blob: true,
+        }));
+        batch.write();
+
+        // Written above the target, e.g. by a blob write still in progress
+        let mut batch = test_db.batch();
+        put_chunk(&mut batch, b"recent");
+        batch.write();
+
+        let mut batch = test_db.batch();
+        compact(&mut batch, 3);
+        let db = test_db.db();
+        for (chunk, stored) in [(&b"kept"[..], true), (b"orphan", false), (b"recent", true)] {
+            assert_eq!(
+                batch.kv.get(&db, &chunk_key(chunk)).unwrap().is_some(),
+                stored
+            );
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    KVResponse, Response, ResponseResult,
+    store::{
+        common::{get_cf_handle, rocksdb_err_to_io, serialize},
+        kv::{
+            common::{KVOverlay, prefix_end},
+            index::{IndexDefinition, index_entry_key, index_prefix},
+        },
+    },
+};
+
+/// Register the index `name` and build it from the keys that already exist, including those
+/// written earlier in the same batch. An existing index of that name is replaced, entries and
+/// all. Creating an index does not consume a revision. Returns the number of indexed keys.
+#[allow(clippy::too_many_arguments)]
+pub fn operation_create_index(
+    name: String,
+    definition: IndexDefinition,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut KVOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let sm_data = get_cf_handle(&db, "sm_data")?;
+    let sm_index = get_cf_handle(&db, "sm_index")?;
+    let sm_index_defs = get_cf_handle(&db, "sm_index_defs")?;
+
+    let prefix = index_prefix(&name);
+    batch.delete_range_cf(sm_index, &prefix, prefix_end(&prefix));
+    batch.put_cf(sm_index_defs, name.as_bytes(), serialize(&definition)?);
+
+    let mut keys = Vec::new();
+    for item in db.iterator_cf(
+        sm_data,
+        rocksdb::IteratorMode::From(&definition.key_prefix, rocksdb::Direction::Forward),
+    ) {
+        let (key, _) = item.map_err(rocksdb_err_to_io)?;
+        if !key.starts_with(&definition.key_prefix) {
+            break;
+        }
+        keys.push(key.to_vec());
+    }
+    keys.extend(
+        pending_state
+            .values
+            .keys()
+            .filter(|key| key.starts_with(&definition.key_prefix))
+            .cloned(),
+    );
+    keys.sort();
+    keys.dedup();
+
+    let mut indexed = 0;
+    for key in keys {
+        let Some(stored) = pending_state.get(&db, &key)? else {
+            continue;
+        };
+        let Some(data) = pending_state.indexed_data(&db, &stored)? else {
+            continue;
+        };
+        if let Some(value) = definition.extract(&data) {
+            batch.put_cf(sm_index, index_entry_key(&name, &value, &key), &key);
+            indexed += 1;
+        }
+    }
+
+    pending_state.indexes(&db)?.insert(name, definition);
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::KV(KVResponse::CreateIndex { indexed }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use serde::Serialize;
+
+    use super::*;
+    use crate::distkv::keyspace::OrderedKey;
+    use crate::raft::store::kv::{KVDel, KVOperation};
+    use crate::raft::store::test_util::{TestBatch, TestDb, set};
+
+    #[derive(Serialize)]
+    struct User {
+        status: String,
+        age: i64,
+    }
+
+    fn user(status: &str, age: i64) -> Vec<u8> {
+        rmp_serde::to_vec_named(&User {
+            status: status.into(),
+            age,
+        })
+        .unwrap()
+    }
+
+    fn ordered<K: OrderedKey>(value: K) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        value.write_ordered(&mut encoded);
+        encoded
+    }
+
+    fn create_index(batch: &mut TestBatch, name: &str, field: &str) -> u64 {
+        match batch.kv(KVOperation::CreateIndex {
+            name: name.into(),
+            definition: IndexDefinition::new("user/", &[field]),
+        }) {
+            ResponseResult::KV(KVResponse::CreateIndex { indexed }) => indexed,
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    async fn keys_with(test_db: &TestDb, name: &str, value: Vec<u8>) -> Vec<Vec<u8>> {
+        let sm = test_db.state_machine().await;
+        sm.query_index(
+            name.into(),
+            Bound::Included(value.clone()),
+            Bound::Included(value),
+            None,
+        )
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn indexes_stored_and_pending_keys_under_the_prefix() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.kv(set(b"user/1", &user("active", 30)));
+        batch.kv(set(b"user/2", &user("banned", 20)));
+        batch.kv(set(b"user/3", b"not messagepack \xc1"));
+        batch.kv(set(b"other/1", &user("active", 40)));
+        batch.write();
+
+        let mut batch = test_db.batch();
+        batch.kv(set(b"user/4", &user("active", -5)));
+        assert_eq!(create_index(&mut batch, "by_status", "status"), 3);
+        assert_eq!(create_index(&mut batch, "by_age", "age"), 3);
+        batch.write();
+
+        assert_eq!(
+            keys_with(&test_db, "by_status", ordered("active".to_string())).await,
+            vec![b"user/1".to_vec(), b"user/4".to_vec()]
+        );
+
+        // Integers are ordered, negative ones first
+        let sm = test_db.state_machine().await;
+        let by_age: Vec<_> = sm
+            .query_index("by_age".into(), Bound::Unbounded, Bound::Unbounded, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(
+            by_age,
+            vec![b"user/4".to_vec(), b"user/2".to_vec(), b"user/1".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_after_the_index_move_and_remove_its_entries() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.kv(set(b"user/1", &user("active", 30)));
+        batch.kv(set(b"user/2", &user("active", 20)));
+        create_index(&mut batch, "by_status", "status");
+        batch.kv(set(b"user/1", &user("banned", 30)));
+        batch.kv(KVOperation::Del(KVDel {
+            key: b"user/2".to_vec(),
+            expected_revision: None,
+            return_previous: false,
+        }));
+        batch.write();
+
+        assert!(
+            keys_with(&test_db, "by_status", ordered("active".to_string()))
+                .await
+                .is_empty()
+        );
+        assert_eq!(
+            keys_with(&test_db, "by_status", ordered("banned".to_string())).await,
+            vec![b"user/1".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn dropped_indexes_can_no_longer_be_queried() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.kv(set(b"user/1", &user("active", 30)));
+        create_index(&mut batch, "by_status", "status");
+        assert!(matches!(
+            batch.kv(KVOperation::DropIndex {
+                name: "by_status".into()
+            }),
+            ResponseResult::KV(KVResponse::DropIndex { existed: true })
+        ));
+        // Later writes of the batch no longer maintain it
+        batch.kv(set(b"user/2", &user("active", 30)));
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        assert!(
+            sm.query_index("by_status".into(), Bound::Unbounded, Bound::Unbounded, None)
+                .await
+                .is_err()
+        );
+        let db = test_db.db();
+        let sm_index = get_cf_handle(&db, "sm_index").unwrap();
+        assert_eq!(
+            db.iterator_cf(sm_index, rocksdb::IteratorMode::Start)
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn fields_are_found_through_maps_and_arrays() {
+        let data = rmp_serde::to_vec_named(&(
+            "x",
+            User {
+                status: "active".into(),
+                age: 3,
+            },
+        ))
+        .unwrap();
+        assert_eq!(
+            IndexDefinition::new("", &["1", "status"]).extract(&data),
+            Some(ordered("active".to_string()))
+        );
+        assert_eq!(
+            IndexDefinition::new("", &["0", "status"]).extract(&data),
+            None
+        );
+        assert_eq!(IndexDefinition::new("", &["2"]).extract(&data), None);
+        // Positional structs have no field names
+        let positional = rmp_serde::to_vec(&User {
+            status: "active".into(),
+            age: 3,
+        })
+        .unwrap();
+        assert_eq!(
+            IndexDefinition::new("", &["status"]).extract(&positional),
+            None
+        );
+    }
+}
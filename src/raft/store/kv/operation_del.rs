@@ -3,39 +3,108 @@ use std::sync::Arc;
 use rocksdb::DB;
 
 use crate::raft::{
-    KVResponse, Response, ResponseResult,
-    store::common::{deserialize, get_cf_handle, rocksdb_err_to_io, serialize},
+    DeleteResponse, KVResponse, Response, ResponseResult, SessionToken,
+    store::kv::{KVDel, common::KVOverlay},
 };
 
 pub fn operation_del(
-    key: String,
+    op: KVDel,
     db: Arc<DB>,
     client_id: u64,
     seq_id: Option<u64>,
-    pending_state: &mut std::collections::HashMap<Vec<u8>, Option<(Vec<u8>, u64)>>,
+    pending_state: &mut KVOverlay,
     batch: &mut rocksdb::WriteBatchWithTransaction<false>,
 ) -> Result<crate::raft::Response, std::io::Error> {
-    let key_bytes = key.as_bytes().to_vec();
-    let sm_data = get_cf_handle(&db, "sm_data")?;
-
-    // Check pending state first for read-your-writes semantics
-    let existed = if let Some(pending) = pending_state.get(&key_bytes) {
-        // Value is in pending state from earlier entry in this batch
-        pending.is_some()
+    // Check pending state first for read-your-writes semantics. A missing key has revision 0.
+    let current = pending_state.get(&db, &op.key)?;
+    let current_revision = current.as_ref().map_or(0, |stored| stored.revision);
+    let existed = current.is_some();
+    let prev_value = if op.return_previous {
+        current.as_ref().map(|stored| stored.data.clone())
     } else {
-        // Not in pending state, read from DB
-        db.get_cf(sm_data, &key_bytes)
-            .map_err(rocksdb_err_to_io)?
-            .is_some()
+        None
     };
 
-    // Track this deletion in pending state
-    pending_state.insert(key_bytes.clone(), None);
-    batch.delete_cf(sm_data, &key_bytes);
+    let success = op
+        .expected_revision
+        .is_none_or(|expected_revision| expected_revision == current_revision);
+
+    // Deleting a missing key does not consume a revision
+    let revision = if success && existed {
+        let revision = pending_state.next_revision(&db)?;
+        pending_state.delete(&db, batch, &op.key, revision)?;
+        Some(revision)
+    } else {
+        None
+    };
 
     Ok(Response::Result {
         client_id,
         seq_id,
-        res: ResponseResult::KV(KVResponse::Del { existed }),
+        res: ResponseResult::KV(KVResponse::Del {
+            success,
+            response: DeleteResponse {
+                existed,
+                prev_value,
+                prev_revision: current_revision,
+                revision,
+                token: SessionToken::default(),
+            },
+        }),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::kv::KVOperation;
+    use crate::raft::store::test_util::{TestBatch, TestDb, set};
+
+    fn del(batch: &mut TestBatch, expected_revision: Option<u64>) -> (bool, DeleteResponse) {
+        match batch.kv(KVOperation::Del(KVDel {
+            key: b"a".to_vec(),
+            expected_revision,
+            return_previous: true,
+        })) {
+            ResponseResult::KV(KVResponse::Del { success, response }) => (success, response),
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    #[test]
+    fn deletes_only_at_the_expected_revision() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.kv(set(b"a", b"1"));
+        batch.kv(set(b"a", b"2"));
+
+        let (success, response) = del(&mut batch, Some(1));
+        assert!(!success);
+        assert!(response.existed && response.revision.is_none());
+        assert_eq!(response.prev_revision, 2);
+        assert!(batch.kv.get(&test_db.db(), b"a").unwrap().is_some());
+
+        let (success, response) = del(&mut batch, Some(2));
+        assert!(success);
+        assert_eq!(response.prev_value, Some(b"2".to_vec()));
+        assert_eq!(response.revision, Some(3));
+        batch.write();
+
+        let batch = test_db.batch();
+        assert!(batch.kv.get(&test_db.db(), b"a").unwrap().is_none());
+    }
+
+    #[test]
+    fn missing_keys_match_revision_zero_without_consuming_a_revision() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+
+        let (success, response) = del(&mut batch, Some(0));
+        assert!(success && !response.existed);
+        assert_eq!(response.revision, None);
+
+        let (success, _) = del(&mut batch, Some(1));
+        assert!(!success);
+        assert_eq!(batch.kv.latest_revision(&test_db.db()).unwrap(), 0);
+    }
+}
@@ -0,0 +1,164 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    KVResponse, Response, ResponseResult,
+    store::{
+        common::{get_cf_handle, rocksdb_err_to_io},
+        kv::common::KVOverlay,
+    },
+};
+
+/// Delete every key in `[start, end)` at a single revision. An empty `end` means no upper
+/// bound. The data is removed with one RocksDB range deletion, and every deleted key gets a
+/// history tombstone so that reads at later revisions do not see it. Keys written earlier in
+/// the same batch are included.
+#[allow(clippy::too_many_arguments)]
+pub fn operation_delete_range(
+    start: Vec<u8>,
+    end: Vec<u8>,
+    count: bool,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut KVOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let sm_data = get_cf_handle(&db, "sm_data")?;
+    let in_range = |key: &[u8]| key >= start.as_slice() && (end.is_empty() || key < end.as_slice());
+
+    let mut keys = Vec::new();
+    for item in db.iterator_cf(
+        sm_data,
+        rocksdb::IteratorMode::From(&start, rocksdb::Direction::Forward),
+    ) {
+        let (key, _) = item.map_err(rocksdb_err_to_io)?;
+        if !in_range(&key) {
+            break;
+        }
+        keys.push(key.to_vec());
+    }
+    keys.extend(
+        pending_state
+            .values
+            .keys()
+            .filter(|key| in_range(key))
+            .cloned(),
+    );
+    keys.sort();
+    keys.dedup();
+
+    let mut existing = Vec::with_capacity(keys.len());
+    for key in keys {
+        if pending_state.get(&db, &key)?.is_some() {
+            existing.push(key);
+        }
+    }
+
+    // Deleting an empty range does not consume a revision
+    if let Some(last) = existing.last() {
+        let revision = pending_state.next_revision(&db)?;
+        let range_end = if end.is_empty() {
+            let mut after_last = last.clone();
+            after_last.push(0x00);
+            after_last
+        } else {
+            end.clone()
+        };
+        pending_state.delete_range(&db, batch, &start, &range_end, &existing, revision)?;
+    }
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::KV(KVResponse::DeleteRange {
+            deleted: count.then_some(existing.len() as u64),
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raft::store::kv::KVOperation;
+    use crate::raft::store::kv::common::HistoryRead;
+    use crate::raft::store::test_util::{TestBatch, TestDb, set};
+    use crate::raft::{KVResponse, ResponseResult};
+
+    fn delete_range(batch: &mut TestBatch, start: &[u8], end: &[u8]) -> Option<u64> {
+        match batch.kv(KVOperation::DeleteRange {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            count: true,
+        }) {
+            ResponseResult::KV(KVResponse::DeleteRange { deleted }) => deleted,
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn deletes_stored_and_pending_keys_in_the_range_at_one_revision() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.kv(set(b"a1", b"1"));
+        batch.kv(set(b"b", b"1"));
+        batch.write();
+
+        let mut batch = test_db.batch();
+        batch.kv(set(b"a2", b"1"));
+        assert_eq!(delete_range(&mut batch, b"a", b"b"), Some(2));
+        // Written after the range deletion of the same batch, so it survives it
+        batch.kv(set(b"a3", b"1"));
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        let keys: Vec<_> = sm
+            .get_many_stored(vec![
+                b"a1".to_vec(),
+                b"a2".to_vec(),
+                b"a3".to_vec(),
+                b"b".to_vec(),
+            ])
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|stored| stored.map(|stored| stored.revision))
+            .collect();
+        assert_eq!(keys, vec![None, None, Some(5), Some(2)]);
+
+        // Both deleted keys got a tombstone at the same revision
+        for key in [b"a1", b"a2"] {
+            assert!(matches!(
+                sm.get_at_revision(key, 4).await.unwrap(),
+                HistoryRead::Value(None)
+            ));
+        }
+        assert!(matches!(
+            sm.get_at_revision(b"a2", 3).await.unwrap(),
+            HistoryRead::Value(Some(_))
+        ));
+    }
+
+    #[test]
+    fn an_open_range_deletes_to_the_end_and_an_empty_one_consumes_no_revision() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.kv(set(b"a", b"1"));
+        batch.kv(set(b"b", b"1"));
+        batch.kv(set(b"c", b"1"));
+        batch.write();
+
+        let mut batch = test_db.batch();
+        assert_eq!(delete_range(&mut batch, b"b", b""), Some(2));
+        assert_eq!(delete_range(&mut batch, b"b", b""), Some(0));
+        assert_eq!(delete_range(&mut batch, b"x", b"z"), Some(0));
+        assert_eq!(batch.kv.latest_revision(&test_db.db()).unwrap(), 4);
+        batch.write();
+
+        let batch = test_db.batch();
+        let db = test_db.db();
+        assert!(batch.kv.get(&db, b"a").unwrap().is_some());
+        assert!(batch.kv.get(&db, b"b").unwrap().is_none());
+        assert!(batch.kv.get(&db, b"c").unwrap().is_none());
+    }
+}
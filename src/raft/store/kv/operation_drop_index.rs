@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    KVResponse, Response, ResponseResult,
+    store::{
+        common::get_cf_handle,
+        kv::{
+            common::{KVOverlay, prefix_end},
+            index::index_prefix,
+        },
+    },
+};
+
+/// Remove the index `name` and all of its entries.
+pub fn operation_drop_index(
+    name: String,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut KVOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let sm_index = get_cf_handle(&db, "sm_index")?;
+    let sm_index_defs = get_cf_handle(&db, "sm_index_defs")?;
+
+    let existed = pending_state.indexes(&db)?.remove(&name).is_some();
+    if existed {
+        let prefix = index_prefix(&name);
+        batch.delete_range_cf(sm_index, &prefix, prefix_end(&prefix));
+        batch.delete_cf(sm_index_defs, name.as_bytes());
+    }
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::KV(KVResponse::DropIndex { existed }),
+    })
+}
@@ -0,0 +1,161 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    IncrResult, KVResponse, Response, ResponseResult,
+    store::{
+        common::serialize,
+        kv::{KVIncr, common::KVOverlay},
+    },
+};
+
+/// Add `delta` to the integer stored at `key`, treating a missing key as 0.
+///
+/// Counters are stored as MessagePack integers so they can be read back with `execute::<i64>()`.
+/// The write is rejected, without consuming a revision, when the result would leave the
+/// optional `[min, max]` bounds or overflow.
+pub fn operation_incr(
+    op: KVIncr,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut KVOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    let key_bytes = op.key;
+
+    // Check pending state first for read-your-writes semantics
+    let current = pending_state.get(&db, &key_bytes)?;
+    let current_revision = current.as_ref().map_or(0, |stored| stored.revision);
+    let current_value = match &current {
+        None => Some(0),
+        Some(stored) => rmp_serde::from_slice::<i64>(&stored.data).ok(),
+    };
+
+    let result = match current_value {
+        None => IncrResult::NotAnInteger {
+            revision: current_revision,
+        },
+        Some(current_value) => match current_value.checked_add(op.delta) {
+            Some(value)
+                if op.min.is_none_or(|min| value >= min)
+                    && op.max.is_none_or(|max| value <= max) =>
+            {
+                let new_revision = pending_state.next_revision(&db)?;
+                pending_state.put(
+                    &db,
+                    batch,
+                    &key_bytes,
+                    serialize(&value)?,
+                    false,
+                    new_revision,
+                    current.as_ref(),
+                )?;
+                IncrResult::Applied {
+                    value,
+                    revision: new_revision,
+                }
+            }
+            _ => IncrResult::OutOfRange {
+                value: current_value,
+                revision: current_revision,
+            },
+        },
+    };
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::KV(KVResponse::Incr(result)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::kv::KVOperation;
+    use crate::raft::store::test_util::{TestBatch, TestDb, set};
+
+    fn incr(batch: &mut TestBatch, delta: i64, min: Option<i64>, max: Option<i64>) -> IncrResult {
+        match batch.kv(KVOperation::Incr(KVIncr {
+            key: b"counter".to_vec(),
+            delta,
+            min,
+            max,
+        })) {
+            ResponseResult::KV(KVResponse::Incr(result)) => result,
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    #[test]
+    fn increments_start_from_zero_and_see_earlier_increments_of_the_batch() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        assert!(matches!(
+            incr(&mut batch, 5, None, None),
+            IncrResult::Applied {
+                value: 5,
+                revision: 1
+            }
+        ));
+        assert!(matches!(
+            incr(&mut batch, -7, None, None),
+            IncrResult::Applied {
+                value: -2,
+                revision: 2
+            }
+        ));
+        batch.write();
+
+        let mut batch = test_db.batch();
+        assert!(matches!(
+            incr(&mut batch, 2, None, None),
+            IncrResult::Applied {
+                value: 0,
+                revision: 3
+            }
+        ));
+        let stored = batch.kv.get(&test_db.db(), b"counter").unwrap().unwrap();
+        assert_eq!(rmp_serde::from_slice::<i64>(&stored.data).unwrap(), 0);
+    }
+
+    #[test]
+    fn out_of_range_increments_write_nothing() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        incr(&mut batch, i64::MAX - 1, None, None);
+        assert!(matches!(
+            incr(&mut batch, 2, None, None),
+            IncrResult::OutOfRange {
+                value: v,
+                revision: 1
+            } if v == i64::MAX - 1
+        ));
+        assert!(matches!(
+            incr(&mut batch, -10, Some(i64::MAX - 5), None),
+            IncrResult::OutOfRange { revision: 1, .. }
+        ));
+        assert!(matches!(
+            incr(&mut batch, 1, None, Some(i64::MAX - 1)),
+            IncrResult::OutOfRange { revision: 1, .. }
+        ));
+        // No revision was consumed by the rejected increments
+        assert_eq!(batch.kv.latest_revision(&test_db.db()).unwrap(), 1);
+    }
+
+    #[test]
+    fn non_integer_values_are_left_untouched() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        let text = rmp_serde::to_vec("not a number").unwrap();
+        batch.kv(set(b"counter", &text));
+        assert!(matches!(
+            incr(&mut batch, 1, None, None),
+            IncrResult::NotAnInteger { revision: 1 }
+        ));
+        let stored = batch.kv.get(&test_db.db(), b"counter").unwrap().unwrap();
+        assert_eq!(stored.data, text);
+    }
+}
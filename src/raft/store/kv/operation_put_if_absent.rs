@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use rocksdb::DB;
+
+use crate::raft::{
+    KVResponse, PutIfAbsentResponse, Response, ResponseResult, SessionToken,
+    store::kv::{KVPutIfAbsent, common::KVOverlay},
+};
+
+/// Store the value only if the key does not exist, which is unaffected by the key having
+/// existed and been deleted before. Otherwise the existing value is returned untouched.
+pub fn operation_put_if_absent(
+    op: KVPutIfAbsent,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    pending_state: &mut KVOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    // Check pending state first for read-your-writes semantics
+    let response = match pending_state.get(&db, &op.key)? {
+        Some(current) => PutIfAbsentResponse {
+            inserted: false,
+            existing: Some(current.data),
+            revision: current.revision,
+            token: SessionToken::default(),
+        },
+        None => {
+            let revision = pending_state.next_revision(&db)?;
+            pending_state.put(&db, batch, &op.key, op.value, false, revision, None)?;
+            PutIfAbsentResponse {
+                inserted: true,
+                existing: None,
+                revision,
+                token: SessionToken::default(),
+            }
+        }
+    };
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::KV(KVResponse::PutIfAbsent(response)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::kv::{KVDel, KVOperation};
+    use crate::raft::store::test_util::{TestBatch, TestDb, set};
+
+    fn put_if_absent(batch: &mut TestBatch, value: &[u8]) -> PutIfAbsentResponse {
+        match batch.kv(KVOperation::PutIfAbsent(KVPutIfAbsent {
+            key: b"a".to_vec(),
+            value: value.to_vec(),
+        })) {
+            ResponseResult::KV(KVResponse::PutIfAbsent(response)) => response,
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    #[test]
+    fn only_the_first_put_of_the_batch_is_inserted() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        let first = put_if_absent(&mut batch, b"1");
+        assert!(first.inserted && first.existing.is_none());
+        assert_eq!(first.revision, 1);
+
+        let second = put_if_absent(&mut batch, b"2");
+        assert!(!second.inserted);
+        assert_eq!(second.existing, Some(b"1".to_vec()));
+        assert_eq!(second.revision, 1);
+        batch.write();
+
+        let mut batch = test_db.batch();
+        assert!(!put_if_absent(&mut batch, b"3").inserted);
+        assert_eq!(
+            batch.kv.get(&test_db.db(), b"a").unwrap().unwrap().data,
+            b"1"
+        );
+    }
+
+    #[test]
+    fn a_deleted_key_is_absent_again() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.kv(set(b"a", b"1"));
+        batch.kv(KVOperation::Del(KVDel {
+            key: b"a".to_vec(),
+            expected_revision: None,
+            return_previous: false,
+        }));
+        let response = put_if_absent(&mut batch, b"2");
+        assert!(response.inserted);
+        assert_eq!(response.revision, 3);
+
+        // A new create, so the version count starts over
+        let stored = batch.kv.get(&test_db.db(), b"a").unwrap().unwrap();
+        assert_eq!((stored.create_revision, stored.version), (3, 1));
+    }
+}
@@ -5,9 +5,8 @@ use rocksdb::DB;
 use crate::{
     SetResponse,
     raft::{
-        KVResponse, Response, ResponseResult,
-        store::common::{deserialize, get_cf_handle, rocksdb_err_to_io, serialize},
-        store::kv::{KVSet, common::StoredValue},
+        KVResponse, Response, ResponseResult, SessionToken,
+        store::kv::{KVSet, common::KVOverlay},
     },
 };
 
@@ -16,58 +15,38 @@ pub fn operation_set(
     db: Arc<DB>,
     client_id: u64,
     seq_id: Option<u64>,
-    pending_state: &mut std::collections::HashMap<Vec<u8>, Option<(Vec<u8>, u64)>>,
+    pending_state: &mut KVOverlay,
     batch: &mut rocksdb::WriteBatchWithTransaction<false>,
 ) -> Result<crate::raft::Response, std::io::Error> {
-    let key_bytes = op.key.as_bytes().to_vec();
-    let sm_data = get_cf_handle(&db, "sm_data")?;
-
-    // Get current value and revision (only if return_previous is true)
-    let (prev_value, current_revision) = if op.return_previous {
-        if let Some(pending) = pending_state.get(&key_bytes) {
-            // Value is in pending state from earlier entry in this batch
-            match pending {
-                Some((data, rev)) => (Some(data.clone()), *rev),
-                None => (None, 0),
-            }
-        } else {
-            // Not in pending state, read from DB
-            match db.get_cf(sm_data, &key_bytes).map_err(rocksdb_err_to_io)? {
-                Some(bytes) => {
-                    let stored = deserialize::<StoredValue>(&bytes)?;
-                    (Some(stored.data), stored.revision)
-                }
-                None => (None, 0),
-            }
-        }
+    let key_bytes = op.key;
+
+    if op.blob && !pending_state.blob_chunks_present(&db, &op.value)? {
+        return Ok(Response::Result {
+            client_id,
+            seq_id,
+            res: ResponseResult::KV(KVResponse::MissingBlobChunks),
+        });
+    }
+
+    // Check pending state first for read-your-writes semantics
+    let current = pending_state.get(&db, &key_bytes)?;
+    let prev_value = if op.return_previous {
+        current.as_ref().map(|stored| stored.data.clone())
     } else {
-        // Only get revision, skip fetching the value
-        let current_revision = if let Some(pending) = pending_state.get(&key_bytes) {
-            match pending {
-                Some((_, rev)) => *rev,
-                None => 0,
-            }
-        } else {
-            match db.get_cf(sm_data, &key_bytes).map_err(rocksdb_err_to_io)? {
-                Some(bytes) => deserialize::<StoredValue>(&bytes)?.revision,
-                None => 0,
-            }
-        };
-        (None, current_revision)
-    };
-
-    // Increment revision
-    let new_revision = current_revision + 1;
-
-    // Store with new revision
-    let stored_value = StoredValue {
-        revision: new_revision,
-        data: op.value.clone(),
+        None
     };
-    let stored_bytes = serialize(&stored_value)?;
 
-    batch.put_cf(sm_data, &key_bytes, &stored_bytes);
-    pending_state.insert(key_bytes.clone(), Some((op.value.clone(), new_revision)));
+    // Store with the next cluster-wide revision
+    let new_revision = pending_state.next_revision(&db)?;
+    pending_state.put(
+        &db,
+        batch,
+        &key_bytes,
+        op.value,
+        op.blob,
+        new_revision,
+        current.as_ref(),
+    )?;
 
     Ok(Response::Result {
         client_id: client_id,
@@ -75,6 +54,7 @@ pub fn operation_set(
         res: ResponseResult::KV(KVResponse::Set(SetResponse {
             prev_value,
             revision: new_revision,
+            token: SessionToken::default(),
         })),
     })
 }
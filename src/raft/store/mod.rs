@@ -1,9 +1,12 @@
 pub mod common;
 pub mod fifo;
 pub mod kv;
+pub mod txn;
 
 mod log_store;
 mod state_machine;
+#[cfg(test)]
+pub(crate) mod test_util;
 
 use log_store::RocksLogStore;
 use openraft::RaftTypeConfig;
@@ -15,6 +18,26 @@ use std::io;
 use std::path::Path;
 use std::sync::Arc;
 
+/// Column families owned by the state machine. Their full contents are copied into snapshots.
+pub(crate) const STATE_MACHINE_CFS: &[&str] = &[
+    "sm_data",
+    "sm_history",
+    "sm_kv_meta",
+    "sm_index",
+    "sm_index_defs",
+    "sm_blob_refs",
+    "fifo_queue_meta",
+    "fifo_queue_data",
+    "fifo_inflight",
+    "fifo_inflight_deadlines",
+    "fifo_queue_config",
+    "fifo_delayed",
+    "pqueue_meta",
+    "pqueue_data",
+    "pqueue_order",
+    "pqueue_config",
+];
+
 /// Create a pair of `RocksLogStore` and `RocksStateMachine` that are backed by a same rocks db
 /// instance.
 pub async fn create_rocks_stores<C, P: AsRef<Path>>(
@@ -29,20 +52,19 @@ where
 
     let meta = ColumnFamilyDescriptor::new("meta", Options::default());
     let sm_meta = ColumnFamilyDescriptor::new("sm_meta", Options::default());
-    let sm_data = ColumnFamilyDescriptor::new("sm_data", Options::default());
     let logs = ColumnFamilyDescriptor::new("logs", Options::default());
-    let fifo_queue_meta = ColumnFamilyDescriptor::new("fifo_queue_meta", Options::default());
-    let fifo_queue_data = ColumnFamilyDescriptor::new("fifo_queue_data", Options::default());
+
+    let mut descriptors = vec![meta, sm_meta, logs];
+    descriptors.extend(
+        STATE_MACHINE_CFS
+            .iter()
+            .map(|name| ColumnFamilyDescriptor::new(*name, Options::default())),
+    );
 
     let db_path = db_path.as_ref();
     let snapshot_dir = db_path.join("snapshots");
 
-    let db = DB::open_cf_descriptors(
-        &db_opts,
-        db_path,
-        vec![meta, sm_meta, sm_data, logs, fifo_queue_meta, fifo_queue_data],
-    )
-        .map_err(io::Error::other)?;
+    let db = DB::open_cf_descriptors(&db_opts, db_path, descriptors).map_err(io::Error::other)?;
 
     let db = Arc::new(db);
     Ok((
@@ -4,6 +4,7 @@ use openraft::OptionalSend;
 use std::fs;
 use std::io;
 use std::io::Cursor;
+use std::ops::Bound;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -25,13 +26,30 @@ use serde::Deserialize;
 use serde::Serialize;
 use tokio::task::spawn_blocking;
 
-use crate::raft::FIFOOperation;
-use crate::raft::KVOperation;
+use crate::protocol::ReadQuery;
+use crate::protocol::ReadQueryError;
+use crate::protocol::ReadQueryResult;
 use crate::raft::RequestOperation;
+use crate::raft::store::STATE_MACHINE_CFS;
 use crate::raft::store::common::deserialize;
+use crate::raft::store::common::get_cf_handle;
+use crate::raft::store::common::rocksdb_err_to_io;
 use crate::raft::store::common::serialize;
-use crate::raft::store::fifo::{common::FIFOOverlay, operation_dequeue, operation_enqueue};
-use crate::raft::store::kv::{StoredValue, operation_cas, operation_del, operation_set};
+use crate::raft::store::fifo::{
+    apply_fifo_operation,
+    common::{
+        FIFOOverlay, PriorityItem, PriorityQueueMeta, QueueItem, QueueMeta, QueueNotifier,
+        QueueStats, item_key, parse_priority_suffix, priority_item_key,
+    },
+};
+use crate::raft::store::kv::blob::resolve_blob;
+use crate::raft::store::kv::common::{
+    CompactionState, HistoryRead, KV_META_COMPACTED_REVISION, KV_META_REVISION, history_key,
+    history_prefix, prefix_end, read_compaction, read_kv_meta,
+};
+use crate::raft::store::kv::index::index_prefix;
+use crate::raft::store::kv::{KVOverlay, StoredValue, apply_kv_operation};
+use crate::raft::store::txn::apply_txn;
 use crate::raft::{Response, TypeConfig};
 fn cf_sm_meta<'a>(db: &'a DB) -> &'a rocksdb::ColumnFamily {
     db.cf_handle("sm_meta").unwrap()
@@ -41,13 +59,36 @@ fn cf_sm_data<'a>(db: &'a DB) -> &'a rocksdb::ColumnFamily {
     db.cf_handle("sm_data").unwrap()
 }
 
+fn cf_sm_history<'a>(db: &'a DB) -> &'a rocksdb::ColumnFamily {
+    db.cf_handle("sm_history").unwrap()
+}
+
+/// Reassemble a value read from `snapshot` from chunks read from the same snapshot if it is a
+/// blob.
+fn resolve_stored(
+    db: &DB,
+    snapshot: &rocksdb::Snapshot,
+    stored: StoredValue,
+) -> Result<StoredValue, io::Error> {
+    let cf = cf_sm_data(db);
+    resolve_blob(stored, |key| {
+        match snapshot.get_cf(cf, key).map_err(rocksdb_err_to_io)? {
+            Some(bytes) => Ok(Some(deserialize::<StoredValue>(&bytes)?)),
+            None => Ok(None),
+        }
+    })
+}
+
 /// State machine backed by RocksDB for full persistence.
-/// All application data is stored directly in the `sm_data` column family.
+/// All application data is stored directly in the `sm_data` column family, with every version
+/// of a key also kept in `sm_history` until it is compacted.
 /// Snapshots are persisted to the `snapshot_dir` directory.
 #[derive(Debug, Clone)]
 pub struct RocksStateMachine {
     db: Arc<DB>,
     snapshot_dir: PathBuf,
+    queue_notifier: QueueNotifier,
+    space_notifier: QueueNotifier,
 }
 
 impl RocksStateMachine {
@@ -56,47 +97,603 @@ impl RocksStateMachine {
         snapshot_dir: PathBuf,
     ) -> Result<RocksStateMachine, io::Error> {
         // Validate column families exist at construction time
-        db.cf_handle("sm_meta")
-            .ok_or_else(|| io::Error::other("column family `sm_meta` not found"))?;
-        db.cf_handle("sm_data")
-            .ok_or_else(|| io::Error::other("column family `sm_data` not found"))?;
+        get_cf_handle(&db, "sm_meta")?;
+        for name in STATE_MACHINE_CFS {
+            get_cf_handle(&db, name)?;
+        }
 
         // Create snapshot directory if it doesn't exist
         fs::create_dir_all(&snapshot_dir)?;
 
-        Ok(Self { db, snapshot_dir })
+        Ok(Self {
+            db,
+            snapshot_dir,
+            queue_notifier: QueueNotifier::default(),
+            space_notifier: QueueNotifier::default(),
+        })
+    }
+
+    /// Notifies waiting consumers when items are appended to a FIFO queue
+    pub fn queue_notifier(&self) -> &QueueNotifier {
+        &self.queue_notifier
     }
 
-    /// Get a value from the state machine by key
-    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, io::Error> {
-        self.get_with_revision(key)
-            .await
-            .map(|opt| opt.map(|(data, _)| data))
+    /// Notifies blocked producers when items leave the head of a FIFO queue
+    pub fn space_notifier(&self) -> &QueueNotifier {
+        &self.space_notifier
     }
 
-    /// Get a value and its revision from the state machine by key
-    pub async fn get_with_revision(&self, key: &str) -> Result<Option<(Vec<u8>, u64)>, io::Error> {
+    /// Get the stored value, including its version metadata, from the state machine by key.
+    /// Blobs are reassembled, like in every read below.
+    pub async fn get_stored(&self, key: &[u8]) -> Result<Option<StoredValue>, io::Error> {
         let db = self.db.clone();
-        let key = key.to_string();
+        let key = key.to_vec();
 
         spawn_blocking(move || {
+            let snapshot = db.snapshot();
             let cf = cf_sm_data(&db);
-            let stored = db
-                .get_cf(cf, key.as_bytes())
-                .map_err(|e| io::Error::other(e.to_string()))?;
+            let stored = snapshot.get_cf(cf, &key).map_err(rocksdb_err_to_io)?;
 
             match stored {
                 None => Ok(None),
-                Some(bytes) => {
-                    let stored_value = deserialize::<StoredValue>(&bytes)?;
-                    Ok(Some((stored_value.data, stored_value.revision)))
+                Some(bytes) => Ok(Some(resolve_stored(
+                    &db,
+                    &snapshot,
+                    deserialize::<StoredValue>(&bytes)?,
+                )?)),
+            }
+        })
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?
+    }
+
+    /// Get the stored values of many keys from one consistent snapshot, in the order of `keys`
+    pub async fn get_many_stored(
+        &self,
+        keys: Vec<Vec<u8>>,
+    ) -> Result<Vec<Option<StoredValue>>, io::Error> {
+        let db = self.db.clone();
+
+        spawn_blocking(move || -> Result<Vec<Option<StoredValue>>, io::Error> {
+            let snapshot = db.snapshot();
+            let cf = cf_sm_data(&db);
+
+            snapshot
+                .multi_get_cf(keys.iter().map(|key| (cf, key.as_slice())))
+                .into_iter()
+                .map(|stored| -> Result<Option<StoredValue>, io::Error> {
+                    match stored.map_err(rocksdb_err_to_io)? {
+                        None => Ok(None),
+                        Some(bytes) => Ok(Some(resolve_stored(
+                            &db,
+                            &snapshot,
+                            deserialize::<StoredValue>(&bytes)?,
+                        )?)),
+                    }
+                })
+                .collect()
+        })
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?
+    }
+
+    /// Get the value of a key as of `revision`, i.e. the newest version written at or before it
+    pub async fn get_at_revision(
+        &self,
+        key: &[u8],
+        revision: u64,
+    ) -> Result<HistoryRead, io::Error> {
+        let db = self.db.clone();
+        let key = key.to_vec();
+
+        spawn_blocking(move || {
+            let snapshot = db.snapshot();
+            let compacted_revision = read_kv_meta(&db, KV_META_COMPACTED_REVISION)?;
+            if revision < compacted_revision {
+                return Ok(HistoryRead::Compacted { compacted_revision });
+            }
+
+            let cf = cf_sm_history(&db);
+            let prefix = history_prefix(&key);
+            let seek_key = history_key(&key, revision);
+            let mut iter = snapshot.iterator_cf(
+                cf,
+                rocksdb::IteratorMode::From(&seek_key, rocksdb::Direction::Reverse),
+            );
+
+            match iter.next() {
+                Some(item) => {
+                    let (history_key, value) = item.map_err(rocksdb_err_to_io)?;
+                    if history_key.len() == seek_key.len() && history_key.starts_with(&prefix) {
+                        // Chunks of an older blob are gone once no live value references them
+                        let stored = deserialize::<Option<StoredValue>>(&value)?
+                            .map(|stored| resolve_stored(&db, &snapshot, stored))
+                            .transpose()?;
+                        Ok(HistoryRead::Value(stored))
+                    } else {
+                        Ok(HistoryRead::Value(None))
+                    }
+                }
+                None => Ok(HistoryRead::Value(None)),
+            }
+        })
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?
+    }
+
+    /// Scan keys starting with `prefix` in key order, from `start` up to `end`, returning at
+    /// most `limit` entries. All entries come from one consistent snapshot.
+    pub async fn scan_stored(
+        &self,
+        prefix: Vec<u8>,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, StoredValue)>, io::Error> {
+        let db = self.db.clone();
+
+        spawn_blocking(move || -> Result<Vec<(Vec<u8>, StoredValue)>, io::Error> {
+            let snapshot = db.snapshot();
+            let cf = cf_sm_data(&db);
+
+            let seek_key = match &start {
+                Bound::Included(key) | Bound::Excluded(key) if key > &prefix => key.clone(),
+                _ => prefix.clone(),
+            };
+
+            let mut entries = Vec::new();
+            for item in snapshot.iterator_cf(
+                cf,
+                rocksdb::IteratorMode::From(&seek_key, rocksdb::Direction::Forward),
+            ) {
+                if limit.is_some_and(|limit| entries.len() >= limit) {
+                    break;
+                }
+
+                let (key, value) = item.map_err(rocksdb_err_to_io)?;
+                if !key.starts_with(&prefix) {
+                    break;
+                }
+                if let Bound::Excluded(start) = &start {
+                    if key.as_ref() == start.as_slice() {
+                        continue;
+                    }
+                }
+                let past_end = match &end {
+                    Bound::Included(end) => key.as_ref() > end.as_slice(),
+                    Bound::Excluded(end) => key.as_ref() >= end.as_slice(),
+                    Bound::Unbounded => false,
+                };
+                if past_end {
+                    break;
+                }
+
+                entries.push((
+                    key.to_vec(),
+                    resolve_stored(&db, &snapshot, deserialize::<StoredValue>(&value)?)?,
+                ));
+            }
+            Ok(entries)
+        })
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?
+    }
+
+    /// Keys of the index `name` whose encoded value lies between `start` and `end`, ordered by
+    /// value and then key, with their stored values. At most `limit` entries are returned. All
+    /// entries come from one consistent snapshot.
+    pub async fn query_index(
+        &self,
+        name: String,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, StoredValue)>, io::Error> {
+        let db = self.db.clone();
+
+        spawn_blocking(move || -> Result<Vec<(Vec<u8>, StoredValue)>, io::Error> {
+            let snapshot = db.snapshot();
+            let cf_index_defs = get_cf_handle(&db, "sm_index_defs")?;
+            let cf_index = get_cf_handle(&db, "sm_index")?;
+            let cf_data = cf_sm_data(&db);
+
+            if snapshot
+                .get_cf(cf_index_defs, name.as_bytes())
+                .map_err(rocksdb_err_to_io)?
+                .is_none()
+            {
+                return Err(io::Error::other(format!("index `{}` does not exist", name)));
+            }
+
+            // Entries of one value share the prefix `len(name) | name | value`
+            let prefix = index_prefix(&name);
+            let with_value = |value: &[u8]| [prefix.as_slice(), value].concat();
+            let seek_key = match &start {
+                Bound::Included(value) => with_value(value),
+                Bound::Excluded(value) => prefix_end(&with_value(value)),
+                Bound::Unbounded => prefix.clone(),
+            };
+            let end_key = match &end {
+                Bound::Included(value) => prefix_end(&with_value(value)),
+                Bound::Excluded(value) => with_value(value),
+                Bound::Unbounded => prefix_end(&prefix),
+            };
+
+            let mut entries = Vec::new();
+            for item in snapshot.iterator_cf(
+                cf_index,
+                rocksdb::IteratorMode::From(&seek_key, rocksdb::Direction::Forward),
+            ) {
+                if limit.is_some_and(|limit| entries.len() >= limit) {
+                    break;
+                }
+
+                let (entry_key, key) = item.map_err(rocksdb_err_to_io)?;
+                if entry_key.as_ref() >= end_key.as_slice() {
+                    break;
+                }
+
+                let stored = snapshot.get_cf(cf_data, &key).map_err(rocksdb_err_to_io)?;
+                if let Some(bytes) = stored {
+                    entries.push((
+                        key.to_vec(),
+                        resolve_stored(&db, &snapshot, deserialize::<StoredValue>(&bytes)?)?,
+                    ));
                 }
             }
+            Ok(entries)
         })
         .await
         .map_err(|e| io::Error::other(e.to_string()))?
     }
 
+    /// Latest and compacted revisions of the KV store, and whether a compaction is still
+    /// dropping history
+    pub async fn compaction_state(&self) -> Result<CompactionState, io::Error> {
+        let db = self.db.clone();
+
+        spawn_blocking(move || {
+            Ok(CompactionState {
+                revision: read_kv_meta(&db, KV_META_REVISION)?,
+                compacted_revision: read_kv_meta(&db, KV_META_COMPACTED_REVISION)?,
+                in_progress: read_compaction(&db)?.is_some(),
+            })
+        })
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?
+    }
+
+    /// Up to `limit` items from the head of the queue, oldest first, without removing them
+    pub async fn peek_queue(
+        &self,
+        queue_key: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>, io::Error> {
+        let db = self.db.clone();
+
+        spawn_blocking(move || -> Result<Vec<Vec<u8>>, io::Error> {
+            let snapshot = db.snapshot();
+            let cf_meta = get_cf_handle(&db, "fifo_queue_meta")?;
+            let cf_data = get_cf_handle(&db, "fifo_queue_data")?;
+
+            let meta = match snapshot
+                .get_cf(cf_meta, &queue_key)
+                .map_err(rocksdb_err_to_io)?
+            {
+                Some(bytes) => deserialize::<QueueMeta>(&bytes)?,
+                None => return Ok(Vec::new()),
+            };
+
+            let mut items = Vec::new();
+            for index in (meta.head + 1..=meta.tail).take(limit) {
+                let item = snapshot
+                    .get_cf(cf_data, item_key(&queue_key, index))
+                    .map_err(rocksdb_err_to_io)?;
+                if let Some(bytes) = item {
+                    items.push(deserialize::<QueueItem>(&bytes)?.data);
+                }
+            }
+            Ok(items)
+        })
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?
+    }
+
+    /// Length, counters and oldest item of the queue, from one consistent snapshot
+    pub async fn queue_stats(&self, queue_key: Vec<u8>) -> Result<QueueStats, io::Error> {
+        let db = self.db.clone();
+
+        spawn_blocking(move || -> Result<QueueStats, io::Error> {
+            let snapshot = db.snapshot();
+            let cf_meta = get_cf_handle(&db, "fifo_queue_meta")?;
+            let cf_data = get_cf_handle(&db, "fifo_queue_data")?;
+            let meta = match snapshot
+                .get_cf(cf_meta, &queue_key)
+                .map_err(rocksdb_err_to_io)?
+            {
+                Some(bytes) => deserialize::<QueueMeta>(&bytes)?,
+                None => return Ok(QueueStats::default()),
+            };
+
+            let oldest_enqueued_ms = if meta.tail > meta.head {
+                snapshot
+                    .get_cf(cf_data, item_key(&queue_key, meta.head + 1))
+                    .map_err(rocksdb_err_to_io)?
+                    .map(|bytes| deserialize::<QueueItem>(&bytes))
+                    .transpose()?
+                    .map(|item| item.enqueued_ms)
+                    .filter(|enqueued_ms| *enqueued_ms > 0)
+            } else {
+                None
+            };
+
+            Ok(QueueStats {
+                length: meta.tail.saturating_sub(meta.head),
+                bytes: meta.bytes,
+                in_flight: meta.inflight,
+                delayed: meta.delayed,
+                enqueued_total: meta.tail,
+                dequeued_total: meta.head,
+                oldest_enqueued_ms,
+            })
+        })
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?
+    }
+
+    /// Up to `limit` items of the priority queue with their priorities, highest priority first,
+    /// without removing them
+    pub async fn peek_priority_queue(
+        &self,
+        queue_key: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<(u32, Vec<u8>)>, io::Error> {
+        let db = self.db.clone();
+
+        spawn_blocking(move || -> Result<Vec<(u32, Vec<u8>)>, io::Error> {
+            let snapshot = db.snapshot();
+            let cf_data = get_cf_handle(&db, "pqueue_data")?;
+
+            let mut items = Vec::new();
+            for item in snapshot.iterator_cf(
+                cf_data,
+                rocksdb::IteratorMode::From(&queue_key, rocksdb::Direction::Forward),
+            ) {
+                if items.len() >= limit {
+                    break;
+                }
+                let (key, value) = item.map_err(rocksdb_err_to_io)?;
+                if !key.starts_with(&queue_key) {
+                    break;
+                }
+                // Skip the items of other queues whose key starts with this queue's key
+                if key.len() != queue_key.len() + 12 {
+                    continue;
+                }
+                let (priority, _) = parse_priority_suffix(&key[queue_key.len()..]);
+                items.push((priority, deserialize::<PriorityItem>(&value)?.data));
+            }
+            Ok(items)
+        })
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?
+    }
+
+    /// Length, counters and oldest item of the priority queue, from one consistent snapshot
+    pub async fn priority_queue_stats(&self, queue_key: Vec<u8>) -> Result<QueueStats, io::Error> {
+        let db = self.db.clone();
+
+        spawn_blocking(move || -> Result<QueueStats, io::Error> {
+            let snapshot = db.snapshot();
+            let cf_meta = get_cf_handle(&db, "pqueue_meta")?;
+            let cf_data = get_cf_handle(&db, "pqueue_data")?;
+            let cf_order = get_cf_handle(&db, "pqueue_order")?;
+            let meta = match snapshot
+                .get_cf(cf_meta, &queue_key)
+                .map_err(rocksdb_err_to_io)?
+            {
+                Some(bytes) => deserialize::<PriorityQueueMeta>(&bytes)?,
+                None => return Ok(QueueStats::default()),
+            };
+
+            // The first item in enqueue order is the oldest one
+            let mut oldest_enqueued_ms = None;
+            for entry in snapshot.iterator_cf(
+                cf_order,
+                rocksdb::IteratorMode::From(&queue_key, rocksdb::Direction::Forward),
+            ) {
+                let (key, value) = entry.map_err(rocksdb_err_to_io)?;
+                if !key.starts_with(&queue_key) {
+                    break;
+                }
+                if key.len() != queue_key.len() + 8 || value.len() != 4 {
+                    continue;
+                }
+                let seq = u64::from_be_bytes(key[queue_key.len()..].try_into().unwrap());
+                let priority = u32::from_be_bytes(value.as_ref().try_into().unwrap());
+                oldest_enqueued_ms = snapshot
+                    .get_cf(cf_data, priority_item_key(&queue_key, priority, seq))
+                    .map_err(rocksdb_err_to_io)?
+                    .map(|bytes| deserialize::<PriorityItem>(&bytes))
+                    .transpose()?
+                    .map(|item| item.enqueued_ms);
+                break;
+            }
+
+            Ok(QueueStats {
+                length: meta.length,
+                bytes: meta.bytes,
+                in_flight: 0,
+                delayed: 0,
+                enqueued_total: meta.seq,
+                dequeued_total: meta.seq.saturating_sub(meta.length),
+                oldest_enqueued_ms,
+            })
+        })
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?
+    }
+
+    /// Whether a dequeue, or with `in_flight` a reserve, on the queue at `now_ms` would find an
+    /// item: a waiting one, a scheduled one that is due, or an expired reservation.
+    pub async fn has_deliverable(
+        &self,
+        queue_key: Vec<u8>,
+        in_flight: bool,
+        now_ms: u64,
+    ) -> Result<bool, io::Error> {
+        let db = self.db.clone();
+        let key = queue_key.clone();
+        let waiting = spawn_blocking(move || -> Result<bool, io::Error> {
+            let fifo_queue_meta = get_cf_handle(&db, "fifo_queue_meta")?;
+            let meta = match db
+                .get_cf(fifo_queue_meta, &key)
+                .map_err(rocksdb_err_to_io)?
+            {
+                Some(bytes) => deserialize::<QueueMeta>(&bytes)?,
+                None => QueueMeta::default(),
+            };
+            Ok(meta.tail > meta.head)
+        })
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))??;
+        if waiting {
+            return Ok(true);
+        }
+        Ok(self
+            .next_due_ms(queue_key, in_flight)
+            .await?
+            .is_some_and(|due_ms| due_ms <= now_ms))
+    }
+
+    /// Earliest time, in milliseconds since the Unix epoch, at which an item of the queue
+    /// becomes deliverable without an append: its first scheduled item, and with `in_flight`
+    /// also its first expiring reservation.
+    pub async fn next_due_ms(
+        &self,
+        queue_key: Vec<u8>,
+        in_flight: bool,
+    ) -> Result<Option<u64>, io::Error> {
+        let db = self.db.clone();
+
+        spawn_blocking(move || -> Result<Option<u64>, io::Error> {
+            let mut cf_names = vec!["fifo_delayed"];
+            if in_flight {
+                cf_names.push("fifo_inflight_deadlines");
+            }
+
+            let mut next_due_ms: Option<u64> = None;
+            for cf_name in cf_names {
+                let cf = get_cf_handle(&db, cf_name)?;
+                // Both keys are `queue_key | due_ms | u64`, sorted by due time within the queue
+                for item in db.iterator_cf(
+                    cf,
+                    rocksdb::IteratorMode::From(&queue_key, rocksdb::Direction::Forward),
+                ) {
+                    let (key, _) = item.map_err(rocksdb_err_to_io)?;
+                    if !key.starts_with(&queue_key) {
+                        break;
+                    }
+                    if key.len() == queue_key.len() + 16 {
+                        let due_ms = u64::from_be_bytes(
+                            key[queue_key.len()..queue_key.len() + 8]
+                                .try_into()
+                                .unwrap(),
+                        );
+                        next_due_ms = Some(next_due_ms.map_or(due_ms, |next| next.min(due_ms)));
+                        break;
+                    }
+                }
+            }
+            Ok(next_due_ms)
+        })
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?
+    }
+
+    /// Keys of every FIFO queue, in key order
+    pub async fn list_queues(&self) -> Result<Vec<Vec<u8>>, io::Error> {
+        let db = self.db.clone();
+
+        spawn_blocking(move || -> Result<Vec<Vec<u8>>, io::Error> {
+            let snapshot = db.snapshot();
+            let cf_meta = get_cf_handle(&db, "fifo_queue_meta")?;
+
+            let mut queues = Vec::new();
+            for item in snapshot.iterator_cf(cf_meta, rocksdb::IteratorMode::Start) {
+                let (key, _) = item.map_err(rocksdb_err_to_io)?;
+                queues.push(key.to_vec());
+            }
+            Ok(queues)
+        })
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?
+    }
+
+    /// Execute a read query against the local state machine
+    pub async fn execute_query(&self, query: ReadQuery) -> Result<ReadQueryResult, ReadQueryError> {
+        let other = |e: io::Error| ReadQueryError::Other(e.to_string());
+        match query {
+            ReadQuery::Get {
+                key,
+                at_revision: None,
+            } => Ok(ReadQueryResult::Value(
+                self.get_stored(&key).await.map_err(other)?,
+            )),
+            ReadQuery::Get {
+                key,
+                at_revision: Some(revision),
+            } => match self.get_at_revision(&key, revision).await.map_err(other)? {
+                HistoryRead::Value(value) => Ok(ReadQueryResult::Value(value)),
+                HistoryRead::Compacted { compacted_revision } => {
+                    Err(ReadQueryError::Compacted { compacted_revision })
+                }
+            },
+            ReadQuery::GetMany { keys } => Ok(ReadQueryResult::Values(
+                self.get_many_stored(keys).await.map_err(other)?,
+            )),
+            ReadQuery::Scan {
+                prefix,
+                start,
+                end,
+                limit,
+            } => Ok(ReadQueryResult::Entries(
+                self.scan_stored(prefix, start, end, limit)
+                    .await
+                    .map_err(other)?,
+            )),
+            ReadQuery::Index {
+                name,
+                start,
+                end,
+                limit,
+            } => Ok(ReadQueryResult::Entries(
+                self.query_index(name, start, end, limit)
+                    .await
+                    .map_err(other)?,
+            )),
+            ReadQuery::QueuePeek { queue_key, limit } => Ok(ReadQueryResult::Items(
+                self.peek_queue(queue_key, limit).await.map_err(other)?,
+            )),
+            ReadQuery::QueueStats { queue_key } => Ok(ReadQueryResult::QueueStats(
+                self.queue_stats(queue_key).await.map_err(other)?,
+            )),
+            ReadQuery::ListQueues => Ok(ReadQueryResult::Queues(
+                self.list_queues().await.map_err(other)?,
+            )),
+            ReadQuery::PriorityQueuePeek { queue_key, limit } => {
+                Ok(ReadQueryResult::PriorityItems(
+                    self.peek_priority_queue(queue_key, limit)
+                        .await
+                        .map_err(other)?,
+                ))
+            }
+            ReadQuery::PriorityQueueStats { queue_key } => Ok(ReadQueryResult::QueueStats(
+                self.priority_queue_stats(queue_key).await.map_err(other)?,
+            )),
+        }
+    }
+
     fn cf_sm_meta(&self) -> &rocksdb::ColumnFamily {
         cf_sm_meta(&self.db)
     }
@@ -131,11 +728,18 @@ impl RocksStateMachine {
     }
 }
 
+/// Contents of one state machine column family inside a snapshot
+#[derive(Serialize, Deserialize, Clone)]
+struct SnapshotColumnFamily {
+    name: String,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
 /// Snapshot file format: metadata + data stored together
 #[derive(Serialize, Deserialize)]
 struct SnapshotFile {
     meta: SnapshotMeta<TypeConfig>,
-    data: Vec<(Vec<u8>, Vec<u8>)>,
+    data: Vec<SnapshotColumnFamily>,
 }
 
 impl RaftSnapshotBuilder<TypeConfig> for RocksStateMachine {
@@ -166,17 +770,24 @@ impl RaftSnapshotBuilder<TypeConfig> for RocksStateMachine {
         // Use RocksDB snapshot for consistent point-in-time view
         let db = self.db.clone();
 
-        #[allow(clippy::type_complexity)]
-        let data = spawn_blocking(move || -> Result<Vec<(Vec<u8>, Vec<u8>)>, io::Error> {
+        let data = spawn_blocking(move || -> Result<Vec<SnapshotColumnFamily>, io::Error> {
             let snapshot = db.snapshot();
-            let cf_data = cf_sm_data(&db);
 
-            let mut snapshot_data = Vec::new();
-            let iter = snapshot.iterator_cf(cf_data, rocksdb::IteratorMode::Start);
+            let mut snapshot_data = Vec::with_capacity(STATE_MACHINE_CFS.len());
+            for name in STATE_MACHINE_CFS {
+                let cf = get_cf_handle(&db, name)?;
+
+                let mut entries = Vec::new();
+                let iter = snapshot.iterator_cf(cf, rocksdb::IteratorMode::Start);
+                for item in iter {
+                    let (key, value) = item.map_err(rocksdb_err_to_io)?;
+                    entries.push((key.to_vec(), value.to_vec()));
+                }
 
-            for item in iter {
-                let (key, value) = item.map_err(|e| io::Error::other(e.to_string()))?;
-                snapshot_data.push((key.to_vec(), value.to_vec()));
+                snapshot_data.push(SnapshotColumnFamily {
+                    name: name.to_string(),
+                    entries,
+                });
             }
 
             Ok(snapshot_data)
@@ -227,14 +838,10 @@ impl RaftStateMachine<TypeConfig> for RocksStateMachine {
         let mut responses = Vec::new();
 
         // Track pending state changes within this batch for correct read-your-writes semantics
-        // None = deleted, Some((value, revision)) = written
-        let mut pending_state: std::collections::HashMap<Vec<u8>, Option<(Vec<u8>, u64)>> =
-            std::collections::HashMap::new();
+        let mut pending_state = KVOverlay::default();
 
         // Track FIFO queue state within this batch
-        let mut fifo_overlay = FIFOOverlay {
-            meta: std::collections::HashMap::new(),
-        };
+        let mut fifo_overlay = FIFOOverlay::default();
 
         while let Some((entry, responder)) = entries.try_next().await? {
             let _cf_data = self.cf_sm_data();
@@ -247,50 +854,33 @@ impl RaftStateMachine<TypeConfig> for RocksStateMachine {
             let response = match entry.payload {
                 EntryPayload::Blank => Response::Empty,
                 EntryPayload::Normal(req) => match &req.op {
-                    RequestOperation::KV(KVOperation::Set(kvset)) => operation_set(
-                        kvset.clone(),
+                    RequestOperation::KV(kv_op) => apply_kv_operation(
+                        kv_op.clone(),
                         self.db.clone(),
                         req.client_id,
                         req.seq_id,
                         &mut pending_state,
                         &mut batch,
                     )?,
-                    RequestOperation::KV(KVOperation::Del { key }) => operation_del(
-                        key.clone(),
+                    RequestOperation::FIFO(fifo_op) => apply_fifo_operation(
+                        fifo_op.clone(),
                         self.db.clone(),
                         req.client_id,
                         req.seq_id,
-                        &mut pending_state,
+                        req.time_ms,
+                        &mut fifo_overlay,
                         &mut batch,
                     )?,
-                    RequestOperation::KV(KVOperation::Cas(kvcas)) => operation_cas(
-                        kvcas.clone(),
+                    RequestOperation::Txn(txn) => apply_txn(
+                        txn.clone(),
                         self.db.clone(),
                         req.client_id,
                         req.seq_id,
+                        req.time_ms,
                         &mut pending_state,
+                        &mut fifo_overlay,
                         &mut batch,
                     )?,
-                    RequestOperation::FIFO(FIFOOperation::Enqueue(enqueue_op)) => {
-                        operation_enqueue::operation_enqueue(
-                            enqueue_op.clone(),
-                            self.db.clone(),
-                            req.client_id,
-                            req.seq_id,
-                            &mut fifo_overlay,
-                            &mut batch,
-                        )?
-                    }
-                    RequestOperation::FIFO(FIFOOperation::Dequeue(dequeue_op)) => {
-                        operation_dequeue::operation_dequeue(
-                            dequeue_op.clone(),
-                            self.db.clone(),
-                            req.client_id,
-                            req.seq_id,
-                            &mut fifo_overlay,
-                            &mut batch,
-                        )?
-                    }
                 },
                 EntryPayload::Membership(ref mem) => {
                     last_membership = Some(StoredMembership::new(Some(entry.log_id), mem.clone()));
@@ -303,6 +893,8 @@ impl RaftStateMachine<TypeConfig> for RocksStateMachine {
             }
         }
 
+        pending_state.flush(&self.db, &mut batch)?;
+
         let cf_meta = self.cf_sm_meta();
 
         // Add metadata writes to the batch for atomic commit
@@ -315,15 +907,29 @@ impl RaftStateMachine<TypeConfig> for RocksStateMachine {
         }
 
         // Atomic write of all data + metadata - fail fast before sending any responses
-        self.db
-            .write(batch)
-            .map_err(|e| io::Error::other(e.to_string()))?;
+        self.db.write(batch).map_err(rocksdb_err_to_io)?;
 
         // Only send responses after successful write
         for (responder, response) in responses {
             responder.send(response);
         }
 
+        // Wake consumers waiting for items on the queues that got some
+        for queue_key in &fifo_overlay.pushed {
+            self.queue_notifier.notify(queue_key);
+        }
+        // and producers waiting for room on the queues that lost some
+        for (queue_key, overlay_queue) in &fifo_overlay.meta {
+            if overlay_queue.popped {
+                self.space_notifier.notify(queue_key);
+            }
+        }
+        for (queue_key, overlay_queue) in &fifo_overlay.priority {
+            if overlay_queue.popped {
+                self.space_notifier.notify(queue_key);
+            }
+        }
+
         Ok(())
     }
 
@@ -346,7 +952,7 @@ impl RaftStateMachine<TypeConfig> for RocksStateMachine {
         );
 
         // Deserialize snapshot data
-        let snapshot_data: Vec<(Vec<u8>, Vec<u8>)> = deserialize(snapshot.get_ref())
+        let snapshot_data: Vec<SnapshotColumnFamily> = deserialize(snapshot.get_ref())
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
 
         // Clone data for file writing later
@@ -369,25 +975,26 @@ impl RaftStateMachine<TypeConfig> for RocksStateMachine {
         let db = self.db.clone();
 
         spawn_blocking(move || -> Result<(), io::Error> {
-            let cf_data = db
-                .cf_handle("sm_data")
-                .expect("column family `sm_data` not found");
-            let cf_meta = db
-                .cf_handle("sm_meta")
-                .expect("column family `sm_meta` not found");
+            let cf_meta = get_cf_handle(&db, "sm_meta")?;
 
             let mut batch = rocksdb::WriteBatch::default();
 
-            // Clear existing data in sm_data
-            let iter = db.iterator_cf(cf_data, rocksdb::IteratorMode::Start);
-            for item in iter {
-                let (key, _) = item.map_err(|e| io::Error::other(e.to_string()))?;
-                batch.delete_cf(cf_data, &key);
+            // Clear existing data in every state machine column family
+            for name in STATE_MACHINE_CFS {
+                let cf = get_cf_handle(&db, name)?;
+                let iter = db.iterator_cf(cf, rocksdb::IteratorMode::Start);
+                for item in iter {
+                    let (key, _) = item.map_err(rocksdb_err_to_io)?;
+                    batch.delete_cf(cf, &key);
+                }
             }
 
-            // Restore snapshot data to sm_data
-            for (key, value) in snapshot_data {
-                batch.put_cf(cf_data, &key, &value);
+            // Restore snapshot data
+            for cf_data in snapshot_data {
+                let cf = get_cf_handle(&db, &cf_data.name)?;
+                for (key, value) in cf_data.entries {
+                    batch.put_cf(cf, &key, &value);
+                }
             }
 
             // Restore metadata to sm_meta
@@ -397,11 +1004,9 @@ impl RaftStateMachine<TypeConfig> for RocksStateMachine {
             batch.put_cf(cf_meta, "last_membership", last_membership_bytes);
 
             // Atomic write of all changes
-            db.write(batch)
-                .map_err(|e| io::Error::other(e.to_string()))?;
+            db.write(batch).map_err(rocksdb_err_to_io)?;
 
-            db.flush_wal(true)
-                .map_err(|e| io::Error::other(e.to_string()))
+            db.flush_wal(true).map_err(rocksdb_err_to_io)
         })
         .await
         .map_err(|e| io::Error::other(e.to_string()))??;
@@ -466,3 +1071,93 @@ impl RaftStateMachine<TypeConfig> for RocksStateMachine {
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::raft::store::fifo::{FIFOOperation, FIFOReserve};
+    use crate::raft::store::test_util::{TestDb, enqueue, set};
+
+    #[tokio::test]
+    async fn get_many_stored_returns_values_in_key_order() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.kv(set(b"a", b"1"));
+        batch.kv(set(b"b", b"2"));
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        let values = sm
+            .get_many_stored(vec![b"b".to_vec(), b"missing".to_vec(), b"a".to_vec()])
+            .await
+            .unwrap();
+        let data: Vec<_> = values
+            .into_iter()
+            .map(|stored| stored.map(|stored| stored.data))
+            .collect();
+        assert_eq!(data, vec![Some(b"2".to_vec()), None, Some(b"1".to_vec())]);
+    }
+
+    #[tokio::test]
+    async fn deliverable_items_include_due_reservations_only_for_reserves() {
+        let test_db = TestDb::new();
+        let sm = test_db.state_machine().await;
+        assert!(!sm.has_deliverable(b"q".to_vec(), true, 0).await.unwrap());
+
+        let mut batch = test_db.batch();
+        batch.fifo(enqueue(b"q", &[b"a"]), 0);
+        batch.write();
+        assert!(sm.has_deliverable(b"q".to_vec(), false, 0).await.unwrap());
+
+        let mut batch = test_db.batch();
+        let reserve = FIFOOperation::Reserve(FIFOReserve {
+            queue_key: b"q".to_vec(),
+            count: 1,
+            visibility_timeout_ms: 100,
+        });
+        batch.fifo(reserve, 0);
+        batch.write();
+        assert!(!sm.has_deliverable(b"q".to_vec(), true, 99).await.unwrap());
+        assert!(sm.has_deliverable(b"q".to_vec(), true, 100).await.unwrap());
+        assert!(!sm.has_deliverable(b"q".to_vec(), false, 100).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn queue_introspection_reads_items_and_counters_without_removing_them() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.fifo(enqueue(b"q", &[b"a", b"bb", b"ccc"]), 1000);
+        batch.fifo(enqueue(b"q", &[b"dddd"]), 2000);
+        batch.fifo(enqueue(b"other", &[b"x"]), 1000);
+        let reserve = FIFOOperation::Reserve(FIFOReserve {
+            queue_key: b"q".to_vec(),
+            count: 1,
+            visibility_timeout_ms: 100,
+        });
+        batch.fifo(reserve, 3000);
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        assert_eq!(
+            sm.peek_queue(b"q".to_vec(), 2).await.unwrap(),
+            vec![b"bb".to_vec(), b"ccc".to_vec()]
+        );
+        assert_eq!(
+            sm.list_queues().await.unwrap(),
+            vec![b"other".to_vec(), b"q".to_vec()]
+        );
+
+        let stats = sm.queue_stats(b"q".to_vec()).await.unwrap();
+        assert_eq!((stats.length, stats.bytes, stats.in_flight), (3, 9, 1));
+        assert_eq!((stats.enqueued_total, stats.dequeued_total), (4, 1));
+        assert_eq!(stats.oldest_enqueued_ms, Some(1000));
+
+        let missing = sm.queue_stats(b"missing".to_vec()).await.unwrap();
+        assert_eq!((missing.length, missing.oldest_enqueued_ms), (0, None));
+        assert!(
+            sm.peek_queue(b"missing".to_vec(), 10)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
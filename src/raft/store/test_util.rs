@@ -0,0 +1,173 @@
+//! Helpers for state machine tests: a throwaway database, and a batch of operations applied with
+//! shared overlays and written at once, like one `apply` call.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rocksdb::{ColumnFamilyDescriptor, DB, Options};
+
+use crate::raft::store::RocksStateMachine;
+use crate::raft::store::STATE_MACHINE_CFS;
+use crate::raft::store::fifo::common::FIFOOverlay;
+use crate::raft::store::fifo::{
+    FIFODequeue, FIFOEnqueue, FIFOOperation, FIFOResponse, apply_fifo_operation,
+};
+use crate::raft::store::kv::{KVOperation, KVOverlay, KVSet, apply_kv_operation};
+use crate::raft::store::txn::{Txn, apply_txn};
+use crate::raft::{Response, ResponseResult};
+
+static NEXT_DB: AtomicU64 = AtomicU64::new(0);
+
+/// Database with every column family of the store, removed when dropped
+pub(crate) struct TestDb {
+    path: PathBuf,
+    db: Option<Arc<DB>>,
+}
+
+impl TestDb {
+    pub(crate) fn new() -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "distacean-test-{}-{}",
+            std::process::id(),
+            NEXT_DB.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut db_opts = Options::default();
+        db_opts.create_missing_column_families(true);
+        db_opts.create_if_missing(true);
+
+        let descriptors = ["meta", "sm_meta", "logs"]
+            .iter()
+            .chain(STATE_MACHINE_CFS)
+            .map(|name| ColumnFamilyDescriptor::new(*name, Options::default()));
+        let db = DB::open_cf_descriptors(&db_opts, &path, descriptors).expect("open test db");
+
+        Self {
+            path,
+            db: Some(Arc::new(db)),
+        }
+    }
+
+    pub(crate) fn db(&self) -> Arc<DB> {
+        self.db.clone().expect("test db is open")
+    }
+
+    /// Start a batch on the current state of the database
+    pub(crate) fn batch(&self) -> TestBatch {
+        TestBatch {
+            db: self.db(),
+            kv: KVOverlay::default(),
+            fifo: FIFOOverlay::default(),
+            batch: rocksdb::WriteBatchWithTransaction::<false>::default(),
+        }
+    }
+
+    /// State machine on this database, for the read side
+    pub(crate) async fn state_machine(&self) -> RocksStateMachine {
+        RocksStateMachine::new(self.db(), self.path.join("snapshots"))
+            .await
+            .expect("open test state machine")
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        self.db.take();
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Operations applied in order with the overlays of one `apply` call. Nothing reaches the
+/// database until `write`.
+pub(crate) struct TestBatch {
+    db: Arc<DB>,
+    pub(crate) kv: KVOverlay,
+    pub(crate) fifo: FIFOOverlay,
+    pub(crate) batch: rocksdb::WriteBatchWithTransaction<false>,
+}
+
+impl TestBatch {
+    pub(crate) fn kv(&mut self, op: KVOperation) -> ResponseResult {
+        result(apply_kv_operation(
+            op,
+            self.db.clone(),
+            1,
+            None,
+            &mut self.kv,
+            &mut self.batch,
+        ))
+    }
+
+    pub(crate) fn fifo(&mut self, op: FIFOOperation, now_ms: u64) -> ResponseResult {
+        result(apply_fifo_operation(
+            op,
+            self.db.clone(),
+            1,
+            None,
+            now_ms,
+            &mut self.fifo,
+            &mut self.batch,
+        ))
+    }
+
+    pub(crate) fn txn(&mut self, txn: Txn, now_ms: u64) -> ResponseResult {
+        result(apply_txn(
+            txn,
+            self.db.clone(),
+            1,
+            None,
+            now_ms,
+            &mut self.kv,
+            &mut self.fifo,
+            &mut self.batch,
+        ))
+    }
+
+    /// Dequeue up to `count` items of `queue_key` and return them
+    pub(crate) fn dequeue(&mut self, queue_key: &[u8], count: usize, now_ms: u64) -> Vec<Vec<u8>> {
+        let op = FIFOOperation::Dequeue(FIFODequeue {
+            queue_key: queue_key.to_vec(),
+            count,
+        });
+        match self.fifo(op, now_ms) {
+            ResponseResult::FIFO(FIFOResponse::Dequeue(response)) => response.items,
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    /// Flush the pending state and write the batch
+    pub(crate) fn write(mut self) {
+        self.kv
+            .flush(&self.db, &mut self.batch)
+            .expect("flush pending state");
+        self.db.write(self.batch).expect("write batch");
+    }
+}
+
+fn result(response: Result<Response, std::io::Error>) -> ResponseResult {
+    match response.expect("apply operation") {
+        Response::Result { res, .. } => res,
+        Response::Empty => ResponseResult::Empty,
+    }
+}
+
+/// Plain set of `key` to `value`
+pub(crate) fn set(key: &[u8], value: &[u8]) -> KVOperation {
+    KVOperation::Set(KVSet {
+        key: key.to_vec(),
+        value: value.to_vec(),
+        return_previous: false,
+        blob: false,
+    })
+}
+
+/// Enqueue of `values` for immediate delivery
+pub(crate) fn enqueue(queue_key: &[u8], values: &[&[u8]]) -> FIFOOperation {
+    FIFOOperation::Enqueue(FIFOEnqueue {
+        queue_key: queue_key.to_vec(),
+        values: values.iter().map(|value| value.to_vec()).collect(),
+        deliver_at_ms: None,
+        delay_ms: None,
+    })
+}
@@ -0,0 +1,447 @@
+use std::fmt;
+use std::sync::Arc;
+
+use rocksdb::DB;
+use serde::{Deserialize, Serialize};
+
+use crate::raft::store::common::rocksdb_err_to_io;
+use crate::raft::store::fifo::{
+    FIFOEnqueue, FIFOOperation, FIFOPriorityEnqueue, FIFOResponse, apply_fifo_operation,
+    common::FIFOOverlay,
+};
+use crate::raft::store::kv::{
+    KVCas, KVDel, KVIncr, KVOperation, KVOverlay, KVPutIfAbsent, KVSet, apply_kv_operation,
+};
+use crate::raft::{IncrResult, KVResponse, PutIfAbsentResponse, Response, ResponseResult};
+
+/// Condition on a key, checked before any operation of the transaction is applied
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TxnCompare {
+    /// The key is at this revision, 0 for a missing key
+    Revision { key: Vec<u8>, revision: u64 },
+    /// The key holds exactly this value, `None` for a missing key
+    Value {
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+    },
+}
+
+/// Operation of a transaction. Only writes and enqueues are allowed, as their changes can be
+/// undone when a later operation declines.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TxnOperation {
+    Set(KVSet),
+    Del(KVDel),
+    Cas(KVCas),
+    Incr(KVIncr),
+    PutIfAbsent(KVPutIfAbsent),
+    Enqueue(FIFOEnqueue),
+    PriorityEnqueue(FIFOPriorityEnqueue),
+}
+
+impl fmt::Display for TxnOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxnOperation::Set(KVSet { key, value, .. }) => write!(
+                f,
+                "Set {{ key: {}, value: Vec<u8>[{}] }}",
+                String::from_utf8_lossy(key),
+                value.len()
+            ),
+            TxnOperation::Del(KVDel { key, .. }) => {
+                write!(f, "Del {{ key: {} }}", String::from_utf8_lossy(key))
+            }
+            TxnOperation::Cas(KVCas {
+                key,
+                expected_revision,
+                value,
+                ..
+            }) => write!(
+                f,
+                "Cas {{ key: {}, expected_revision: {}, value: Vec<u8>[{}] }}",
+                String::from_utf8_lossy(key),
+                expected_revision,
+                value.len()
+            ),
+            TxnOperation::Incr(KVIncr { key, delta, .. }) => write!(
+                f,
+                "Incr {{ key: {}, delta: {} }}",
+                String::from_utf8_lossy(key),
+                delta
+            ),
+            TxnOperation::PutIfAbsent(KVPutIfAbsent { key, value }) => write!(
+                f,
+                "PutIfAbsent {{ key: {}, value: Vec<u8>[{}] }}",
+                String::from_utf8_lossy(key),
+                value.len()
+            ),
+            TxnOperation::Enqueue(FIFOEnqueue { values, .. }) => {
+                write!(f, "Enqueue {{ values: Vec<Vec<u8>>[{}] }}", values.len())
+            }
+            TxnOperation::PriorityEnqueue(FIFOPriorityEnqueue { items, .. }) => {
+                write!(f, "PriorityEnqueue {{ items: [{}] }}", items.len())
+            }
+        }
+    }
+}
+
+/// KV and FIFO operations applied in order within one raft entry, only if every condition in
+/// `compare` holds
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Txn {
+    pub compare: Vec<TxnCompare>,
+    pub ops: Vec<TxnOperation>,
+}
+
+impl fmt::Display for Txn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Txn {{ compare: [{}], ops: [", self.compare.len())?;
+        for (i, op) in self.ops.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", op)?;
+        }
+        write!(f, "] }}")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxnResponse {
+    /// Whether every condition held and every operation was applied. Otherwise nothing was
+    /// applied.
+    pub succeeded: bool,
+    /// One response per operation, in order. When an operation declined, the responses stop
+    /// with its own, and when a condition failed they are empty.
+    pub responses: Vec<ResponseResult>,
+    /// Index of the operation that declined
+    #[serde(default)]
+    pub failed_op: Option<usize>,
+}
+
+/// Check the conditions against the pending KV state, then apply every operation to the batch
+/// and the KV and FIFO overlays, so that the transaction lands in the same atomic write as the
+/// rest of the entry. Each operation sees the writes of the ones before it. An operation that
+/// declines, such as a CAS mismatch or an enqueue into a full queue, fails the whole
+/// transaction: the batch rolls back to a save point taken before the first operation and the
+/// overlays undo the changes they logged since, at a cost proportional to the transaction.
+#[allow(clippy::too_many_arguments)]
+pub fn apply_txn(
+    txn: Txn,
+    db: Arc<DB>,
+    client_id: u64,
+    seq_id: Option<u64>,
+    now_ms: u64,
+    kv_state: &mut KVOverlay,
+    fifo_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<crate::raft::Response, std::io::Error> {
+    for compare in &txn.compare {
+        let holds = match compare {
+            TxnCompare::Revision { key, revision } => {
+                kv_state.get(&db, key)?.map_or(0, |stored| stored.revision) == *revision
+            }
+            TxnCompare::Value { key, value } => {
+                kv_state.get(&db, key)?.map(|stored| stored.data) == *value
+            }
+        };
+        if !holds {
+            return Ok(Response::Result {
+                client_id,
+                seq_id,
+                res: ResponseResult::Txn(TxnResponse {
+                    succeeded: false,
+                    responses: Vec::new(),
+                    failed_op: None,
+                }),
+            });
+        }
+    }
+
+    batch.set_save_point();
+    kv_state.begin_txn();
+    fifo_state.begin_txn();
+
+    let mut responses = Vec::with_capacity(txn.ops.len());
+    for (index, op) in txn.ops.into_iter().enumerate() {
+        let response = match op.into_operation() {
+            Ok(kv_op) => apply_kv_operation(kv_op, db.clone(), client_id, seq_id, kv_state, batch),
+            Err(fifo_op) => apply_fifo_operation(
+                fifo_op,
+                db.clone(),
+                client_id,
+                seq_id,
+                now_ms,
+                fifo_state,
+                batch,
+            ),
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                rollback(kv_state, fifo_state, batch)?;
+                return Err(e);
+            }
+        };
+        let res = match response {
+            Response::Result { res, .. } => res,
+            Response::Empty => ResponseResult::Empty,
+        };
+        let declined = is_declined(&res);
+        responses.push(res);
+        if declined {
+            rollback(kv_state, fifo_state, batch)?;
+            return Ok(Response::Result {
+                client_id,
+                seq_id,
+                res: ResponseResult::Txn(TxnResponse {
+                    succeeded: false,
+                    responses,
+                    failed_op: Some(index),
+                }),
+            });
+        }
+    }
+
+    batch.pop_save_point().map_err(rocksdb_err_to_io)?;
+    kv_state.commit_txn();
+    fifo_state.commit_txn();
+
+    Ok(Response::Result {
+        client_id,
+        seq_id,
+        res: ResponseResult::Txn(TxnResponse {
+            succeeded: true,
+            responses,
+            failed_op: None,
+        }),
+    })
+}
+
+impl TxnOperation {
+    /// The KV operation to apply, or the FIFO one.
+    fn into_operation(self) -> Result<KVOperation, FIFOOperation> {
+        match self {
+            TxnOperation::Set(op) => Ok(KVOperation::Set(op)),
+            TxnOperation::Del(op) => Ok(KVOperation::Del(op)),
+            TxnOperation::Cas(op) => Ok(KVOperation::Cas(op)),
+            TxnOperation::Incr(op) => Ok(KVOperation::Incr(op)),
+            TxnOperation::PutIfAbsent(op) => Ok(KVOperation::PutIfAbsent(op)),
+            TxnOperation::Enqueue(op) => Err(FIFOOperation::Enqueue(op)),
+            TxnOperation::PriorityEnqueue(op) => Err(FIFOOperation::PriorityEnqueue(op)),
+        }
+    }
+}
+
+/// Undo every change of the running transaction.
+fn rollback(
+    kv_state: &mut KVOverlay,
+    fifo_state: &mut FIFOOverlay,
+    batch: &mut rocksdb::WriteBatchWithTransaction<false>,
+) -> Result<(), std::io::Error> {
+    kv_state.rollback_txn();
+    fifo_state.rollback_txn();
+    batch.rollback_to_save_point().map_err(rocksdb_err_to_io)
+}
+
+/// Whether the operation was not applied, leaving the state unchanged
+fn is_declined(res: &ResponseResult) -> bool {
+    matches!(
+        res,
+        ResponseResult::KV(
+            KVResponse::Cas { success: false, .. }
+                | KVResponse::Del { success: false, .. }
+                | KVResponse::MissingBlobChunks
+                | KVResponse::Incr(IncrResult::OutOfRange { .. } | IncrResult::NotAnInteger { .. })
+                | KVResponse::PutIfAbsent(PutIfAbsentResponse {
+                    inserted: false,
+                    ..
+                })
+        ) | ResponseResult::FIFO(FIFOResponse::QueueFull { .. })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::store::fifo::FIFOUpdateQueue;
+    use crate::raft::store::fifo::common::DropPolicy;
+    use crate::raft::store::test_util::{TestBatch, TestDb, enqueue, set};
+
+    fn txn(batch: &mut TestBatch, compare: Vec<TxnCompare>, ops: Vec<TxnOperation>) -> TxnResponse {
+        match batch.txn(Txn { compare, ops }, 0) {
+            ResponseResult::Txn(response) => response,
+            res => panic!("unexpected response {res:?}"),
+        }
+    }
+
+    fn txn_set(key: &[u8], value: &[u8]) -> TxnOperation {
+        match set(key, value) {
+            KVOperation::Set(op) => TxnOperation::Set(op),
+            _ => unreachable!(),
+        }
+    }
+
+    fn txn_enqueue(queue_key: &[u8], values: &[&[u8]]) -> TxnOperation {
+        match enqueue(queue_key, values) {
+            FIFOOperation::Enqueue(op) => TxnOperation::Enqueue(op),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn operations_see_the_writes_before_them() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.kv(set(b"k", b"0"));
+
+        let compare = vec![
+            TxnCompare::Value {
+                key: b"k".to_vec(),
+                value: Some(b"0".to_vec()),
+            },
+            TxnCompare::Revision {
+                key: b"missing".to_vec(),
+                revision: 0,
+            },
+        ];
+        let ops = vec![
+            txn_set(b"k", b"1"),
+            TxnOperation::Cas(KVCas {
+                key: b"k".to_vec(),
+                expected_revision: 2,
+                value: b"2".to_vec(),
+                return_previous: false,
+                blob: false,
+            }),
+            txn_enqueue(b"q", &[b"a"]),
+        ];
+        let response = txn(&mut batch, compare, ops);
+        assert!(response.succeeded);
+        assert_eq!(response.responses.len(), 3);
+
+        let stored = batch.kv.get(&test_db.db(), b"k").unwrap().unwrap();
+        assert_eq!((stored.data, stored.revision), (b"2".to_vec(), 3));
+        assert_eq!(batch.dequeue(b"q", 10, 0), vec![b"a".to_vec()]);
+
+        // Failed conditions apply nothing
+        let compare = vec![TxnCompare::Revision {
+            key: b"k".to_vec(),
+            revision: 2,
+        }];
+        let written = batch.batch.len();
+        let response = txn(&mut batch, compare, vec![txn_set(b"k", b"3")]);
+        assert!(!response.succeeded);
+        assert!(response.responses.is_empty());
+        assert_eq!(response.failed_op, None);
+        assert_eq!(batch.batch.len(), written);
+    }
+
+    #[tokio::test]
+    async fn declined_operations_roll_back_the_whole_transaction() {
+        let test_db = TestDb::new();
+        let mut batch = test_db.batch();
+        batch.kv(set(b"k", &rmp_serde::to_vec("old").unwrap()));
+        batch.fifo(enqueue(b"q", &[b"a"]), 0);
+        let limit = FIFOOperation::UpdateQueue(FIFOUpdateQueue {
+            queue_key: b"full".to_vec(),
+            max_length: Some(1),
+            max_bytes: None,
+            message_ttl_ms: None,
+            drop_policy: DropPolicy::Block,
+        });
+        batch.fifo(limit, 0);
+        batch.fifo(enqueue(b"full", &[b"f"]), 0);
+        batch.write();
+
+        let declining = [
+            TxnOperation::Cas(KVCas {
+                key: b"k".to_vec(),
+                expected_revision: 99,
+                value: b"new".to_vec(),
+                return_previous: false,
+                blob: false,
+            }),
+            TxnOperation::Del(KVDel {
+                key: b"k".to_vec(),
+                expected_revision: Some(99),
+                return_previous: false,
+            }),
+            TxnOperation::Incr(KVIncr {
+                key: b"k".to_vec(),
+                delta: 1,
+                min: None,
+                max: None,
+            }),
+            TxnOperation::PutIfAbsent(KVPutIfAbsent {
+                key: b"k".to_vec(),
+                value: b"new".to_vec(),
+            }),
+            txn_enqueue(b"full", &[b"g"]),
+        ];
+
+        let mut batch = test_db.batch();
+        // Written before the transactions, so kept
+        batch.kv(set(b"before", b"1"));
+        batch.fifo(enqueue(b"q", &[b"b"]), 0);
+        let revision = batch.kv.latest_revision(&test_db.db()).unwrap();
+        let written = batch.batch.len();
+        for declining_op in declining {
+            let ops = vec![
+                // Still not an integer for the increment
+                txn_set(b"k", &rmp_serde::to_vec("new").unwrap()),
+                txn_set(b"created", b"1"),
+                txn_enqueue(b"q", &[b"c"]),
+                TxnOperation::PriorityEnqueue(FIFOPriorityEnqueue {
+                    queue_key: b"p".to_vec(),
+                    items: vec![(1, b"x".to_vec())],
+                }),
+                txn_enqueue(b"q", &[b"d"]),
+                declining_op,
+            ];
+            let response = txn(&mut batch, Vec::new(), ops);
+            assert!(!response.succeeded);
+            assert_eq!(response.failed_op, Some(5));
+            assert_eq!(response.responses.len(), 6);
+            assert!(is_declined(&response.responses[5]));
+
+            assert_eq!(batch.batch.len(), written);
+            assert_eq!(batch.kv.latest_revision(&test_db.db()).unwrap(), revision);
+            let db = test_db.db();
+            assert_eq!(batch.kv.get(&db, b"created").unwrap(), None);
+            assert_eq!(batch.fifo.queue(&db, b"q").unwrap().meta.tail, 2);
+            assert!(!batch.fifo.priority_queue(&db, b"p").unwrap().exists);
+            assert!(batch.fifo.pushed.contains(b"q".as_slice()));
+            assert!(!batch.fifo.pushed.contains(b"p".as_slice()));
+        }
+
+        // Revisions go on from where the declined transactions left them
+        let response = txn(&mut batch, Vec::new(), vec![txn_set(b"after", b"1")]);
+        assert!(response.succeeded);
+        assert_eq!(
+            batch
+                .kv
+                .get(&test_db.db(), b"after")
+                .unwrap()
+                .unwrap()
+                .revision,
+            revision + 1
+        );
+        assert_eq!(
+            batch.dequeue(b"q", 10, 0),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+        batch.write();
+
+        let sm = test_db.state_machine().await;
+        let stored = sm.get_stored(b"k").await.unwrap().unwrap();
+        assert_eq!(stored.data, rmp_serde::to_vec("old").unwrap());
+        assert!(sm.get_stored(b"created").await.unwrap().is_none());
+        assert!(sm.get_stored(b"before").await.unwrap().is_some());
+        assert_eq!(
+            sm.peek_priority_queue(b"p".to_vec(), 10).await.unwrap(),
+            Vec::new()
+        );
+        assert_eq!(sm.queue_stats(b"full".to_vec()).await.unwrap().length, 1);
+    }
+}
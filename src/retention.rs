@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use openraft::error::decompose::DecomposeResult;
+
+use crate::raft::{KVOperation, NodeId, Raft, Request, RequestOperation, StateMachineStore};
+use crate::util::now_ms;
+
+/// How often the leader checks whether history should be compacted
+const COMPACTOR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How much KV history the leader keeps before compacting it automatically. History is kept
+/// while either bound still needs it; with neither set, history is only compacted by
+/// `DistKV::compact`.
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    /// Keep at least this many revisions below the latest one
+    pub revisions: Option<u64>,
+    /// Keep the revisions written within this long. Revision times are sampled by the
+    /// current leader, so a new leader keeps everything for this long before compacting.
+    pub time: Option<Duration>,
+}
+
+impl Retention {
+    fn is_enabled(&self) -> bool {
+        self.revisions.is_some() || self.time.is_some()
+    }
+}
+
+/// While this node leads, propose compactions as `retention` allows and the steps of any
+/// compaction still in progress, one raft entry per step.
+pub(crate) async fn run_compactor(
+    node_id: NodeId,
+    raft: Raft,
+    state_machine_store: StateMachineStore,
+    retention: Retention,
+) {
+    // Latest revision seen at each check, oldest first
+    let mut samples: VecDeque<(u64, u64)> = VecDeque::new();
+    loop {
+        tokio::time::sleep(COMPACTOR_INTERVAL).await;
+        if raft.metrics().borrow().current_leader != Some(node_id) {
+            samples.clear();
+            continue;
+        }
+
+        loop {
+            let Ok(state) = state_machine_store.compaction_state().await else {
+                break;
+            };
+            let now = now_ms();
+            if samples
+                .back()
+                .is_none_or(|&(revision, _)| revision != state.revision)
+            {
+                samples.push_back((state.revision, now));
+            }
+
+            let mut target = state.compacted_revision;
+            if retention.is_enabled() {
+                target = retention.revisions.map_or(state.revision, |revisions| {
+                    state.revision.saturating_sub(revisions)
+                });
+                if let Some(time) = retention.time {
+                    let cutoff = now.saturating_sub(time.as_millis() as u64);
+                    // Newest revision that was already the latest one before the cutoff
+                    while samples.len() > 1 && samples[1].1 <= cutoff {
+                        samples.pop_front();
+                    }
+                    let old_enough = match samples.front() {
+                        Some(&(revision, sampled_ms)) if sampled_ms <= cutoff => revision,
+                        _ => 0,
+                    };
+                    target = target.min(old_enough);
+                }
+            }
+            if target <= state.compacted_revision && !state.in_progress {
+                break;
+            }
+            // A new target would restart the compaction in progress, so finish it first
+            if state.in_progress {
+                target = state.compacted_revision;
+            }
+
+            let written = raft
+                .client_write(Request {
+                    client_id: node_id,
+                    seq_id: None,
+                    op: RequestOperation::KV(KVOperation::Compact { revision: target }),
+                    time_ms: now,
+                })
+                .await
+                .decompose();
+            if !matches!(written, Ok(Ok(_))) {
+                break;
+            }
+        }
+    }
+}
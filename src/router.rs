@@ -7,18 +7,38 @@ use openraft::{
 use tokio::net::TcpStream;
 
 use crate::{
+    core::{wait_applied, wait_on_queue},
+    limits::Limits,
     network_tcp::TcpStreamStarter,
     peernet::{PeerConnection, RecvMessage},
-    protocol::{LinearizerData, RequestType},
-    raft::{Raft, TypeConfig},
+    protocol::{ForwardedWriteResult, LinearizerData, ReadQueryError, RequestType},
+    raft::{Raft, StateMachineStore, TypeConfig},
+    util::{LeaderContact, now_ms},
 };
 
 pub fn route_peer_connection_messages(
     peer_con: Arc<PeerConnection<TcpStream, TcpStreamStarter>>,
     raft: Raft,
+    state_machine_store: StateMachineStore,
+    leader_contact: LeaderContact,
+    limits: Limits,
 ) {
     let peer_clone = peer_con.clone();
     let mut read_channel = peer_con.get_read_channel();
+
+    // Forwarded writes of one connection are proposed one at a time, in the order they arrive
+    let (ordered_writes, mut ordered_writes_rx) =
+        tokio::sync::mpsc::unbounded_channel::<(u64, crate::raft::Request)>();
+    let ordered_raft = raft.clone();
+    let ordered_peer = peer_con.clone();
+    let ordered_limits = limits.clone();
+    tokio::spawn(async move {
+        while let Some((req_id, app_req)) = ordered_writes_rx.recv().await {
+            let res_bytes = client_write_response(&ordered_raft, &ordered_limits, app_req).await;
+            ordered_peer.send_response(req_id, res_bytes).await.unwrap();
+        }
+    });
+
     tokio::spawn(async move {
         loop {
             let msg = read_channel.recv().await.unwrap();
@@ -29,10 +49,19 @@ pub fn route_peer_connection_messages(
                         RequestType::AppendEntriesRequest(bytes) => {
                             let req: AppendEntriesRequest<TypeConfig> =
                                 rmp_serde::from_slice(&bytes).unwrap();
+                            let leader_commit =
+                                req.leader_commit.map_or(0, |log_id| log_id.index());
                             let res: Result<
                                 openraft::raft::AppendEntriesResponse<TypeConfig>,
                                 openraft::error::RaftError<TypeConfig>,
                             > = raft.append_entries(req).await;
+                            if matches!(
+                                res,
+                                Ok(openraft::raft::AppendEntriesResponse::Success
+                                    | openraft::raft::AppendEntriesResponse::PartialSuccess(_))
+                            ) {
+                                leader_contact.record(leader_commit);
+                            }
                             let res_bytes = rmp_serde::to_vec(&res).unwrap();
                             peer_clone
                                 .clone()
@@ -68,17 +97,45 @@ pub fn route_peer_connection_messages(
                                 .await
                                 .unwrap();
                         }
-                        RequestType::AppRequest(app_req) => {
-                            let res: Result<
-                                openraft::raft::ClientWriteResponse<TypeConfig>,
-                                openraft::error::ClientWriteError<TypeConfig>,
-                            > = raft.client_write(app_req).await.decompose().unwrap();
-                            let res_bytes = rmp_serde::to_vec(&res).unwrap();
-                            peer_clone
-                                .clone()
-                                .send_response(req_id, res_bytes)
-                                .await
-                                .unwrap();
+                        RequestType::AppRequest(mut app_req) => {
+                            app_req.time_ms = now_ms();
+                            ordered_writes.send((req_id, app_req)).unwrap();
+                        }
+                        RequestType::PipelinedAppRequest(mut app_req) => {
+                            // Handled concurrently so followers can pipeline writes
+                            app_req.time_ms = now_ms();
+                            let raft = raft.clone();
+                            let limits = limits.clone();
+                            let peer_clone = peer_clone.clone();
+                            tokio::spawn(async move {
+                                let res_bytes =
+                                    client_write_response(&raft, &limits, app_req).await;
+                                peer_clone.send_response(req_id, res_bytes).await.unwrap();
+                            });
+                        }
+                        RequestType::WaitOnQueue {
+                            request,
+                            timeout_ms,
+                        } => {
+                            // Long polls must not hold up the other requests of the peer
+                            let raft = raft.clone();
+                            let state_machine_store = state_machine_store.clone();
+                            let limits = limits.clone();
+                            let peer_clone = peer_clone.clone();
+                            tokio::spawn(async move {
+                                let res: ForwardedWriteResult = match limits.check(&request.op) {
+                                    Ok(()) => Ok(wait_on_queue(
+                                        &raft,
+                                        &state_machine_store,
+                                        request,
+                                        std::time::Duration::from_millis(timeout_ms),
+                                    )
+                                    .await),
+                                    Err(e) => Err(e),
+                                };
+                                let res_bytes = rmp_serde::to_vec(&res).unwrap();
+                                peer_clone.send_response(req_id, res_bytes).await.unwrap();
+                            });
                         }
                         RequestType::Linearizer { read_policy } => {
                             let linearizer = raft
@@ -105,6 +162,51 @@ pub fn route_peer_connection_messages(
                                 .await
                                 .unwrap();
                         }
+                        RequestType::Read {
+                            query,
+                            read_policy,
+                            after,
+                        } => {
+                            // Reads may wait on the linearizer, so they don't block the loop
+                            let raft = raft.clone();
+                            let state_machine_store = state_machine_store.clone();
+                            let peer_clone = peer_clone.clone();
+                            tokio::spawn(async move {
+                                let linearized = match read_policy {
+                                    Some(read_policy) => {
+                                        match raft
+                                            .get_read_linearizer(read_policy.into())
+                                            .await
+                                            .decompose()
+                                            .unwrap()
+                                        {
+                                            Ok(lin) => match lin.await_ready(&raft).await {
+                                                Ok(_) => Ok(()),
+                                                Err(e) => Err(ReadQueryError::Other(format!(
+                                                    "Failed to linearize read: {:?}",
+                                                    e
+                                                ))),
+                                            },
+                                            Err(e) => Err(ReadQueryError::Other(format!(
+                                                "Failed to get linearizer: {:?}",
+                                                e
+                                            ))),
+                                        }
+                                    }
+                                    None => Ok(()),
+                                };
+                                let linearized = match (linearized, after) {
+                                    (Ok(()), Some(token)) => wait_applied(&raft, token).await,
+                                    (linearized, _) => linearized,
+                                };
+                                let res = match linearized {
+                                    Ok(()) => state_machine_store.execute_query(query).await,
+                                    Err(e) => Err(e),
+                                };
+                                let res_bytes = rmp_serde::to_vec(&res).unwrap();
+                                peer_clone.send_response(req_id, res_bytes).await.unwrap();
+                            });
+                        }
                     }
                 }
                 RecvMessage::Res { .. } => {}
@@ -115,3 +217,17 @@ pub fn route_peer_connection_messages(
         }
     });
 }
+
+/// Propose a forwarded write that fits this node's limits and encode the answer sent back to the
+/// follower. The follower's own check can't be trusted, as its limits may differ.
+async fn client_write_response(
+    raft: &Raft,
+    limits: &Limits,
+    app_req: crate::raft::Request,
+) -> Vec<u8> {
+    let res: ForwardedWriteResult = match limits.check(&app_req.op) {
+        Ok(()) => Ok(raft.client_write(app_req).await.decompose().unwrap()),
+        Err(e) => Err(e),
+    };
+    rmp_serde::to_vec(&res).unwrap()
+}
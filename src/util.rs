@@ -19,3 +19,76 @@ impl<T> AutoAbort<T> {
         self.0.take().unwrap().await
     }
 }
+
+/// Time of the last successful AppendEntries received from the leader and the highest commit
+/// index it announced, used to bound how stale a follower read can be.
+#[derive(Debug, Clone)]
+pub(crate) struct LeaderContact {
+    started: std::time::Instant,
+    /// Milliseconds since `started`, or `u64::MAX` if the leader never contacted this node
+    last_contact_millis: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    /// Highest commit index announced by a leader
+    leader_commit: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+impl LeaderContact {
+    pub fn new() -> Self {
+        Self {
+            started: std::time::Instant::now(),
+            last_contact_millis: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(u64::MAX)),
+            leader_commit: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        }
+    }
+
+    /// Record a successful AppendEntries carrying the leader's commit index.
+    pub fn record(&self, leader_commit: u64) {
+        let millis = self.started.elapsed().as_millis() as u64;
+        self.last_contact_millis
+            .store(millis, std::sync::atomic::Ordering::Relaxed);
+        // Commit indexes never go back, even across leader changes
+        self.leader_commit
+            .fetch_max(leader_commit, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Highest commit index announced by a leader.
+    pub fn leader_commit(&self) -> u64 {
+        self.leader_commit
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Time since the last contact, `None` if there was none.
+    pub fn elapsed(&self) -> Option<std::time::Duration> {
+        let millis = self
+            .last_contact_millis
+            .load(std::sync::atomic::Ordering::Relaxed);
+        if millis == u64::MAX {
+            return None;
+        }
+        let now = self.started.elapsed().as_millis() as u64;
+        Some(std::time::Duration::from_millis(now.saturating_sub(millis)))
+    }
+}
+
+/// Wall clock time in milliseconds since the Unix epoch.
+pub(crate) fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leader_contact_starts_unknown_and_keeps_the_highest_commit_index() {
+        let contact = LeaderContact::new();
+        assert_eq!(contact.elapsed(), None);
+        assert_eq!(contact.leader_commit(), 0);
+
+        contact.record(10);
+        contact.record(7);
+        assert_eq!(contact.leader_commit(), 10);
+        assert!(contact.elapsed().unwrap() < std::time::Duration::from_secs(60));
+    }
+}